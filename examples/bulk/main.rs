@@ -22,25 +22,16 @@ async fn main() {
     Keys::from_env().unwrap(),
   );
 
-  let mut request_handles = vec![];
-  for _ in 0..100 {
-    let request = ChatSisoRequest::new(
+  let requests = (0..100).map(|_| {
+    ChatSisoRequest::new(
       "You are a helpful assistant.".to_string(),
       "Hi".to_string(),
       ChatModelParams::default(),
-    );
-    request_handles.push(orch.add_request(request.clone()).await);
-  }
+    )
+  });
+  let request_ids = orch.add_requests(requests).await;
 
-  let mut response_handles = vec![];
-  for handle in request_handles {
-    response_handles.push(tokio::spawn({
-      let orch = orch.clone();
-      async move {
-        let response = orch.get_response(handle).await.unwrap();
-        info!("{}", response);
-      }
-    }));
+  for response in orch.await_all(request_ids).await {
+    info!("{}", response.unwrap());
   }
-  futures::future::join_all(response_handles).await;
 }
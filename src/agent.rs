@@ -0,0 +1,230 @@
+//! An agent which answers a prompt by calling tools until the model has
+//! what it needs to reply.
+//!
+//! Every turn of the conversation is sent through the `Orchestrator`, so the
+//! agent follows the same retry, concurrency, and budget policies as any
+//! other request.
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use serde::de::DeserializeOwned;
+use tokio::task::JoinSet;
+
+use crate::{
+  chat::{
+    mimo::ChatMimoRequest, ChatMessage, ChatModelParams, ChatReply, ChatTool,
+    ChatToolCall,
+  },
+  cost::Usage,
+  error::{OrchError, Result},
+  trace::debug,
+  Orchestrator,
+};
+
+type ToolFuture = Pin<Box<dyn Future<Output = Result<String>> + Send>>;
+type ToolHandler = Arc<dyn Fn(serde_json::Value) -> ToolFuture + Send + Sync>;
+
+/// A model with tools it may call, and the handlers that run them.
+///
+/// ```rust,no_run
+/// # use openai_orch::prelude::*;
+/// use openai_orch::agent::Agent;
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize)]
+/// struct Weather {
+///   city: String,
+/// }
+///
+/// # async fn example(orchestrator: Orchestrator) -> Result<(), OrchError> {
+/// let agent = Agent::new(Default::default()).tool(
+///   "get_weather",
+///   "Returns the current weather in a city.",
+///   serde_json::json!({
+///     "type": "object",
+///     "properties": { "city": { "type": "string" } },
+///     "required": ["city"],
+///   }),
+///   |args: Weather| async move { Ok(format!("It is sunny in {}.", args.city)) },
+/// );
+/// let response = agent
+///   .run(&orchestrator, "Should I bring an umbrella in Oslo?".to_string())
+///   .await?;
+/// println!("{}", response.content);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Agent {
+  pub model_params: ChatModelParams,
+  system_prompt:    Option<String>,
+  tools:            Vec<(ChatTool, ToolHandler)>,
+  max_turns:        usize,
+}
+
+/// The final answer of an `Agent`, and how it got there.
+#[derive(Clone, Debug)]
+pub struct AgentResponse {
+  /// The model's final reply.
+  pub content:  String,
+  /// The whole conversation, including the tool calls and their results.
+  pub messages: Vec<ChatMessage>,
+  /// The tokens used across every turn, if the API reported them.
+  pub usage:    Option<Usage>,
+  /// The number of requests sent to the model.
+  pub turns:    usize,
+}
+
+impl Agent {
+  /// Returns an agent without tools, which gives up after 10 turns.
+  pub fn new(model_params: ChatModelParams) -> Self {
+    Self {
+      model_params,
+      system_prompt: None,
+      tools: Vec::new(),
+      max_turns: 10,
+    }
+  }
+
+  /// Sets the system prompt, which is sent before the user's prompt.
+  pub fn with_system_prompt(mut self, system_prompt: String) -> Self {
+    self.system_prompt = Some(system_prompt);
+    self
+  }
+
+  /// Sets how many requests the agent may send before giving up with
+  /// `OrchError::MaxTurnsExceeded`.
+  pub fn with_max_turns(mut self, max_turns: usize) -> Self {
+    self.max_turns = max_turns;
+    self
+  }
+
+  /// Registers a tool. `parameters` is the JSON Schema of the arguments, and
+  /// `handler` is called with the arguments deserialized from the model's
+  /// call. Its output is sent back to the model as the tool's result.
+  ///
+  /// If the arguments don't deserialize or the handler fails, the error is
+  /// sent back to the model instead, so that it can correct itself.
+  pub fn tool<A, F, Fut>(
+    mut self,
+    name: impl Into<String>,
+    description: impl Into<String>,
+    parameters: serde_json::Value,
+    handler: F,
+  ) -> Self
+  where
+    A: DeserializeOwned + Send + 'static,
+    F: Fn(A) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<String>> + Send + 'static,
+  {
+    let name = name.into();
+    let tool =
+      ChatTool::new(name.clone(), Some(description.into()), parameters);
+    let handler: ToolHandler =
+      Arc::new(
+        move |arguments| match serde_json::from_value::<A>(arguments) {
+          Ok(arguments) => Box::pin(handler(arguments)),
+          Err(err) => {
+            let err = OrchError::InvalidResponse(format!(
+              "arguments for tool call to `{}` do not match the target: {}",
+              name, err
+            ));
+            Box::pin(async move { Err(err) })
+          }
+        },
+      );
+    self
+      .tools
+      .retain(|(registered, _)| registered.name != tool.name);
+    self.tools.push((tool, handler));
+    self
+  }
+
+  /// Answers a user prompt, calling tools as the model asks.
+  pub async fn run(
+    &self,
+    orchestrator: &Orchestrator,
+    prompt: String,
+  ) -> Result<AgentResponse> {
+    let messages = self
+      .system_prompt
+      .iter()
+      .map(|prompt| ChatMessage::system(prompt.clone()))
+      .chain([ChatMessage::user(prompt)])
+      .collect();
+    self.run_messages(orchestrator, messages).await
+  }
+
+  /// Continues an existing conversation, calling tools as the model asks.
+  pub async fn run_messages(
+    &self,
+    orchestrator: &Orchestrator,
+    mut messages: Vec<ChatMessage>,
+  ) -> Result<AgentResponse> {
+    let model_params = ChatModelParams {
+      tools: self.tools.iter().map(|(tool, _)| tool.clone()).collect(),
+      ..self.model_params.clone()
+    };
+    let mut usage: Option<Usage> = None;
+
+    for turn in 1..=self.max_turns {
+      let request =
+        ChatMimoRequest::new(messages.clone(), model_params.clone());
+      let response = orchestrator.add_request(request).await.await?;
+      usage = Usage::merge(usage, response.usage);
+
+      match response.reply {
+        ChatReply::Content(content) => {
+          messages.push(ChatMessage::assistant(content.clone()));
+          return Ok(AgentResponse {
+            content,
+            messages,
+            usage,
+            turns: turn,
+          });
+        }
+        ChatReply::ToolCalls(tool_calls) => {
+          messages.push(ChatMessage::assistant_tool_calls(tool_calls.clone()));
+          let results = self.call_tools(&tool_calls).await;
+          messages.extend(
+            tool_calls
+              .iter()
+              .zip(results)
+              .map(|(call, result)| ChatMessage::tool(call.id.clone(), result)),
+          );
+        }
+      }
+    }
+
+    Err(OrchError::MaxTurnsExceeded {
+      turns: self.max_turns,
+    })
+  }
+
+  /// Runs the tool calls side by side, returning their results in order.
+  async fn call_tools(&self, tool_calls: &[ChatToolCall]) -> Vec<String> {
+    // replaced by the output, unless the handler panics
+    let mut results =
+      vec![String::from("error: the tool failed"); tool_calls.len()];
+    let mut tasks = JoinSet::new();
+    for (i, call) in tool_calls.iter().enumerate() {
+      debug!("calling tool {}", call.name);
+      match self.tools.iter().find(|(tool, _)| tool.name == call.name) {
+        Some((_, handler)) => {
+          let future = handler(call.arguments.clone());
+          tasks.spawn(async move { (i, future.await) });
+        }
+        None => results[i] = format!("error: no tool named `{}`", call.name),
+      }
+    }
+
+    while let Some(joined) = tasks.join_next().await {
+      match joined {
+        Ok((i, Ok(output))) => results[i] = output,
+        Ok((i, Err(err))) => results[i] = format!("error: {}", err),
+        Err(_) => {}
+      }
+    }
+    results
+  }
+}
@@ -0,0 +1,212 @@
+//! Requests and responses using the OpenAI Audio API.
+
+use std::path::PathBuf;
+
+use async_openai::types::{
+  AudioInput, AudioResponseFormat, CreateTranscriptionRequest,
+  CreateTranscriptionResponseJson, CreateTranscriptionResponseVerboseJson,
+  InputSource,
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// Where the audio to transcribe comes from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AudioSource {
+  /// A file on disk, read each time the request is attempted.
+  Path(PathBuf),
+  /// Audio already in memory. The filename's extension tells the API the
+  /// format, e.g. `clip.mp3`.
+  Bytes { filename: String, bytes: Vec<u8> },
+}
+
+impl From<&AudioSource> for AudioInput {
+  fn from(source: &AudioSource) -> Self {
+    let source = match source {
+      AudioSource::Path(path) => InputSource::Path { path: path.clone() },
+      AudioSource::Bytes { filename, bytes } => InputSource::VecU8 {
+        filename: filename.clone(),
+        vec:      bytes.clone(),
+      },
+    };
+    AudioInput { source }
+  }
+}
+
+/// The form a transcription is returned in.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum TranscriptionFormat {
+  /// Plain text.
+  #[default]
+  Text,
+  /// Plain text, along with the detected language and the duration of the
+  /// audio.
+  VerboseText,
+  /// SubRip subtitles.
+  Srt,
+  /// WebVTT subtitles.
+  Vtt,
+}
+
+/// The model and options for a transcription.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TranscriptionParams {
+  /// The model to use, e.g. `whisper-1`.
+  pub model:       String,
+  /// The language of the audio as an ISO-639-1 code, e.g. `en`. Giving it
+  /// improves accuracy and latency; when unset, the language is detected.
+  pub language:    Option<String>,
+  /// Text to guide the model's style, or to continue a previous segment.
+  pub prompt:      Option<String>,
+  pub format:      TranscriptionFormat,
+  pub temperature: f32,
+}
+
+impl Default for TranscriptionParams {
+  fn default() -> Self {
+    Self {
+      model:       String::from("whisper-1"),
+      language:    None,
+      prompt:      None,
+      format:      TranscriptionFormat::default(),
+      temperature: 0.0,
+    }
+  }
+}
+
+/// A request to transcribe audio into text in the audio's language.
+///
+/// Long recordings can take longer to transcribe than the default
+/// `TimeoutPolicy` allows, so raise it for bulk transcription.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TranscriptionRequest {
+  pub source: AudioSource,
+  pub params: TranscriptionParams,
+}
+
+impl TranscriptionRequest {
+  pub fn new(source: AudioSource, params: TranscriptionParams) -> Self {
+    Self { source, params }
+  }
+
+  /// Returns a new request to transcribe the audio file at `path`.
+  pub fn from_path(
+    path: impl Into<PathBuf>,
+    params: TranscriptionParams,
+  ) -> Self {
+    Self::new(AudioSource::Path(path.into()), params)
+  }
+}
+
+/// The transcription given by a `TranscriptionRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TranscriptionResponse {
+  /// The transcribed text, or the subtitles for the `Srt` and `Vtt` formats.
+  pub text:     String,
+  /// The detected language, for the `VerboseText` format.
+  pub language: Option<String>,
+  /// The duration of the audio in seconds, for the `VerboseText` format.
+  pub duration: Option<f32>,
+}
+
+impl ResponseType for TranscriptionResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+#[async_trait]
+impl OrchRequest for TranscriptionRequest {
+  type Res = TranscriptionResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let transport = transport::current();
+
+    let format = self.params.format;
+    let request = CreateTranscriptionRequest {
+      file:                    AudioInput::from(&self.source),
+      model:                   self.params.model.clone(),
+      prompt:                  self.params.prompt.clone(),
+      response_format:         Some(match format {
+        TranscriptionFormat::Text => AudioResponseFormat::Json,
+        TranscriptionFormat::VerboseText => AudioResponseFormat::VerboseJson,
+        TranscriptionFormat::Srt => AudioResponseFormat::Srt,
+        TranscriptionFormat::Vtt => AudioResponseFormat::Vtt,
+      }),
+      temperature:             Some(self.params.temperature),
+      language:                self.params.language.clone(),
+      timestamp_granularities: None,
+    };
+
+    with_retries(&policies, policies.timeout_policy.timeout(), id, || async {
+      let bytes = transport.transcription(&keys, request.clone()).await?;
+      Ok(match format {
+        TranscriptionFormat::Text => {
+          let response: CreateTranscriptionResponseJson = parse(&bytes)?;
+          TranscriptionResponse {
+            text:     response.text,
+            language: None,
+            duration: None,
+          }
+        }
+        TranscriptionFormat::VerboseText => {
+          let response: CreateTranscriptionResponseVerboseJson = parse(&bytes)?;
+          TranscriptionResponse {
+            text:     response.text,
+            language: Some(response.language),
+            duration: Some(response.duration),
+          }
+        }
+        TranscriptionFormat::Srt | TranscriptionFormat::Vtt => {
+          let text = String::from_utf8(bytes).map_err(|err| {
+            OrchError::InvalidResponse(format!(
+              "subtitles are not valid UTF-8: {}",
+              err
+            ))
+          })?;
+          TranscriptionResponse {
+            text,
+            language: None,
+            duration: None,
+          }
+        }
+      })
+    })
+    .await
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
+
+/// Parses a transcription returned as JSON.
+fn parse<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+  serde_json::from_slice(bytes).map_err(|err| {
+    OrchError::InvalidResponse(format!("invalid transcription: {}", err))
+  })
+}
@@ -0,0 +1,96 @@
+//! A synchronous facade over the `Orchestrator`, for CLI tools and codebases
+//! that aren't async but still want its concurrency and retry policies.
+//!
+//! A `BlockingOrchestrator` owns a multi-threaded `tokio` runtime, which runs
+//! the requests in the background, and blocks the calling thread until their
+//! responses arrive. It can't be used from within an async runtime, where
+//! blocking would stall the runtime's threads: use the `Orchestrator` there.
+//!
+//! ```rust,no_run
+//! use openai_orch::{blocking::BlockingOrchestrator, prelude::*};
+//!
+//! fn main() -> Result<(), OrchError> {
+//!   let keys = Keys::from_env().unwrap();
+//!   let orchestrator = BlockingOrchestrator::new(Policies::default(), keys)?;
+//!
+//!   let request = ChatSisoRequest::new(
+//!     "You are a helpful assistant.".to_string(),
+//!     "What are you?".to_string(),
+//!     Default::default(),
+//!   );
+//!   println!("{}", orchestrator.send(request)?);
+//!   Ok(())
+//! }
+//! ```
+
+use tokio::{runtime::Runtime, time::Duration};
+
+use crate::{
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  OrchRequest, Orchestrator, ResponseType,
+};
+
+/// An `Orchestrator` with a runtime of its own, whose methods block instead
+/// of being async. See the module documentation.
+pub struct BlockingOrchestrator {
+  orchestrator: Orchestrator,
+  runtime:      Runtime,
+}
+
+impl BlockingOrchestrator {
+  /// Create a new `BlockingOrchestrator` with the given policies and keys.
+  /// Fails if the runtime can't be started.
+  pub fn new(policies: Policies, keys: Keys) -> Result<Self> {
+    Self::from_orchestrator(Orchestrator::new(policies, keys))
+  }
+
+  /// Wraps an `Orchestrator`, such as one made with `Orchestrator::builder`.
+  /// Its clones can still be used from async code on other runtimes, sharing
+  /// the same limits.
+  pub fn from_orchestrator(orchestrator: Orchestrator) -> Result<Self> {
+    let runtime = Runtime::new().map_err(OrchError::other)?;
+    Ok(Self {
+      orchestrator,
+      runtime,
+    })
+  }
+
+  /// Sends a request and blocks until its response arrives.
+  pub fn send<R, Req>(&self, request: Req) -> Result<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    self
+      .runtime
+      .block_on(async { self.orchestrator.add_request(request).await.await })
+  }
+
+  /// Sends several requests concurrently, as far as the concurrency policy
+  /// allows, and blocks until every response has arrived. The responses are
+  /// returned in the same order as the requests were given.
+  pub fn send_all<R, Req, I>(&self, requests: I) -> Vec<Result<R>>
+  where
+    I: IntoIterator<Item = Req>,
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    self.runtime.block_on(async {
+      let request_ids = self.orchestrator.add_requests(requests).await;
+      self.orchestrator.await_all(request_ids).await
+    })
+  }
+
+  /// Stops accepting requests and blocks until those in flight finish, as
+  /// `Orchestrator::shutdown` does.
+  pub fn shutdown(&self, deadline: Option<Duration>) {
+    self.runtime.block_on(self.orchestrator.shutdown(deadline))
+  }
+
+  /// The wrapped `Orchestrator`, for its progress, spend, and policies.
+  pub fn orchestrator(&self) -> &Orchestrator {
+    &self.orchestrator
+  }
+}
@@ -0,0 +1,204 @@
+//! A builder for `Orchestrator`s with named construction options.
+
+use std::sync::Arc;
+
+use crate::{
+  cache::CacheStore,
+  cost::PricingTable,
+  failures::FailureLog,
+  hooks::OrchestratorHooks,
+  keys::{KeyBalancing, Keys},
+  policies::Policies,
+  prompt_log::PromptLog,
+  redact::Redactor,
+  transport::Transport,
+  Orchestrator,
+};
+
+/// Builds an `Orchestrator` step by step, as returned by
+/// `Orchestrator::builder`.
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+///
+/// use openai_orch::{prelude::*, policies::ConcurrencyPolicy};
+///
+/// let primary = Keys::from_env().unwrap();
+/// let secondary = Keys::new("sk-...".to_string(), None);
+/// let orchestrator = Orchestrator::builder()
+///   .policies(Policies {
+///     concurrency_policy: ConcurrencyPolicy::new(20),
+///     ..Default::default()
+///   })
+///   .keys([primary, secondary])
+///   .balancing(KeyBalancing::LeastInFlight)
+///   .cache(MemoryCache::new(Duration::from_secs(3600)))
+///   .coalescing()
+///   .build();
+/// ```
+#[derive(Default)]
+#[must_use]
+pub struct OrchestratorBuilder {
+  policies:         Policies,
+  keys:             Vec<Keys>,
+  balancing:        KeyBalancing,
+  base_url:         Option<String>,
+  http_client:      Option<reqwest::Client>,
+  pricing:          Option<PricingTable>,
+  hooks:            Vec<Arc<dyn OrchestratorHooks>>,
+  cache:            Option<Arc<dyn CacheStore>>,
+  transport:        Option<Arc<dyn Transport>>,
+  failure_log:      Option<FailureLog>,
+  prompt_log:       Option<PromptLog>,
+  redactor:         Option<Redactor>,
+  coalescing:       bool,
+  describe_metrics: bool,
+}
+
+impl OrchestratorBuilder {
+  /// Sets the policies. Defaults to `Policies::default()`.
+  pub fn policies(mut self, policies: Policies) -> Self {
+    self.policies = policies;
+    self
+  }
+
+  /// Adds a key to send requests with.
+  pub fn key(mut self, keys: Keys) -> Self {
+    self.keys.push(keys);
+    self
+  }
+
+  /// Adds several keys to spread requests across, as
+  /// `Orchestrator::with_keys`.
+  pub fn keys(mut self, keys: impl IntoIterator<Item = Keys>) -> Self {
+    self.keys.extend(keys);
+    self
+  }
+
+  /// Sets how requests are spread across the keys.
+  pub fn balancing(mut self, balancing: KeyBalancing) -> Self {
+    self.balancing = balancing;
+    self
+  }
+
+  /// Sends requests to `base_url` instead of the OpenAI API, for keys which
+  /// don't set a base URL of their own, as `Keys::with_base_url`.
+  pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+    self.base_url = Some(base_url.into());
+    self
+  }
+
+  /// Sends requests with the given HTTP client, for keys which don't set a
+  /// client of their own, as `Keys::with_http_client`. See the `http`
+  /// module.
+  pub fn http_client(mut self, http_client: reqwest::Client) -> Self {
+    self.http_client = Some(http_client);
+    self
+  }
+
+  /// Prices usage with the given table, as `Orchestrator::with_pricing`.
+  pub fn pricing(mut self, pricing: PricingTable) -> Self {
+    self.pricing = Some(pricing);
+    self
+  }
+
+  /// Registers hooks, as `Orchestrator::with_hooks`.
+  pub fn hooks(mut self, hooks: impl OrchestratorHooks + 'static) -> Self {
+    self.hooks.push(Arc::new(hooks));
+    self
+  }
+
+  /// Serves and stores responses with `cache`, as
+  /// `Orchestrator::with_cache`.
+  pub fn cache(mut self, cache: impl CacheStore + 'static) -> Self {
+    self.cache = Some(Arc::new(cache));
+    self
+  }
+
+  /// Makes the calls to the API with `transport`, as
+  /// `Orchestrator::with_transport`.
+  pub fn transport(mut self, transport: impl Transport + 'static) -> Self {
+    self.transport = Some(Arc::new(transport));
+    self
+  }
+
+  /// Appends every request which fails to `log`, as
+  /// `Orchestrator::with_failure_log`.
+  pub fn failure_log(mut self, log: FailureLog) -> Self {
+    self.failure_log = Some(log);
+    self
+  }
+
+  /// Records the prompts and completions of every call to the API to `log`,
+  /// as `Orchestrator::with_prompt_log`. With a `redactor`, the log sees
+  /// calls after they are redacted.
+  pub fn prompt_log(mut self, log: PromptLog) -> Self {
+    self.prompt_log = Some(log);
+    self
+  }
+
+  /// Scrubs prompts and the failure log with `redactor`, as
+  /// `Orchestrator::with_redactor`. The redactor wraps the transport, whether
+  /// it is set before or after.
+  pub fn redactor(mut self, redactor: Redactor) -> Self {
+    self.redactor = Some(redactor);
+    self
+  }
+
+  /// Shares one upstream call between identical requests in flight, as
+  /// `Orchestrator::with_coalescing`.
+  pub fn coalescing(mut self) -> Self {
+    self.coalescing = true;
+    self
+  }
+
+  /// Describes the metrics recorded by the `Orchestrator` to the installed
+  /// `metrics` recorder, so exporters can show their help text and units.
+  /// The recorder must be installed before `build` is called.
+  #[cfg(feature = "metrics")]
+  pub fn describe_metrics(mut self) -> Self {
+    self.describe_metrics = true;
+    self
+  }
+
+  /// Builds the `Orchestrator`.
+  ///
+  /// Panics if no keys were given.
+  pub fn build(self) -> Orchestrator {
+    let (base_url, http_client) = (self.base_url, self.http_client);
+    let keys = self.keys.into_iter().map(|mut keys| {
+      keys.base_url = keys.base_url.or_else(|| base_url.clone());
+      keys.http_client = keys.http_client.or_else(|| http_client.clone());
+      keys
+    });
+
+    let mut orchestrator =
+      Orchestrator::with_keys(self.policies, keys, self.balancing);
+    if let Some(pricing) = self.pricing {
+      orchestrator = orchestrator.with_pricing(pricing);
+    }
+    for hooks in self.hooks {
+      orchestrator.hooks.push(hooks);
+    }
+    orchestrator.cache = self.cache;
+    if let Some(transport) = self.transport {
+      orchestrator.transport = transport;
+    }
+    if let Some(log) = self.failure_log {
+      orchestrator = orchestrator.with_failure_log(log);
+    }
+    if let Some(log) = self.prompt_log {
+      orchestrator = orchestrator.with_prompt_log(log);
+    }
+    if let Some(redactor) = self.redactor {
+      orchestrator = orchestrator.with_redactor(redactor);
+    }
+    if self.coalescing {
+      orchestrator = orchestrator.with_coalescing();
+    }
+    if self.describe_metrics {
+      crate::telemetry::describe();
+    }
+    orchestrator
+  }
+}
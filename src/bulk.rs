@@ -0,0 +1,389 @@
+//! Sending many requests through an `Orchestrator` at once, from an
+//! iterator or from a JSONL file of records.
+
+use std::{
+  collections::HashSet,
+  future::Future,
+  path::Path,
+  pin::Pin,
+  task::{ready, Context, Poll},
+};
+
+use futures::{Sink, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+  fs::{File, OpenOptions},
+  io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+  sync::mpsc,
+  task::JoinSet,
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use crate::{
+  error::{OrchError, Result},
+  trace::debug,
+  OrchRequest, Orchestrator, RequestID, ResponseType,
+};
+
+/// A line of the output written by `process_jsonl`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BulkRecord<R> {
+  /// The line of the input the result is for, starting at one.
+  pub line:     u64,
+  /// The response, if the request succeeded.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub response: Option<R>,
+  /// The error, if the record couldn't be parsed or the request failed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error:    Option<String>,
+}
+
+/// What a call to `process_jsonl` did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BulkSummary {
+  /// The records skipped because the output already had a result for them.
+  pub skipped:   u64,
+  /// The records whose request succeeded.
+  pub completed: u64,
+  /// The records which couldn't be parsed, or whose request failed.
+  pub failed:    u64,
+}
+
+/// Sends a request for every item of `items`, made by `make_request`, and
+/// returns the results in the order of the items.
+///
+/// Requests are added as earlier ones finish, with at most twice the
+/// `Orchestrator`'s concurrency limit waiting at a time, so a long iterator
+/// isn't turned into requests all at once. A failed request does not prevent
+/// the remaining items from being sent.
+///
+/// ```rust,no_run
+/// # use openai_orch::{bulk::orchestrate_map, prelude::*};
+/// # async fn example(orchestrator: Orchestrator, reviews: Vec<String>) {
+/// let sentiments = orchestrate_map(&orchestrator, reviews, |review| {
+///   ChatSisoRequest::new(
+///     "Reply with the sentiment of the review, in one word.".to_string(),
+///     review,
+///     Default::default(),
+///   )
+/// })
+/// .await;
+/// # }
+/// ```
+pub async fn orchestrate_map<T, I, Req, R, F>(
+  orchestrator: &Orchestrator,
+  items: I,
+  make_request: F,
+) -> Vec<Result<R>>
+where
+  I: IntoIterator<Item = T>,
+  F: FnMut(T) -> Req,
+  Req: OrchRequest<Res = R> + Send + Sync + 'static,
+  R: ResponseType,
+{
+  let mut results: Vec<_> =
+    orchestrate_map_unordered(orchestrator, items, make_request)
+      .collect()
+      .await;
+  results.sort_unstable_by_key(|(index, _)| *index);
+  results.into_iter().map(|(_, res)| res).collect()
+}
+
+/// Sends a request for every item of `items`, as `orchestrate_map` does, but
+/// yields each result as soon as its request finishes, along with the index
+/// of the item it is for, so results can be handled incrementally.
+pub fn orchestrate_map_unordered<T, I, Req, R, F>(
+  orchestrator: &Orchestrator,
+  items: I,
+  mut make_request: F,
+) -> impl Stream<Item = (usize, Result<R>)>
+where
+  I: IntoIterator<Item = T>,
+  F: FnMut(T) -> Req,
+  Req: OrchRequest<Res = R> + Send + Sync + 'static,
+  R: ResponseType,
+{
+  let window = (orchestrator.concurrency_limit() * 2).max(1);
+  let orchestrator = orchestrator.clone();
+  futures::stream::iter(items.into_iter().enumerate())
+    .map(move |(index, item)| {
+      let request = make_request(item);
+      let orchestrator = orchestrator.clone();
+      async move { (index, orchestrator.add_request(request).await.await) }
+    })
+    .buffer_unordered(window)
+}
+
+/// Returns a `Sink` which adds the requests sent to it to `orchestrator`,
+/// along with a stream of their request IDs, in the order the requests were
+/// sent, for streaming pipelines built with `forward` or `send_all`.
+///
+/// Sending a request waits while the queue is full, if the `QueuePolicy`
+/// bounds it, so a pipeline forwarded into the sink is read no faster than
+/// its requests can be queued. Requests whose IDs are dropped unread are
+/// still sent, but their responses are discarded.
+///
+/// ```rust,no_run
+/// # use futures::{SinkExt, StreamExt};
+/// # use openai_orch::{bulk::request_sink, prelude::*};
+/// # async fn example(orchestrator: Orchestrator, prompts: Vec<String>) {
+/// let (mut sink, request_ids) = request_sink(&orchestrator);
+/// let requests = futures::stream::iter(prompts).map(|prompt| {
+///   Ok(ChatSisoRequest::new(
+///     "You are a helpful assistant.".to_string(),
+///     prompt,
+///     Default::default(),
+///   ))
+/// });
+/// let forwarding = tokio::spawn(async move {
+///   sink.send_all(&mut Box::pin(requests)).await?;
+///   sink.close().await
+/// });
+///
+/// let mut responses =
+///   Box::pin(request_ids.then(|request_id| async move { request_id.await }));
+/// while let Some(response) = responses.next().await {
+///   println!("{:?}", response);
+/// }
+/// # }
+/// ```
+pub fn request_sink<Req, R>(
+  orchestrator: &Orchestrator,
+) -> (RequestSink<Req>, impl Stream<Item = RequestID<R>>)
+where
+  Req: OrchRequest<Res = R> + Send + Sync + 'static,
+  R: ResponseType,
+{
+  let (tx, rx) = mpsc::unbounded_channel();
+  let sink = RequestSink {
+    orchestrator: orchestrator.clone(),
+    adding:       None,
+    tx:           Some(tx),
+  };
+  (sink, UnboundedReceiverStream::new(rx))
+}
+
+/// A request being added to the `Orchestrator`, resolving to its ID.
+type Adding<R> = Pin<Box<dyn Future<Output = RequestID<R>> + Send>>;
+
+/// A `Sink` of requests, as returned by `request_sink`.
+#[must_use = "sinks do nothing unless requests are sent to them"]
+pub struct RequestSink<Req: OrchRequest> {
+  orchestrator: Orchestrator,
+  /// The request being added, if one is waiting for room in the queue.
+  adding:       Option<Adding<Req::Res>>,
+  /// Where request IDs are sent, until the sink is closed.
+  tx:           Option<mpsc::UnboundedSender<RequestID<Req::Res>>>,
+}
+
+impl<Req: OrchRequest> RequestSink<Req> {
+  /// Finishes adding the request being added, if there is one.
+  fn poll_added(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+    if let Some(adding) = &mut self.adding {
+      let request_id = ready!(adding.as_mut().poll(cx));
+      self.adding = None;
+      if let Some(tx) = &self.tx {
+        let _ = tx.send(request_id);
+      }
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl<Req, R> Sink<Req> for RequestSink<Req>
+where
+  Req: OrchRequest<Res = R> + Send + Sync + 'static,
+  R: ResponseType,
+{
+  type Error = OrchError;
+
+  fn poll_ready(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<()>> {
+    self.get_mut().poll_added(cx)
+  }
+
+  fn start_send(self: Pin<&mut Self>, request: Req) -> Result<()> {
+    let this = self.get_mut();
+    let orchestrator = this.orchestrator.clone();
+    this.adding =
+      Some(Box::pin(
+        async move { orchestrator.add_request(request).await },
+      ));
+    Ok(())
+  }
+
+  fn poll_flush(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<()>> {
+    self.get_mut().poll_added(cx)
+  }
+
+  fn poll_close(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<()>> {
+    let this = self.get_mut();
+    ready!(this.poll_added(cx))?;
+    // ends the stream of request IDs
+    this.tx = None;
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Sends a request for every record in the JSONL file at `input_path`, made
+/// by `make_request`, and appends the results to the JSONL file at
+/// `output_path` as `BulkRecord`s, in the order they finish.
+///
+/// The output doubles as a checkpoint: each result is flushed as soon as it
+/// is written, and records which already have a result in the output are
+/// skipped, so an interrupted run can be continued by calling this again with
+/// the same paths. Failed records count as having a result; remove their
+/// lines from the output to retry them.
+///
+/// The input is read as requests finish rather than all at once, with at most
+/// twice the `Orchestrator`'s concurrency limit waiting at a time. Blank lines
+/// are ignored.
+pub async fn process_jsonl<T, Req, R, F>(
+  orchestrator: &Orchestrator,
+  input_path: impl AsRef<Path>,
+  output_path: impl AsRef<Path>,
+  mut make_request: F,
+) -> Result<BulkSummary>
+where
+  T: DeserializeOwned,
+  F: FnMut(T) -> Req,
+  Req: OrchRequest<Res = R> + Send + Sync + 'static,
+  R: ResponseType + Serialize,
+{
+  let done = read_checkpoint(output_path.as_ref()).await?;
+  let input = File::open(input_path).await.map_err(OrchError::other)?;
+  let mut lines = BufReader::new(input).lines();
+  let mut output = OpenOptions::new()
+    .create(true)
+    .read(true)
+    .append(true)
+    .open(output_path)
+    .await
+    .map_err(OrchError::other)?;
+  // a line cut short by a crash is ended, so the next record starts afresh
+  if ends_mid_line(&mut output).await? {
+    output.write_all(b"\n").await.map_err(OrchError::other)?;
+  }
+
+  let mut summary = BulkSummary {
+    skipped: done.len() as u64,
+    ..Default::default()
+  };
+  let mut pending = JoinSet::new();
+  let mut line = 0;
+  loop {
+    // keep the queue topped up, but don't read the whole input into it
+    while pending.len() < orchestrator.concurrency_limit() * 2 {
+      let Some(text) = lines.next_line().await.map_err(OrchError::other)?
+      else {
+        break;
+      };
+      line += 1;
+      if text.trim().is_empty() || done.contains(&line) {
+        continue;
+      }
+
+      match serde_json::from_str(&text) {
+        Ok(record) => {
+          let request_id = orchestrator.add_request(make_request(record)).await;
+          pending.spawn(async move { (line, request_id.await) });
+        }
+        Err(err) => {
+          let record = BulkRecord::<R> {
+            line,
+            response: None,
+            error: Some(format!("invalid record: {}", err)),
+          };
+          write_record(&mut output, &record).await?;
+          summary.failed += 1;
+        }
+      }
+    }
+
+    let Some(finished) = pending.join_next().await else {
+      break;
+    };
+    let (line, res) = finished.map_err(OrchError::other)?;
+    let record = match res {
+      Ok(response) => {
+        summary.completed += 1;
+        BulkRecord {
+          line,
+          response: Some(response),
+          error: None,
+        }
+      }
+      Err(err) => {
+        debug!("record on line {} failed: {}", line, err);
+        summary.failed += 1;
+        BulkRecord {
+          line,
+          response: None,
+          error: Some(err.to_string()),
+        }
+      }
+    };
+    write_record(&mut output, &record).await?;
+  }
+
+  Ok(summary)
+}
+
+/// Returns the input lines which already have a result in the output at
+/// `path`, if it exists.
+async fn read_checkpoint(path: &Path) -> Result<HashSet<u64>> {
+  #[derive(Deserialize)]
+  struct Line {
+    line: u64,
+  }
+
+  let output = match File::open(path).await {
+    Ok(output) => output,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+      return Ok(HashSet::new());
+    }
+    Err(err) => return Err(OrchError::other(err)),
+  };
+  let mut lines = BufReader::new(output).lines();
+  let mut done = HashSet::new();
+  while let Some(text) = lines.next_line().await.map_err(OrchError::other)? {
+    // a line cut short by a crash is ignored, and its record sent again
+    if let Ok(record) = serde_json::from_str::<Line>(&text) {
+      done.insert(record.line);
+    }
+  }
+  Ok(done)
+}
+
+/// Whether the last line of `file` is missing its newline.
+async fn ends_mid_line(file: &mut File) -> Result<bool> {
+  let len = file.metadata().await.map_err(OrchError::other)?.len();
+  if len == 0 {
+    return Ok(false);
+  }
+  let mut last = [0];
+  file
+    .seek(std::io::SeekFrom::Start(len - 1))
+    .await
+    .map_err(OrchError::other)?;
+  file.read_exact(&mut last).await.map_err(OrchError::other)?;
+  Ok(last[0] != b'\n')
+}
+
+async fn write_record<R: Serialize>(
+  output: &mut File,
+  record: &BulkRecord<R>,
+) -> Result<()> {
+  let mut json = serde_json::to_vec(record).map_err(OrchError::other)?;
+  json.push(b'\n');
+  output.write_all(&json).await.map_err(OrchError::other)?;
+  output.flush().await.map_err(OrchError::other)
+}
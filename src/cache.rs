@@ -0,0 +1,93 @@
+//! Caching of responses, so that identical requests are only sent once.
+
+use std::{
+  collections::HashMap,
+  hash::{DefaultHasher, Hash, Hasher},
+  sync::Mutex,
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+/// Storage for cached responses, registered with `Orchestrator::with_cache`.
+///
+/// Keys come from `OrchRequest::cache_key`, and values from
+/// `ResponseType::to_cache`. Implement this to keep responses somewhere that
+/// outlives the process, such as on disk or in Redis.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+  /// Returns the value stored under `key`, if there is one.
+  async fn get(&self, key: u64) -> Option<Vec<u8>>;
+  /// Stores `value` under `key`, replacing any previous value.
+  async fn insert(&self, key: u64, value: Vec<u8>);
+}
+
+/// A `CacheStore` which keeps responses in memory for a fixed time.
+pub struct MemoryCache {
+  ttl:     Duration,
+  entries: Mutex<HashMap<u64, (Instant, Vec<u8>)>>,
+}
+
+impl MemoryCache {
+  /// Returns a new, empty cache whose entries expire after `ttl`.
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      ttl,
+      entries: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Removes every expired entry. Expired entries are never returned, but
+  /// they are otherwise only removed when they are looked up.
+  pub fn purge_expired(&self) {
+    let now = Instant::now();
+    self
+      .entries
+      .lock()
+      .expect("cache lock poisoned")
+      .retain(|_, (expires_at, _)| *expires_at > now);
+  }
+}
+
+#[async_trait]
+impl CacheStore for MemoryCache {
+  async fn get(&self, key: u64) -> Option<Vec<u8>> {
+    let mut entries = self.entries.lock().expect("cache lock poisoned");
+    match entries.get(&key) {
+      Some((expires_at, value)) if *expires_at > Instant::now() => {
+        Some(value.clone())
+      }
+      Some(_) => {
+        entries.remove(&key);
+        None
+      }
+      None => None,
+    }
+  }
+
+  async fn insert(&self, key: u64, value: Vec<u8>) {
+    let expires_at = Instant::now() + self.ttl;
+    self
+      .entries
+      .lock()
+      .expect("cache lock poisoned")
+      .insert(key, (expires_at, value));
+  }
+}
+
+/// Hashes the JSON form of `value` into a cache key, for use in
+/// `OrchRequest::cache_key`. The built-in requests hash the body they send to
+/// the API, so any difference in model, messages, or parameters gives a
+/// different key.
+///
+/// Keys are stable between runs of the same build, but may change when the
+/// crate or the Rust toolchain is upgraded, which only causes cache misses.
+pub fn cache_key(value: &impl Serialize) -> Option<u64> {
+  // going through a `Value` sorts the keys of maps, whose order would
+  // otherwise differ between runs
+  let json = serde_json::to_value(value).ok()?.to_string();
+  let mut hasher = DefaultHasher::new();
+  json.hash(&mut hasher);
+  Some(hasher.finish())
+}
@@ -0,0 +1,303 @@
+//! Recording calls to the API to a file and replaying them, for fast and
+//! deterministic integration tests.
+//!
+//! A `Cassette` is a `Transport`. When recording, it passes every call on to
+//! the API, or to another `Transport`, and appends the request and response
+//! to a JSONL file. When replaying, it answers calls from that file instead,
+//! without a network connection or a key. `Cassette::new` replays the file if
+//! it exists and records it otherwise, so the first run of a test records
+//! what later runs replay; delete the file to record it again.
+//!
+//! Keys are never written: the API key and organization ID are replaced with
+//! `[REDACTED]` wherever they appear in a request or response. Failed calls
+//! aren't recorded, and streamed chat completions and transcriptions aren't
+//! supported.
+//!
+//! ```rust,no_run
+//! use openai_orch::{cassette::Cassette, prelude::*};
+//!
+//! # async fn example() -> Result<(), OrchError> {
+//! let cassette = Cassette::new("tests/cassettes/summaries.jsonl").await?;
+//! let orchestrator = Orchestrator::builder()
+//!   .key(Keys::from_env().unwrap_or_else(|| Keys::new(String::new(), None)))
+//!   .transport(cassette)
+//!   .build();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use async_openai::types::{
+  CreateChatCompletionRequest, CreateChatCompletionResponse,
+  CreateCompletionRequest, CreateCompletionResponse, CreateEmbeddingRequest,
+  CreateEmbeddingResponse, CreateImageRequest, CreateModerationRequest,
+  CreateModerationResponse, ImagesResponse,
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+  fs::{File, OpenOptions},
+  io::AsyncWriteExt,
+};
+
+use crate::{
+  error::{OrchError, Result},
+  jsonl,
+  keys::Keys,
+  trace::debug,
+  transport::{Call, OpenAITransport, Transport},
+};
+
+/// What keys are replaced with in a cassette.
+const REDACTED: &str = "[REDACTED]";
+
+/// A call to a `Transport`, as a line of a cassette.
+#[derive(Serialize, Deserialize)]
+struct Recording {
+  /// The `Transport` method called, such as "chat".
+  call:     String,
+  request:  Value,
+  response: Value,
+}
+
+impl Recording {
+  fn is_of(&self, call: &str, request: &Value) -> bool {
+    self.call == call && self.request == *request
+  }
+}
+
+/// The calls recorded in a cassette being replayed, and whether each has
+/// been replayed yet.
+struct Tape(Mutex<Vec<(Recording, bool)>>);
+
+impl Tape {
+  /// Finds the response to a call: the first recording of an identical call
+  /// which hasn't been replayed yet, or the last one if all of them have.
+  fn play(&self, call: &str, request: &Value) -> Result<Value> {
+    let mut recordings = self.0.lock().expect("cassette lock poisoned");
+    let unplayed = recordings.iter().position(|(recording, played)| {
+      !played && recording.is_of(call, request)
+    });
+    let (recording, played) = match unplayed {
+      Some(index) => &mut recordings[index],
+      None => recordings
+        .iter_mut()
+        .rev()
+        .find(|(recording, _)| recording.is_of(call, request))
+        .ok_or(OrchError::Unsupported("calls missing from the cassette"))?,
+    };
+    *played = true;
+    Ok(recording.response.clone())
+  }
+}
+
+enum Mode {
+  Record {
+    transport: Arc<dyn Transport>,
+    file:      tokio::sync::Mutex<File>,
+  },
+  Replay(Tape),
+}
+
+/// A `Transport` which records calls to a file, or replays them from one.
+/// See the module documentation.
+pub struct Cassette {
+  path: PathBuf,
+  mode: Mode,
+}
+
+impl Cassette {
+  /// Replays the cassette at `path` if it exists, and records it with an
+  /// `OpenAITransport` otherwise.
+  pub async fn new(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref();
+    match tokio::fs::try_exists(path)
+      .await
+      .map_err(OrchError::other)?
+    {
+      true => Self::replay(path).await,
+      false => Self::record(path).await,
+    }
+  }
+
+  /// Records calls made with an `OpenAITransport` to `path`, replacing any
+  /// cassette already there.
+  pub async fn record(path: impl AsRef<Path>) -> Result<Self> {
+    Self::record_with(path, OpenAITransport).await
+  }
+
+  /// Records calls made with `transport` to `path`, replacing any cassette
+  /// already there.
+  pub async fn record_with(
+    path: impl AsRef<Path>,
+    transport: impl Transport + 'static,
+  ) -> Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    if let Some(parent) = path.parent() {
+      tokio::fs::create_dir_all(parent)
+        .await
+        .map_err(OrchError::other)?;
+    }
+    let file = OpenOptions::new()
+      .create(true)
+      .write(true)
+      .truncate(true)
+      .open(&path)
+      .await
+      .map_err(OrchError::other)?;
+    debug!("recording cassette {}", path.display());
+    Ok(Self {
+      path,
+      mode: Mode::Record {
+        transport: Arc::new(transport),
+        file:      tokio::sync::Mutex::new(file),
+      },
+    })
+  }
+
+  /// Replays the calls recorded to `path`. Calls which weren't recorded fail
+  /// with `OrchError::Unsupported`.
+  pub async fn replay(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    let contents = tokio::fs::read_to_string(&path)
+      .await
+      .map_err(OrchError::other)?;
+    let recordings = jsonl::parse(&contents)?
+      .into_iter()
+      .map(|recording| (recording, false))
+      .collect();
+    debug!("replaying cassette {}", path.display());
+    Ok(Self {
+      path,
+      mode: Mode::Replay(Tape(Mutex::new(recordings))),
+    })
+  }
+
+  /// The file the cassette is recorded to or replayed from.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Whether the cassette is recording, rather than replaying.
+  pub fn is_recording(&self) -> bool {
+    matches!(self.mode, Mode::Record { .. })
+  }
+
+  /// Records or replays a single call.
+  async fn call<Req, Res>(
+    &self,
+    call: &str,
+    keys: &Keys,
+    request: Req,
+    send: Call<Req, Res>,
+  ) -> Result<Res>
+  where
+    Req: Serialize + Send,
+    Res: Serialize + DeserializeOwned,
+  {
+    let request_json = redact(keys, &request)?;
+    match &self.mode {
+      Mode::Replay(tape) => {
+        let response = tape.play(call, &request_json)?;
+        serde_json::from_value(response).map_err(OrchError::other)
+      }
+      Mode::Record { transport, file } => {
+        let response = send(transport.as_ref(), keys, request).await?;
+        let recording = Recording {
+          call:     call.to_string(),
+          request:  request_json,
+          response: redact(keys, &response)?,
+        };
+        let line = jsonl::line(&recording)?;
+
+        let mut file = file.lock().await;
+        file.write_all(&line).await.map_err(OrchError::other)?;
+        file.flush().await.map_err(OrchError::other)?;
+        Ok(response)
+      }
+    }
+  }
+}
+
+/// Serializes `value`, replacing the keys wherever they appear in it.
+fn redact(keys: &Keys, value: &impl Serialize) -> Result<Value> {
+  let mut json = serde_json::to_string(value).map_err(OrchError::other)?;
+  let secrets = [Some(&keys.openai_api_key), keys.openai_org_id.as_ref()];
+  for secret in secrets.into_iter().flatten() {
+    // keys are escaped the same way wherever they appear in the JSON
+    let secret = serde_json::to_string(secret).map_err(OrchError::other)?;
+    let secret = secret.trim_matches('"');
+    if !secret.is_empty() {
+      json = json.replace(secret, REDACTED);
+    }
+  }
+  serde_json::from_str(&json).map_err(OrchError::other)
+}
+
+#[async_trait]
+impl Transport for Cassette {
+  async fn chat(
+    &self,
+    keys: &Keys,
+    request: CreateChatCompletionRequest,
+  ) -> Result<CreateChatCompletionResponse> {
+    self
+      .call("chat", keys, request, |transport, keys, request| {
+        transport.chat(keys, request)
+      })
+      .await
+  }
+
+  async fn completion(
+    &self,
+    keys: &Keys,
+    request: CreateCompletionRequest,
+  ) -> Result<CreateCompletionResponse> {
+    self
+      .call("completion", keys, request, |transport, keys, request| {
+        transport.completion(keys, request)
+      })
+      .await
+  }
+
+  async fn embedding(
+    &self,
+    keys: &Keys,
+    request: CreateEmbeddingRequest,
+  ) -> Result<CreateEmbeddingResponse> {
+    self
+      .call("embedding", keys, request, |transport, keys, request| {
+        transport.embedding(keys, request)
+      })
+      .await
+  }
+
+  async fn moderation(
+    &self,
+    keys: &Keys,
+    request: CreateModerationRequest,
+  ) -> Result<CreateModerationResponse> {
+    self
+      .call("moderation", keys, request, |transport, keys, request| {
+        transport.moderation(keys, request)
+      })
+      .await
+  }
+
+  async fn image(
+    &self,
+    keys: &Keys,
+    request: CreateImageRequest,
+  ) -> Result<ImagesResponse> {
+    self
+      .call("image", keys, request, |transport, keys, request| {
+        transport.image(keys, request)
+      })
+      .await
+  }
+}
@@ -0,0 +1,248 @@
+//! A request which samples several completions of the same prompt and
+//! reduces them to one answer, for self-consistency.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  cache::cache_key,
+  chat::{
+    mimo::ChatMimoRequest, simo::ChatSimoRequest, ChatMessage, ChatModelParams,
+  },
+  cost::Usage,
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  OrchRequest, ResponseType,
+};
+
+/// The system prompt of the judge for `EnsembleStrategy::Judge`.
+const JUDGE_PROMPT: &str = "You are given a prompt and several candidate \
+                            answers to it. Reply with only the number of the \
+                            best candidate.";
+
+/// A function reducing the candidates to one answer, or `None` if it can't.
+pub type EnsembleReducer =
+  Arc<dyn Fn(&[String]) -> Option<String> + Send + Sync>;
+
+/// How a `ChatEnsembleRequest` picks its answer from the candidates.
+#[derive(Clone)]
+pub enum EnsembleStrategy {
+  /// The candidate given most often, ignoring surrounding whitespace. Ties go
+  /// to the candidate which was sampled first.
+  MajorityVote,
+  /// A function of the candidates.
+  Reducer(EnsembleReducer),
+  /// A model asked to pick the best candidate, which costs an extra request.
+  Judge { model_params: Box<ChatModelParams> },
+}
+
+/// A self-consistency request for the OpenAI Chat API: samples `n`
+/// completions of a prompt in one call, then reduces them to one answer with
+/// the `EnsembleStrategy`, which defaults to a majority vote.
+///
+/// Sampling at a temperature of 0 gives the same completion every time, so
+/// set a higher `temperature` in the `ChatModelParams`.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone)]
+pub struct ChatEnsembleRequest {
+  pub system_prompt: String,
+  pub user_prompt:   String,
+  /// The number of candidates to sample, from 1 to 128.
+  pub n:             u8,
+  pub model_params:  ChatModelParams,
+  pub strategy:      EnsembleStrategy,
+}
+
+impl ChatEnsembleRequest {
+  pub fn new(
+    system_prompt: String,
+    user_prompt: String,
+    n: u8,
+    model_params: ChatModelParams,
+  ) -> Self {
+    Self {
+      system_prompt,
+      user_prompt,
+      n,
+      model_params,
+      strategy: EnsembleStrategy::MajorityVote,
+    }
+  }
+
+  /// Sets how the answer is picked from the candidates.
+  pub fn with_strategy(mut self, strategy: EnsembleStrategy) -> Self {
+    self.strategy = strategy;
+    self
+  }
+
+  fn simo(&self) -> ChatSimoRequest {
+    ChatSimoRequest::new(
+      self.system_prompt.clone(),
+      self.user_prompt.clone(),
+      self.n,
+      self.model_params.clone(),
+    )
+  }
+
+  /// Asks the judge model for the best candidate.
+  async fn judge(
+    &self,
+    model_params: &ChatModelParams,
+    candidates: &[String],
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<(String, Option<Usage>)> {
+    let listing = candidates
+      .iter()
+      .enumerate()
+      .map(|(i, candidate)| format!("{}. {}", i + 1, candidate))
+      .collect::<Vec<_>>()
+      .join("\n\n");
+    let request = ChatMimoRequest::new(
+      vec![
+        ChatMessage::system(JUDGE_PROMPT.to_string()),
+        ChatMessage::user(format!(
+          "Prompt:\n{}\n\nCandidates:\n{}",
+          self.user_prompt, listing
+        )),
+      ],
+      model_params.clone(),
+    );
+    let response = request.send(policies, keys, id).await?;
+
+    let choice = response
+      .content()
+      .and_then(|content| content.trim().trim_end_matches('.').parse().ok())
+      .filter(|choice: &usize| (1..=candidates.len()).contains(choice))
+      .ok_or_else(|| {
+        OrchError::InvalidResponse(format!(
+          "judge did not reply with a candidate number: {}",
+          response.reply
+        ))
+      })?;
+    Ok((candidates[choice - 1].clone(), response.usage))
+  }
+}
+
+/// The response given by a `ChatEnsembleRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatEnsembleResponse {
+  /// The answer picked from the candidates.
+  pub content:    String,
+  /// Every candidate, in the order they were sampled.
+  pub candidates: Vec<String>,
+  /// The tokens used by the request, including any judge, if the API
+  /// reported them.
+  pub usage:      Option<Usage>,
+}
+
+impl ChatEnsembleResponse {
+  /// The share of candidates which are the same as the answer, as a measure
+  /// of confidence.
+  pub fn agreement(&self) -> f64 {
+    if self.candidates.is_empty() {
+      return 0.0;
+    }
+    let votes = self
+      .candidates
+      .iter()
+      .filter(|candidate| candidate.trim() == self.content.trim())
+      .count();
+    votes as f64 / self.candidates.len() as f64
+  }
+}
+
+impl ResponseType for ChatEnsembleResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+/// Returns the candidate given most often, preferring the earliest on ties.
+fn majority_vote(candidates: &[String]) -> Option<String> {
+  let mut best: Option<(&str, usize)> = None;
+  for candidate in candidates {
+    let candidate = candidate.trim();
+    let votes = candidates
+      .iter()
+      .filter(|other| other.trim() == candidate)
+      .count();
+    if best.is_none_or(|(_, best_votes)| votes > best_votes) {
+      best = Some((candidate, votes));
+    }
+  }
+  best.map(|(candidate, _)| candidate.to_string())
+}
+
+#[async_trait]
+impl OrchRequest for ChatEnsembleRequest {
+  type Res = ChatEnsembleResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    let response = self.simo().send(policies.clone(), keys.clone(), id).await?;
+    let candidates: Vec<String> = response
+      .contents()
+      .into_iter()
+      .map(str::to_string)
+      .collect();
+
+    let (content, judge_usage) = match &self.strategy {
+      EnsembleStrategy::MajorityVote => (majority_vote(&candidates), None),
+      EnsembleStrategy::Reducer(reducer) => (reducer(&candidates), None),
+      EnsembleStrategy::Judge { model_params } if !candidates.is_empty() => {
+        let (content, usage) = self
+          .judge(model_params, &candidates, policies, keys, id)
+          .await?;
+        (Some(content), usage)
+      }
+      EnsembleStrategy::Judge { .. } => (None, None),
+    };
+    let content = content.ok_or_else(|| {
+      OrchError::InvalidResponse(
+        "no answer could be picked from the candidates".to_string(),
+      )
+    })?;
+
+    Ok(ChatEnsembleResponse {
+      content,
+      candidates,
+      usage: Usage::merge(response.usage, judge_usage),
+    })
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    self.simo().estimated_tokens()
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    let (_, usage) = self.simo().estimated_usage()?;
+    Some((self.model_params.model.as_str(), usage))
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    // a reducer can't be told apart from another, so only the built-in
+    // strategies are cached. The key differs from that of the equivalent
+    // `ChatSimoRequest`, whose response is of a different type.
+    let simo_key = self.simo().cache_key()?;
+    match &self.strategy {
+      EnsembleStrategy::MajorityVote => cache_key(&("vote", simo_key)),
+      EnsembleStrategy::Reducer(_) => None,
+      EnsembleStrategy::Judge { model_params } => {
+        cache_key(&("judge", simo_key, model_params))
+      }
+    }
+  }
+}
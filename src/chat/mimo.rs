@@ -0,0 +1,356 @@
+//! A "multiple input, single output" request for the OpenAI Chat API.
+
+use async_openai::types::{
+  ChatCompletionRequestMessage, ChatCompletionTool,
+  ChatCompletionToolChoiceOption, CreateChatCompletionRequest, ReasoningEffort,
+  ResponseFormat, Stop,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  cache::cache_key,
+  chat::{
+    check_guardrails, check_truncation, completion_timeout, estimate_tokens,
+    estimate_usage, fit_context, retry_guardrails,
+    validate::{repair_prompt, Validator},
+    with_fallback, ChatFinishReason, ChatMessage, ChatModelParams, ChatReply,
+    ChatResponse,
+  },
+  cost::{record_usage, Usage},
+  error::{OrchError, Result},
+  keys::Keys,
+  moderation,
+  policies::{Policies, TruncationPolicy},
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest,
+};
+
+/// A MIMO (multiple input messages) request for the OpenAI Chat API. Use this
+/// to continue an existing conversation through the `Orchestrator`.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMimoRequest {
+  pub messages:     Vec<ChatMessage>,
+  pub model_params: ChatModelParams,
+  /// Checks the reply, asking the model to repair it if it is invalid. See
+  /// the `validate` module.
+  #[serde(skip)]
+  pub validator:    Option<Validator>,
+}
+
+impl ChatMimoRequest {
+  pub fn new(
+    messages: Vec<ChatMessage>,
+    model_params: ChatModelParams,
+  ) -> Self {
+    Self {
+      messages,
+      model_params,
+      validator: None,
+    }
+  }
+
+  /// Checks the reply with `validator`, asking the model to repair it if it
+  /// is invalid.
+  ///
+  /// Validators can't be told apart, so requests with one are neither
+  /// cached nor coalesced, and a reply is never reused without being
+  /// checked.
+  pub fn with_validator(mut self, validator: Validator) -> Self {
+    self.validator = Some(validator);
+    self
+  }
+}
+
+/// The response given by a `ChatMimoRequest`.
+pub type ChatMimoResponse = ChatResponse;
+
+#[async_trait]
+impl OrchRequest for ChatMimoRequest {
+  type Res = ChatMimoResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let messages =
+      fit_context(self.messages.clone(), &self.model_params, &policies)?;
+    moderation::screen(&messages, &policies, &keys, id).await?;
+
+    // continuations and repairs go to whichever model gave the reply, which
+    // may be one of the `FallbackPolicy`'s
+    let (mut response, model_params) =
+      complete(&keys, &policies, &messages, &self.model_params, true, id)
+        .await?;
+
+    if let TruncationPolicy::Continue { max_continuations } =
+      policies.truncation_policy
+    {
+      let mut continuations = 0;
+      while response.is_truncated() && continuations < max_continuations {
+        let ChatReply::Content(partial) = &response.reply else {
+          break;
+        };
+        debug!("continuing truncated completion for {}", id);
+        let mut messages = messages.clone();
+        messages.push(ChatMessage::assistant(partial.clone()));
+        messages.push(ChatMessage::user(CONTINUE_PROMPT.to_string()));
+        let (next, _) =
+          complete(&keys, &policies, &messages, &model_params, false, id)
+            .await?;
+        response = stitch(response, next)?;
+        continuations += 1;
+      }
+    }
+
+    check_truncation(&policies, response.finish_reason, &response.reply)?;
+    let mut response = match &self.validator {
+      Some(validator) => {
+        repair(
+          &keys,
+          &policies,
+          messages,
+          &model_params,
+          validator,
+          response,
+          id,
+        )
+        .await?
+      }
+      None => response,
+    };
+    check_guardrails(&policies, &mut response.reply)?;
+    Ok(response)
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    estimate_tokens(&self.messages, &self.model_params)
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    let usage = estimate_usage(&self.messages, &self.model_params);
+    Some((self.model_params.model.as_str(), usage))
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    // a reply cached for another validator, or none, may not pass this one
+    if self.validator.is_some() {
+      return None;
+    }
+    cache_key(&build_inner_request(&self.messages, &self.model_params))
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
+
+/// The user message asking the model to carry on with a truncated completion.
+const CONTINUE_PROMPT: &str =
+  "Continue exactly where you left off, without repeating anything.";
+
+/// Sends a single chat completion request, retrying it as the policies allow.
+/// Returns the response along with the model params it was sent with, which
+/// are those of a fallback model if the `FallbackPolicy` stepped in.
+///
+/// Attempts breaking the `GuardrailPolicy` are only retried if `guarded` is
+/// set, for the first completion of a request. A continuation is only a piece
+/// of the reply, so continuations and repairs are left to `check_guardrails`
+/// once the reply is put together.
+async fn complete(
+  keys: &Keys,
+  policies: &Policies,
+  messages: &[ChatMessage],
+  model_params: &ChatModelParams,
+  guarded: bool,
+  id: u64,
+) -> Result<(ChatResponse, ChatModelParams)> {
+  let prompt_len: usize =
+    messages.iter().map(|message| message.content.len()).sum();
+  let timeout_duration = completion_timeout(prompt_len, model_params, policies);
+  let transport = &transport::current();
+
+  let (response, model_params) =
+    with_fallback(policies, model_params, id, |model_params| async move {
+      let request = build_inner_request(messages, &model_params);
+      let response = with_retries(policies, timeout_duration, id, move || {
+        let request = request.clone();
+        async move {
+          let response = transport.chat(keys, request).await?;
+          if let Some(usage) = &response.usage {
+            record_usage(&response.model, usage.into());
+          }
+          let content = response
+            .choices
+            .first()
+            .and_then(|choice| choice.message.content.as_deref());
+          if guarded {
+            retry_guardrails(policies, content)?;
+          }
+          Ok(response)
+        }
+      })
+      .await?;
+      Ok((response, model_params))
+    })
+    .await?;
+
+  let usage = response.usage.as_ref().map(Usage::from);
+  let system_fingerprint = response.system_fingerprint;
+  let choice = response.choices.into_iter().next().ok_or_else(|| {
+    OrchError::InvalidResponse("response.choices is empty".to_string())
+  })?;
+
+  let response = ChatResponse {
+    reply: ChatReply::try_from(choice.message)?,
+    usage,
+    system_fingerprint,
+    finish_reason: choice.finish_reason.map(ChatFinishReason::from),
+  };
+  Ok((response, model_params))
+}
+
+/// Asks the model to repair its reply for as long as `validator` rejects it,
+/// up to the validator's `max_repairs` times, by sending the conversation
+/// again with the reply and the reason it is invalid appended.
+async fn repair(
+  keys: &Keys,
+  policies: &Policies,
+  mut messages: Vec<ChatMessage>,
+  model_params: &ChatModelParams,
+  validator: &Validator,
+  mut response: ChatResponse,
+  id: u64,
+) -> Result<ChatResponse> {
+  let mut repairs = 0;
+  loop {
+    let ChatReply::Content(content) = &response.reply else {
+      return Ok(response);
+    };
+    let Err(reason) = validator.check(content) else {
+      return Ok(response);
+    };
+    if repairs == validator.max_repairs() {
+      return Err(OrchError::ValidationFailed { reason, repairs });
+    }
+    debug!("asking the model to repair the reply to {}: {}", id, reason);
+    messages.push(ChatMessage::assistant(content.clone()));
+    messages.push(ChatMessage::user(repair_prompt(&reason)));
+    let (next, _) =
+      complete(keys, policies, &messages, model_params, false, id).await?;
+    check_truncation(policies, next.finish_reason, &next.reply)?;
+    response = ChatResponse {
+      usage: Usage::merge(response.usage, next.usage),
+      ..next
+    };
+    repairs += 1;
+  }
+}
+
+/// Joins the continuation of a truncated completion onto it.
+fn stitch(response: ChatResponse, next: ChatResponse) -> Result<ChatResponse> {
+  let (ChatReply::Content(mut content), ChatReply::Content(rest)) =
+    (response.reply, next.reply)
+  else {
+    return Err(OrchError::InvalidResponse(
+      "expected content continuing a truncated completion".to_string(),
+    ));
+  };
+  content.push_str(&rest);
+
+  Ok(ChatResponse {
+    reply:              ChatReply::Content(content),
+    usage:              Usage::merge(response.usage, next.usage),
+    system_fingerprint: next.system_fingerprint,
+    finish_reason:      next.finish_reason,
+  })
+}
+
+fn saturating_u32(n: u64) -> u32 {
+  u32::try_from(n).unwrap_or(u32::MAX)
+}
+
+// `max_tokens` is deprecated in favor of `max_completion_tokens`, but the
+// latter is not supported by all models yet.
+#[allow(deprecated)]
+pub(crate) fn build_inner_request(
+  messages: &[ChatMessage],
+  model_params: &ChatModelParams,
+) -> CreateChatCompletionRequest {
+  let request = CreateChatCompletionRequest {
+    model: model_params.model.to_string(),
+    messages: messages
+      .iter()
+      .map(ChatCompletionRequestMessage::from)
+      .collect(),
+    temperature: Some(model_params.temperature),
+    top_p: Some(model_params.top_p),
+    max_tokens: model_params.max_tokens.map(saturating_u32),
+    max_completion_tokens: model_params
+      .max_completion_tokens
+      .map(saturating_u32),
+    presence_penalty: Some(model_params.presence_penalty),
+    frequency_penalty: Some(model_params.frequency_penalty),
+    stop: if model_params.stop.is_empty() {
+      None
+    } else if model_params.stop.len() == 1 {
+      Some(Stop::String(model_params.stop[0].clone()))
+    } else {
+      Some(Stop::StringArray(model_params.stop.clone()))
+    },
+    tools: if model_params.tools.is_empty() {
+      None
+    } else {
+      Some(
+        model_params
+          .tools
+          .iter()
+          .map(ChatCompletionTool::from)
+          .collect(),
+      )
+    },
+    tool_choice: model_params
+      .tool_choice
+      .as_ref()
+      .map(ChatCompletionToolChoiceOption::from),
+    response_format: model_params
+      .response_format
+      .as_ref()
+      .map(ResponseFormat::from),
+    seed: model_params.seed,
+    logit_bias: if model_params.logit_bias.is_empty() {
+      None
+    } else {
+      Some(
+        model_params
+          .logit_bias
+          .iter()
+          .map(|(token, bias)| (token.clone(), (*bias).into()))
+          .collect(),
+      )
+    },
+    reasoning_effort: model_params.reasoning_effort.map(ReasoningEffort::from),
+    ..Default::default()
+  };
+
+  if !model_params.is_reasoning() {
+    return request;
+  }
+  // reasoning models reject sampling parameters and `max_tokens`
+  CreateChatCompletionRequest {
+    temperature: None,
+    top_p: None,
+    presence_penalty: None,
+    frequency_penalty: None,
+    logit_bias: None,
+    max_tokens: None,
+    max_completion_tokens: model_params.completion_limit().map(saturating_u32),
+    ..request
+  }
+}
@@ -1,31 +1,1134 @@
 //! Requests and responses using Chat models.
 
+pub mod ensemble;
+pub mod mimo;
+pub mod session;
+pub mod simo;
 pub mod siso;
+pub mod stream;
+pub mod structured;
+pub mod validate;
+
+use core::fmt::{Display, Formatter};
+use std::{
+  collections::HashMap, future::Future, ops::Range, path::Path, time::Duration,
+};
+
+use async_openai::types::{
+  ChatCompletionMessageToolCall, ChatCompletionNamedToolChoice,
+  ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
+  ChatCompletionRequestMessageContentPartImage,
+  ChatCompletionRequestMessageContentPartText,
+  ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessageContent,
+  ChatCompletionRequestUserMessageContentPart, ChatCompletionResponseMessage,
+  ChatCompletionTool, ChatCompletionToolChoiceOption, ChatCompletionToolType,
+  FinishReason, FunctionCall, FunctionName, FunctionObject,
+  ImageDetail as ApiImageDetail, ImageUrl, ReasoningEffort, ResponseFormat,
+  ResponseFormatJsonSchema,
+};
+use base64::Engine;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+  chat::siso::ChatSisoRequest,
+  cost::Usage,
+  error::{OrchError, Result},
+  keys::Keys,
+  models::Model,
+  policies::{ContextPolicy, GuardrailAction, Policies, TruncationPolicy},
+  trace::debug,
+  OrchRequest, ResponseType,
+};
+
+/// Sends a single chat completion of a system prompt followed by a user
+/// prompt, without an `Orchestrator`, for scripts which only need one call.
+/// Failed attempts are retried following the default `Policies`; nothing
+/// else, such as rate or concurrency limits, applies.
+///
+/// ```rust,no_run
+/// # async fn example() -> Result<(), openai_orch::error::OrchError> {
+/// use openai_orch::{chat, prelude::*};
+///
+/// let keys = Keys::from_env().unwrap();
+/// let response = chat::complete(
+///   &keys,
+///   "You are a helpful assistant.",
+///   "What are you?",
+///   Default::default(),
+/// )
+/// .await?;
+/// println!("{}", response);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn complete(
+  keys: &Keys,
+  system: impl Into<String>,
+  user: impl Into<String>,
+  params: ChatModelParams,
+) -> Result<ChatResponse> {
+  ChatSisoRequest::new(system.into(), user.into(), params)
+    .send(Policies::default(), keys.clone(), crate::next_request_id())
+    .await
+}
+
+/// The author of a message in a chat conversation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatRole {
+  System,
+  User,
+  Assistant,
+  /// The result of a tool call requested by the assistant.
+  Tool,
+}
+
+/// A single role-tagged message in a chat conversation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+  pub role:         ChatRole,
+  pub content:      String,
+  /// The tool calls made by the assistant, if any.
+  #[serde(default)]
+  pub tool_calls:   Vec<ChatToolCall>,
+  /// The tool call a `ChatRole::Tool` message is responding to.
+  #[serde(default)]
+  pub tool_call_id: Option<String>,
+  /// Images attached to a `ChatRole::User` message, for models with vision.
+  #[serde(default)]
+  pub images:       Vec<ChatImage>,
+}
+
+impl ChatMessage {
+  pub fn new(role: ChatRole, content: String) -> Self {
+    Self {
+      role,
+      content,
+      tool_calls: vec![],
+      tool_call_id: None,
+      images: vec![],
+    }
+  }
+
+  /// Returns a new message from the system.
+  pub fn system(content: String) -> Self {
+    Self::new(ChatRole::System, content)
+  }
+
+  /// Returns a new message from the user.
+  pub fn user(content: String) -> Self {
+    Self::new(ChatRole::User, content)
+  }
+
+  /// Returns a new message from the user with the given images attached.
+  pub fn user_with_images(content: String, images: Vec<ChatImage>) -> Self {
+    Self {
+      images,
+      ..Self::user(content)
+    }
+  }
+
+  /// Returns a new message from the assistant.
+  pub fn assistant(content: String) -> Self {
+    Self::new(ChatRole::Assistant, content)
+  }
+
+  /// Returns a new message from the assistant requesting the given tool calls.
+  pub fn assistant_tool_calls(tool_calls: Vec<ChatToolCall>) -> Self {
+    Self {
+      tool_calls,
+      ..Self::new(ChatRole::Assistant, String::new())
+    }
+  }
+
+  /// Returns a new message carrying the result of a tool call.
+  pub fn tool(tool_call_id: String, content: String) -> Self {
+    Self {
+      tool_call_id: Some(tool_call_id),
+      ..Self::new(ChatRole::Tool, content)
+    }
+  }
+}
+
+impl From<&ChatMessage> for ChatCompletionRequestMessage {
+  fn from(message: &ChatMessage) -> Self {
+    let content = message.content.clone();
+    match message.role {
+      ChatRole::System => ChatCompletionRequestMessage::System(content.into()),
+      ChatRole::User if message.images.is_empty() => {
+        ChatCompletionRequestMessage::User(content.into())
+      }
+      ChatRole::User => {
+        let text = (!content.is_empty()).then_some(
+          ChatCompletionRequestUserMessageContentPart::Text(
+            ChatCompletionRequestMessageContentPartText { text: content },
+          ),
+        );
+        let images = message.images.iter().map(|image| {
+          ChatCompletionRequestUserMessageContentPart::ImageUrl(
+            ChatCompletionRequestMessageContentPartImage {
+              image_url: ImageUrl::from(image),
+            },
+          )
+        });
+        ChatCompletionRequestMessage::User(
+          ChatCompletionRequestUserMessageContent::Array(
+            text.into_iter().chain(images).collect(),
+          )
+          .into(),
+        )
+      }
+      ChatRole::Assistant => {
+        let tool_calls = message
+          .tool_calls
+          .iter()
+          .map(ChatCompletionMessageToolCall::from)
+          .collect::<Vec<_>>();
+        ChatCompletionRequestMessage::Assistant(
+          ChatCompletionRequestAssistantMessage {
+            content: (!content.is_empty()).then(|| content.into()),
+            tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+            ..Default::default()
+          },
+        )
+      }
+      ChatRole::Tool => {
+        ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+          content:      content.into(),
+          tool_call_id: message.tool_call_id.clone().unwrap_or_default(),
+        })
+      }
+    }
+  }
+}
+
+/// How closely a model with vision looks at an image.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ImageDetail {
+  /// Let the model choose based on the size of the image.
+  #[default]
+  Auto,
+  /// A 512x512 version of the image, for a fixed 85 tokens.
+  Low,
+  /// The full image, in 512x512 tiles costing 170 tokens each.
+  High,
+}
+
+/// An image attached to a chat message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatImage {
+  /// A URL to the image, or a `data:` URL holding the encoded image.
+  pub url:    String,
+  pub detail: ImageDetail,
+}
+
+impl ChatImage {
+  /// Returns an image at the given URL, which the API fetches itself.
+  pub fn url(url: String, detail: ImageDetail) -> Self {
+    Self { url, detail }
+  }
+
+  /// Returns an image from its encoded bytes, such as the contents of a PNG
+  /// file, with the given MIME type, e.g. `image/png`.
+  pub fn from_bytes(
+    bytes: &[u8],
+    mime_type: &str,
+    detail: ImageDetail,
+  ) -> Self {
+    let data = base64::engine::general_purpose::STANDARD.encode(bytes);
+    Self::url(format!("data:{};base64,{}", mime_type, data), detail)
+  }
+
+  /// Loads a PNG, JPEG, GIF, or WebP image from a file, telling its type from
+  /// the file's extension.
+  pub fn from_file(
+    path: impl AsRef<Path>,
+    detail: ImageDetail,
+  ) -> Result<Self> {
+    let path = path.as_ref();
+    let extension = path
+      .extension()
+      .and_then(|extension| extension.to_str())
+      .map(str::to_ascii_lowercase);
+    let mime_type = match extension.as_deref() {
+      Some("png") => "image/png",
+      Some("jpg" | "jpeg") => "image/jpeg",
+      Some("gif") => "image/gif",
+      Some("webp") => "image/webp",
+      _ => {
+        return Err(OrchError::other(format!(
+          "unsupported image type: {}",
+          path.display()
+        )))
+      }
+    };
+    let bytes = std::fs::read(path).map_err(OrchError::other)?;
+    Ok(Self::from_bytes(&bytes, mime_type, detail))
+  }
+
+  /// A rough estimate of the prompt tokens the image uses, since its size
+  /// isn't known without decoding it. High detail is assumed to be a
+  /// 1024x1024 image.
+  pub(crate) fn estimated_tokens(&self) -> u64 {
+    match self.detail {
+      ImageDetail::Low => 85,
+      ImageDetail::Auto | ImageDetail::High => 765,
+    }
+  }
+}
+
+impl From<&ChatImage> for ImageUrl {
+  fn from(image: &ChatImage) -> Self {
+    ImageUrl {
+      url:    image.url.clone(),
+      detail: Some(match image.detail {
+        ImageDetail::Auto => ApiImageDetail::Auto,
+        ImageDetail::Low => ApiImageDetail::Low,
+        ImageDetail::High => ApiImageDetail::High,
+      }),
+    }
+  }
+}
+
+/// A tool (function) that the model may call instead of replying with content.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatTool {
+  /// The name of the function.
+  pub name:        String,
+  /// A description of what the function does, used by the model to choose
+  /// when and how to call it.
+  pub description: Option<String>,
+  /// The parameters the function accepts, as a JSON Schema object.
+  pub parameters:  serde_json::Value,
+}
+
+impl ChatTool {
+  pub fn new(
+    name: String,
+    description: Option<String>,
+    parameters: serde_json::Value,
+  ) -> Self {
+    Self {
+      name,
+      description,
+      parameters,
+    }
+  }
+}
+
+impl From<&ChatTool> for ChatCompletionTool {
+  fn from(tool: &ChatTool) -> Self {
+    ChatCompletionTool {
+      r#type:   ChatCompletionToolType::Function,
+      function: FunctionObject {
+        name:        tool.name.clone(),
+        description: tool.description.clone(),
+        parameters:  Some(tool.parameters.clone()),
+        strict:      None,
+      },
+    }
+  }
+}
+
+/// Controls whether and which tool the model calls.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ChatToolChoice {
+  /// The model will not call a tool.
+  None,
+  /// The model picks between replying and calling tools.
+  Auto,
+  /// The model must call one or more tools.
+  Required,
+  /// The model must call the tool with the given name.
+  Function(String),
+}
+
+impl From<&ChatToolChoice> for ChatCompletionToolChoiceOption {
+  fn from(choice: &ChatToolChoice) -> Self {
+    match choice {
+      ChatToolChoice::None => ChatCompletionToolChoiceOption::None,
+      ChatToolChoice::Auto => ChatCompletionToolChoiceOption::Auto,
+      ChatToolChoice::Required => ChatCompletionToolChoiceOption::Required,
+      ChatToolChoice::Function(name) => {
+        ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
+          r#type:   ChatCompletionToolType::Function,
+          function: FunctionName { name: name.clone() },
+        })
+      }
+    }
+  }
+}
+
+/// A call to one of the request's tools, as generated by the model.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatToolCall {
+  /// The ID of the tool call, to be referenced by the tool's result message.
+  pub id:        String,
+  /// The name of the function to call.
+  pub name:      String,
+  /// The arguments to call the function with.
+  pub arguments: serde_json::Value,
+}
+
+impl ChatToolCall {
+  /// Deserializes the arguments of the tool call into `T`.
+  pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T> {
+    serde_json::from_value(self.arguments.clone()).map_err(|err| {
+      OrchError::InvalidResponse(format!(
+        "arguments for tool call to `{}` do not match the target: {}",
+        self.name, err
+      ))
+    })
+  }
+}
+
+impl TryFrom<ChatCompletionMessageToolCall> for ChatToolCall {
+  type Error = OrchError;
+
+  fn try_from(tool_call: ChatCompletionMessageToolCall) -> Result<Self> {
+    let arguments = serde_json::from_str(&tool_call.function.arguments)
+      .map_err(|err| {
+        OrchError::InvalidResponse(format!(
+          "arguments for tool call to `{}` are not valid JSON: {}",
+          tool_call.function.name, err
+        ))
+      })?;
+    Ok(Self {
+      id: tool_call.id,
+      name: tool_call.function.name,
+      arguments,
+    })
+  }
+}
+
+impl From<&ChatToolCall> for ChatCompletionMessageToolCall {
+  fn from(tool_call: &ChatToolCall) -> Self {
+    ChatCompletionMessageToolCall {
+      id:       tool_call.id.clone(),
+      r#type:   ChatCompletionToolType::Function,
+      function: FunctionCall {
+        name:      tool_call.name.clone(),
+        arguments: tool_call.arguments.to_string(),
+      },
+    }
+  }
+}
+
+/// What the model replied with: either message content or one or more tool
+/// calls.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ChatReply {
+  /// The model replied with message content.
+  Content(String),
+  /// The model called one or more of the request's tools.
+  ToolCalls(Vec<ChatToolCall>),
+}
+
+impl ChatReply {
+  /// Returns the message content, if the model replied with content.
+  pub fn content(&self) -> Option<&str> {
+    match self {
+      ChatReply::Content(content) => Some(content),
+      ChatReply::ToolCalls(_) => None,
+    }
+  }
+
+  /// Returns the tool calls, if the model called any tools.
+  pub fn tool_calls(&self) -> &[ChatToolCall] {
+    match self {
+      ChatReply::Content(_) => &[],
+      ChatReply::ToolCalls(tool_calls) => tool_calls,
+    }
+  }
+}
+
+impl TryFrom<ChatCompletionResponseMessage> for ChatReply {
+  type Error = OrchError;
+
+  fn try_from(message: ChatCompletionResponseMessage) -> Result<Self> {
+    match message.tool_calls {
+      Some(tool_calls) if !tool_calls.is_empty() => Ok(ChatReply::ToolCalls(
+        tool_calls
+          .into_iter()
+          .map(ChatToolCall::try_from)
+          .collect::<Result<_>>()?,
+      )),
+      _ => message.content.map(ChatReply::Content).ok_or_else(|| {
+        OrchError::InvalidResponse(
+          "response message content is None".to_string(),
+        )
+      }),
+    }
+  }
+}
+
+impl Display for ChatReply {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ChatReply::Content(content) => write!(f, "{}", content),
+      ChatReply::ToolCalls(tool_calls) => {
+        for (i, tool_call) in tool_calls.iter().enumerate() {
+          if i > 0 {
+            writeln!(f)?;
+          }
+          write!(f, "{}({})", tool_call.name, tool_call.arguments)?;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+impl From<ChatReply> for String {
+  fn from(reply: ChatReply) -> Self {
+    match reply {
+      ChatReply::Content(content) => content,
+      reply => reply.to_string(),
+    }
+  }
+}
+
+/// Why the model stopped generating.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatFinishReason {
+  /// The model reached a natural stopping point or a stop sequence.
+  Stop,
+  /// The completion reached `max_tokens`, so it is likely cut off.
+  Length,
+  /// The model called one or more tools.
+  ToolCalls,
+  /// Content was omitted by OpenAI's content filters.
+  ContentFilter,
+}
+
+impl From<FinishReason> for ChatFinishReason {
+  fn from(reason: FinishReason) -> Self {
+    match reason {
+      FinishReason::Stop => ChatFinishReason::Stop,
+      FinishReason::Length => ChatFinishReason::Length,
+      FinishReason::ToolCalls | FinishReason::FunctionCall => {
+        ChatFinishReason::ToolCalls
+      }
+      FinishReason::ContentFilter => ChatFinishReason::ContentFilter,
+    }
+  }
+}
+
+/// Fails with `OrchError::Truncated` if the completion was cut off and the
+/// `TruncationPolicy` doesn't allow that.
+pub(crate) fn check_truncation(
+  policies: &Policies,
+  finish_reason: Option<ChatFinishReason>,
+  reply: &ChatReply,
+) -> Result<()> {
+  if policies.truncation_policy == TruncationPolicy::Error
+    && finish_reason == Some(ChatFinishReason::Length)
+  {
+    return Err(OrchError::Truncated {
+      partial: reply.content().unwrap_or_default().to_string(),
+    });
+  }
+  Ok(())
+}
+
+/// Applies the `GuardrailPolicy` to a reply, cutting it off if the policy
+/// truncates violations, and failing with `OrchError::GuardrailViolated`
+/// otherwise.
+pub(crate) fn check_guardrails(
+  policies: &Policies,
+  reply: &mut ChatReply,
+) -> Result<()> {
+  let guardrail_policy = &policies.guardrail_policy;
+  let ChatReply::Content(content) = reply else {
+    return Ok(());
+  };
+  if !guardrail_policy.is_enabled() {
+    return Ok(());
+  }
+  let Some(violation) = guardrail_policy.violation(content) else {
+    return Ok(());
+  };
+  match (guardrail_policy.on_violation, violation.valid_up_to) {
+    (GuardrailAction::Truncate, Some(valid_up_to)) => {
+      debug!("truncating a reply: {}", violation.reason);
+      content.truncate(valid_up_to);
+      Ok(())
+    }
+    _ => Err(OrchError::GuardrailViolated {
+      reason: violation.reason,
+    }),
+  }
+}
+
+/// Fails an attempt with `OrchError::GuardrailViolated` if its content
+/// breaks the `GuardrailPolicy` and the policy retries violations, so that
+/// the attempt is retried. Other violations are left to `check_guardrails`.
+pub(crate) fn retry_guardrails(
+  policies: &Policies,
+  content: Option<&str>,
+) -> Result<()> {
+  let guardrail_policy = &policies.guardrail_policy;
+  let Some(content) = content else {
+    return Ok(());
+  };
+  if guardrail_policy.on_violation != GuardrailAction::Retry
+    || !guardrail_policy.is_enabled()
+  {
+    return Ok(());
+  }
+  match guardrail_policy.violation(content) {
+    Some(violation) => Err(OrchError::GuardrailViolated {
+      reason: violation.reason,
+    }),
+    None => Ok(()),
+  }
+}
+
+/// Whether a request should be sent again with a fallback model after
+/// failing with the given error.
+fn should_fall_back(err: &OrchError) -> bool {
+  match err {
+    OrchError::MaxRetriesExceeded { .. } => true,
+    OrchError::ApiError { code, .. } => {
+      code.as_deref() == Some("model_not_found")
+    }
+    _ => false,
+  }
+}
+
+/// Runs `attempt` with the request's model params, then again with each
+/// model of the `FallbackPolicy` for as long as the attempts keep failing.
+pub(crate) async fn with_fallback<T, F, Fut>(
+  policies: &Policies,
+  model_params: &ChatModelParams,
+  id: u64,
+  mut attempt: F,
+) -> Result<T>
+where
+  F: FnMut(ChatModelParams) -> Fut,
+  Fut: Future<Output = Result<T>>,
+{
+  let mut result = attempt(model_params.clone()).await;
+  for model in &policies.fallback_policy.models {
+    match &result {
+      Err(err) if should_fall_back(err) => {}
+      _ => break,
+    }
+    debug!("falling back to {} for request {}", model, id);
+    result = attempt(ChatModelParams {
+      model: model.clone(),
+      ..model_params.clone()
+    })
+    .await;
+  }
+  result
+}
+
+/// The response given by a chat request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatResponse {
+  /// What the model replied with.
+  pub reply:              ChatReply,
+  /// The tokens used by the request, if the API reported them.
+  pub usage:              Option<Usage>,
+  /// Identifies the backend configuration the model ran with, if the API
+  /// reported it. A change means a `seed` may no longer reproduce results.
+  #[serde(default)]
+  pub system_fingerprint: Option<String>,
+  /// Why the model stopped generating, if the API said.
+  #[serde(default)]
+  pub finish_reason:      Option<ChatFinishReason>,
+}
+
+impl ChatResponse {
+  /// Whether the completion was cut off because it reached `max_tokens`.
+  pub fn is_truncated(&self) -> bool {
+    self.finish_reason == Some(ChatFinishReason::Length)
+  }
+
+  /// Returns the message content, if the model replied with content.
+  pub fn content(&self) -> Option<&str> {
+    self.reply.content()
+  }
+
+  /// Returns the tool calls, if the model called any tools.
+  pub fn tool_calls(&self) -> &[ChatToolCall] {
+    self.reply.tool_calls()
+  }
+}
+
+impl Display for ChatResponse {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    self.reply.fmt(f)
+  }
+}
+
+impl From<ChatResponse> for String {
+  fn from(response: ChatResponse) -> Self {
+    response.reply.into()
+  }
+}
+
+impl ResponseType for ChatResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+/// Applies the `ContextPolicy` if the messages of a chat request and its
+/// completion can't fit in the model's context window, returning the
+/// messages to send. Prompts are counted as `prompt_tokens` does.
+pub(crate) fn fit_context(
+  mut messages: Vec<ChatMessage>,
+  model_params: &ChatModelParams,
+  policies: &Policies,
+) -> Result<Vec<ChatMessage>> {
+  let policy = policies.context_policy;
+  let model = model_params.model.as_str();
+  let Some(limit) = model_params.model.context_window() else {
+    return Ok(messages);
+  };
+  let needed = prompt_tokens(model, &messages)
+    + model_params.completion_limit().unwrap_or(0);
+  if needed <= limit {
+    return Ok(messages);
+  }
+  let exceeded = OrchError::ContextLengthExceeded {
+    tokens: needed,
+    limit,
+  };
+  if policy == ContextPolicy::Fail {
+    return Err(exceeded);
+  }
+
+  // a message and the tool results answering it are dropped together, since
+  // the API rejects one without the other
+  let start = messages
+    .iter()
+    .take_while(|message| message.role == ChatRole::System)
+    .count();
+  let mut groups: Vec<Range<usize>> = Vec::new();
+  for (i, message) in messages.iter().enumerate().skip(start) {
+    match groups.last_mut() {
+      Some(group) if message.role == ChatRole::Tool => group.end = i + 1,
+      _ => groups.push(i..i + 1),
+    }
+  }
+  // the newest message is always kept
+  groups.pop();
+  // the tokens priming the reply are counted once, not with every group
+  let overhead = prompt_tokens(model, &[]);
+  let mut droppable: Vec<(Range<usize>, u64)> = groups
+    .into_iter()
+    .map(|group| {
+      let tokens = prompt_tokens(model, &messages[group.clone()]) - overhead;
+      (group, tokens)
+    })
+    .collect();
+
+  let mut dropped = vec![false; messages.len()];
+  let mut tokens = needed;
+  while tokens > limit {
+    if droppable.is_empty() {
+      return Err(exceeded);
+    }
+    let index = match policy {
+      ContextPolicy::TruncateMiddle => droppable.len() / 2,
+      _ => 0,
+    };
+    let (group, group_tokens) = droppable.remove(index);
+    tokens -= group_tokens;
+    dropped[group].fill(true);
+  }
+
+  debug!(
+    "dropped {} messages to fit the context window of {}",
+    dropped.iter().filter(|dropped| **dropped).count(),
+    model
+  );
+  let mut dropped = dropped.into_iter();
+  messages.retain(|_| !dropped.next().unwrap_or(false));
+  Ok(messages)
+}
+
+/// Renders a system prompt, few-shot examples, and a user prompt as messages.
+pub(crate) fn prompt_messages(
+  system_prompt: &str,
+  examples: &[(String, String)],
+  user_prompt: &str,
+) -> Vec<ChatMessage> {
+  let mut messages = vec![ChatMessage::system(system_prompt.to_string())];
+  for (user, assistant) in examples {
+    messages.push(ChatMessage::user(user.clone()));
+    messages.push(ChatMessage::assistant(assistant.clone()));
+  }
+  messages.push(ChatMessage::user(user_prompt.to_string()));
+  messages
+}
+
+/// The completion assumed by estimates when its length isn't limited.
+const UNLIMITED_COMPLETION_ESTIMATE: u64 = 256;
+
+/// Estimates the tokens used by a chat request, assuming a completion as long
+/// as its limit, or of 256 tokens without one. Prompt tokens are counted as
+/// `prompt_tokens` does.
+pub(crate) fn estimate_usage(
+  messages: &[ChatMessage],
+  model_params: &ChatModelParams,
+) -> Usage {
+  Usage::new(
+    prompt_tokens(model_params.model.as_str(), messages),
+    model_params
+      .completion_limit()
+      .unwrap_or(UNLIMITED_COMPLETION_ESTIMATE),
+  )
+}
+
+/// The timeout for a single attempt at a chat completion, scaled by the
+/// `TimeoutPolicy` to the size of the prompt and the completion limit.
+/// Without a limit the longest timeout the policy allows is used.
+pub(crate) fn completion_timeout(
+  prompt_len: usize,
+  model_params: &ChatModelParams,
+  policies: &Policies,
+) -> Duration {
+  match model_params.completion_limit() {
+    Some(limit) => policies
+      .timeout_policy
+      .timeout_for(limit + prompt_len as u64 / 4),
+    None => policies.timeout_policy.timeout(),
+  }
+}
+
+/// Returns the prompt tokens used by the given messages. They are counted
+/// exactly with the `tokens` feature, and otherwise assumed to be roughly four
+/// characters each.
+#[cfg(feature = "tokens")]
+pub(crate) fn prompt_tokens(model: &str, messages: &[ChatMessage]) -> u64 {
+  crate::tokens::count_message_tokens(model, messages) as u64
+}
+
+#[cfg(not(feature = "tokens"))]
+pub(crate) fn prompt_tokens(_model: &str, messages: &[ChatMessage]) -> u64 {
+  messages
+    .iter()
+    .map(|message| message.content.len() as u64)
+    .sum::<u64>()
+    / 4
+    + image_tokens(messages)
+}
+
+/// Estimates the prompt tokens used by the images attached to the messages.
+pub(crate) fn image_tokens(messages: &[ChatMessage]) -> u64 {
+  messages
+    .iter()
+    .flat_map(|message| &message.images)
+    .map(ChatImage::estimated_tokens)
+    .sum()
+}
+
+/// Estimates the total tokens used by a chat request, as `estimate_usage`.
+pub(crate) fn estimate_tokens(
+  messages: &[ChatMessage],
+  model_params: &ChatModelParams,
+) -> u64 {
+  estimate_usage(messages, model_params).total_tokens()
+}
+
+/// The form the model replies in.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ChatResponseFormat {
+  /// Free-form text.
+  Text,
+  /// JSON mode: the model produces a valid JSON object. The messages must
+  /// instruct the model to produce JSON.
+  JsonObject,
+  /// Structured outputs: the model produces JSON adhering to a schema.
+  JsonSchema {
+    /// The name of the schema.
+    name:   String,
+    /// The JSON Schema describing the expected object.
+    schema: serde_json::Value,
+    /// Whether the model must follow the schema exactly.
+    strict: bool,
+  },
+}
+
+impl From<&ChatResponseFormat> for ResponseFormat {
+  fn from(format: &ChatResponseFormat) -> Self {
+    match format {
+      ChatResponseFormat::Text => ResponseFormat::Text,
+      ChatResponseFormat::JsonObject => ResponseFormat::JsonObject,
+      ChatResponseFormat::JsonSchema {
+        name,
+        schema,
+        strict,
+      } => ResponseFormat::JsonSchema {
+        json_schema: ResponseFormatJsonSchema {
+          description: None,
+          name:        name.clone(),
+          schema:      Some(schema.clone()),
+          strict:      Some(*strict),
+        },
+      },
+    }
+  }
+}
+
+/// How much a reasoning model reasons before it answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatReasoningEffort {
+  Low,
+  Medium,
+  High,
+}
+
+impl From<ChatReasoningEffort> for ReasoningEffort {
+  fn from(effort: ChatReasoningEffort) -> Self {
+    match effort {
+      ChatReasoningEffort::Low => ReasoningEffort::Low,
+      ChatReasoningEffort::Medium => ReasoningEffort::Medium,
+      ChatReasoningEffort::High => ReasoningEffort::High,
+    }
+  }
+}
 
 /// Parameters common to all OpenAI Chat models.
 ///
+/// For reasoning models, such as the o-series, the sampling parameters
+/// (`temperature`, `top_p`, the penalties, and `logit_bias`) are left out of
+/// the request, since those models reject them, and the completion limit is
+/// sent as `max_completion_tokens`. The reasoning tokens used are reported in
+/// `Usage::reasoning_tokens`.
+///
 /// Refer to `async-openai`'s `CreateChatCompletionRequest` for exact details.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ChatModelParams {
-  pub model:             String,
-  pub temperature:       f32,
-  pub top_p:             f32,
-  pub stop:              Vec<String>,
-  pub max_tokens:        u64,
-  pub frequency_penalty: f32,
-  pub presence_penalty:  f32,
+  pub model:                 Model,
+  pub temperature:           f32,
+  pub top_p:                 f32,
+  pub stop:                  Vec<String>,
+  /// The most tokens to generate. `None` leaves the field out, so that only
+  /// the context window limits the completion. Newer models, such as the
+  /// o-series, use `max_completion_tokens` instead.
+  pub max_tokens:            Option<u64>,
+  /// The most tokens to generate, including reasoning tokens, for models
+  /// which don't accept `max_tokens`.
+  pub max_completion_tokens: Option<u64>,
+  pub frequency_penalty:     f32,
+  pub presence_penalty:      f32,
+  /// Tools the model may call instead of replying with content.
+  pub tools:                 Vec<ChatTool>,
+  /// Controls which (if any) tool is called. `None` leaves the choice to the
+  /// API's default.
+  pub tool_choice:           Option<ChatToolChoice>,
+  /// The form the model replies in. `None` leaves it to the API's default,
+  /// which is text.
+  pub response_format:       Option<ChatResponseFormat>,
+  /// Asks the API to sample deterministically, so that repeating a request
+  /// with the same seed and parameters mostly gives the same result. Compare
+  /// the `system_fingerprint` of responses to notice when the backend changes.
+  pub seed:                  Option<i64>,
+  /// Biases the likelihood of tokens, keyed by token ID, from -100 (banned)
+  /// to 100 (exclusive selection). The IDs depend on the model's tokenizer;
+  /// with the `tokens` feature, use `with_text_bias` to bias by string.
+  pub logit_bias:            HashMap<String, i32>,
+  /// How much a reasoning model reasons before it answers. Setting it marks
+  /// the model as a reasoning model, even if `Model` doesn't know it as one.
+  pub reasoning_effort:      Option<ChatReasoningEffort>,
 }
 
 impl Default for ChatModelParams {
   fn default() -> Self {
     Self {
-      model:             String::from("gpt-3.5-turbo"),
-      temperature:       0.0,
-      top_p:             1.0,
-      stop:              vec![],
-      max_tokens:        256,
-      frequency_penalty: 0.0,
-      presence_penalty:  0.0,
+      model:                 Model::Gpt35Turbo,
+      temperature:           0.0,
+      top_p:                 1.0,
+      stop:                  vec![],
+      max_tokens:            None,
+      max_completion_tokens: None,
+      frequency_penalty:     0.0,
+      presence_penalty:      0.0,
+      tools:                 vec![],
+      tool_choice:           None,
+      response_format:       None,
+      seed:                  None,
+      logit_bias:            HashMap::new(),
+      reasoning_effort:      None,
+    }
+  }
+}
+
+impl ChatModelParams {
+  /// Returns the default params for a reasoning model with the given effort.
+  pub fn reasoning(
+    model: impl Into<Model>,
+    reasoning_effort: ChatReasoningEffort,
+  ) -> Self {
+    Self {
+      model: model.into(),
+      reasoning_effort: Some(reasoning_effort),
+      ..Default::default()
+    }
+  }
+
+  /// Whether the params are for a reasoning model: either the reasoning
+  /// effort is set, or `Model` knows the model as one.
+  pub fn is_reasoning(&self) -> bool {
+    self.reasoning_effort.is_some() || self.model.is_reasoning()
+  }
+
+  /// The most tokens the completion may use, if it is limited.
+  pub fn completion_limit(&self) -> Option<u64> {
+    self.max_completion_tokens.or(self.max_tokens)
+  }
+
+  /// Returns a builder starting from the default params.
+  pub fn builder() -> ChatModelParamsBuilder {
+    ChatModelParamsBuilder {
+      model_params: Self::default(),
+    }
+  }
+
+  /// Adds `bias` to the `logit_bias` of every token `text` is made of for this
+  /// params' model. Tokenizers treat a leading space as part of a word, so
+  /// bias both `"yes"` and `" yes"` to cover either.
+  #[cfg(feature = "tokens")]
+  pub fn with_text_bias(mut self, text: &str, bias: i32) -> Self {
+    for token in crate::tokens::encode(self.model.as_str(), text) {
+      self.logit_bias.insert(token.to_string(), bias);
+    }
+    self
+  }
+}
+
+/// Generates setters for the fields of the `ChatModelParams` held by a
+/// builder in its `model_params` field.
+macro_rules! model_param_setters {
+  () => {
+    /// Sets the model.
+    pub fn model(mut self, model: impl Into<$crate::models::Model>) -> Self {
+      self.model_params.model = model.into();
+      self
+    }
+
+    /// Sets the sampling temperature.
+    pub fn temperature(mut self, temperature: f32) -> Self {
+      self.model_params.temperature = temperature;
+      self
+    }
+
+    /// Sets the nucleus sampling probability mass.
+    pub fn top_p(mut self, top_p: f32) -> Self {
+      self.model_params.top_p = top_p;
+      self
     }
+
+    /// Adds a sequence which stops the completion.
+    pub fn stop(mut self, stop: impl Into<String>) -> Self {
+      self.model_params.stop.push(stop.into());
+      self
+    }
+
+    /// Sets the maximum number of tokens to generate.
+    pub fn max_tokens(mut self, max_tokens: u64) -> Self {
+      self.model_params.max_tokens = Some(max_tokens);
+      self
+    }
+
+    /// Sets the maximum number of tokens to generate, including reasoning
+    /// tokens.
+    pub fn max_completion_tokens(mut self, max_completion_tokens: u64) -> Self {
+      self.model_params.max_completion_tokens = Some(max_completion_tokens);
+      self
+    }
+
+    /// Sets the frequency penalty.
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+      self.model_params.frequency_penalty = frequency_penalty;
+      self
+    }
+
+    /// Sets the presence penalty.
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+      self.model_params.presence_penalty = presence_penalty;
+      self
+    }
+
+    /// Adds a tool the model may call.
+    pub fn tool(mut self, tool: $crate::chat::ChatTool) -> Self {
+      self.model_params.tools.push(tool);
+      self
+    }
+
+    /// Sets which (if any) tool is called.
+    pub fn tool_choice(
+      mut self,
+      tool_choice: $crate::chat::ChatToolChoice,
+    ) -> Self {
+      self.model_params.tool_choice = Some(tool_choice);
+      self
+    }
+
+    /// Sets the form the model replies in.
+    pub fn response_format(
+      mut self,
+      response_format: $crate::chat::ChatResponseFormat,
+    ) -> Self {
+      self.model_params.response_format = Some(response_format);
+      self
+    }
+
+    /// Sets how much a reasoning model reasons before it answers.
+    pub fn reasoning_effort(
+      mut self,
+      reasoning_effort: $crate::chat::ChatReasoningEffort,
+    ) -> Self {
+      self.model_params.reasoning_effort = Some(reasoning_effort);
+      self
+    }
+
+    /// Sets the seed for deterministic sampling.
+    pub fn seed(mut self, seed: i64) -> Self {
+      self.model_params.seed = Some(seed);
+      self
+    }
+
+    /// Biases the likelihood of a token, by its ID.
+    pub fn logit_bias(mut self, token: impl Into<String>, bias: i32) -> Self {
+      self.model_params.logit_bias.insert(token.into(), bias);
+      self
+    }
+
+    /// Biases the likelihood of every token `text` is made of, as
+    /// `ChatModelParams::with_text_bias`. Set the model first, since the
+    /// tokens depend on its tokenizer.
+    #[cfg(feature = "tokens")]
+    pub fn text_bias(mut self, text: &str, bias: i32) -> Self {
+      self.model_params = self.model_params.with_text_bias(text, bias);
+      self
+    }
+  };
+}
+
+pub(crate) use model_param_setters;
+
+/// A builder for `ChatModelParams`, starting from the defaults.
+#[derive(Clone)]
+pub struct ChatModelParamsBuilder {
+  model_params: ChatModelParams,
+}
+
+impl ChatModelParamsBuilder {
+  model_param_setters!();
+
+  pub fn build(self) -> ChatModelParams {
+    self.model_params
   }
 }
@@ -0,0 +1,236 @@
+//! A conversation which keeps its history between turns.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  chat::{
+    estimate_usage, mimo::ChatMimoRequest, ChatMessage, ChatModelParams,
+    ChatReply, ChatResponse, ChatRole,
+  },
+  error::{OrchError, Result},
+  Orchestrator,
+};
+
+/// The system prompt used to summarize history for
+/// `HistoryStrategy::Summarize`.
+const SUMMARIZE_PROMPT: &str = "Summarize the following conversation \
+                                concisely, keeping any facts, names, and \
+                                decisions needed to continue it.";
+
+/// How a `ChatSession` keeps its history from growing without bound. The
+/// system prompt and the newest message are always kept.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub enum HistoryStrategy {
+  /// Every message is kept.
+  #[default]
+  KeepAll,
+  /// Only the `max_messages` newest messages are kept.
+  DropOldest { max_messages: usize },
+  /// The oldest messages are dropped until the prompt is estimated to use at
+  /// most `max_tokens`.
+  TokenBudget { max_tokens: u64 },
+  /// Once there are more than `max_messages` messages, all but the
+  /// `keep_recent` newest are replaced by a summary written by the model,
+  /// which costs an extra request.
+  Summarize {
+    max_messages: usize,
+    keep_recent:  usize,
+  },
+}
+
+/// A chat conversation which sends each turn through an `Orchestrator` and
+/// remembers the replies, so that callers don't have to manage the history
+/// themselves.
+///
+/// ```rust,no_run
+/// # use openai_orch::prelude::*;
+/// # async fn example(orchestrator: Orchestrator) -> Result<(), OrchError> {
+/// let mut session = ChatSession::new(Default::default())
+///   .with_system_prompt("You are a helpful assistant.".to_string());
+/// session.send(&orchestrator, "My name is Sam.".to_string()).await?;
+/// let reply = session
+///   .send(&orchestrator, "What is my name?".to_string())
+///   .await?;
+/// println!("{}", reply);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatSession {
+  pub model_params: ChatModelParams,
+  system_prompt:    Option<String>,
+  history:          Vec<ChatMessage>,
+  strategy:         HistoryStrategy,
+}
+
+impl ChatSession {
+  pub fn new(model_params: ChatModelParams) -> Self {
+    Self {
+      model_params,
+      system_prompt: None,
+      history: Vec::new(),
+      strategy: HistoryStrategy::default(),
+    }
+  }
+
+  /// Sets the system prompt, which is sent first on every turn.
+  pub fn with_system_prompt(mut self, system_prompt: String) -> Self {
+    self.system_prompt = Some(system_prompt);
+    self
+  }
+
+  /// Sets how the history is kept from growing without bound.
+  pub fn with_history_strategy(mut self, strategy: HistoryStrategy) -> Self {
+    self.strategy = strategy;
+    self
+  }
+
+  /// The messages of the conversation so far, without the system prompt.
+  pub fn history(&self) -> &[ChatMessage] {
+    &self.history
+  }
+
+  /// Appends a message to the history without sending it, for example the
+  /// result of a tool call before calling `respond`.
+  pub fn push(&mut self, message: ChatMessage) {
+    self.history.push(message);
+  }
+
+  /// Forgets the history, keeping the system prompt.
+  pub fn clear(&mut self) {
+    self.history.clear();
+  }
+
+  /// Sends a user message and returns the reply, which is appended to the
+  /// history. If the request fails, the message is removed again, so that it
+  /// can be resent.
+  pub async fn send(
+    &mut self,
+    orchestrator: &Orchestrator,
+    content: String,
+  ) -> Result<ChatResponse> {
+    self.history.push(ChatMessage::user(content));
+    let result = self.respond(orchestrator).await;
+    if result.is_err() {
+      self.history.pop();
+    }
+    result
+  }
+
+  /// Asks the model to reply to the history as it is, and appends the reply.
+  pub async fn respond(
+    &mut self,
+    orchestrator: &Orchestrator,
+  ) -> Result<ChatResponse> {
+    self.apply_strategy(orchestrator).await?;
+
+    let request =
+      ChatMimoRequest::new(self.messages(), self.model_params.clone());
+    let response = orchestrator.add_request(request).await.await?;
+    self.history.push(match &response.reply {
+      ChatReply::Content(content) => ChatMessage::assistant(content.clone()),
+      ChatReply::ToolCalls(tool_calls) => {
+        ChatMessage::assistant_tool_calls(tool_calls.clone())
+      }
+    });
+    Ok(response)
+  }
+
+  /// The messages sent to the model: the system prompt and the history.
+  fn messages(&self) -> Vec<ChatMessage> {
+    self
+      .system_prompt
+      .iter()
+      .map(|prompt| ChatMessage::system(prompt.clone()))
+      .chain(self.history.iter().cloned())
+      .collect()
+  }
+
+  async fn apply_strategy(
+    &mut self,
+    orchestrator: &Orchestrator,
+  ) -> Result<()> {
+    match self.strategy {
+      HistoryStrategy::KeepAll => {}
+      HistoryStrategy::DropOldest { max_messages } => {
+        let excess = self.history.len().saturating_sub(max_messages.max(1));
+        self.drop_oldest(excess);
+      }
+      HistoryStrategy::TokenBudget { max_tokens } => {
+        while self.history.len() > 1
+          && estimate_usage(&self.messages(), &self.model_params).prompt_tokens
+            > max_tokens
+        {
+          self.drop_oldest(1);
+        }
+      }
+      HistoryStrategy::Summarize {
+        max_messages,
+        keep_recent,
+      } => {
+        if self.history.len() > max_messages {
+          self.summarize(orchestrator, keep_recent.max(1)).await?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Drops the `n` oldest messages, along with any tool results left without
+  /// the tool calls they answer, which the API would reject.
+  fn drop_oldest(&mut self, n: usize) {
+    self
+      .history
+      .drain(..n.min(self.history.len().saturating_sub(1)));
+    while self.history.len() > 1 && self.history[0].role == ChatRole::Tool {
+      self.history.remove(0);
+    }
+  }
+
+  /// Replaces all but the `keep_recent` newest messages with a summary.
+  async fn summarize(
+    &mut self,
+    orchestrator: &Orchestrator,
+    keep_recent: usize,
+  ) -> Result<()> {
+    let mut split = self.history.len().saturating_sub(keep_recent);
+    // tool results must stay with the tool calls they answer
+    while split < self.history.len() - 1
+      && self.history[split].role == ChatRole::Tool
+    {
+      split += 1;
+    }
+    if split == 0 {
+      return Ok(());
+    }
+
+    let transcript = self.history[..split]
+      .iter()
+      .map(|message| format!("{:?}: {}", message.role, message.content))
+      .collect::<Vec<_>>()
+      .join("\n");
+    let model_params = ChatModelParams {
+      tools: Vec::new(),
+      tool_choice: None,
+      response_format: None,
+      ..self.model_params.clone()
+    };
+    let request = ChatMimoRequest::new(
+      vec![
+        ChatMessage::system(SUMMARIZE_PROMPT.to_string()),
+        ChatMessage::user(transcript),
+      ],
+      model_params,
+    );
+    let response = orchestrator.add_request(request).await.await?;
+    let summary = response.content().ok_or_else(|| {
+      OrchError::InvalidResponse("expected a summary, got tool calls".into())
+    })?;
+
+    self.history.splice(..split, [ChatMessage::system(format!(
+      "Summary of the conversation so far: {}",
+      summary
+    ))]);
+    Ok(())
+  }
+}
@@ -0,0 +1,212 @@
+//! A "single input, multiple output" request for the OpenAI Chat API.
+
+use async_openai::types::CreateChatCompletionRequest;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  cache::cache_key,
+  chat::{
+    check_truncation, completion_timeout, estimate_usage, fit_context,
+    mimo::build_inner_request, with_fallback, ChatFinishReason, ChatMessage,
+    ChatModelParams, ChatReply,
+  },
+  cost::{record_usage, Usage},
+  error::{OrchError, Result},
+  keys::Keys,
+  moderation,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// A SIMO (single input, multiple output) request for the OpenAI Chat API.
+///
+/// Samples `n` completions of a system prompt followed by a single user
+/// prompt in one API call, for example to rerank candidates afterwards. The
+/// prompt is only paid for once, but each completion uses up to the
+/// completion limit.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatSimoRequest {
+  pub system_prompt: String,
+  pub user_prompt:   String,
+  /// The number of completions to sample, from 1 to 128.
+  pub n:             u8,
+  pub model_params:  ChatModelParams,
+}
+
+impl ChatSimoRequest {
+  pub fn new(
+    system_prompt: String,
+    user_prompt: String,
+    n: u8,
+    model_params: ChatModelParams,
+  ) -> Self {
+    Self {
+      system_prompt,
+      user_prompt,
+      n,
+      model_params,
+    }
+  }
+
+  fn messages(&self) -> Vec<ChatMessage> {
+    vec![
+      ChatMessage::system(self.system_prompt.clone()),
+      ChatMessage::user(self.user_prompt.clone()),
+    ]
+  }
+
+  fn build_inner_request(
+    &self,
+    messages: &[ChatMessage],
+    model_params: &ChatModelParams,
+  ) -> CreateChatCompletionRequest {
+    CreateChatCompletionRequest {
+      n: Some(self.n),
+      ..build_inner_request(messages, model_params)
+    }
+  }
+}
+
+/// One of the completions sampled by a `ChatSimoRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatChoice {
+  /// What the model replied with.
+  pub reply:         ChatReply,
+  /// Why the model stopped generating, if the API said.
+  pub finish_reason: Option<ChatFinishReason>,
+}
+
+impl ChatChoice {
+  /// Whether the completion was cut off because it reached `max_tokens`.
+  pub fn is_truncated(&self) -> bool {
+    self.finish_reason == Some(ChatFinishReason::Length)
+  }
+}
+
+/// The response given by a `ChatSimoRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatSimoResponse {
+  /// The sampled completions, in the order the API returned them.
+  pub choices:            Vec<ChatChoice>,
+  /// The tokens used by the request, if the API reported them.
+  pub usage:              Option<Usage>,
+  /// Identifies the backend configuration the model ran with, if the API
+  /// reported it.
+  #[serde(default)]
+  pub system_fingerprint: Option<String>,
+}
+
+impl ChatSimoResponse {
+  /// Returns the message content of the choices that replied with content.
+  pub fn contents(&self) -> Vec<&str> {
+    self
+      .choices
+      .iter()
+      .filter_map(|choice| choice.reply.content())
+      .collect()
+  }
+}
+
+impl ResponseType for ChatSimoResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+#[async_trait]
+impl OrchRequest for ChatSimoRequest {
+  type Res = ChatSimoResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let messages = fit_context(self.messages(), &self.model_params, &policies)?;
+    moderation::screen(&messages, &policies, &keys, id).await?;
+    let transport = transport::current();
+
+    // the choices are generated side by side, so `n` doesn't add to the time
+    let prompt_len = self.system_prompt.len() + self.user_prompt.len();
+    let timeout_duration =
+      completion_timeout(prompt_len, &self.model_params, &policies);
+
+    let (transport, keys) = (&transport, &keys);
+    let response =
+      with_fallback(&policies, &self.model_params, id, |model_params| {
+        let request = self.build_inner_request(&messages, &model_params);
+        with_retries(&policies, timeout_duration, id, move || {
+          let request = request.clone();
+          async move {
+            let response = transport.chat(keys, request).await?;
+            if let Some(usage) = &response.usage {
+              record_usage(&response.model, usage.into());
+            }
+            Ok(response)
+          }
+        })
+      })
+      .await?;
+
+    if response.choices.is_empty() {
+      return Err(OrchError::InvalidResponse(
+        "response.choices is empty".to_string(),
+      ));
+    }
+    let usage = response.usage.as_ref().map(Usage::from);
+    let system_fingerprint = response.system_fingerprint;
+    let choices = response
+      .choices
+      .into_iter()
+      .map(|choice| {
+        let reply = ChatReply::try_from(choice.message)?;
+        let finish_reason = choice.finish_reason.map(ChatFinishReason::from);
+        check_truncation(&policies, finish_reason, &reply)?;
+        Ok(ChatChoice {
+          reply,
+          finish_reason,
+        })
+      })
+      .collect::<Result<_>>()?;
+
+    Ok(ChatSimoResponse {
+      choices,
+      usage,
+      system_fingerprint,
+    })
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    self
+      .estimated_usage()
+      .map_or(0, |(_, usage)| usage.total_tokens())
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    let usage = estimate_usage(&self.messages(), &self.model_params);
+    let usage = Usage::new(
+      usage.prompt_tokens,
+      usage.completion_tokens * self.n.max(1) as u64,
+    );
+    Some((self.model_params.model.as_str(), usage))
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    cache_key(&self.build_inner_request(&self.messages(), &self.model_params))
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
@@ -1,28 +1,40 @@
 //! A "single input, single output" request for the OpenAI Chat API.
 
-use core::fmt::{Display, Formatter};
-
-use anyhow::{Error, Result};
-use async_openai::types::{
-  ChatCompletionRequestMessage, CreateChatCompletionRequest, Role, Stop,
-};
 use async_trait::async_trait;
-use log::{debug, error};
-use tokio::time::timeout;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-  chat::ChatModelParams, keys::Keys, policies::Policies,
-  utils::get_openai_client, OrchRequest, ResponseType,
+  chat::{
+    estimate_usage, mimo::ChatMimoRequest, model_param_setters,
+    prompt_messages, validate::Validator, ChatModelParams, ChatResponse,
+  },
+  cost::Usage,
+  error::Result,
+  keys::Keys,
+  policies::Policies,
+  OrchRequest,
 };
 
 /// A SISO (single input, single output) request for the OpenAI Chat API.
 ///
+/// This is a convenience over a `ChatMimoRequest` consisting of a system
+/// prompt followed by a single user prompt. Few-shot examples can be given
+/// with `with_examples`, and are sent between the two.
+///
 /// Refer to the `Orchestrator` for usage.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ChatSisoRequest {
   pub system_prompt: String,
   pub user_prompt:   String,
+  /// Example pairs of a user prompt and the assistant's reply, sent as
+  /// alternating messages to demonstrate what is expected.
+  #[serde(default)]
+  pub examples:      Vec<(String, String)>,
   pub model_params:  ChatModelParams,
+  /// Checks the reply, asking the model to repair it if it is invalid. See
+  /// the `validate` module.
+  #[serde(skip)]
+  pub validator:     Option<Validator>,
 }
 
 impl ChatSisoRequest {
@@ -34,27 +46,122 @@ impl ChatSisoRequest {
     Self {
       system_prompt,
       user_prompt,
+      examples: Vec::new(),
       model_params,
+      validator: None,
     }
   }
-}
 
-/// The response given by a `ChatSisoRequest`.
-pub struct ChatSisoResponse(pub String);
+  /// Returns a builder with empty prompts and the default `ChatModelParams`.
+  ///
+  /// ```rust
+  /// # use openai_orch::prelude::*;
+  /// let request = ChatSisoRequest::builder()
+  ///   .system("You are a helpful assistant.")
+  ///   .user("What are you?")
+  ///   .model("gpt-4o")
+  ///   .temperature(0.2)
+  ///   .build();
+  /// ```
+  pub fn builder() -> ChatSisoRequestBuilder {
+    ChatSisoRequestBuilder {
+      system_prompt: String::new(),
+      user_prompt:   String::new(),
+      examples:      Vec::new(),
+      model_params:  ChatModelParams::default(),
+    }
+  }
+
+  /// Adds few-shot examples, each a user prompt and the assistant's reply.
+  pub fn with_examples(
+    mut self,
+    examples: impl IntoIterator<Item = (String, String)>,
+  ) -> Self {
+    self.examples.extend(examples);
+    self
+  }
 
-impl Display for ChatSisoResponse {
-  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}", self.0)
+  /// Checks the reply with `validator`, asking the model to repair it if it
+  /// is invalid.
+  ///
+  /// Validators can't be told apart, so requests with one are neither
+  /// cached nor coalesced, and a reply is never reused without being
+  /// checked.
+  pub fn with_validator(mut self, validator: Validator) -> Self {
+    self.validator = Some(validator);
+    self
   }
 }
 
-impl From<ChatSisoResponse> for String {
-  fn from(response: ChatSisoResponse) -> Self {
-    response.0
+/// A builder for a `ChatSisoRequest`.
+#[derive(Clone)]
+pub struct ChatSisoRequestBuilder {
+  system_prompt: String,
+  user_prompt:   String,
+  examples:      Vec<(String, String)>,
+  model_params:  ChatModelParams,
+}
+
+impl ChatSisoRequestBuilder {
+  /// Sets the system prompt.
+  pub fn system(mut self, system_prompt: impl Into<String>) -> Self {
+    self.system_prompt = system_prompt.into();
+    self
+  }
+
+  /// Sets the user prompt.
+  pub fn user(mut self, user_prompt: impl Into<String>) -> Self {
+    self.user_prompt = user_prompt.into();
+    self
+  }
+
+  /// Adds a few-shot example of a user prompt and the assistant's reply.
+  pub fn example(
+    mut self,
+    user: impl Into<String>,
+    assistant: impl Into<String>,
+  ) -> Self {
+    self.examples.push((user.into(), assistant.into()));
+    self
+  }
+
+  /// Replaces all of the model params at once.
+  pub fn model_params(mut self, model_params: ChatModelParams) -> Self {
+    self.model_params = model_params;
+    self
+  }
+
+  model_param_setters!();
+
+  pub fn build(self) -> ChatSisoRequest {
+    ChatSisoRequest {
+      system_prompt: self.system_prompt,
+      user_prompt:   self.user_prompt,
+      examples:      self.examples,
+      model_params:  self.model_params,
+      validator:     None,
+    }
   }
 }
 
-impl ResponseType for ChatSisoResponse {}
+/// The response given by a `ChatSisoRequest`.
+pub type ChatSisoResponse = ChatResponse;
+
+impl From<ChatSisoRequest> for ChatMimoRequest {
+  fn from(request: ChatSisoRequest) -> Self {
+    Self {
+      validator: request.validator,
+      ..Self::new(
+        prompt_messages(
+          &request.system_prompt,
+          &request.examples,
+          &request.user_prompt,
+        ),
+        request.model_params,
+      )
+    }
+  }
+}
 
 #[async_trait]
 impl OrchRequest for ChatSisoRequest {
@@ -65,101 +172,26 @@ impl OrchRequest for ChatSisoRequest {
     keys: Keys,
     id: u64,
   ) -> Result<Self::Res> {
-    debug!("starting request {}", id);
-    let client = get_openai_client(&keys);
-    let mut retry_policy = policies.retry_policy;
-
-    // continue trying until we get a response or we reach max retry
-    loop {
-      let request = build_inner_request(self.clone());
-      let timer = timing::start();
-      let timeout_duration = std::cmp::min(
-        std::time::Duration::from_secs_f32(
-          10.0
-            * ((self.model_params.max_tokens as f32
-              + (self.system_prompt.len() + self.user_prompt.len()) as f32
-                / 4.0)
-              / 512.0),
-        ),
-        policies.timeout_policy.timeout,
-      );
-      let response =
-        timeout(timeout_duration, client.chat().create(request)).await;
-
-      // if we timed out, we need to check if we should retry
-      let response = match response {
-        Ok(response) => response,
-        Err(err) => {
-          debug!(
-            "request {} timed out after {}s",
-            id,
-            timeout_duration.as_secs_f32()
-          );
-          if retry_policy.failed_request().await {
-            continue;
-          } else {
-            error!("request {} reached max retry", id);
-            return Err(Error::new(err).context("reached max retry"));
-          }
-        }
-      };
-
-      // if we got a response, we need to check if it's an error
-      let response = match response {
-        Ok(response) => response,
-        Err(err) => {
-          if retry_policy.failed_request().await {
-            continue;
-          } else {
-            return Err(Error::new(err).context("reached max retry"));
-          }
-        }
-      };
-
-      debug!(
-        "got response for {} in {}",
-        id,
-        timer.elapsed().as_secs_f32()
-      );
-      let completion =
-        response.choices[0].message.clone().content.ok_or_else(|| {
-          Error::msg("response.choices[0].message.content is None")
-        })?;
-
-      return Ok(ChatSisoResponse(completion));
-    }
+    ChatMimoRequest::from(self.clone())
+      .send(policies, keys, id)
+      .await
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    ChatMimoRequest::from(self.clone()).estimated_tokens()
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    let mimo = ChatMimoRequest::from(self.clone());
+    let usage = estimate_usage(&mimo.messages, &self.model_params);
+    Some((self.model_params.model.as_str(), usage))
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    ChatMimoRequest::from(self.clone()).cache_key()
   }
-}
 
-fn build_inner_request(params: ChatSisoRequest) -> CreateChatCompletionRequest {
-  CreateChatCompletionRequest {
-    model: params.model_params.model,
-    messages: vec![
-      ChatCompletionRequestMessage {
-        role:          Role::System,
-        content:       Some(params.system_prompt),
-        name:          None,
-        function_call: None,
-      },
-      ChatCompletionRequestMessage {
-        role:          Role::User,
-        content:       Some(params.user_prompt),
-        name:          None,
-        function_call: None,
-      },
-    ],
-    temperature: Some(params.model_params.temperature),
-    top_p: Some(params.model_params.top_p),
-    max_tokens: Some(params.model_params.max_tokens as u16),
-    presence_penalty: Some(params.model_params.presence_penalty),
-    frequency_penalty: Some(params.model_params.frequency_penalty),
-    stop: if params.model_params.stop.is_empty() {
-      None
-    } else if params.model_params.stop.len() == 1 {
-      Some(Stop::String(params.model_params.stop[0].clone()))
-    } else {
-      Some(Stop::StringArray(params.model_params.stop.clone()))
-    },
-    ..Default::default()
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
   }
 }
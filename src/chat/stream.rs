@@ -0,0 +1,257 @@
+//! A streaming "single input, single output" request for the OpenAI Chat API.
+
+use core::{
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+use async_openai::types::{
+  ChatCompletionResponseStream, ChatCompletionStreamOptions,
+  CreateChatCompletionStreamResponse,
+};
+use async_trait::async_trait;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+  chat::{
+    estimate_tokens, estimate_usage, fit_context, mimo::build_inner_request,
+    prompt_messages, siso::ChatSisoRequest, with_fallback, ChatFinishReason,
+    ChatMessage, ChatModelParams,
+  },
+  cost::{current_recorder, Usage, UsageRecorder},
+  error::Result,
+  keys::Keys,
+  moderation,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, Permit, ResponseType,
+};
+
+/// A streaming SISO (single input, single output) request for the OpenAI Chat
+/// API.
+///
+/// The response is a stream of content deltas, which are yielded as the model
+/// generates them. The request keeps its place in the `ConcurrencyPolicy`
+/// until the stream is exhausted or dropped.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone)]
+pub struct ChatSisoStreamRequest {
+  pub system_prompt: String,
+  pub user_prompt:   String,
+  /// Example pairs of a user prompt and the assistant's reply, as in a
+  /// `ChatSisoRequest`.
+  pub examples:      Vec<(String, String)>,
+  pub model_params:  ChatModelParams,
+}
+
+impl ChatSisoStreamRequest {
+  pub fn new(
+    system_prompt: String,
+    user_prompt: String,
+    model_params: ChatModelParams,
+  ) -> Self {
+    Self {
+      system_prompt,
+      user_prompt,
+      examples: Vec::new(),
+      model_params,
+    }
+  }
+
+  /// Adds few-shot examples, each a user prompt and the assistant's reply.
+  pub fn with_examples(
+    mut self,
+    examples: impl IntoIterator<Item = (String, String)>,
+  ) -> Self {
+    self.examples.extend(examples);
+    self
+  }
+}
+
+impl ChatSisoStreamRequest {
+  fn messages(&self) -> Vec<ChatMessage> {
+    prompt_messages(&self.system_prompt, &self.examples, &self.user_prompt)
+  }
+}
+
+impl From<ChatSisoRequest> for ChatSisoStreamRequest {
+  fn from(request: ChatSisoRequest) -> Self {
+    Self::new(
+      request.system_prompt,
+      request.user_prompt,
+      request.model_params,
+    )
+    .with_examples(request.examples)
+  }
+}
+
+/// The response given by a `ChatSisoStreamRequest`: a stream of content
+/// deltas.
+pub struct ChatSisoStreamResponse {
+  first:              Option<CreateChatCompletionStreamResponse>,
+  inner:              ChatCompletionResponseStream,
+  permit:             Option<Permit>,
+  recorder:           Option<UsageRecorder>,
+  usage:              Option<Usage>,
+  system_fingerprint: Option<String>,
+  finish_reason:      Option<ChatFinishReason>,
+}
+
+impl ResponseType for ChatSisoStreamResponse {
+  fn hold_permit(&mut self, permit: Permit) {
+    self.permit = Some(permit);
+  }
+}
+
+impl ChatSisoStreamResponse {
+  /// The tokens used by the request. The API reports them in the final chunk,
+  /// so this is `None` until the stream is exhausted.
+  pub fn usage(&self) -> Option<Usage> {
+    self.usage
+  }
+
+  /// Identifies the backend configuration the model ran with, if the API
+  /// reported it. Every chunk carries it, so it is known once the stream has
+  /// started.
+  pub fn system_fingerprint(&self) -> Option<&str> {
+    self.system_fingerprint.as_deref()
+  }
+
+  /// Why the model stopped generating. The API reports it in the last chunk
+  /// with content, so this is `None` until then.
+  pub fn finish_reason(&self) -> Option<ChatFinishReason> {
+    self.finish_reason
+  }
+
+  /// Returns the content of a chunk, recording its usage if it has any. Usage
+  /// arrives in a final chunk without content.
+  fn delta_content(
+    &mut self,
+    chunk: CreateChatCompletionStreamResponse,
+  ) -> Option<String> {
+    if let Some(usage) = &chunk.usage {
+      let usage = Usage::from(usage);
+      if let Some(recorder) = &self.recorder {
+        recorder.record(&chunk.model, usage);
+      }
+      self.usage = Some(usage);
+    }
+    if let Some(reason) = chunk
+      .choices
+      .first()
+      .and_then(|choice| choice.finish_reason)
+    {
+      self.finish_reason = Some(reason.into());
+    }
+    delta_content(chunk)
+  }
+}
+
+fn delta_content(chunk: CreateChatCompletionStreamResponse) -> Option<String> {
+  chunk
+    .choices
+    .into_iter()
+    .next()
+    .and_then(|choice| choice.delta.content)
+}
+
+impl Stream for ChatSisoStreamResponse {
+  type Item = Result<String>;
+
+  fn poll_next(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Self::Item>> {
+    if let Some(first) = self.first.take() {
+      if let Some(content) = self.delta_content(first) {
+        return Poll::Ready(Some(Ok(content)));
+      }
+    }
+
+    loop {
+      match Pin::new(&mut self.inner).poll_next(cx) {
+        Poll::Ready(Some(Ok(chunk))) => {
+          if let Some(content) = self.delta_content(chunk) {
+            return Poll::Ready(Some(Ok(content)));
+          }
+        }
+        Poll::Ready(Some(Err(err))) => {
+          return Poll::Ready(Some(Err(err.into())));
+        }
+        Poll::Ready(None) => {
+          // release our place in the concurrency policy as soon as possible
+          self.permit.take();
+          return Poll::Ready(None);
+        }
+        Poll::Pending => return Poll::Pending,
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl OrchRequest for ChatSisoStreamRequest {
+  type Res = ChatSisoStreamResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let messages = fit_context(self.messages(), &self.model_params, &policies)?;
+    moderation::screen(&messages, &policies, &keys, id).await?;
+    let transport = transport::current();
+
+    let (transport, keys) = (&transport, &keys);
+    let (first, stream) =
+      with_fallback(&policies, &self.model_params, id, |model_params| {
+        let mut request = build_inner_request(&messages, &model_params);
+        request.stream = Some(true);
+        request.stream_options = Some(ChatCompletionStreamOptions {
+          include_usage: true,
+        });
+        with_retries(
+          &policies,
+          policies.timeout_policy.timeout(),
+          id,
+          move || {
+            let request = request.clone();
+            async move {
+              let mut stream = transport.chat_stream(keys, request).await?;
+              // wait for the first chunk so that connection errors can be
+              // retried
+              let first = stream.next().await.transpose()?;
+              Ok((first, stream))
+            }
+          },
+        )
+      })
+      .await?;
+
+    let system_fingerprint = first
+      .as_ref()
+      .and_then(|chunk| chunk.system_fingerprint.clone());
+    Ok(ChatSisoStreamResponse {
+      first,
+      inner: stream,
+      permit: None,
+      recorder: current_recorder(),
+      usage: None,
+      system_fingerprint,
+      finish_reason: None,
+    })
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    estimate_tokens(&self.messages(), &self.model_params)
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    let usage = estimate_usage(&self.messages(), &self.model_params);
+    Some((self.model_params.model.as_str(), usage))
+  }
+}
@@ -0,0 +1,180 @@
+//! A request for the OpenAI Chat API that parses the completion as JSON.
+
+use core::marker::PhantomData;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+
+use crate::{
+  chat::{
+    check_truncation, completion_timeout, estimate_tokens, estimate_usage,
+    fit_context, mimo::build_inner_request, with_fallback, ChatFinishReason,
+    ChatMessage, ChatModelParams, ChatReply, ChatResponseFormat,
+  },
+  cost::{record_usage, Usage},
+  error::{OrchError, Result},
+  keys::Keys,
+  moderation,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// How the model is instructed to produce JSON.
+#[derive(Clone, Debug)]
+pub enum ChatStructuredFormat {
+  /// JSON mode: the model produces a valid JSON object. The messages must
+  /// instruct the model to produce JSON, and should describe the expected
+  /// shape of the object.
+  JsonObject,
+  /// Structured outputs: the model produces JSON adhering to a schema.
+  JsonSchema {
+    /// The name of the schema.
+    name:   String,
+    /// The JSON Schema describing the expected object.
+    schema: serde_json::Value,
+    /// Whether the model must follow the schema exactly.
+    strict: bool,
+  },
+}
+
+impl From<&ChatStructuredFormat> for ChatResponseFormat {
+  fn from(format: &ChatStructuredFormat) -> Self {
+    match format {
+      ChatStructuredFormat::JsonObject => ChatResponseFormat::JsonObject,
+      ChatStructuredFormat::JsonSchema {
+        name,
+        schema,
+        strict,
+      } => ChatResponseFormat::JsonSchema {
+        name:   name.clone(),
+        schema: schema.clone(),
+        strict: *strict,
+      },
+    }
+  }
+}
+
+/// A request for the OpenAI Chat API whose completion is deserialized into
+/// `T`. If the completion isn't valid JSON for `T`, the request is retried
+/// according to the `RetryPolicy`.
+///
+/// Refer to the `Orchestrator` for usage.
+pub struct ChatStructuredRequest<T> {
+  pub messages:     Vec<ChatMessage>,
+  pub model_params: ChatModelParams,
+  pub format:       ChatStructuredFormat,
+  _marker:          PhantomData<fn() -> T>,
+}
+
+impl<T> ChatStructuredRequest<T> {
+  pub fn new(
+    messages: Vec<ChatMessage>,
+    model_params: ChatModelParams,
+    format: ChatStructuredFormat,
+  ) -> Self {
+    Self {
+      messages,
+      model_params,
+      format,
+      _marker: PhantomData,
+    }
+  }
+}
+
+impl<T> Clone for ChatStructuredRequest<T> {
+  fn clone(&self) -> Self {
+    Self::new(
+      self.messages.clone(),
+      self.model_params.clone(),
+      self.format.clone(),
+    )
+  }
+}
+
+/// The response given by a `ChatStructuredRequest`.
+pub struct ChatStructuredResponse<T>(pub T);
+
+impl<T: Send + 'static> ResponseType for ChatStructuredResponse<T> {}
+
+#[async_trait]
+impl<T> OrchRequest for ChatStructuredRequest<T>
+where
+  T: DeserializeOwned + Send + 'static,
+{
+  type Res = ChatStructuredResponse<T>;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let messages =
+      fit_context(self.messages.clone(), &self.model_params, &policies)?;
+    moderation::screen(&messages, &policies, &keys, id).await?;
+    let transport = transport::current();
+
+    // the format of a structured request takes the place of any in its params
+    let model_params = ChatModelParams {
+      response_format: Some(ChatResponseFormat::from(&self.format)),
+      ..self.model_params.clone()
+    };
+    let prompt_len: usize =
+      messages.iter().map(|message| message.content.len()).sum();
+    let timeout_duration =
+      completion_timeout(prompt_len, &self.model_params, &policies);
+    let (transport, keys, policies) = (&transport, &keys, &policies);
+
+    // parsing happens inside the attempt so that malformed JSON is retried
+    with_fallback(policies, &model_params, id, |model_params| {
+      let request = build_inner_request(&messages, &model_params);
+      with_retries(policies, timeout_duration, id, move || {
+        let request = request.clone();
+        async move {
+          let response = transport.chat(keys, request).await?;
+          if let Some(usage) = &response.usage {
+            record_usage(&response.model, usage.into());
+          }
+          let choice =
+            response.choices.into_iter().next().ok_or_else(|| {
+              OrchError::InvalidResponse(
+                "response.choices is empty".to_string(),
+              )
+            })?;
+          let reply = ChatReply::try_from(choice.message)?;
+          let finish_reason = choice.finish_reason.map(ChatFinishReason::from);
+          check_truncation(policies, finish_reason, &reply)?;
+
+          let content = match reply {
+            ChatReply::Content(content) => content,
+            ChatReply::ToolCalls(_) => {
+              return Err(OrchError::InvalidResponse(
+                "expected content, got tool calls".to_string(),
+              ));
+            }
+          };
+          let parsed = serde_json::from_str(&content).map_err(|err| {
+            OrchError::InvalidResponse(format!(
+              "completion is not valid JSON for the target: {}",
+              err
+            ))
+          })?;
+          Ok(ChatStructuredResponse(parsed))
+        }
+      })
+    })
+    .await
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    estimate_tokens(&self.messages, &self.model_params)
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    let usage = estimate_usage(&self.messages, &self.model_params);
+    Some((self.model_params.model.as_str(), usage))
+  }
+}
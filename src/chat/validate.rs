@@ -0,0 +1,265 @@
+//! Validating the replies to chat requests, and asking the model to repair
+//! replies which fail.
+//!
+//! A `Validator` is attached to a `ChatMimoRequest` or `ChatSisoRequest`
+//! with `with_validator`. When the reply fails validation, the request is
+//! sent again with the reply and the reason it failed appended to the
+//! conversation, so the model can correct itself, up to the validator's
+//! `max_repairs` times. If the reply still fails, the request fails with
+//! `OrchError::ValidationFailed`.
+//!
+//! ```rust,no_run
+//! # use openai_orch::prelude::*;
+//! use openai_orch::chat::validate::Validator;
+//!
+//! # async fn example(orchestrator: Orchestrator) -> Result<(), OrchError> {
+//! let validator = Validator::json_schema(serde_json::json!({
+//!   "type": "object",
+//!   "properties": { "sentiment": { "enum": ["positive", "negative"] } },
+//!   "required": ["sentiment"],
+//! }))
+//! .with_max_repairs(3);
+//! let request = ChatSisoRequest::new(
+//!   "Reply with a JSON object holding the sentiment of the review.".into(),
+//!   "The food was cold.".into(),
+//!   Default::default(),
+//! )
+//! .with_validator(validator);
+//! let response = orchestrator.add_request(request).await.await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{fmt, sync::Arc};
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::error::{OrchError, Result};
+
+type Check = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Checks the content of a chat reply, returning why it is invalid if it
+/// is. Replies with tool calls aren't validated.
+#[derive(Clone)]
+pub struct Validator {
+  check:       Check,
+  max_repairs: u32,
+}
+
+impl Validator {
+  /// Returns a validator which calls `check` with the reply's content. The
+  /// error is told to the model when asking it to repair its reply, so it
+  /// should say what is wrong.
+  ///
+  /// Validators try to repair a reply twice by default.
+  pub fn custom(
+    check: impl Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+  ) -> Self {
+    Self {
+      check:       Arc::new(check),
+      max_repairs: 2,
+    }
+  }
+
+  /// Returns a validator which requires the reply to match `pattern`
+  /// somewhere; anchor it with `^` and `$` to match the whole reply. Fails
+  /// if the pattern isn't a valid regex.
+  pub fn regex(pattern: &str) -> Result<Self> {
+    let regex = Regex::new(pattern).map_err(OrchError::other)?;
+    Ok(Self::custom(move |content| match regex.is_match(content) {
+      true => Ok(()),
+      false => Err(format!(
+        "the reply must match the regular expression `{}`",
+        regex.as_str()
+      )),
+    }))
+  }
+
+  /// Returns a validator which requires the reply to be JSON matching
+  /// `schema`.
+  ///
+  /// Only the most common keywords of JSON Schema are checked: `type`,
+  /// `enum`, `const`, `properties`, `required`, `additionalProperties`,
+  /// `items`, `minItems`, `maxItems`, `minLength`, `maxLength`, `minimum`,
+  /// and `maximum`. Other keywords are ignored.
+  pub fn json_schema(schema: Value) -> Self {
+    Self::custom(move |content| {
+      let value: Value = serde_json::from_str(content)
+        .map_err(|err| format!("the reply is not valid JSON: {}", err))?;
+      check_schema(&value, &schema, "$")
+    })
+  }
+
+  /// Sets how many times the model is asked to repair an invalid reply
+  /// before the request fails. Zero fails the request on the first invalid
+  /// reply.
+  pub fn with_max_repairs(mut self, max_repairs: u32) -> Self {
+    self.max_repairs = max_repairs;
+    self
+  }
+
+  /// How many times the model is asked to repair an invalid reply.
+  pub fn max_repairs(&self) -> u32 {
+    self.max_repairs
+  }
+
+  /// Checks a reply's content, returning why it is invalid if it is.
+  pub fn check(&self, content: &str) -> Result<(), String> {
+    (self.check)(content)
+  }
+}
+
+impl fmt::Debug for Validator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Validator")
+      .field("max_repairs", &self.max_repairs)
+      .finish_non_exhaustive()
+  }
+}
+
+/// The user message asking the model to repair a reply which failed
+/// validation, given why it failed.
+pub(crate) fn repair_prompt(problem: &str) -> String {
+  format!(
+    "Your reply was invalid: {}. Reply again in full, correcting it.",
+    problem
+  )
+}
+
+/// The name of the JSON type of `value`, as used by JSON Schema.
+fn type_name(value: &Value) -> &'static str {
+  match value {
+    Value::Null => "null",
+    Value::Bool(_) => "boolean",
+    Value::Number(_) => "number",
+    Value::String(_) => "string",
+    Value::Array(_) => "array",
+    Value::Object(_) => "object",
+  }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+  match expected {
+    "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+    expected => type_name(value) == expected,
+  }
+}
+
+/// Checks `value` against the subset of JSON Schema `Validator::json_schema`
+/// supports. `path` locates the value in the reply, for the error.
+fn check_schema(
+  value: &Value,
+  schema: &Value,
+  path: &str,
+) -> Result<(), String> {
+  let Value::Object(schema) = schema else {
+    return Ok(());
+  };
+  let fail = |problem: String| Err(format!("{} {}", path, problem));
+
+  match schema.get("type") {
+    Some(Value::String(expected)) if !has_type(value, expected) => {
+      return fail(format!(
+        "must be of type {}, not {}",
+        expected,
+        type_name(value)
+      ));
+    }
+    Some(Value::Array(expected))
+      if !expected
+        .iter()
+        .filter_map(Value::as_str)
+        .any(|expected| has_type(value, expected)) =>
+    {
+      return fail(format!("must not be of type {}", type_name(value)));
+    }
+    _ => {}
+  }
+  if let Some(Value::Array(allowed)) = schema.get("enum") {
+    if !allowed.contains(value) {
+      let allowed: Vec<_> = allowed.iter().map(Value::to_string).collect();
+      return fail(format!("must be one of {}", allowed.join(", ")));
+    }
+  }
+  if let Some(expected) = schema.get("const") {
+    if value != expected {
+      return fail(format!("must be {}", expected));
+    }
+  }
+
+  match value {
+    Value::Object(object) => {
+      if let Some(Value::Array(required)) = schema.get("required") {
+        for key in required.iter().filter_map(Value::as_str) {
+          if !object.contains_key(key) {
+            return fail(format!("is missing the property `{}`", key));
+          }
+        }
+      }
+      let properties = schema.get("properties").and_then(Value::as_object);
+      for (key, property) in object {
+        match properties.and_then(|properties| properties.get(key)) {
+          Some(property_schema) => check_schema(
+            property,
+            property_schema,
+            &format!("{}.{}", path, key),
+          )?,
+          None
+            if schema.get("additionalProperties")
+              == Some(&Value::Bool(false)) =>
+          {
+            return fail(format!("has an unexpected property `{}`", key));
+          }
+          None => {}
+        }
+      }
+    }
+    Value::Array(items) => {
+      let len = items.len() as u64;
+      if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+        if len < min {
+          return fail(format!("must have at least {} items", min));
+        }
+      }
+      if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+        if len > max {
+          return fail(format!("must have at most {} items", max));
+        }
+      }
+      if let Some(item_schema) = schema.get("items") {
+        for (i, item) in items.iter().enumerate() {
+          check_schema(item, item_schema, &format!("{}[{}]", path, i))?;
+        }
+      }
+    }
+    Value::String(string) => {
+      let len = string.chars().count() as u64;
+      if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+        if len < min {
+          return fail(format!("must be at least {} characters long", min));
+        }
+      }
+      if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+        if len > max {
+          return fail(format!("must be at most {} characters long", max));
+        }
+      }
+    }
+    Value::Number(number) => {
+      let number = number.as_f64().unwrap_or_default();
+      if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+        if number < min {
+          return fail(format!("must be at least {}", min));
+        }
+      }
+      if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+        if number > max {
+          return fail(format!("must be at most {}", max));
+        }
+      }
+    }
+    Value::Null | Value::Bool(_) => {}
+  }
+  Ok(())
+}
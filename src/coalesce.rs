@@ -0,0 +1,241 @@
+//! Sharing one upstream call between identical requests that are in flight
+//! at the same time.
+
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use tokio::{
+  sync::{watch, Notify},
+  task::AbortHandle,
+  time::Instant,
+};
+
+use crate::{
+  error::{OrchError, Result},
+  hooks::{Hooks, OrchestratorHooks},
+  meta::ResponseMeta,
+  progress::{Progress, ProgressExt},
+  tags::Tags,
+  trace::debug,
+  Delivery, Responder, ResponseType,
+};
+
+/// What a follower is given once the request it is waiting on finishes: the
+/// response as serialized by `ResponseType::to_cache`, or the error.
+type Shared<'a> = std::result::Result<Option<&'a [u8]>, &'a OrchError>;
+
+/// A waiter whose response type has been erased, so that the followers of a
+/// request can be kept together.
+trait Pending: Send {
+  fn deliver(self: Box<Self>, shared: Shared<'_>, leader: &ResponseMeta);
+  fn cancel(self: Box<Self>);
+}
+
+/// A request waiting on the request in flight under the same key.
+struct Follower {
+  id:     u64,
+  waiter: Box<dyn Pending>,
+  _watch: CancelWatch,
+}
+
+/// The task waiting for a follower to be cancelled, which is stopped once
+/// the follower is answered or dropped.
+struct CancelWatch(AbortHandle);
+
+impl Drop for CancelWatch {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+/// A request waiting to be answered, either by its own upstream call or by
+/// that of an identical request.
+pub(crate) struct Waiter<R> {
+  pub(crate) tx:       Responder<R>,
+  pub(crate) id:       u64,
+  pub(crate) added_at: Instant,
+  pub(crate) progress: Arc<watch::Sender<Progress>>,
+  pub(crate) hooks:    Hooks,
+  pub(crate) tags:     Tags,
+  pub(crate) cancel:   Arc<Notify>,
+}
+
+impl<R: ResponseType> Pending for Waiter<R> {
+  fn deliver(self: Box<Self>, shared: Shared<'_>, leader: &ResponseMeta) {
+    let res: Result<R> = match shared {
+      Ok(bytes) => bytes.and_then(R::from_cache).ok_or_else(|| {
+        OrchError::other("the response could not be shared between requests")
+      }),
+      Err(err) => Err(err.duplicate()),
+    };
+    let latency = self.added_at.elapsed();
+    let meta = ResponseMeta {
+      id: self.id,
+      latency,
+      queued: leader.queued.min(latency),
+      ..leader.clone()
+    }
+    .tagged(&self.tags);
+
+    self.progress.started();
+    self.progress.finished(res.is_ok());
+    self.tags.finished(&meta, res.is_ok());
+    match &res {
+      Ok(_) => self.hooks.on_completed(&meta),
+      Err(err) => self.hooks.on_failed(&meta, err),
+    }
+    self.tx.send((res, meta));
+  }
+
+  fn cancel(self: Box<Self>) {
+    let err = OrchError::Cancelled;
+    let meta =
+      ResponseMeta::finished(self.id, self.added_at, None, Default::default())
+        .tagged(&self.tags);
+    self.progress.cancelled();
+    self.tags.finished(&meta, false);
+    self.hooks.on_failed(&meta, &err);
+    self.tx.send((Err(err), meta));
+  }
+}
+
+/// The requests in flight, by `OrchRequest::cache_key`, with the identical
+/// requests waiting on each of them.
+#[derive(Default)]
+pub(crate) struct Coalescer {
+  in_flight: Mutex<HashMap<u64, Vec<Follower>>>,
+}
+
+/// Whether a request joined an identical one or must be sent itself.
+pub(crate) enum Joined<R> {
+  Following,
+  Leading(Waiter<R>, Lead),
+}
+
+impl Coalescer {
+  /// Makes `waiter` follow the request in flight under `key`, if there is
+  /// one. Otherwise the waiter is handed back, and its request is the one in
+  /// flight until the `Lead` is finished or dropped.
+  pub(crate) fn follow<R: ResponseType>(
+    self: &Arc<Self>,
+    key: u64,
+    waiter: Waiter<R>,
+  ) -> Joined<R> {
+    let mut in_flight = self.in_flight.lock().expect("coalescer lock poisoned");
+    match in_flight.get_mut(&key) {
+      Some(followers) => {
+        let id = waiter.id;
+        let cancel = waiter.cancel.clone();
+        let coalescer = self.clone();
+        // the watch can't look for the follower before it is pushed, as the
+        // lock is held until then
+        let watch = tokio::spawn(async move {
+          cancel.notified().await;
+          coalescer.cancel(key, id);
+        });
+        followers.push(Follower {
+          id,
+          waiter: Box::new(waiter),
+          _watch: CancelWatch(watch.abort_handle()),
+        });
+        Joined::Following
+      }
+      None => {
+        in_flight.insert(key, Vec::new());
+        let lead = Lead {
+          coalescer: self.clone(),
+          key,
+          finished: AtomicBool::new(false),
+        };
+        Joined::Leading(waiter, lead)
+      }
+    }
+  }
+
+  /// Fails the follower numbered `id` of the request under `key` with
+  /// `OrchError::Cancelled`. The request it was following is left alone.
+  fn cancel(&self, key: u64, id: u64) {
+    let follower = {
+      let mut in_flight =
+        self.in_flight.lock().expect("coalescer lock poisoned");
+      let Some(followers) = in_flight.get_mut(&key) else {
+        return;
+      };
+      let Some(index) = followers.iter().position(|f| f.id == id) else {
+        return;
+      };
+      followers.swap_remove(index)
+    };
+    debug!("request {} was cancelled while following another", id);
+    follower.waiter.cancel();
+  }
+
+  fn take_followers(&self, key: u64) -> Vec<Follower> {
+    self
+      .in_flight
+      .lock()
+      .expect("coalescer lock poisoned")
+      .remove(&key)
+      .unwrap_or_default()
+  }
+}
+
+/// The request in flight under a key. If it is dropped without finishing,
+/// such as when a hook panics, its followers are dropped too and fail with
+/// `OrchError::ResponseMissing`.
+pub(crate) struct Lead {
+  coalescer: Arc<Coalescer>,
+  key:       u64,
+  finished:  AtomicBool,
+}
+
+impl Lead {
+  /// Whether identical requests are still waiting on the request.
+  pub(crate) fn has_followers(&self) -> bool {
+    if self.finished.load(Ordering::Relaxed) {
+      return false;
+    }
+    self
+      .coalescer
+      .in_flight
+      .lock()
+      .expect("coalescer lock poisoned")
+      .get(&self.key)
+      .is_some_and(|followers| !followers.is_empty())
+  }
+
+  /// Fans the delivery of the request out to its followers. Only the first
+  /// delivery is shared, as the key may have been taken by an identical
+  /// request since.
+  pub(crate) fn finish<R: ResponseType>(&self, (res, meta): &Delivery<R>) {
+    if self.finished.swap(true, Ordering::Relaxed) {
+      return;
+    }
+    let followers = self.coalescer.take_followers(self.key);
+    if followers.is_empty() {
+      return;
+    }
+
+    let bytes = res.as_ref().ok().and_then(R::to_cache);
+    for follower in followers {
+      let shared = match res {
+        Ok(_) => Ok(bytes.as_deref()),
+        Err(err) => Err(err),
+      };
+      follower.waiter.deliver(shared, meta);
+    }
+  }
+}
+
+impl Drop for Lead {
+  fn drop(&mut self) {
+    if !*self.finished.get_mut() {
+      self.coalescer.take_followers(self.key);
+    }
+  }
+}
@@ -0,0 +1,194 @@
+//! Requests and responses using the legacy OpenAI Completions API, for
+//! instruct and fine-tuned models which aren't available as Chat models.
+
+use async_openai::types::{CreateCompletionRequest, Prompt, Stop};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  cache::cache_key,
+  cost::{record_usage, Usage},
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// Parameters for the Completions API.
+///
+/// Refer to `async-openai`'s `CreateCompletionRequest` for exact details.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompletionParams {
+  pub model:             String,
+  /// Text that comes after the completion, for inserting text.
+  pub suffix:            Option<String>,
+  pub temperature:       f32,
+  pub top_p:             f32,
+  pub stop:              Vec<String>,
+  pub max_tokens:        u64,
+  pub frequency_penalty: f32,
+  pub presence_penalty:  f32,
+  /// The number of most likely tokens to return the log probabilities of at
+  /// each position, up to five. `None` returns no log probabilities.
+  pub logprobs:          Option<u8>,
+  /// Whether to include the prompt at the start of the completion.
+  pub echo:              bool,
+}
+
+impl Default for CompletionParams {
+  fn default() -> Self {
+    Self {
+      model:             String::from("gpt-3.5-turbo-instruct"),
+      suffix:            None,
+      temperature:       0.0,
+      top_p:             1.0,
+      stop:              vec![],
+      max_tokens:        256,
+      frequency_penalty: 0.0,
+      presence_penalty:  0.0,
+      logprobs:          None,
+      echo:              false,
+    }
+  }
+}
+
+/// A request for the Completions API, which continues a prompt.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompletionRequest {
+  pub prompt: String,
+  pub params: CompletionParams,
+}
+
+impl CompletionRequest {
+  pub fn new(prompt: String, params: CompletionParams) -> Self {
+    Self { prompt, params }
+  }
+
+  fn build_inner_request(&self) -> CreateCompletionRequest {
+    let params = &self.params;
+    CreateCompletionRequest {
+      model: params.model.clone(),
+      prompt: Prompt::String(self.prompt.clone()),
+      suffix: params.suffix.clone(),
+      max_tokens: Some(params.max_tokens as u32),
+      temperature: Some(params.temperature),
+      top_p: Some(params.top_p),
+      logprobs: params.logprobs,
+      echo: Some(params.echo),
+      stop: match params.stop.len() {
+        0 => None,
+        1 => Some(Stop::String(params.stop[0].clone())),
+        _ => Some(Stop::StringArray(params.stop.clone())),
+      },
+      presence_penalty: Some(params.presence_penalty),
+      frequency_penalty: Some(params.frequency_penalty),
+      ..Default::default()
+    }
+  }
+}
+
+/// The log probabilities of the tokens in a completion.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompletionLogprobs {
+  /// The tokens of the completion.
+  pub tokens:         Vec<String>,
+  /// The log probability of each token, which is `None` for the first token
+  /// of an echoed prompt.
+  pub token_logprobs: Vec<Option<f32>>,
+}
+
+/// The response given by a `CompletionRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompletionResponse {
+  /// The generated text.
+  pub text:     String,
+  /// The log probabilities of the generated tokens, if they were asked for.
+  pub logprobs: Option<CompletionLogprobs>,
+  /// The tokens used by the request, if the API reported them.
+  pub usage:    Option<Usage>,
+}
+
+impl ResponseType for CompletionResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+#[async_trait]
+impl OrchRequest for CompletionRequest {
+  type Res = CompletionResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let transport = transport::current();
+
+    let request = self.build_inner_request();
+    let response = with_retries(
+      &policies,
+      policies.timeout_policy.timeout(),
+      id,
+      || async {
+        let response = transport.completion(&keys, request.clone()).await?;
+        if let Some(usage) = &response.usage {
+          record_usage(&response.model, usage.into());
+        }
+        Ok(response)
+      },
+    )
+    .await?;
+
+    let usage = response.usage.as_ref().map(Usage::from);
+    let choice = response.choices.into_iter().next().ok_or_else(|| {
+      OrchError::InvalidResponse("response.choices is empty".to_string())
+    })?;
+
+    Ok(CompletionResponse {
+      text: choice.text,
+      logprobs: choice.logprobs.map(|logprobs| CompletionLogprobs {
+        tokens:         logprobs.tokens,
+        token_logprobs: logprobs.token_logprobs,
+      }),
+      usage,
+    })
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    self
+      .estimated_usage()
+      .map_or(0, |(_, usage)| usage.total_tokens())
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    #[cfg(feature = "tokens")]
+    let prompt_tokens =
+      crate::tokens::count_tokens(&self.params.model, &self.prompt) as u64;
+    #[cfg(not(feature = "tokens"))]
+    let prompt_tokens = self.prompt.len() as u64 / 4;
+    Some((
+      &self.params.model,
+      Usage::new(prompt_tokens, self.params.max_tokens),
+    ))
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    cache_key(&self.build_inner_request())
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
@@ -0,0 +1,266 @@
+//! Pricing for OpenAI models, and tracking of what requests have spent.
+
+use std::sync::{Arc, Mutex};
+
+use async_openai::types::{CompletionUsage, EmbeddingUsage};
+use serde::{Deserialize, Serialize};
+
+use crate::{models::Model, scope, tags::Tags, telemetry};
+
+/// The tokens used by a single API call.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct Usage {
+  pub prompt_tokens:     u64,
+  pub completion_tokens: u64,
+  /// The completion tokens a reasoning model spent on reasoning, which are
+  /// included in `completion_tokens` but not in the reply.
+  #[serde(default)]
+  pub reasoning_tokens:  u64,
+}
+
+impl Usage {
+  pub fn new(prompt_tokens: u64, completion_tokens: u64) -> Self {
+    Self {
+      prompt_tokens,
+      completion_tokens,
+      reasoning_tokens: 0,
+    }
+  }
+
+  /// The number of prompt and completion tokens together.
+  pub fn total_tokens(&self) -> u64 {
+    self.prompt_tokens + self.completion_tokens
+  }
+
+  /// Adds the usage of two calls, either of which may not have reported
+  /// any. The sum is only missing if both are.
+  pub(crate) fn merge(a: Option<Usage>, b: Option<Usage>) -> Option<Usage> {
+    match (a, b) {
+      (Some(a), Some(b)) => Some(a + b),
+      (a, b) => a.or(b),
+    }
+  }
+}
+
+impl std::ops::Add for Usage {
+  type Output = Usage;
+
+  fn add(self, other: Usage) -> Usage {
+    Usage {
+      prompt_tokens:     self.prompt_tokens + other.prompt_tokens,
+      completion_tokens: self.completion_tokens + other.completion_tokens,
+      reasoning_tokens:  self.reasoning_tokens + other.reasoning_tokens,
+    }
+  }
+}
+
+impl From<&CompletionUsage> for Usage {
+  fn from(usage: &CompletionUsage) -> Self {
+    let reasoning_tokens = usage
+      .completion_tokens_details
+      .as_ref()
+      .and_then(|details| details.reasoning_tokens)
+      .unwrap_or(0);
+    Self {
+      reasoning_tokens: reasoning_tokens as u64,
+      ..Self::new(usage.prompt_tokens as u64, usage.completion_tokens as u64)
+    }
+  }
+}
+
+impl From<&EmbeddingUsage> for Usage {
+  fn from(usage: &EmbeddingUsage) -> Self {
+    Self::new(usage.prompt_tokens as u64, 0)
+  }
+}
+
+/// The price of a model, in US dollars per million tokens.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pricing {
+  pub prompt_per_million:     f64,
+  pub completion_per_million: f64,
+}
+
+impl Pricing {
+  pub fn new(prompt_per_million: f64, completion_per_million: f64) -> Self {
+    Self {
+      prompt_per_million,
+      completion_per_million,
+    }
+  }
+
+  /// The cost of the given usage, in US dollars.
+  pub fn cost(&self, usage: &Usage) -> f64 {
+    (usage.prompt_tokens as f64 * self.prompt_per_million
+      + usage.completion_tokens as f64 * self.completion_per_million)
+      / 1_000_000.0
+  }
+}
+
+/// Prices for models, looked up by the longest matching prefix of the model
+/// name, so that `gpt-4o-2024-08-06` is priced as `gpt-4o`.
+///
+/// The default table holds OpenAI's published list prices at the time of
+/// writing. Prices change, so add entries with `with_model` to override
+/// them.
+#[derive(Clone, Debug)]
+pub struct PricingTable {
+  models: Vec<(String, Pricing)>,
+}
+
+impl PricingTable {
+  /// Returns a table without any prices.
+  pub fn empty() -> Self {
+    Self { models: Vec::new() }
+  }
+
+  /// Sets the price of models whose names start with `prefix`.
+  pub fn with_model(
+    mut self,
+    prefix: impl Into<String>,
+    pricing: Pricing,
+  ) -> Self {
+    let prefix = prefix.into();
+    self.models.retain(|(model, _)| *model != prefix);
+    self.models.push((prefix, pricing));
+    self
+  }
+
+  /// Returns the price of the given model, if it is known.
+  pub fn pricing(&self, model: &str) -> Option<Pricing> {
+    self
+      .models
+      .iter()
+      .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+      .max_by_key(|(prefix, _)| prefix.len())
+      .map(|(_, pricing)| *pricing)
+  }
+
+  /// Returns the cost of the given usage of a model, in US dollars, if the
+  /// model's price is known.
+  pub fn cost(&self, model: &str, usage: &Usage) -> Option<f64> {
+    self.pricing(model).map(|pricing| pricing.cost(usage))
+  }
+}
+
+impl Default for PricingTable {
+  fn default() -> Self {
+    let known = Model::KNOWN
+      .iter()
+      .filter_map(|model| Some((model.as_str(), model.pricing()?)));
+    // models that `Model` doesn't list, by prefix
+    let others = [
+      ("gpt-4-turbo", 10.0, 30.0),
+      ("gpt-4", 30.0, 60.0),
+      ("gpt-4-32k", 60.0, 120.0),
+      ("o1", 15.0, 60.0),
+      ("o1-mini", 1.1, 4.4),
+      ("o3-mini", 1.1, 4.4),
+      ("text-embedding-3-small", 0.02, 0.0),
+      ("text-embedding-3-large", 0.13, 0.0),
+      ("text-embedding-ada-002", 0.1, 0.0),
+    ]
+    .map(|(model, prompt, completion)| {
+      (model, Pricing::new(prompt, completion))
+    });
+    known
+      .chain(others)
+      .fold(Self::empty(), |table, (model, pricing)| {
+        table.with_model(model, pricing)
+      })
+  }
+}
+
+/// What the requests sent through an `Orchestrator` have used so far,
+/// including attempts that were retried.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Spend {
+  pub prompt_tokens:     u64,
+  pub completion_tokens: u64,
+  /// The cost of the tokens from models with a known price, in US dollars.
+  pub cost_usd:          f64,
+  /// The tokens from models without a known price, which are not included in
+  /// `cost_usd`.
+  pub unpriced_tokens:   u64,
+}
+
+impl Spend {
+  /// The number of prompt and completion tokens together.
+  pub fn total_tokens(&self) -> u64 {
+    self.prompt_tokens + self.completion_tokens
+  }
+
+  /// Adds the usage of a single API call, costing `cost` if it is priced.
+  pub(crate) fn add(&mut self, usage: &Usage, cost: Option<f64>) {
+    self.prompt_tokens += usage.prompt_tokens;
+    self.completion_tokens += usage.completion_tokens;
+    match cost {
+      Some(cost) => self.cost_usd += cost,
+      None => self.unpriced_tokens += usage.total_tokens(),
+    }
+  }
+}
+
+/// Accumulates the usage reported by requests into a `Spend`.
+#[derive(Clone)]
+pub(crate) struct UsageRecorder {
+  spend:   Arc<Mutex<Spend>>,
+  pricing: Arc<PricingTable>,
+  tags:    Tags,
+}
+
+impl UsageRecorder {
+  pub(crate) fn new(pricing: PricingTable) -> Self {
+    Self {
+      spend:   Arc::new(Mutex::new(Spend::default())),
+      pricing: Arc::new(pricing),
+      tags:    Tags::default(),
+    }
+  }
+
+  /// Returns a recorder sharing this one's spend, which also credits the
+  /// usage to the request's tags.
+  pub(crate) fn with_tags(&self, tags: Tags) -> Self {
+    Self {
+      tags,
+      ..self.clone()
+    }
+  }
+
+  pub(crate) fn pricing(&self) -> &PricingTable {
+    &self.pricing
+  }
+
+  pub(crate) fn spend(&self) -> Spend {
+    *self.spend.lock().expect("spend lock poisoned")
+  }
+
+  pub(crate) fn record(&self, model: &str, usage: Usage) {
+    telemetry::tokens(model, &usage);
+    let cost = self.pricing.cost(model, &usage);
+    self
+      .spend
+      .lock()
+      .expect("spend lock poisoned")
+      .add(&usage, cost);
+    self.tags.spent(&usage, cost);
+  }
+}
+
+/// Records the usage of a single API call against the `Orchestrator` that is
+/// sending the current request, for use in custom `OrchRequest`
+/// implementations. Does nothing outside of an `Orchestrator`.
+pub fn record_usage(model: &str, usage: Usage) {
+  scope::with_current(|scope| {
+    scope.usage.record(model, usage);
+    scope.update_stats(|stats| stats.model = Some(model.to_string()));
+  });
+}
+
+/// Returns the recorder for the current request, for responses which keep
+/// using tokens after `send` returns, such as streams.
+pub(crate) fn current_recorder() -> Option<UsageRecorder> {
+  scope::with_current(|scope| scope.usage.clone())
+}
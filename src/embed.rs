@@ -1,22 +1,110 @@
 //! Requests and responses using Embeddings models.
 
-use anyhow::{Error, Result};
-use async_openai::types::CreateEmbeddingRequest;
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use async_openai::types::{CreateEmbeddingRequest, EmbeddingInput};
 use async_trait::async_trait;
-use log::{debug, error};
-use tokio::time::timeout;
+use serde::{Deserialize, Serialize};
+use tokio::{
+  sync::mpsc,
+  time::{timeout_at, Duration, Instant},
+};
 
 use crate::{
-  keys::Keys, policies::Policies, utils::get_openai_client, OrchRequest,
-  ResponseType,
+  cache::cache_key,
+  cost::{record_usage, Usage},
+  error::{OrchError, Result},
+  keys::Keys,
+  meta::ResponseMeta,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, Orchestrator, RequestID, Responder, ResponseType,
 };
 
-pub const EMBEDDING_SIZE: usize = 1536;
+/// The model and shape of the embeddings to create.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmbeddingParams {
+  /// The model to use, e.g. `text-embedding-3-small`.
+  pub model:      String,
+  /// The size of the embeddings. Only supported by `text-embedding-3` and
+  /// later models; when unset, the model's native size is used.
+  pub dimensions: Option<u32>,
+}
+
+impl EmbeddingParams {
+  pub fn new(model: impl Into<String>, dimensions: Option<u32>) -> Self {
+    Self {
+      model: model.into(),
+      dimensions,
+    }
+  }
+}
+
+impl Default for EmbeddingParams {
+  fn default() -> Self {
+    Self::new("text-embedding-ada-002", None)
+  }
+}
+
+/// A request to embed a single string.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+  pub input:  String,
+  pub params: EmbeddingParams,
+}
+
+impl EmbeddingRequest {
+  pub fn new(input: String, params: EmbeddingParams) -> Self {
+    Self { input, params }
+  }
+}
 
-pub struct EmbeddingRequest(pub String);
-pub struct EmbeddingResponse(pub [f32; EMBEDDING_SIZE]);
+/// The embedding given by an `EmbeddingRequest`.
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingResponse(pub Vec<f32>);
+
+impl ResponseType for EmbeddingResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
 
-impl ResponseType for EmbeddingResponse {}
+fn build_inner_request(
+  input: EmbeddingInput,
+  params: &EmbeddingParams,
+) -> CreateEmbeddingRequest {
+  CreateEmbeddingRequest {
+    model: params.model.clone(),
+    input,
+    dimensions: params.dimensions,
+    ..Default::default()
+  }
+}
+
+/// Checks that an embedding has the size that was asked for.
+fn check_dimensions(
+  embedding: Vec<f32>,
+  params: &EmbeddingParams,
+) -> Result<Vec<f32>> {
+  match params.dimensions {
+    Some(dimensions) if embedding.len() != dimensions as usize => {
+      Err(OrchError::InvalidResponse(format!(
+        "expected an embedding of size {}, got {}",
+        dimensions,
+        embedding.len()
+      )))
+    }
+    _ => Ok(embedding),
+  }
+}
 
 #[async_trait]
 impl OrchRequest for EmbeddingRequest {
@@ -28,66 +116,297 @@ impl OrchRequest for EmbeddingRequest {
     id: u64,
   ) -> Result<Self::Res> {
     debug!("starting request {}", id);
-    let client = get_openai_client(&keys);
-    let mut retry_policy = policies.retry_policy;
-
-    let request = CreateEmbeddingRequest {
-      model: "text-embedding-ada-002".to_string(),
-      input: async_openai::types::EmbeddingInput::String(self.0.to_string()),
-      user:  None,
-    };
-
-    // continue trying until we get a response or we reach max retry
-    loop {
-      let timer = timing::start();
-      let response = timeout(
-        policies.timeout_policy.timeout,
-        client.embeddings().create(request.clone()),
-      )
-      .await;
-
-      let response = match response {
-        Ok(response) => response,
-        Err(err) => {
-          debug!(
-            "request {} timed out after {}s",
-            id,
-            policies.timeout_policy.timeout.as_secs_f32()
-          );
-          if retry_policy.failed_request().await {
-            continue;
-          } else {
-            error!("request {} reached max retry", id);
-            return Err(Error::new(err).context("reached max retry"));
+    let transport = transport::current();
+
+    let request = build_inner_request(
+      EmbeddingInput::String(self.input.clone()),
+      &self.params,
+    );
+
+    let timeout_duration =
+      policies.timeout_policy.timeout_for(self.estimated_tokens());
+    let response = with_retries(&policies, timeout_duration, id, || async {
+      let response = transport.embedding(&keys, request.clone()).await?;
+      record_usage(&response.model, (&response.usage).into());
+      Ok(response)
+    })
+    .await?;
+
+    let embedding = response
+      .data
+      .into_iter()
+      .next()
+      .ok_or_else(|| {
+        OrchError::InvalidResponse("response.data is empty".to_string())
+      })?
+      .embedding;
+
+    Ok(EmbeddingResponse(check_dimensions(
+      embedding,
+      &self.params,
+    )?))
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    self.input.len() as u64 / 4
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    Some((&self.params.model, Usage::new(self.estimated_tokens(), 0)))
+  }
+  fn cache_key(&self) -> Option<u64> {
+    cache_key(&build_inner_request(
+      EmbeddingInput::String(self.input.clone()),
+      &self.params,
+    ))
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
+
+/// Embeds `text` with the default `EmbeddingParams`, without an
+/// `Orchestrator`, for scripts which only need one call. Failed attempts are
+/// retried following the default `Policies`; nothing else, such as rate or
+/// concurrency limits, applies.
+pub async fn embed(keys: &Keys, text: impl Into<String>) -> Result<Vec<f32>> {
+  let request = EmbeddingRequest::new(text.into(), EmbeddingParams::default());
+  let response = request
+    .send(Policies::default(), keys.clone(), crate::next_request_id())
+    .await?;
+  Ok(response.0)
+}
+
+/// A request to embed several strings in a single API call.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchEmbeddingRequest {
+  pub inputs: Vec<String>,
+  pub params: EmbeddingParams,
+}
+
+impl BatchEmbeddingRequest {
+  pub fn new(inputs: Vec<String>, params: EmbeddingParams) -> Self {
+    Self { inputs, params }
+  }
+}
+
+/// The embeddings given by a `BatchEmbeddingRequest`, in the same order as
+/// its inputs.
+#[derive(Serialize, Deserialize)]
+pub struct BatchEmbeddingResponse(pub Vec<Vec<f32>>);
+
+impl ResponseType for BatchEmbeddingResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+#[async_trait]
+impl OrchRequest for BatchEmbeddingRequest {
+  type Res = BatchEmbeddingResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    if self.inputs.is_empty() {
+      return Ok(BatchEmbeddingResponse(Vec::new()));
+    }
+    let transport = transport::current();
+
+    let request = build_inner_request(
+      EmbeddingInput::StringArray(self.inputs.clone()),
+      &self.params,
+    );
+
+    let timeout_duration =
+      policies.timeout_policy.timeout_for(self.estimated_tokens());
+    let response = with_retries(&policies, timeout_duration, id, || async {
+      let response = transport.embedding(&keys, request.clone()).await?;
+      record_usage(&response.model, (&response.usage).into());
+      Ok(response)
+    })
+    .await?;
+
+    if response.data.len() != self.inputs.len() {
+      return Err(OrchError::InvalidResponse(format!(
+        "expected {} embeddings, got {}",
+        self.inputs.len(),
+        response.data.len()
+      )));
+    }
+
+    // the API doesn't promise to return embeddings in input order
+    let mut data = response.data;
+    data.sort_by_key(|embedding| embedding.index);
+    let embeddings = data
+      .into_iter()
+      .map(|embedding| check_dimensions(embedding.embedding, &self.params))
+      .collect::<Result<_>>()?;
+
+    Ok(BatchEmbeddingResponse(embeddings))
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    self.inputs.iter().map(|input| input.len() as u64 / 4).sum()
+  }
+
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    Some((&self.params.model, Usage::new(self.estimated_tokens(), 0)))
+  }
+  fn cache_key(&self) -> Option<u64> {
+    cache_key(&build_inner_request(
+      EmbeddingInput::StringArray(self.inputs.clone()),
+      &self.params,
+    ))
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
+
+/// Limits on how `EmbeddingBatcher` groups requests.
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+  /// The most inputs sent in a single API call.
+  pub max_batch_size: usize,
+  /// How long to wait for more requests after the first one arrives.
+  pub max_wait:       Duration,
+}
+
+impl Default for BatchConfig {
+  fn default() -> Self {
+    Self {
+      max_batch_size: 256,
+      max_wait:       Duration::from_millis(20),
+    }
+  }
+}
+
+struct Pending {
+  request: EmbeddingRequest,
+  id:      u64,
+  tx:      Responder<EmbeddingResponse>,
+}
+
+/// Collects `EmbeddingRequest`s added within a short window into
+/// `BatchEmbeddingRequest`s, sent through an `Orchestrator`, and hands each
+/// caller their own embedding.
+///
+/// Each batch counts as a single request in the `Orchestrator`'s `Progress`.
+/// If a batch fails, every request in it fails with the same error.
+///
+/// ```rust,no_run
+/// # use openai_orch::{embed::*, prelude::*};
+/// # async fn example(orchestrator: Orchestrator) -> Result<(), OrchError> {
+/// let batcher = EmbeddingBatcher::new(orchestrator, BatchConfig::default());
+/// let request = EmbeddingRequest::new(
+///   "Hello, world!".to_string(),
+///   EmbeddingParams::default(),
+/// );
+/// let embedding = batcher.add_request(request).await.await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct EmbeddingBatcher {
+  tx:        mpsc::UnboundedSender<Pending>,
+  unclaimed: Arc<AtomicUsize>,
+}
+
+impl EmbeddingBatcher {
+  /// Create a new `EmbeddingBatcher` which sends its batches through the
+  /// given `Orchestrator`. Batching stops once every clone of the batcher is
+  /// dropped.
+  pub fn new(orchestrator: Orchestrator, config: BatchConfig) -> Self {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let unclaimed = orchestrator.unclaimed.clone();
+    tokio::spawn(run_batcher(orchestrator, config, rx));
+    Self { tx, unclaimed }
+  }
+
+  /// Add a request to the next batch. Returns a request ID that can be used
+  /// to get the response.
+  pub async fn add_request(
+    &self,
+    request: EmbeddingRequest,
+  ) -> RequestID<EmbeddingResponse> {
+    let (request_id, tx) = RequestID::channel(&self.unclaimed);
+    let id = request_id.id();
+    if let Err(mpsc::error::SendError(pending)) =
+      self.tx.send(Pending { request, id, tx })
+    {
+      let meta = ResponseMeta::new(id);
+      pending.tx.send((Err(OrchError::Cancelled), meta));
+    }
+    request_id
+  }
+}
+
+async fn run_batcher(
+  orchestrator: Orchestrator,
+  config: BatchConfig,
+  mut rx: mpsc::UnboundedReceiver<Pending>,
+) {
+  while let Some(first) = rx.recv().await {
+    let mut batch = vec![first];
+    let deadline = Instant::now() + config.max_wait;
+    while batch.len() < config.max_batch_size.max(1) {
+      match timeout_at(deadline, rx.recv()).await {
+        Ok(Some(pending)) => batch.push(pending),
+        _ => break,
+      }
+    }
+
+    // only requests with the same params can share an API call
+    let mut groups: Vec<(EmbeddingParams, Vec<Pending>)> = Vec::new();
+    for pending in batch {
+      match groups
+        .iter_mut()
+        .find(|(params, _)| *params == pending.request.params)
+      {
+        Some((_, group)) => group.push(pending),
+        None => groups.push((pending.request.params.clone(), vec![pending])),
+      }
+    }
+
+    for (params, group) in groups {
+      let (inputs, senders): (Vec<_>, Vec<_>) = group
+        .into_iter()
+        .map(|pending| (pending.request.input, (pending.id, pending.tx)))
+        .unzip();
+      let request_id = orchestrator
+        .add_request(BatchEmbeddingRequest::new(inputs, params))
+        .await;
+
+      tokio::spawn(async move {
+        // every request in the batch was served the same way as the batch
+        let (res, meta) = request_id.into_delivery().await;
+        let meta_for = |id| ResponseMeta { id, ..meta.clone() };
+        match res {
+          Ok(BatchEmbeddingResponse(embeddings)) => {
+            for ((id, tx), embedding) in senders.into_iter().zip(embeddings) {
+              let res = Ok(EmbeddingResponse(embedding));
+              tx.send((res, meta_for(id)));
+            }
           }
-        }
-      };
-
-      // if we got a response, we need to check if it's an error
-      let response = match response {
-        Ok(response) => response,
-        Err(err) => {
-          if retry_policy.failed_request().await {
-            continue;
-          } else {
-            return Err(Error::new(err).context("reached max retry"));
+          Err(err) => {
+            for (id, tx) in senders {
+              tx.send((Err(err.duplicate()), meta_for(id)));
+            }
           }
         }
-      };
-
-      debug!(
-        "got response for {} in {}",
-        id,
-        timer.elapsed().as_secs_f32()
-      );
-      let embedding = response
-        .data
-        .first()
-        .ok_or(Error::msg("response.data is empty"))?
-        .embedding
-        .clone();
-
-      return Ok(EmbeddingResponse(embedding.as_slice().try_into()?));
+      });
     }
   }
 }
@@ -0,0 +1,500 @@
+//! The error type returned by requests and the `Orchestrator`.
+
+use async_openai::error::{ApiError, OpenAIError};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use tokio::time::Duration;
+
+use crate::moderation::ModerationCategory;
+
+/// A `Result` defaulting to `OrchError` as its error type.
+pub type Result<T, E = OrchError> = std::result::Result<T, E>;
+
+/// The ways a request sent through the `Orchestrator` can fail.
+#[derive(Debug, thiserror::Error)]
+pub enum OrchError {
+  /// An attempt did not finish within the `TimeoutPolicy`.
+  #[error("request timed out after {}s", .0.as_secs_f32())]
+  Timeout(Duration),
+  /// The request did not finish within the `DeadlinePolicy`, counting the
+  /// time spent queued and retrying.
+  #[error("request missed its deadline of {}s", .0.as_secs_f32())]
+  DeadlineExceeded(Duration),
+  /// The API rejected the request because a rate limit was reached.
+  ///
+  /// `retry_after` is how long the API asked to wait before trying again,
+  /// read from the `Retry-After` and `x-ratelimit-reset-*` headers of the
+  /// response. Streamed chat completions and transcriptions are made with
+  /// `async-openai`, which does not expose response headers, so for those it
+  /// is read from the "Please try again in ..." hint in the message when one
+  /// is given.
+  #[error("rate limited: {message}")]
+  RateLimited {
+    message:     String,
+    retry_after: Option<Duration>,
+  },
+  /// The API rejected the request. The fields are those of the error object
+  /// returned by the API, such as a `code` of `context_length_exceeded` or
+  /// `insufficient_quota`.
+  ///
+  /// `status` is the HTTP status of the response. `async-openai`, which
+  /// makes streamed chat completions and transcriptions, does not expose it,
+  /// so for those it is inferred from the error's type where possible.
+  #[error("api error{}: {message}", .code.as_ref().map(|c| format!(" ({c})")).unwrap_or_default())]
+  ApiError {
+    status:     Option<u16>,
+    error_type: Option<String>,
+    code:       Option<String>,
+    param:      Option<String>,
+    message:    String,
+  },
+  /// The prompt and completion would not fit in the model's context window.
+  /// This is checked before the request is sent, with the prompt counted
+  /// exactly when the `tokens` feature is enabled and estimated otherwise.
+  #[error("request needs {tokens} tokens, but the context window is {limit}")]
+  ContextLengthExceeded { tokens: u64, limit: u64 },
+  /// The request was not sent because the `BudgetPolicy` has been reached.
+  #[error(
+    "budget exceeded after spending ${cost_usd:.4} on {total_tokens} tokens"
+  )]
+  BudgetExceeded {
+    cost_usd:     f64,
+    total_tokens: u64,
+  },
+  /// The `ModerationPolicy` found content in the request's user messages in
+  /// the given categories, so it was not sent.
+  #[error("content flagged for {}", categories.iter().map(|c| c.as_str()).collect::<Vec<_>>().join(", "))]
+  ContentFlagged { categories: Vec<ModerationCategory> },
+  /// The completion reached `max_tokens` before the model finished, and the
+  /// `TruncationPolicy` doesn't allow that. `partial` is the content the
+  /// model produced before it was cut off.
+  #[error("completion was cut off after reaching max_tokens")]
+  Truncated { partial: String },
+  /// The reply still failed the request's `Validator` after the model was
+  /// asked to repair it `repairs` times. `reason` is why the last reply was
+  /// invalid.
+  #[error("reply failed validation after {repairs} repairs: {reason}")]
+  ValidationFailed { reason: String, repairs: u32 },
+  /// The reply broke a guardrail of the `GuardrailPolicy`. `reason` says
+  /// which.
+  #[error("reply broke a guardrail: {reason}")]
+  GuardrailViolated { reason: String },
+  /// An `Agent` was still calling tools after its maximum number of turns.
+  #[error("agent did not finish within {turns} turns")]
+  MaxTurnsExceeded { turns: usize },
+  /// The `RetryPolicy` gave up on the request. `last` is the error from the
+  /// final attempt.
+  #[error("reached max retry after {attempts} attempts: {last}")]
+  MaxRetriesExceeded {
+    attempts: u32,
+    #[source]
+    last:     Box<OrchError>,
+  },
+  /// The request stopped retrying because the retries of the
+  /// `RetryBudgetPolicy` were spent, so the API is likely failing for every
+  /// request. `last` is the error from the final attempt. Unlike
+  /// `MaxRetriesExceeded`, it doesn't start the `FallbackPolicy`.
+  #[error("retry budget exhausted after {attempts} attempts: {last}")]
+  RetryBudgetExhausted {
+    attempts: u32,
+    #[source]
+    last:     Box<OrchError>,
+  },
+  /// The request was not queued because `max_queued` requests were already
+  /// waiting to start, and the `QueuePolicy` rejects requests when full.
+  #[error("queue is full with {max_queued} requests waiting")]
+  QueueFull { max_queued: usize },
+  /// A request added with `Orchestrator::add_dependent_request` was not made
+  /// because the request with ID `id`, one of its dependencies, failed.
+  #[error("dependency {id} failed: {error}")]
+  DependencyFailed {
+    id:    u64,
+    #[source]
+    error: Box<OrchError>,
+  },
+  /// The request was cancelled before it finished, for example because the
+  /// `Orchestrator` was shut down.
+  #[error("request was cancelled")]
+  Cancelled,
+  /// The request's `OrchRequest::send` panicked. The message is that of the
+  /// panic, if it had one.
+  #[error("request panicked: {0}")]
+  TaskPanicked(String),
+  /// The response for a request could not be found, for example because it
+  /// was already retrieved.
+  #[error("no response found for request")]
+  ResponseMissing,
+  /// The `Transport` doesn't support the kind of call the request makes.
+  #[error("the transport does not support {0}")]
+  Unsupported(&'static str),
+  /// The API responded, but not in the shape the request expected.
+  #[error("invalid response: {0}")]
+  InvalidResponse(String),
+  /// The request could not be sent or its response could not be read.
+  #[error(transparent)]
+  OpenAI(OpenAIError),
+  /// An error raised by a custom `OrchRequest` implementation. It is not
+  /// retryable.
+  #[error(transparent)]
+  Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl OrchError {
+  /// Wraps an arbitrary error, for use in custom `OrchRequest`
+  /// implementations.
+  pub fn other(
+    err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+  ) -> Self {
+    OrchError::Other(err.into())
+  }
+
+  /// Returns the error for a failed response from the API, given its HTTP
+  /// status, headers, and body, for use in custom `Transport`s which call
+  /// the API themselves.
+  ///
+  /// Rate limits are returned as `RateLimited`, waiting for as long as the
+  /// `Retry-After`, `retry-after-ms`, or `x-ratelimit-reset-*` headers ask.
+  pub fn from_response(status: u16, headers: &HeaderMap, body: &[u8]) -> Self {
+    // bodies which aren't an error object, such as those of some 5xx
+    // responses, become the message
+    let err = match serde_json::from_slice::<ErrorBody>(body) {
+      Ok(ErrorBody { error }) => error,
+      Err(_) => ApiError {
+        message: String::from_utf8_lossy(body).into_owned(),
+        r#type:  None,
+        param:   None,
+        code:    None,
+      },
+    };
+    let out_of_quota = err.code.as_deref() == Some("insufficient_quota")
+      || err.r#type.as_deref() == Some("insufficient_quota");
+    if err.code.as_deref() == Some("rate_limit_exceeded")
+      || (status == 429 && !out_of_quota)
+    {
+      return OrchError::RateLimited {
+        retry_after: header_retry_after(headers)
+          .or_else(|| parse_retry_after(&err.message)),
+        message:     err.message,
+      };
+    }
+    OrchError::ApiError {
+      status:     Some(status),
+      error_type: err.r#type,
+      code:       err.code,
+      param:      err.param,
+      message:    err.message,
+    }
+  }
+
+  /// Returns the error behind any `MaxRetriesExceeded` or
+  /// `RetryBudgetExhausted` wrapping, i.e. the reason the final attempt failed.
+  pub fn last_error(&self) -> &OrchError {
+    match self {
+      OrchError::MaxRetriesExceeded { last, .. }
+      | OrchError::RetryBudgetExhausted { last, .. } => last.last_error(),
+      err => err,
+    }
+  }
+
+  /// Returns how long the API asked to wait before retrying, if it did.
+  pub fn retry_after(&self) -> Option<Duration> {
+    match self.last_error() {
+      OrchError::RateLimited { retry_after, .. } => *retry_after,
+      _ => None,
+    }
+  }
+
+  /// Makes a copy of the error for each of several requests that share it.
+  /// Errors from other crates can't be copied, so they are replaced by their
+  /// message.
+  pub(crate) fn duplicate(&self) -> OrchError {
+    match self {
+      OrchError::Timeout(duration) => OrchError::Timeout(*duration),
+      OrchError::DeadlineExceeded(duration) => {
+        OrchError::DeadlineExceeded(*duration)
+      }
+      OrchError::RateLimited {
+        message,
+        retry_after,
+      } => OrchError::RateLimited {
+        message:     message.clone(),
+        retry_after: *retry_after,
+      },
+      OrchError::ApiError {
+        status,
+        error_type,
+        code,
+        param,
+        message,
+      } => OrchError::ApiError {
+        status:     *status,
+        error_type: error_type.clone(),
+        code:       code.clone(),
+        param:      param.clone(),
+        message:    message.clone(),
+      },
+      OrchError::ContextLengthExceeded { tokens, limit } => {
+        OrchError::ContextLengthExceeded {
+          tokens: *tokens,
+          limit:  *limit,
+        }
+      }
+      OrchError::BudgetExceeded {
+        cost_usd,
+        total_tokens,
+      } => OrchError::BudgetExceeded {
+        cost_usd:     *cost_usd,
+        total_tokens: *total_tokens,
+      },
+      OrchError::ContentFlagged { categories } => OrchError::ContentFlagged {
+        categories: categories.clone(),
+      },
+      OrchError::Truncated { partial } => OrchError::Truncated {
+        partial: partial.clone(),
+      },
+      OrchError::ValidationFailed { reason, repairs } => {
+        OrchError::ValidationFailed {
+          reason:  reason.clone(),
+          repairs: *repairs,
+        }
+      }
+      OrchError::GuardrailViolated { reason } => OrchError::GuardrailViolated {
+        reason: reason.clone(),
+      },
+      OrchError::MaxTurnsExceeded { turns } => {
+        OrchError::MaxTurnsExceeded { turns: *turns }
+      }
+      OrchError::MaxRetriesExceeded { attempts, last } => {
+        OrchError::MaxRetriesExceeded {
+          attempts: *attempts,
+          last:     Box::new(last.duplicate()),
+        }
+      }
+      OrchError::RetryBudgetExhausted { attempts, last } => {
+        OrchError::RetryBudgetExhausted {
+          attempts: *attempts,
+          last:     Box::new(last.duplicate()),
+        }
+      }
+      OrchError::QueueFull { max_queued } => OrchError::QueueFull {
+        max_queued: *max_queued,
+      },
+      OrchError::DependencyFailed { id, error } => {
+        OrchError::DependencyFailed {
+          id:    *id,
+          error: Box::new(error.duplicate()),
+        }
+      }
+      OrchError::Cancelled => OrchError::Cancelled,
+      OrchError::Unsupported(call) => OrchError::Unsupported(call),
+      OrchError::TaskPanicked(message) => {
+        OrchError::TaskPanicked(message.clone())
+      }
+      OrchError::ResponseMissing => OrchError::ResponseMissing,
+      OrchError::InvalidResponse(message) => {
+        OrchError::InvalidResponse(message.clone())
+      }
+      OrchError::OpenAI(err) => OrchError::other(err.to_string()),
+      OrchError::Other(err) => OrchError::other(err.to_string()),
+    }
+  }
+
+  /// Returns the code of the API error behind the failure, such as
+  /// `context_length_exceeded`, if the API returned one.
+  pub fn code(&self) -> Option<&str> {
+    match self.last_error() {
+      OrchError::RateLimited { .. } => Some("rate_limit_exceeded"),
+      OrchError::ApiError { code, .. } => code.as_deref(),
+      _ => None,
+    }
+  }
+
+  /// Whether trying the request again could succeed. Rate limits, server
+  /// errors, timeouts, and network failures are retryable, while errors
+  /// caused by the request itself, such as a 400 or a 401, are not. Errors
+  /// raised by custom requests through `OrchError::other` aren't retried, so
+  /// their transports should report transient failures as `ApiError` or
+  /// `OpenAI` errors.
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      OrchError::Timeout(_)
+      | OrchError::RateLimited { .. }
+      | OrchError::InvalidResponse(_)
+      | OrchError::GuardrailViolated { .. } => true,
+      OrchError::ApiError { code, .. }
+        if code.as_deref() == Some("insufficient_quota") =>
+      {
+        false
+      }
+      OrchError::ApiError { status, .. } => match status {
+        Some(status) => matches!(status, 408 | 409 | 429) || *status >= 500,
+        None => true,
+      },
+      OrchError::OpenAI(err) => {
+        matches!(err, OpenAIError::Reqwest(_) | OpenAIError::StreamError(_))
+      }
+      OrchError::DeadlineExceeded(_)
+      | OrchError::ContextLengthExceeded { .. }
+      | OrchError::BudgetExceeded { .. }
+      | OrchError::ContentFlagged { .. }
+      | OrchError::Truncated { .. }
+      | OrchError::ValidationFailed { .. }
+      | OrchError::MaxTurnsExceeded { .. }
+      | OrchError::MaxRetriesExceeded { .. }
+      | OrchError::RetryBudgetExhausted { .. }
+      | OrchError::QueueFull { .. }
+      | OrchError::DependencyFailed { .. }
+      | OrchError::Unsupported(_)
+      | OrchError::Cancelled
+      | OrchError::TaskPanicked(_)
+      | OrchError::ResponseMissing
+      | OrchError::Other(_) => false,
+    }
+  }
+
+  /// Whether the error means the key the request was sent with was rejected.
+  pub fn is_auth_failure(&self) -> bool {
+    matches!(self.last_error(), OrchError::ApiError {
+      status: Some(401),
+      ..
+    })
+  }
+
+  /// Whether the error means the API is overloaded, i.e. the final attempt
+  /// was rate limited or failed with a server error.
+  pub fn is_overloaded(&self) -> bool {
+    match self.last_error() {
+      OrchError::RateLimited { .. } => true,
+      OrchError::ApiError {
+        status: Some(status),
+        ..
+      } => *status == 429 || *status >= 500,
+      _ => false,
+    }
+  }
+}
+
+/// Infers the HTTP status of an API error from its documented type.
+fn inferred_status(err: &ApiError) -> Option<u16> {
+  if err.code.as_deref() == Some("invalid_api_key") {
+    return Some(401);
+  }
+  let Some(kind) = err.r#type.as_deref() else {
+    // `async-openai` reports 5xx responses, whose bodies aren't JSON, as an
+    // error with only a message
+    return (err.code.is_none() && err.param.is_none()).then_some(500);
+  };
+  let status = match kind {
+    "invalid_request_error" => 400,
+    "authentication_error" => 401,
+    "permission_error" => 403,
+    "not_found_error" => 404,
+    "insufficient_quota" | "rate_limit_error" | "requests" | "tokens" => 429,
+    "server_error" | "api_error" => 500,
+    _ => return None,
+  };
+  Some(status)
+}
+
+/// The body of a failed response.
+#[derive(Deserialize)]
+struct ErrorBody {
+  error: ApiError,
+}
+
+/// Reads how long to wait before retrying from the headers of a rate limited
+/// response: `retry-after-ms`, then `Retry-After` in seconds, then the
+/// `x-ratelimit-reset-*` header of whichever limit is exhausted.
+fn header_retry_after(headers: &HeaderMap) -> Option<Duration> {
+  let header = |name: &str| {
+    headers
+      .get(name)
+      .and_then(|value| value.to_str().ok())
+      .map(str::trim)
+  };
+  let seconds = |value: &str, scale: f64| {
+    let seconds = value.parse::<f64>().ok()? * scale;
+    // a value too large for a `Duration` is ignored rather than trusted
+    Duration::try_from_secs_f64(seconds).ok()
+  };
+  if let Some(delay) = header("retry-after-ms").and_then(|v| seconds(v, 0.001))
+  {
+    return Some(delay);
+  }
+  if let Some(delay) = header("retry-after").and_then(|v| seconds(v, 1.0)) {
+    return Some(delay);
+  }
+
+  // when neither limit says it's exhausted, wait for the later reset
+  let resets = ["requests", "tokens"].map(|limit| {
+    let reset = header(&format!("x-ratelimit-reset-{limit}"))
+      .and_then(parse_duration_hint)?;
+    let exhausted =
+      header(&format!("x-ratelimit-remaining-{limit}")) == Some("0");
+    Some((exhausted, reset))
+  });
+  resets.into_iter().flatten().max().map(|(_, reset)| reset)
+}
+
+/// Reads the delay from a "Please try again in 1m2.5s." hint in a rate limit
+/// message.
+fn parse_retry_after(message: &str) -> Option<Duration> {
+  let (_, rest) = message.split_once("try again in ")?;
+  parse_duration_hint(rest.split_whitespace().next()?.trim_end_matches('.'))
+}
+
+/// Parses a duration in the format of the API's hints and headers, such as
+/// "1m2.5s" or "20ms".
+fn parse_duration_hint(hint: &str) -> Option<Duration> {
+  let mut total = 0.0;
+  let mut rest = hint;
+  while !rest.is_empty() {
+    let number_len = rest
+      .find(|c: char| !c.is_ascii_digit() && c != '.')
+      .unwrap_or(rest.len());
+    let (number, tail) = rest.split_at(number_len);
+    let unit_len = tail
+      .find(|c: char| !c.is_ascii_alphabetic())
+      .unwrap_or(tail.len());
+    let (unit, tail) = tail.split_at(unit_len);
+
+    let seconds = match unit {
+      "ms" => 0.001,
+      "s" => 1.0,
+      "m" => 60.0,
+      "h" => 3600.0,
+      _ => return None,
+    };
+    total += number.parse::<f64>().ok()? * seconds;
+    rest = tail;
+  }
+  (total > 0.0 && total.is_finite())
+    .then(|| Duration::try_from_secs_f64(total).ok())
+    .flatten()
+}
+
+impl From<OpenAIError> for OrchError {
+  fn from(err: OpenAIError) -> Self {
+    match err {
+      OpenAIError::ApiError(err)
+        if err.code.as_deref() == Some("rate_limit_exceeded") =>
+      {
+        OrchError::RateLimited {
+          retry_after: parse_retry_after(&err.message),
+          message:     err.message,
+        }
+      }
+      OpenAIError::ApiError(err) => OrchError::ApiError {
+        status:     inferred_status(&err),
+        error_type: err.r#type,
+        code:       err.code,
+        param:      err.param,
+        message:    err.message,
+      },
+      OpenAIError::JSONDeserialize(err) => {
+        OrchError::InvalidResponse(err.to_string())
+      }
+      err => OrchError::OpenAI(err),
+    }
+  }
+}
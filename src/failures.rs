@@ -0,0 +1,183 @@
+//! Writing failed requests to a JSONL file as they fail, for auditing long
+//! runs and sending their failures again.
+//!
+//! Give a `FailureLog` to `Orchestrator::with_failure_log`, and every request
+//! which fails is appended to its file as a `FailureRecord`: the request,
+//! the error, how many attempts were made, and when it was added and failed.
+//! Nothing is kept in memory, so a run can fail any number of requests.
+//!
+//! Requests are only written if they can be serialized, with
+//! `OrchRequest::to_json`; the requests of this crate all can. Read a log
+//! back with `FailureLog::read` to send its requests again.
+//!
+//! ```rust,no_run
+//! use openai_orch::{failures::FailureLog, prelude::*};
+//!
+//! # async fn example() -> Result<(), OrchError> {
+//! let orchestrator =
+//!   Orchestrator::new(Policies::default(), Keys::from_env().unwrap())
+//!     .with_failure_log(FailureLog::open("failures.jsonl")?);
+//! // ... later, once the run has finished
+//! for record in FailureLog::read("failures.jsonl")? {
+//!   let request: ChatSisoRequest = record.request()?;
+//!   orchestrator.add_request(request).await;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+  fs::File,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  time::SystemTime,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+  error::{OrchError, Result},
+  hooks::OrchestratorHooks,
+  jsonl,
+  meta::ResponseMeta,
+  redact::Redactor,
+  trace::error,
+};
+
+/// A failed request, as a line of a `FailureLog`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FailureRecord {
+  /// The ID of the request.
+  pub id:           u64,
+  /// The request, as returned by `OrchRequest::to_json`.
+  pub request:      Option<Value>,
+  /// The error the request failed with.
+  pub error:        String,
+  /// The number of attempts made to send the request, which is zero if it
+  /// failed before being sent.
+  pub attempts:     u32,
+  /// When the request was added, in RFC 3339 format.
+  pub submitted_at: String,
+  /// When the request failed, in RFC 3339 format.
+  pub failed_at:    String,
+  /// The model that served the last attempt, if any.
+  #[serde(default)]
+  pub model:        Option<String>,
+  /// The tags the request was added with.
+  #[serde(default)]
+  pub tags:         Vec<String>,
+}
+
+impl FailureRecord {
+  /// Deserializes the request, to send it again. Fails if it wasn't written,
+  /// or isn't a `Req`.
+  pub fn request<Req: DeserializeOwned>(&self) -> Result<Req> {
+    let request = self.request.clone().ok_or_else(|| {
+      OrchError::other(format!("request {} wasn't written", self.id))
+    })?;
+    serde_json::from_value(request).map_err(OrchError::other)
+  }
+}
+
+/// A JSONL file that failed requests are appended to. See the module
+/// documentation.
+pub struct FailureLog {
+  path: PathBuf,
+  file: Mutex<File>,
+}
+
+impl FailureLog {
+  /// Opens the log at `path` to append failures to, creating it if it
+  /// doesn't exist.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    let file = jsonl::open(&path)?;
+    Ok(Self {
+      path,
+      file: Mutex::new(file),
+    })
+  }
+
+  /// Reads every record in the log at `path`.
+  pub fn read(path: impl AsRef<Path>) -> Result<Vec<FailureRecord>> {
+    jsonl::read(path.as_ref())
+  }
+
+  /// The file the log is written to.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn write(&self, record: &FailureRecord) -> Result<()> {
+    jsonl::append(&self.file, record)
+  }
+}
+
+/// Writes the failure of a single request to a `FailureLog`, as one of the
+/// request's hooks.
+pub(crate) struct FailureRecorder {
+  log:      Arc<FailureLog>,
+  request:  Option<Value>,
+  redactor: Option<Arc<Redactor>>,
+  started:  AtomicBool,
+}
+
+impl FailureRecorder {
+  /// Returns a recorder writing `request` to `log` if it fails. With a
+  /// `redactor`, the request and its error are redacted before they are
+  /// written.
+  pub(crate) fn new(
+    log: Arc<FailureLog>,
+    mut request: Option<Value>,
+    redactor: Option<Arc<Redactor>>,
+  ) -> Self {
+    if let (Some(request), Some(redactor)) = (&mut request, &redactor) {
+      redactor.redact_json(request);
+    }
+    Self {
+      log,
+      request,
+      redactor,
+      started: AtomicBool::new(false),
+    }
+  }
+}
+
+impl OrchestratorHooks for FailureRecorder {
+  fn on_started(&self, _id: u64) {
+    self.started.store(true, Ordering::Relaxed);
+  }
+
+  fn on_failed(&self, meta: &ResponseMeta, err: &OrchError) {
+    let failed_at = SystemTime::now();
+    let submitted_at = failed_at.checked_sub(meta.latency).unwrap_or(failed_at);
+    let record = FailureRecord {
+      id:           meta.id,
+      request:      self.request.clone(),
+      error:        match &self.redactor {
+        Some(redactor) => redactor.redact(&err.to_string()),
+        None => err.to_string(),
+      },
+      attempts:     match self.started.load(Ordering::Relaxed) {
+        true => meta.retries + 1,
+        false => 0,
+      },
+      submitted_at: humantime::format_rfc3339_millis(submitted_at).to_string(),
+      failed_at:    humantime::format_rfc3339_millis(failed_at).to_string(),
+      model:        meta.model.clone(),
+      tags:         meta.tags.clone(),
+    };
+    if let Err(err) = self.log.write(&record) {
+      error!(
+        "failed to write the failure of request {} to {}: {}",
+        meta.id,
+        self.log.path.display(),
+        err
+      );
+    }
+  }
+}
@@ -0,0 +1,89 @@
+//! Hooks for observing requests as they move through the `Orchestrator`.
+
+use std::sync::Arc;
+
+use tokio::time::Duration;
+
+use crate::{error::OrchError, meta::ResponseMeta};
+
+/// Callbacks for the lifecycle of every request sent through an
+/// `Orchestrator`, registered with `Orchestrator::with_hooks`. Every method
+/// does nothing by default, so implement only the ones you need.
+///
+/// Hooks are called from the task sending the request, so they should return
+/// quickly; hand slow work such as network calls off to another task.
+pub trait OrchestratorHooks: Send + Sync {
+  /// Called when a request is added to the `Orchestrator`.
+  fn on_submitted(&self, _id: u64) {}
+
+  /// Called when a request leaves the queue and starts being sent.
+  fn on_started(&self, _id: u64) {}
+
+  /// Called when an attempt to send a request fails and will be retried after
+  /// `delay`. `attempt` is the number of the attempt that failed, starting at
+  /// one.
+  fn on_retry(
+    &self,
+    _id: u64,
+    _attempt: u32,
+    _error: &OrchError,
+    _delay: Duration,
+  ) {
+  }
+
+  /// Called when a request finishes with a response.
+  fn on_completed(&self, _meta: &ResponseMeta) {}
+
+  /// Called when a request finishes with an error, including when it is
+  /// cancelled before being sent.
+  fn on_failed(&self, _meta: &ResponseMeta, _error: &OrchError) {}
+}
+
+/// The hooks registered on an `Orchestrator`, called in the order they were
+/// registered.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<Arc<dyn OrchestratorHooks>>);
+
+impl Hooks {
+  pub(crate) fn push(&mut self, hooks: Arc<dyn OrchestratorHooks>) {
+    self.0.push(hooks);
+  }
+
+  /// Returns these hooks followed by `hooks`.
+  pub(crate) fn with(&self, hooks: impl OrchestratorHooks + 'static) -> Self {
+    let mut with = self.clone();
+    with.push(Arc::new(hooks));
+    with
+  }
+}
+
+impl OrchestratorHooks for Hooks {
+  fn on_submitted(&self, id: u64) {
+    self.0.iter().for_each(|hooks| hooks.on_submitted(id));
+  }
+
+  fn on_started(&self, id: u64) {
+    self.0.iter().for_each(|hooks| hooks.on_started(id));
+  }
+
+  fn on_retry(
+    &self,
+    id: u64,
+    attempt: u32,
+    error: &OrchError,
+    delay: Duration,
+  ) {
+    self
+      .0
+      .iter()
+      .for_each(|hooks| hooks.on_retry(id, attempt, error, delay));
+  }
+
+  fn on_completed(&self, meta: &ResponseMeta) {
+    self.0.iter().for_each(|hooks| hooks.on_completed(meta));
+  }
+
+  fn on_failed(&self, meta: &ResponseMeta, error: &OrchError) {
+    self.0.iter().for_each(|hooks| hooks.on_failed(meta, error));
+  }
+}
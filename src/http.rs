@@ -0,0 +1,110 @@
+//! Configuration of the HTTP client requests are sent with.
+//!
+//! By default an `Orchestrator` sends every request with one client, shared
+//! by all of its keys, with `reqwest`'s defaults. To send
+//! requests through a corporate proxy, with client certificates, or with
+//! extra headers, build a client with `HttpConfig`, or configure a
+//! `reqwest::Client` directly, and give it to `Keys::with_http_client` or
+//! `OrchestratorBuilder::http_client`.
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use openai_orch::http::HttpConfig;
+//!
+//! let client = HttpConfig::new()
+//!   .with_connect_timeout(Duration::from_secs(5))
+//!   .with_pool_max_idle_per_host(32)
+//!   .with_header("x-team", "research")
+//!   .build()
+//!   .unwrap();
+//! ```
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tokio::time::Duration;
+
+use crate::error::{OrchError, Result};
+
+/// Common settings for the HTTP client, for building a `reqwest::Client`.
+#[derive(Clone, Debug, Default)]
+pub struct HttpConfig {
+  /// How long to wait for a connection to be established.
+  pub connect_timeout:        Option<Duration>,
+  /// The most idle connections kept open to each host.
+  pub pool_max_idle_per_host: Option<usize>,
+  /// How long idle connections are kept open.
+  pub pool_idle_timeout:      Option<Duration>,
+  /// The URL of a proxy to send every request through.
+  pub proxy:                  Option<String>,
+  /// Headers to send with every request.
+  pub headers:                Vec<(String, String)>,
+}
+
+impl HttpConfig {
+  /// Returns settings which leave everything at `reqwest`'s defaults.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Sets how long to wait for a connection to be established.
+  pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+    self.connect_timeout = Some(connect_timeout);
+    self
+  }
+
+  /// Sets the most idle connections kept open to each host.
+  pub fn with_pool_max_idle_per_host(mut self, n: usize) -> Self {
+    self.pool_max_idle_per_host = Some(n);
+    self
+  }
+
+  /// Sets how long idle connections are kept open.
+  pub fn with_pool_idle_timeout(mut self, pool_idle_timeout: Duration) -> Self {
+    self.pool_idle_timeout = Some(pool_idle_timeout);
+    self
+  }
+
+  /// Sends every request through the proxy at `url`.
+  pub fn with_proxy(mut self, url: impl Into<String>) -> Self {
+    self.proxy = Some(url.into());
+    self
+  }
+
+  /// Adds a header to send with every request.
+  pub fn with_header(
+    mut self,
+    name: impl Into<String>,
+    value: impl Into<String>,
+  ) -> Self {
+    self.headers.push((name.into(), value.into()));
+    self
+  }
+
+  /// Builds a client with these settings. Fails if the proxy URL or a header
+  /// is invalid.
+  pub fn build(&self) -> Result<reqwest::Client> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in &self.headers {
+      let name =
+        HeaderName::try_from(name.as_str()).map_err(OrchError::other)?;
+      let value = HeaderValue::try_from(value).map_err(OrchError::other)?;
+      headers.append(name, value);
+    }
+
+    let mut builder = reqwest::Client::builder().default_headers(headers);
+    if let Some(connect_timeout) = self.connect_timeout {
+      builder = builder.connect_timeout(connect_timeout);
+    }
+    if let Some(n) = self.pool_max_idle_per_host {
+      builder = builder.pool_max_idle_per_host(n);
+    }
+    if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+      builder = builder.pool_idle_timeout(pool_idle_timeout);
+    }
+    if let Some(proxy) = &self.proxy {
+      let proxy = reqwest::Proxy::all(proxy).map_err(OrchError::other)?;
+      builder = builder.proxy(proxy);
+    }
+    builder.build().map_err(OrchError::other)
+  }
+}
@@ -0,0 +1,230 @@
+//! Requests and responses using the OpenAI Images API.
+
+use async_openai::types::{
+  CreateImageRequest, Image, ImageModel, ImageQuality as ApiImageQuality,
+  ImageResponseFormat, ImageSize as ApiImageSize,
+};
+use async_trait::async_trait;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// The size of the images to generate. `dall-e-2` only supports the square
+/// sizes, and `dall-e-3` only supports 1024x1024 and larger.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ImageSize {
+  S256x256,
+  S512x512,
+  #[default]
+  S1024x1024,
+  S1792x1024,
+  S1024x1792,
+}
+
+impl From<ImageSize> for ApiImageSize {
+  fn from(size: ImageSize) -> Self {
+    match size {
+      ImageSize::S256x256 => ApiImageSize::S256x256,
+      ImageSize::S512x512 => ApiImageSize::S512x512,
+      ImageSize::S1024x1024 => ApiImageSize::S1024x1024,
+      ImageSize::S1792x1024 => ApiImageSize::S1792x1024,
+      ImageSize::S1024x1792 => ApiImageSize::S1024x1792,
+    }
+  }
+}
+
+/// The quality of the images to generate. Only supported by `dall-e-3`.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ImageQuality {
+  #[default]
+  Standard,
+  /// Finer details and greater consistency, at a higher price.
+  Hd,
+}
+
+impl From<ImageQuality> for ApiImageQuality {
+  fn from(quality: ImageQuality) -> Self {
+    match quality {
+      ImageQuality::Standard => ApiImageQuality::Standard,
+      ImageQuality::Hd => ApiImageQuality::HD,
+    }
+  }
+}
+
+/// How generated images are returned.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ImageFormat {
+  /// As URLs, which expire an hour after the images are generated.
+  #[default]
+  Url,
+  /// As the bytes of the PNG images.
+  Bytes,
+}
+
+/// The model and shape of the images to generate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageParams {
+  /// The model to use, e.g. `dall-e-3`.
+  pub model:   String,
+  pub size:    ImageSize,
+  pub quality: ImageQuality,
+  /// The number of images to generate. `dall-e-3` only supports one.
+  pub n:       u8,
+  pub format:  ImageFormat,
+}
+
+impl Default for ImageParams {
+  fn default() -> Self {
+    Self {
+      model:   String::from("dall-e-3"),
+      size:    ImageSize::default(),
+      quality: ImageQuality::default(),
+      n:       1,
+      format:  ImageFormat::default(),
+    }
+  }
+}
+
+/// A request to generate images from a prompt.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ImageGenerationRequest {
+  pub prompt: String,
+  pub params: ImageParams,
+}
+
+impl ImageGenerationRequest {
+  pub fn new(prompt: String, params: ImageParams) -> Self {
+    Self { prompt, params }
+  }
+}
+
+/// The contents of a generated image, in the requested `ImageFormat`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ImageData {
+  Url(String),
+  Bytes(Vec<u8>),
+}
+
+/// A single image generated by an `ImageGenerationRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeneratedImage {
+  pub data:           ImageData,
+  /// The prompt the model actually used, if it rewrote the one it was given.
+  pub revised_prompt: Option<String>,
+}
+
+impl TryFrom<&Image> for GeneratedImage {
+  type Error = OrchError;
+
+  fn try_from(image: &Image) -> Result<Self> {
+    Ok(match image {
+      Image::Url {
+        url,
+        revised_prompt,
+      } => Self {
+        data:           ImageData::Url(url.clone()),
+        revised_prompt: revised_prompt.clone(),
+      },
+      Image::B64Json {
+        b64_json,
+        revised_prompt,
+      } => Self {
+        data:           ImageData::Bytes(
+          base64::engine::general_purpose::STANDARD
+            .decode(b64_json.as_bytes())
+            .map_err(|err| {
+              OrchError::InvalidResponse(format!(
+                "image is not valid base64: {}",
+                err
+              ))
+            })?,
+        ),
+        revised_prompt: revised_prompt.clone(),
+      },
+    })
+  }
+}
+
+/// The images given by an `ImageGenerationRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageGenerationResponse(pub Vec<GeneratedImage>);
+
+impl ResponseType for ImageGenerationResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+#[async_trait]
+impl OrchRequest for ImageGenerationRequest {
+  type Res = ImageGenerationResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let transport = transport::current();
+
+    let request = CreateImageRequest {
+      prompt: self.prompt.clone(),
+      model: Some(ImageModel::Other(self.params.model.clone())),
+      n: Some(self.params.n),
+      quality: Some(self.params.quality.into()),
+      response_format: Some(match self.params.format {
+        ImageFormat::Url => ImageResponseFormat::Url,
+        ImageFormat::Bytes => ImageResponseFormat::B64Json,
+      }),
+      size: Some(self.params.size.into()),
+      ..Default::default()
+    };
+
+    let response = with_retries(
+      &policies,
+      policies.timeout_policy.timeout(),
+      id,
+      || async { transport.image(&keys, request.clone()).await },
+    )
+    .await?;
+
+    if response.data.is_empty() {
+      return Err(OrchError::InvalidResponse(
+        "response.data is empty".to_string(),
+      ));
+    }
+    Ok(ImageGenerationResponse(
+      response
+        .data
+        .iter()
+        .map(|image| GeneratedImage::try_from(image.as_ref()))
+        .collect::<Result<_>>()?,
+    ))
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
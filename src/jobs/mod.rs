@@ -0,0 +1,53 @@
+//! Persisting the requests of a bulk run and their results, so that a run
+//! which crashes or is interrupted can be resumed where it left off.
+//!
+//! Add requests with `Orchestrator::add_job` instead of `add_request`, and
+//! after an interruption call `Orchestrator::resume` with the same store to
+//! send the jobs which hadn't completed.
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use async_trait::async_trait;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteJobStore;
+
+use crate::error::Result;
+
+/// Where a job in a `JobStore` is up to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+  /// The job hasn't finished, possibly because its run was interrupted.
+  Pending,
+  /// The job finished with a response, as serialized by
+  /// `ResponseType::to_cache`. Responses which can't be serialized are stored
+  /// empty.
+  Completed(Vec<u8>),
+  /// The job failed with the given error.
+  Failed(String),
+}
+
+/// A request persisted in a `JobStore`.
+#[derive(Clone, Debug)]
+pub struct Job {
+  /// The key the job was added with.
+  pub key:     String,
+  /// The request, serialized as JSON.
+  pub request: Vec<u8>,
+  pub status:  JobStatus,
+}
+
+/// Storage for the jobs of a bulk run. Implement this to keep jobs somewhere
+/// other than the provided `SqliteJobStore`.
+#[async_trait]
+pub trait JobStore: Send + Sync {
+  /// Saves a newly added job as pending. If a job with the same key already
+  /// exists, it is left as it is.
+  async fn insert(&self, key: &str, request: &[u8]) -> Result<()>;
+  /// Sets the status of the job with the given key.
+  async fn set_status(&self, key: &str, status: &JobStatus) -> Result<()>;
+  /// Returns the job with the given key, if there is one.
+  async fn get(&self, key: &str) -> Result<Option<Job>>;
+  /// Returns every job which hasn't completed, in the order they were added.
+  async fn unfinished(&self) -> Result<Vec<Job>>;
+}
@@ -0,0 +1,124 @@
+//! A `JobStore` backed by a SQLite database.
+
+use std::{path::Path, sync::Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::{
+  error::{OrchError, Result},
+  jobs::{Job, JobStatus, JobStore},
+};
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS jobs (
+    seq     INTEGER PRIMARY KEY AUTOINCREMENT,
+    key     TEXT NOT NULL UNIQUE,
+    request BLOB NOT NULL,
+    status  TEXT NOT NULL,
+    output  BLOB,
+    error   TEXT
+  );
+";
+
+/// A `JobStore` which keeps jobs in a SQLite database, so that they survive
+/// the process crashing.
+pub struct SqliteJobStore {
+  conn: Mutex<Connection>,
+}
+
+impl SqliteJobStore {
+  /// Opens the database at `path`, creating it if it doesn't exist.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    Self::from_connection(Connection::open(path).map_err(OrchError::other)?)
+  }
+
+  /// Returns a store backed by a database in memory, which is lost when the
+  /// store is dropped.
+  pub fn in_memory() -> Result<Self> {
+    Self::from_connection(
+      Connection::open_in_memory().map_err(OrchError::other)?,
+    )
+  }
+
+  fn from_connection(conn: Connection) -> Result<Self> {
+    conn.execute_batch(SCHEMA).map_err(OrchError::other)?;
+    Ok(Self {
+      conn: Mutex::new(conn),
+    })
+  }
+
+  fn with_conn<T>(
+    &self,
+    f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+  ) -> Result<T> {
+    f(&self.conn.lock().expect("job store lock poisoned"))
+      .map_err(OrchError::other)
+  }
+}
+
+fn job_from_row(row: &Row<'_>) -> rusqlite::Result<Job> {
+  let status = match row.get::<_, String>(2)?.as_str() {
+    "completed" => JobStatus::Completed(row.get(3)?),
+    "failed" => JobStatus::Failed(row.get(4)?),
+    _ => JobStatus::Pending,
+  };
+  Ok(Job {
+    key: row.get(0)?,
+    request: row.get(1)?,
+    status,
+  })
+}
+
+#[async_trait]
+impl JobStore for SqliteJobStore {
+  async fn insert(&self, key: &str, request: &[u8]) -> Result<()> {
+    self.with_conn(|conn| {
+      conn.execute(
+        "INSERT OR IGNORE INTO jobs (key, request, status)
+         VALUES (?1, ?2, 'pending')",
+        params![key, request],
+      )
+    })?;
+    Ok(())
+  }
+
+  async fn set_status(&self, key: &str, status: &JobStatus) -> Result<()> {
+    let (status, output, error) = match status {
+      JobStatus::Pending => ("pending", None, None),
+      JobStatus::Completed(output) => ("completed", Some(output), None),
+      JobStatus::Failed(error) => ("failed", None, Some(error)),
+    };
+    self.with_conn(|conn| {
+      conn.execute(
+        "UPDATE jobs SET status = ?2, output = ?3, error = ?4 WHERE key = ?1",
+        params![key, status, output, error],
+      )
+    })?;
+    Ok(())
+  }
+
+  async fn get(&self, key: &str) -> Result<Option<Job>> {
+    self.with_conn(|conn| {
+      conn
+        .query_row(
+          "SELECT key, request, status, output, error FROM jobs WHERE key = ?1",
+          params![key],
+          job_from_row,
+        )
+        .optional()
+    })
+  }
+
+  async fn unfinished(&self) -> Result<Vec<Job>> {
+    self.with_conn(|conn| {
+      conn
+        .prepare(
+          "SELECT key, request, status, output, error FROM jobs
+           WHERE status != 'completed' ORDER BY seq",
+        )?
+        .query_map([], job_from_row)?
+        .collect()
+    })
+  }
+}
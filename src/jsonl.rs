@@ -0,0 +1,56 @@
+//! Reading and appending the JSONL files that failures, prompts, and
+//! cassettes are written to, one record per line.
+
+use std::{
+  fs::{File, OpenOptions},
+  io::Write,
+  path::Path,
+  sync::Mutex,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::{OrchError, Result};
+
+/// Opens the file at `path` to append records to, creating it if it doesn't
+/// exist.
+pub(crate) fn open(path: &Path) -> Result<File> {
+  OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(path)
+    .map_err(OrchError::other)
+}
+
+/// Reads every record in the file at `path`.
+pub(crate) fn read<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+  let contents = std::fs::read_to_string(path).map_err(OrchError::other)?;
+  parse(&contents)
+}
+
+/// Parses every record in `contents`, skipping blank lines.
+pub(crate) fn parse<T: DeserializeOwned>(contents: &str) -> Result<Vec<T>> {
+  contents
+    .lines()
+    .filter(|line| !line.trim().is_empty())
+    .map(|line| serde_json::from_str(line).map_err(OrchError::other))
+    .collect()
+}
+
+/// Serializes `record` as a line, ending with a newline.
+pub(crate) fn line(record: &impl Serialize) -> Result<Vec<u8>> {
+  let mut line = serde_json::to_vec(record).map_err(OrchError::other)?;
+  line.push(b'\n');
+  Ok(line)
+}
+
+/// Appends `record` to `file` as a line.
+pub(crate) fn append(
+  file: &Mutex<File>,
+  record: &impl Serialize,
+) -> Result<()> {
+  let line = line(record)?;
+  // each record is written at once, so concurrent writers don't interleave
+  let mut file = file.lock().expect("jsonl lock poisoned");
+  file.write_all(&line).map_err(OrchError::other)
+}
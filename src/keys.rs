@@ -4,6 +4,56 @@
 pub struct Keys {
   pub openai_api_key: String,
   pub openai_org_id:  Option<String>,
+  pub provider:       Provider,
+  /// Overrides the URL of the OpenAI API, for proxies, gateways, and
+  /// OpenAI-compatible servers such as vLLM, LM Studio, or Ollama.
+  pub base_url:       Option<String>,
+  /// The HTTP client to send requests with. The `Orchestrator` gives keys
+  /// without one a client shared by all of its keys. See the `http` module.
+  pub http_client:    Option<reqwest::Client>,
+}
+
+/// The service requests are sent to.
+#[derive(Clone, Debug, Default)]
+pub enum Provider {
+  /// The OpenAI API.
+  #[default]
+  OpenAI,
+  /// A model deployed to Azure OpenAI. The deployment decides which model is
+  /// used, so the `model` in a request's parameters is ignored.
+  Azure {
+    /// The resource endpoint, e.g. `https://my-resource.openai.azure.com`.
+    endpoint:    String,
+    /// The name of the model deployment.
+    deployment:  String,
+    /// The API version, e.g. `2024-06-01`.
+    api_version: String,
+  },
+}
+
+/// How an `Orchestrator` with several keys picks the key for each request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyBalancing {
+  /// Use each key in turn.
+  #[default]
+  RoundRobin,
+  /// Use the key with the fewest requests in flight.
+  LeastInFlight,
+}
+
+/// The health of one of an `Orchestrator`'s keys, as reported by
+/// `Orchestrator::key_status`.
+#[derive(Clone, Debug)]
+pub struct KeyStatus {
+  /// The position of the key in the list given to the `Orchestrator`.
+  pub index:         usize,
+  /// The organization the key belongs to, if one was given.
+  pub openai_org_id: Option<String>,
+  /// Whether the key is in use. Keys are marked unhealthy when the API
+  /// rejects them, and are probed again periodically.
+  pub healthy:       bool,
+  /// The number of requests currently using the key.
+  pub in_flight:     usize,
 }
 
 impl Keys {
@@ -11,13 +61,71 @@ impl Keys {
     Self {
       openai_api_key,
       openai_org_id,
+      provider: Provider::OpenAI,
+      base_url: None,
+      http_client: None,
     }
   }
 
+  /// Returns keys for a model deployed to Azure OpenAI.
+  pub fn azure(
+    api_key: String,
+    endpoint: String,
+    deployment: String,
+    api_version: String,
+  ) -> Self {
+    Self::new(api_key, None).with_provider(Provider::Azure {
+      endpoint,
+      deployment,
+      api_version,
+    })
+  }
+
+  /// Sends requests to the given provider.
+  pub fn with_provider(mut self, provider: Provider) -> Self {
+    self.provider = provider;
+    self
+  }
+
+  /// Sends requests to `base_url` instead of the OpenAI API, e.g.
+  /// `http://localhost:11434/v1`. Servers which don't check keys accept any
+  /// `openai_api_key`, including an empty one.
+  pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+    self.base_url = Some(base_url.into());
+    self
+  }
+
+  /// Sends requests with the given HTTP client, for example one configured
+  /// with a proxy, client certificates, or extra headers.
+  pub fn with_http_client(mut self, http_client: reqwest::Client) -> Self {
+    self.http_client = Some(http_client);
+    self
+  }
+
+  /// Reads keys from the `OPENAI_API_KEY`, `OPENAI_ORG_ID`, and
+  /// `OPENAI_BASE_URL` environment variables.
   pub fn from_env() -> Option<Self> {
     dotenv::dotenv().ok();
     let openai_api_key = std::env::var("OPENAI_API_KEY").ok()?;
     let openai_org_id = std::env::var("OPENAI_ORG_ID").ok();
-    Some(Self::new(openai_api_key, openai_org_id))
+    let keys = Self::new(openai_api_key, openai_org_id);
+    Some(match std::env::var("OPENAI_BASE_URL") {
+      Ok(base_url) => keys.with_base_url(base_url),
+      Err(_) => keys,
+    })
+  }
+
+  /// Reads keys for Azure OpenAI from the `AZURE_OPENAI_API_KEY`,
+  /// `AZURE_OPENAI_ENDPOINT`, `AZURE_OPENAI_DEPLOYMENT`, and
+  /// `AZURE_OPENAI_API_VERSION` environment variables.
+  pub fn azure_from_env() -> Option<Self> {
+    dotenv::dotenv().ok();
+    let var = |name| std::env::var(name).ok();
+    Some(Self::azure(
+      var("AZURE_OPENAI_API_KEY")?,
+      var("AZURE_OPENAI_ENDPOINT")?,
+      var("AZURE_OPENAI_DEPLOYMENT")?,
+      var("AZURE_OPENAI_API_VERSION")?,
+    ))
   }
 }
@@ -9,27 +9,28 @@
 //! # Usage
 //! To use this library, create an `Orchestrator` with the desired policies and
 //! keys. To allow a thread to use the `Orchestrator`, simply clone it. To send
-//! a request, call `add_request` on the `Orchestrator`, and then call get_response
-//! on the `Orchestrator` with the request ID returned by `add_request`. The
-//! `Orchestrator` will handle concurrency automatically.
+//! a request, call `add_request` on the `Orchestrator`, and then call
+//! get_response on the `Orchestrator` with the request ID returned by
+//! `add_request`. The `Orchestrator` will handle concurrency automatically.
+//! The request ID can also be awaited directly in place of `get_response`.
 //!
 //! # Example
-//! ```rust
+//! ```rust,no_run
 //! use openai_orch::prelude::*;
-//! 
+//!
 //! #[tokio::main]
 //! async fn main() {
 //!   let policies = Policies::default();
 //!   let keys = Keys::from_env().unwrap();
 //!   let orchestrator = Orchestrator::new(policies, keys);
-//! 
+//!
 //!   let request = ChatSisoRequest::new(
 //!     "You are a helpful assistant.".to_string(),
 //!     "What are you?".to_string(),
 //!     Default::default(),
 //!   );
 //!   let request_id = orchestrator.add_request(request).await;
-//! 
+//!
 //!   let response = orchestrator
 //!     .get_response::<ChatSisoResponse>(request_id)
 //!     .await;
@@ -38,28 +39,150 @@
 //! ```
 //!
 //! If you'd like, you can implement `OrchRequest` on your own request type.
-//! See the `OrchRequest` trait for more information. Currently the only request
-//! type implemented is `ChatSisoRequest`; `SISO` stands for "Single Input Single
-//! Output".
+//! See the `OrchRequest` trait for more information. The request types
+//! implemented by this crate include `ChatSisoRequest`, where `SISO` stands for
+//! "Single Input Single Output", and `ChatMimoRequest`, where `MIMO` stands for
+//! "Multiple Input (messages)", for continuing existing conversations, and
+//! `ChatSimoRequest`, where `SIMO` stands for "Single Input Multiple Output",
+//! for sampling several completions of the same prompt.
+//!
+//! # Features
+//! - `tokens`: counts prompt tokens with `tiktoken` rather than estimating
+//!   them, to weigh requests accurately against tokens-per-minute limits and
+//!   the model's context window before they are sent.
+//! - `tracing`: logs through `tracing` instead of `log`, with a span around
+//!   each request and each attempt to send it.
+//! - `metrics`: records counters and histograms through the `metrics` facade,
+//!   for requests submitted and finished, retries, timeouts, latency, tokens,
+//!   and requests in flight, so they can be exported to e.g. Prometheus.
+//! - `sqlite`: provides `jobs::SqliteJobStore`, for persisting bulk runs so
+//!   they can be resumed after a crash.
+//! - `toml` and `yaml`: provide `Policies::from_toml` and
+//!   `Policies::from_yaml`, for loading policies from configuration files.
+//! - `blocking`: provides `blocking::BlockingOrchestrator`, for sending
+//!   requests from synchronous code.
+//! - `test-util`: provides the `mock` module, for testing code built on the
+//!   `Orchestrator` against scripted responses instead of the API, and the
+//!   `cassette` module, for recording calls to the API and replaying them.
 
+pub mod agent;
+pub mod audio;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod builder;
+pub mod bulk;
+pub mod cache;
+#[cfg(feature = "test-util")]
+pub mod cassette;
 pub mod chat;
+mod coalesce;
+pub mod completions;
+pub mod cost;
 pub mod embed;
+pub mod error;
+pub mod failures;
+pub mod hooks;
+pub mod http;
+pub mod images;
+pub mod jobs;
+mod jsonl;
 pub mod keys;
+mod limiter;
+pub mod meta;
+#[cfg(feature = "test-util")]
+pub mod mock;
+pub mod models;
+pub mod moderation;
+pub mod pipeline;
 pub mod policies;
+mod pool;
 pub mod prelude;
+pub mod progress;
+pub mod prompt_log;
+pub mod redact;
+mod scheduler;
+mod scope;
+pub mod summarize;
+pub mod tags;
+mod telemetry;
+pub mod templates;
+#[cfg(feature = "tokens")]
+pub mod tokens;
+mod trace;
+pub mod transport;
 pub mod utils;
 
-use std::{any::Any, collections::HashMap, marker::PhantomData, sync::Arc};
+use std::{
+  collections::HashMap,
+  fmt,
+  future::{Future, IntoFuture},
+  panic::AssertUnwindSafe,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, RwLock,
+  },
+};
 
-use anyhow::{Error, Result};
 use async_trait::async_trait;
-use tinyrand::Rand;
-use tinyrand_std::thread_rand;
-use tokio::sync::{mpsc, Mutex, Semaphore};
+use futures::{stream::FuturesUnordered, FutureExt};
+pub use reqwest;
+use serde::{de::DeserializeOwned, Serialize, Serializer};
+use tokio::{
+  sync::{oneshot, watch, Notify},
+  time::{Duration, Instant},
+};
+use tokio_stream::Stream;
+
+pub use crate::{
+  builder::OrchestratorBuilder,
+  scheduler::{Permit, Priority},
+};
+use crate::{
+  cache::CacheStore,
+  coalesce::{Coalescer, Joined, Lead, Waiter},
+  cost::{PricingTable, Spend, Usage, UsageRecorder},
+  error::{OrchError, Result},
+  failures::{FailureLog, FailureRecorder},
+  hooks::{Hooks, OrchestratorHooks},
+  jobs::{JobStatus, JobStore},
+  keys::{KeyBalancing, KeyStatus, Keys},
+  limiter::{Queue, QueueSlot, RetryBudget},
+  meta::{ResponseMeta, WithMeta},
+  policies::{Backpressure, Policies, QueuePolicy, RetryPolicy},
+  pool::KeyPool,
+  progress::{Progress, ProgressExt},
+  prompt_log::{LoggingTransport, PromptLog},
+  redact::{RedactingTransport, Redactor},
+  scheduler::{Scheduler, Tenant},
+  scope::RequestScope,
+  tags::{TagStats, TagTable, Tags},
+  trace::{debug, error},
+  transport::{OpenAITransport, Transport},
+};
 
-use crate::{keys::Keys, policies::Policies};
+pub trait ResponseType: 'static + Send {
+  /// Called with the concurrency permit that was held while the request was
+  /// sent. By default the permit is dropped, which frees a slot for the next
+  /// request as soon as the response is ready. Responses that keep working
+  /// after `send` returns, such as streams, can hold on to the permit instead.
+  fn hold_permit(&mut self, _permit: Permit) {}
 
-pub trait ResponseType: 'static + Send {}
+  /// Serializes the response for the `Orchestrator`'s `CacheStore`. Defaults
+  /// to `None`, which means the response is never cached.
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    None
+  }
+
+  /// Deserializes a response serialized by `to_cache`, or returns `None` if
+  /// it can't be, in which case the request is sent as if it wasn't cached.
+  fn from_cache(_bytes: &[u8]) -> Option<Self>
+  where
+    Self: Sized,
+  {
+    None
+  }
+}
 
 /// Allows a request type to be used with the `Orchestrator`.
 #[async_trait]
@@ -74,16 +197,246 @@ pub trait OrchRequest {
     keys: Keys,
     id: u64,
   ) -> Result<Self::Res>;
+
+  /// An estimate of how many tokens the request will use, including both the
+  /// prompt and the completion. This is used to weight the request when the
+  /// `ConcurrencyPolicy` limits tokens per minute. Defaults to zero.
+  fn estimated_tokens(&self) -> u64 {
+    0
+  }
+
+  /// An estimate of the model the request will be sent to and the tokens it
+  /// will use, for estimating its cost. Defaults to `None`.
+  fn estimated_usage(&self) -> Option<(&str, Usage)> {
+    None
+  }
+
+  /// A key identifying the request for the `Orchestrator`'s `CacheStore`, so
+  /// that requests with the same key are served the same response. See
+  /// `cache::cache_key` for building one. Defaults to `None`, which means the
+  /// request is never cached.
+  fn cache_key(&self) -> Option<u64> {
+    None
+  }
+
+  /// The request as JSON, written to the `Orchestrator`'s `FailureLog` if
+  /// the request fails, so that it can be sent again. Defaults to `None`,
+  /// which means only the failure is written.
+  fn to_json(&self) -> Option<serde_json::Value> {
+    None
+  }
+
+  /// Called by the `Orchestrator` when sending the request failed, after any
+  /// retries, to revise the request before it is sent again, for example by
+  /// lowering `max_tokens` or shortening the prompt after a
+  /// `context_length_exceeded` error. Returns whether the request was
+  /// revised; if it was, it is sent again, so return `false` once there is
+  /// nothing left to change. Defaults to never revising the request.
+  ///
+  /// A request is revised at most 5 times. Responses to
+  /// revised requests are not cached, and identical requests sharing the
+  /// request's response through coalescing are given the error it failed
+  /// with before it was revised.
+  fn on_retry(&mut self, _error: &OrchError) -> bool {
+    false
+  }
 }
 
 /// A unique identifier for a request.
-#[derive(Clone, Copy)]
+///
+/// A `RequestID` can be passed to `Orchestrator::get_response`, or awaited
+/// directly to get the response:
+///
+/// ```rust,no_run
+/// # use openai_orch::prelude::*;
+/// # async fn example(orchestrator: Orchestrator) -> Result<(), OrchError> {
+/// let request = ChatSisoRequest::new(
+///   "You are a helpful assistant.".to_string(),
+///   "What are you?".to_string(),
+///   Default::default(),
+/// );
+/// let response = orchestrator.add_request(request).await.await?;
+/// println!("{}", response);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The `RequestID` carries the receiving end of the channel its response is
+/// sent on, so the response is statically typed and can only be retrieved
+/// once. Dropping a `RequestID` discards its response, so responses which are
+/// never asked for don't pile up in the `Orchestrator`.
+///
+/// Requests are numbered in the order they are added, from 1, across every
+/// `Orchestrator` in the process, so no two requests share an ID. A
+/// `RequestID` serializes as its number.
 pub struct RequestID<R: ResponseType> {
-  id:      u64,
-  _marker: PhantomData<R>,
+  id:     u64,
+  rx:     Option<oneshot::Receiver<(Delivery<R>, Claim)>>,
+  /// The response, once it has been received without being taken yet.
+  ready:  Option<(Delivery<R>, Claim)>,
+  /// Notified to cancel the request.
+  cancel: Arc<Notify>,
+}
+
+/// The number of times `OrchRequest::on_retry` may revise a request, so that
+/// a request which is always revised still finishes.
+const MAX_REVISIONS: u32 = 5;
+
+/// The numeric ID of the next request to be added.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Returns a numeric request ID which hasn't been used yet.
+pub(crate) fn next_request_id() -> u64 {
+  NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What is sent back for a request: its result, and how it was served.
+pub(crate) type Delivery<R> = (Result<R>, ResponseMeta);
+
+/// The sending end of a `RequestID`'s channel.
+pub(crate) struct Responder<R> {
+  tx:        oneshot::Sender<(Delivery<R>, Claim)>,
+  unclaimed: Arc<AtomicUsize>,
+}
+
+impl<R> Responder<R> {
+  /// Sends the result of the request, which counts as unclaimed until the
+  /// `RequestID` takes it or is dropped.
+  pub(crate) fn send(self, delivery: Delivery<R>) {
+    self.unclaimed.fetch_add(1, Ordering::Relaxed);
+    let _ = self.tx.send((delivery, Claim(self.unclaimed)));
+  }
+}
+
+/// Counts a response as unclaimed for as long as it is kept.
+struct Claim(Arc<AtomicUsize>);
+
+impl Drop for Claim {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+impl<R: ResponseType> RequestID<R> {
+  /// Returns a new request ID, along with the responder its response should
+  /// be sent with. Unclaimed responses are counted in `unclaimed`.
+  pub(crate) fn channel(unclaimed: &Arc<AtomicUsize>) -> (Self, Responder<R>) {
+    Self::channel_with_id(next_request_id(), unclaimed)
+  }
+
+  /// Returns a new request ID with the given numeric ID, along with the
+  /// responder its response should be sent with.
+  pub(crate) fn channel_with_id(
+    id: u64,
+    unclaimed: &Arc<AtomicUsize>,
+  ) -> (Self, Responder<R>) {
+    let (tx, rx) = oneshot::channel();
+    let unclaimed = unclaimed.clone();
+    let request_id = RequestID {
+      id,
+      rx: Some(rx),
+      ready: None,
+      cancel: Arc::default(),
+    };
+    (request_id, Responder { tx, unclaimed })
+  }
+
+  /// The numeric ID of the request, as used in log messages.
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  /// Waits for the result of the request and the metadata describing how it
+  /// was served, whether it succeeded or not.
+  pub(crate) async fn into_delivery(mut self) -> Delivery<R> {
+    self.receive().await;
+    match self.ready {
+      Some((delivery, _claim)) => delivery,
+      None => (Err(OrchError::ResponseMissing), ResponseMeta::new(self.id)),
+    }
+  }
+
+  /// Waits for the response to be received, if it hasn't been already. If
+  /// this is cancelled, the response can still be waited for again.
+  async fn receive(&mut self) {
+    if let Some(rx) = &mut self.rx {
+      self.ready = rx.await.ok();
+      self.rx = None;
+    }
+  }
+
+  /// Cancels the request if it hasn't finished, so that it fails with
+  /// `OrchError::Cancelled`: it leaves the queue if it is waiting there, or
+  /// its attempt is dropped if it is being sent.
+  ///
+  /// A request sharing the response of an identical one through coalescing
+  /// stops waiting on it, and the other request goes on. If identical
+  /// requests are sharing this request's response, only this request fails,
+  /// and it is still sent for the others. Requests batched by an
+  /// `EmbeddingBatcher` can't be cancelled on their own.
+  pub fn cancel(&self) {
+    self.cancel.notify_one();
+  }
+
+  /// Whether the request has finished, so that `try_get` won't return
+  /// `Ok(None)`. Doesn't wait.
+  pub fn is_ready(&mut self) -> bool {
+    if self.ready.is_some() {
+      return true;
+    }
+    let Some(rx) = &mut self.rx else {
+      return true;
+    };
+    match rx.try_recv() {
+      Ok(received) => self.ready = Some(received),
+      Err(oneshot::error::TryRecvError::Empty) => return false,
+      Err(oneshot::error::TryRecvError::Closed) => {}
+    }
+    self.rx = None;
+    true
+  }
+
+  /// Takes the response if the request has finished, without waiting.
+  /// Returns `Ok(None)` while the request is queued or in flight. Once the
+  /// response has been taken, this fails with `OrchError::ResponseMissing`.
+  pub fn try_get(&mut self) -> Result<Option<R>> {
+    if !self.is_ready() {
+      return Ok(None);
+    }
+    match self.ready.take() {
+      Some(((res, _), _claim)) => res.map(Some),
+      None => Err(OrchError::ResponseMissing),
+    }
+  }
+
+  /// Waits for the response along with the metadata describing how it was
+  /// served.
+  pub async fn with_meta(self) -> Result<WithMeta<R>> {
+    let (res, meta) = self.into_delivery().await;
+    res.map(|response| WithMeta { response, meta })
+  }
+}
+
+impl<R: ResponseType> fmt::Debug for RequestID<R> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("RequestID").field("id", &self.id).finish()
+  }
 }
 
-type ResponseReceiver = mpsc::Receiver<Result<Box<dyn Any + Send>>>;
+impl<R: ResponseType> Serialize for RequestID<R> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(self.id)
+  }
+}
+
+impl<R: ResponseType> IntoFuture for RequestID<R> {
+  type Output = Result<R>;
+  type IntoFuture = Pin<Box<dyn Future<Output = Self::Output> + Send>>;
+
+  fn into_future(self) -> Self::IntoFuture {
+    Box::pin(async move { self.into_delivery().await.0 })
+  }
+}
 
 /// The central interface for `openai_orch`. The `Orchestrator` is responsible
 /// for managing the concurrency of requests and their responses.
@@ -101,27 +454,27 @@ type ResponseReceiver = mpsc::Receiver<Result<Box<dyn Any + Send>>>;
 /// To use the `Orchestrator` in multiple parts of your application, you can
 /// clone it. The `Orchestrator` is backed by an `Arc`, so cloning it is cheap.
 ///
-/// ```rust
+/// ```rust,no_run
 /// use openai_orch::{
 ///   chat::siso::{ChatSisoRequest, ChatSisoResponse},
 ///   keys::Keys,
 ///   policies::Policies,
 ///   Orchestrator,
 /// };
-/// 
+///
 /// #[tokio::main]
 /// async fn main() {
 ///   let policies = Policies::default();
 ///   let keys = Keys::from_env().unwrap();
 ///   let orchestrator = Orchestrator::new(policies, keys);
-/// 
+///
 ///   let request = ChatSisoRequest::new(
 ///     "You are a helpful assistant.".to_string(),
 ///     "What are you?".to_string(),
 ///     Default::default(),
 ///   );
 ///   let request_id = orchestrator.add_request(request).await;
-/// 
+///
 ///   let response = orchestrator
 ///     .get_response::<ChatSisoResponse>(request_id)
 ///     .await;
@@ -130,85 +483,1147 @@ type ResponseReceiver = mpsc::Receiver<Result<Box<dyn Any + Send>>>;
 /// ```
 #[derive(Clone)]
 pub struct Orchestrator {
-  requests:  Arc<Mutex<HashMap<u64, ResponseReceiver>>>,
-  semaphore: Arc<Semaphore>,
-  policies:  Policies,
-  keys:      Keys,
+  scheduler:    Arc<Scheduler>,
+  shutdown:     Arc<watch::Sender<bool>>,
+  progress:     Arc<watch::Sender<Progress>>,
+  policies:     Arc<RwLock<Policies>>,
+  keys:         Arc<KeyPool>,
+  usage:        UsageRecorder,
+  hooks:        Hooks,
+  cache:        Option<Arc<dyn CacheStore>>,
+  coalescer:    Option<Arc<Coalescer>>,
+  retry_budget: Arc<RetryBudget>,
+  tags:         Arc<TagTable>,
+  queue:        Arc<Queue>,
+  transport:    Arc<dyn Transport>,
+  unclaimed:    Arc<AtomicUsize>,
+  failure_log:  Option<Arc<FailureLog>>,
+  redactor:     Option<Arc<Redactor>>,
 }
 
 impl Orchestrator {
+  /// Returns a builder for an `Orchestrator`, for setting several options by
+  /// name.
+  pub fn builder() -> OrchestratorBuilder {
+    OrchestratorBuilder::default()
+  }
+
   /// Create a new `Orchestrator` with the given policies and keys.
   pub fn new(policies: Policies, keys: Keys) -> Self {
+    Self::with_keys(policies, [keys], KeyBalancing::default())
+  }
+
+  /// Create a new `Orchestrator` which spreads requests across several keys.
+  /// Each key gets its own `RateLimitPolicy` and tokens-per-minute limit,
+  /// while the concurrency limit is shared.
+  ///
+  /// Keys without an HTTP client of their own share one, so that connections
+  /// are reused across requests instead of being opened for each.
+  ///
+  /// Panics if `keys` is empty.
+  pub fn with_keys(
+    policies: Policies,
+    keys: impl IntoIterator<Item = Keys>,
+    balancing: KeyBalancing,
+  ) -> Self {
+    let http_client = reqwest::Client::new();
+    let keys = keys.into_iter().map(|mut keys| {
+      keys.http_client.get_or_insert_with(|| http_client.clone());
+      keys
+    });
     Self {
-      requests: Arc::new(Mutex::new(HashMap::new())),
-      semaphore: Arc::new(Semaphore::new(
-        policies.concurrency_policy.max_concurrent_requests,
-      )),
-      policies,
-      keys,
+      scheduler:    Scheduler::new(&policies),
+      shutdown:     Arc::new(watch::channel(false).0),
+      progress:     Arc::new(watch::channel(Progress::default()).0),
+      keys:         KeyPool::new(keys.collect(), balancing, &policies),
+      usage:        UsageRecorder::new(PricingTable::default()),
+      hooks:        Hooks::default(),
+      cache:        None,
+      coalescer:    None,
+      retry_budget: Arc::default(),
+      tags:         Arc::default(),
+      queue:        Arc::default(),
+      transport:    Arc::new(OpenAITransport),
+      unclaimed:    Arc::default(),
+      failure_log:  None,
+      redactor:     None,
+      policies:     Arc::new(RwLock::new(policies)),
     }
   }
 
+  /// Prices usage with the given table instead of the default one. Spend
+  /// accumulated so far is discarded.
+  pub fn with_pricing(mut self, pricing: PricingTable) -> Self {
+    self.usage = UsageRecorder::new(pricing);
+    self
+  }
+
+  /// Makes the calls to the API with `transport` instead of
+  /// `OpenAITransport`. See the `transport` module.
+  pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+    self.transport = Arc::new(transport);
+    self
+  }
+
+  /// Records the prompts and completions of every call to the API to `log`.
+  /// See the `prompt_log` module.
+  ///
+  /// The transport set so far is wrapped in a `LoggingTransport`, so call
+  /// this after `with_transport`, and before `with_redactor` for the log to
+  /// see the calls as they are sent, after redaction.
+  pub fn with_prompt_log(mut self, log: PromptLog) -> Self {
+    self.transport =
+      Arc::new(LoggingTransport::wrapping(self.transport, Arc::new(log)));
+    self
+  }
+
+  /// Scrubs the prompts of every call to the API with `redactor`, and the
+  /// requests and errors written to the `FailureLog`. See the `redact`
+  /// module.
+  ///
+  /// The transport set so far is wrapped in a `RedactingTransport`, so call
+  /// this after `with_transport`.
+  pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+    self.transport = Arc::new(RedactingTransport::wrapping(
+      self.transport,
+      redactor.clone(),
+    ));
+    self.redactor = Some(Arc::new(redactor));
+    self
+  }
+
+  /// Registers hooks to be called as requests move through the
+  /// `Orchestrator`. Hooks registered more than once are all called, in the
+  /// order they were registered.
+  pub fn with_hooks(mut self, hooks: impl OrchestratorHooks + 'static) -> Self {
+    self.hooks.push(Arc::new(hooks));
+    self
+  }
+
+  /// Appends every request which fails to `log`, along with its error. See
+  /// the `failures` module.
+  pub fn with_failure_log(mut self, log: FailureLog) -> Self {
+    self.failure_log = Some(Arc::new(log));
+    self
+  }
+
+  /// Serves requests from `cache` when it holds a response for them, and
+  /// stores every successful response that can be cached. Only requests with
+  /// an `OrchRequest::cache_key` are cached.
+  pub fn with_cache(mut self, cache: impl CacheStore + 'static) -> Self {
+    self.cache = Some(Arc::new(cache));
+    self
+  }
+
+  /// Sends requests only once while an identical request is in flight, and
+  /// gives its response to both. Requests are identical when they have the
+  /// same `OrchRequest::cache_key`, and their response must be serializable
+  /// with `ResponseType::to_cache` to be shared.
+  ///
+  /// This complements `with_cache`, which only helps once the first request
+  /// has finished. Leave it off when identical requests are meant to sample
+  /// different completions.
+  pub fn with_coalescing(mut self) -> Self {
+    self.coalescer = Some(Arc::default());
+    self
+  }
+
   /// Add a request to the `Orchestrator`. Returns a request ID that can be used
   /// to get the response.
   ///
   /// Behind the scenes the `Orchestrator` will create a task for the request
   /// using the `OrchRequest`'s `send` method when the concurrency policy
-  /// allows it. The result will be sent back to the `Orchestrator` using a
-  /// channel which is mapped to the request ID.
+  /// allows it. The result will be sent back using a channel whose receiving
+  /// end is held by the returned request ID.
+  ///
+  /// When the `QueuePolicy` bounds the queue and it is full, this waits for a
+  /// place in the queue first, or the request fails with
+  /// `OrchError::QueueFull`.
   pub async fn add_request<R, Req>(&self, request: Req) -> RequestID<R>
   where
     Req: OrchRequest<Res = R> + Send + Sync + 'static,
     R: ResponseType,
   {
-    let id = thread_rand().next_u64();
-    let (tx, rx) = mpsc::channel(1);
-    self.requests.lock().await.insert(id, rx);
+    self
+      .add_request_with_priority(request, Priority::Normal)
+      .await
+  }
+
+  /// Add a request to the `Orchestrator` with the given priority. Returns a
+  /// request ID that can be used to get the response.
+  ///
+  /// When the concurrency policy frees up a slot, it is given to the waiting
+  /// request with the highest priority, so high priority requests jump ahead
+  /// of any queued background work. Requests that are already running are
+  /// not interrupted.
+  pub async fn add_request_with_priority<R, Req>(
+    &self,
+    request: Req,
+    priority: Priority,
+  ) -> RequestID<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    self.submit(request, priority, Arc::new([]), None).await
+  }
+
+  /// Add a request to the `Orchestrator` on behalf of a tenant. Returns a
+  /// request ID that can be used to get the response.
+  ///
+  /// The request counts against the tenant's quota in the `TenantPolicy`,
+  /// and waits its turn in weighted fair order with the requests of other
+  /// tenants.
+  pub async fn add_request_for_tenant<R, Req>(
+    &self,
+    request: Req,
+    tenant: impl Into<String>,
+  ) -> RequestID<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let tenant = Some(Arc::from(tenant.into()));
+    self
+      .submit(request, Priority::Normal, Arc::new([]), tenant)
+      .await
+  }
+
+  /// Add a request to the `Orchestrator` with the given tags. Returns a
+  /// request ID that can be used to get the response.
+  ///
+  /// Tags are free-form labels, such as the name of a pipeline stage or the
+  /// customer a request is made for. They are included in the request's
+  /// `ResponseMeta`, its logs and metrics, and in `stats_by_tag`.
+  pub async fn add_request_tagged<R, Req>(
+    &self,
+    request: Req,
+    tags: impl IntoIterator<Item = impl Into<String>>,
+  ) -> RequestID<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let tags = tags.into_iter().map(Into::into).collect();
+    self.submit(request, Priority::Normal, tags, None).await
+  }
 
-    let semaphore = self.semaphore.clone();
-    let policies = self.policies.clone();
-    let keys = self.keys.clone();
+  async fn submit<R, Req>(
+    &self,
+    request: Req,
+    priority: Priority,
+    tags: Arc<[String]>,
+    tenant: Tenant,
+  ) -> RequestID<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    self
+      .submit_with_id(next_request_id(), request, priority, tags, tenant)
+      .await
+  }
+
+  /// Adds a request as `submit`, numbered `id` rather than the next request
+  /// ID, for requests whose ID was handed out before they could be added.
+  async fn submit_with_id<R, Req>(
+    &self,
+    id: u64,
+    request: Req,
+    priority: Priority,
+    tags: Arc<[String]>,
+    tenant: Tenant,
+  ) -> RequestID<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let (request_id, mut tx) = RequestID::channel_with_id(id, &self.unclaimed);
+    let id = request_id.id();
+    let added_at = Instant::now();
+    let tags = Tags::new(tags, self.tags.clone());
+    let policies = self.policies();
+    let hooks = match &self.failure_log {
+      Some(log) => self.hooks.with(FailureRecorder::new(
+        log.clone(),
+        request.to_json(),
+        self.redactor.clone(),
+      )),
+      None => self.hooks.clone(),
+    };
 
+    self.progress.submitted();
+    tags.submitted();
+    hooks.on_submitted(id);
+    let queued = match self.enter_queue(&policies.queue_policy).await {
+      Ok(queued) => queued,
+      Err(err) => {
+        self.progress.cancelled();
+        let meta = ResponseMeta::new(id).tagged(&tags);
+        tags.finished(&meta, false);
+        hooks.on_failed(&meta, &err);
+        tx.send((Err(err), meta));
+        return request_id;
+      }
+    };
+
+    let key = if self.cache.is_some() || self.coalescer.is_some() {
+      request.cache_key()
+    } else {
+      None
+    };
+    let mut lead = None;
+    if let Some((coalescer, key)) = self.coalescer.as_ref().zip(key) {
+      let waiter = Waiter {
+        tx,
+        id,
+        added_at,
+        progress: self.progress.clone(),
+        hooks: hooks.clone(),
+        tags: tags.clone(),
+        cancel: request_id.cancel.clone(),
+      };
+      match coalescer.follow(key, waiter) {
+        Joined::Leading(waiter, leading) => {
+          tx = waiter.tx;
+          lead = Some(leading);
+        }
+        Joined::Following => {
+          debug!("request {} joined an identical request in flight", id);
+          return request_id;
+        }
+      }
+    }
+
+    // the place in line is taken now rather than in the task, whose start
+    // isn't ordered with that of other tasks
+    let ticket = self.scheduler.enqueue(priority, tenant);
+    let cancel = request_id.cancel.clone();
+    let tokens = request.estimated_tokens();
+    let scheduler = self.scheduler.clone();
+    let mut shutdown = self.shutdown.subscribe();
+    let progress = self.progress.clone();
+    let pool = self.keys.clone();
+    let usage = self.usage.with_tags(tags.clone());
+    let cache = self.cache.clone();
+    let retry_budget = self.retry_budget.clone();
+    let transport = self.transport.clone();
+
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+      "request",
+      id,
+      model = request
+        .estimated_usage()
+        .map(|(model, _)| model.to_string()),
+      tags = ?tags.as_slice(),
+      retries = 0,
+    );
+
+    let task = async move {
+      let mut request = request;
+      match tags.as_slice() {
+        [] => debug!("request {} queued", id),
+        tags => debug!("request {} queued with tags {:?}", id, tags),
+      }
+      let scope = RequestScope::new(
+        usage.clone(),
+        hooks.clone(),
+        retry_budget,
+        transport,
+        scheduler,
+      );
+      let unsent = |err| {
+        let meta = ResponseMeta::finished(id, added_at, None, scope.stats())
+          .tagged(&tags);
+        (Err(err), meta)
+      };
+
+      // the request's own response, which is sent at most once: when the
+      // request finishes, or when it is cancelled while identical requests
+      // still follow it
+      let tx = std::sync::Mutex::new(Some(tx));
+      let respond = |delivery: Delivery<R>| {
+        let Some(tx) = tx.lock().expect("responder lock poisoned").take()
+        else {
+          return;
+        };
+        tags.finished(&delivery.1, delivery.0.is_ok());
+        match &delivery.0 {
+          Ok(_) => hooks.on_completed(&delivery.1),
+          Err(err) => hooks.on_failed(&delivery.1, err),
+        }
+        tx.send(delivery);
+      };
+      let deliver = |delivery: Delivery<R>| {
+        if let Some(lead) = &lead {
+          lead.finish(&delivery);
+        }
+        respond(delivery);
+      };
+      // a request followed by identical ones only stops waiting itself when
+      // it is cancelled, and its upstream call goes on for the followers
+      let cancelled = || async {
+        cancel.notified().await;
+        if lead.as_ref().is_some_and(Lead::has_followers) {
+          debug!("request {} was cancelled, but others still follow it", id);
+          respond(unsent(OrchError::Cancelled));
+          std::future::pending::<()>().await;
+        }
+      };
+
+      let cache = cache.zip(key);
+      if let Some((cache, key)) = &cache {
+        let cached = cache
+          .get(*key)
+          .await
+          .and_then(|bytes| R::from_cache(&bytes));
+        if let Some(res) = cached {
+          debug!("request {} served from the cache", id);
+          progress.started();
+          progress.finished(true);
+          let mut meta =
+            ResponseMeta::finished(id, added_at, None, scope.stats())
+              .tagged(&tags);
+          meta.cached = true;
+          deliver((Ok(res), meta));
+          return;
+        }
+      }
+
+      let deadline = policies.deadline_policy.deadline;
+      let deadline_at = deadline.map(|deadline| added_at + deadline);
+      let missed = || OrchError::DeadlineExceeded(deadline.unwrap_or_default());
+
+      let acquired = tokio::select! {
+        permit = ticket.acquire() => {
+          permit.ok_or(OrchError::Cancelled)
+        }
+        _ = deadline_passed(deadline_at) => Err(missed()),
+        _ = cancelled() => Err(OrchError::Cancelled),
+      };
+      drop(queued);
+      let mut permit = match acquired {
+        Ok(permit) => permit,
+        Err(err) => {
+          progress.cancelled();
+          deliver(unsent(err));
+          return;
+        }
+      };
+
+      let spend = usage.spend();
+      if policies.budget_policy.is_exceeded(&spend) {
+        progress.cancelled();
+        let err = OrchError::BudgetExceeded {
+          cost_usd:     spend.cost_usd,
+          total_tokens: spend.total_tokens(),
+        };
+        deliver(unsent(err));
+        return;
+      }
+      debug!("request {} acquired a permit", id);
+      progress.started();
+      hooks.on_started(id);
+
+      // a rejected key is marked unhealthy and the request moves on to the
+      // next key, as it does when the `RetryStrategy` asks to switch keys,
+      // trying each key at most once
+      let mut keys_left = pool.len();
+      let mut started_at = None;
+      let mut revisions = 0;
+      let res = loop {
+        let lease = tokio::select! {
+          lease = pool.acquire(tokens) => lease,
+          _ = deadline_passed(deadline_at) => break Err(missed()),
+          _ = cancelled() => break Err(OrchError::Cancelled),
+        };
+        started_at.get_or_insert_with(Instant::now);
+        scope
+          .retry_budget
+          .record_request(&policies.retry_budget_policy);
+        let keys = lease.keys().clone();
+        let res = tokio::select! {
+          res = scope.clone().run(send_caught(&request, &policies, keys, id)) => {
+            res
+          }
+          _ = aborted(&mut shutdown) => Err(OrchError::Cancelled),
+          _ = deadline_passed(deadline_at) => Err(missed()),
+          _ = cancelled() => Err(OrchError::Cancelled),
+        };
+        let rejected = matches!(&res, Err(err) if err.is_auth_failure());
+        if rejected || res.is_ok() {
+          lease.record(rejected);
+        }
+        let mut switch_key = false;
+        scope.update_stats(|stats| {
+          switch_key = std::mem::take(&mut stats.switch_key)
+        });
+
+        if rejected || switch_key {
+          keys_left = keys_left.saturating_sub(1);
+          if keys_left > 0 && pool.any_available() {
+            continue;
+          }
+        }
+        let revise = match &res {
+          Ok(_)
+          | Err(OrchError::Cancelled | OrchError::DeadlineExceeded(_)) => false,
+          Err(_) if revisions == MAX_REVISIONS => false,
+          Err(err) => request.on_retry(err),
+        };
+        if revise {
+          debug!("request {} was revised after failing, sending it again", id);
+          // followers sent the request as it was, so they are given its
+          // outcome rather than that of the revised request
+          if let (Some(lead), Err(err)) = (&lead, &res) {
+            let meta =
+              ResponseMeta::finished(id, added_at, started_at, scope.stats());
+            lead.finish::<R>(&(Err(err.duplicate()), meta));
+          }
+          revisions += 1;
+          continue;
+        }
+        permit.attach_lease(lease);
+        break res;
+      };
+      // requests sent through `with_retries` report every attempt as it
+      // finishes; custom requests which aren't only have their final outcome
+      if !scope.stats().recorded_outcome {
+        match &res {
+          Ok(_) => scope.record_outcome(false),
+          Err(err) if err.is_overloaded() => scope.record_outcome(true),
+          Err(_) => {}
+        }
+      }
+      progress.finished(res.is_ok());
+      // the response to a revised request isn't the response to the request
+      // the key was made from
+      if let (Some((cache, key)), Ok(res), 0) = (&cache, &res, revisions) {
+        if let Some(bytes) = res.to_cache() {
+          cache.insert(*key, bytes).await;
+        }
+      }
+      let res = res.map(|mut res| {
+        res.hold_permit(permit);
+        res
+      });
+      let meta =
+        ResponseMeta::finished(id, added_at, started_at, scope.stats())
+          .tagged(&tags);
+      debug!("request {} finished in {}s", id, meta.latency.as_secs_f32());
+      telemetry::latency(meta.latency);
+      deliver((res, meta));
+    };
+    #[cfg(feature = "tracing")]
+    let task = tracing::Instrument::instrument(task, span);
+    tokio::spawn(task);
+
+    request_id
+  }
+
+  /// Takes a place in the queue of requests which haven't started yet, as
+  /// the `QueuePolicy` allows. Fails if the `Orchestrator` is shut down, or
+  /// if the queue is full and the policy rejects requests.
+  async fn enter_queue(
+    &self,
+    queue_policy: &QueuePolicy,
+  ) -> Result<Option<QueueSlot>> {
+    if self.is_shutdown() {
+      return Err(OrchError::Cancelled);
+    }
+    let Some(max_queued) = queue_policy.max_queued else {
+      return Ok(None);
+    };
+    match queue_policy.when_full {
+      Backpressure::Wait => {
+        let mut shutdown = self.shutdown.subscribe();
+        tokio::select! {
+          slot = self.queue.enter(max_queued) => Ok(Some(slot)),
+          _ = aborted(&mut shutdown) => Err(OrchError::Cancelled),
+        }
+      }
+      Backpressure::Reject => match self.queue.try_enter(max_queued) {
+        Some(slot) => Ok(Some(slot)),
+        None => Err(OrchError::QueueFull { max_queued }),
+      },
+    }
+  }
+
+  /// Add a request to the `Orchestrator` as a job in `store`, which records
+  /// the response or error once the request finishes. If the run is
+  /// interrupted before then, `resume` sends the job again.
+  ///
+  /// Jobs are identified by `key`. If the store already has a job with the
+  /// same key, the request is still sent, but the stored request is kept.
+  /// Fails if the request can't be serialized or the store can't be written
+  /// to.
+  pub async fn add_job<R, Req, S>(
+    &self,
+    store: &Arc<S>,
+    key: impl Into<String>,
+    request: Req,
+  ) -> Result<RequestID<R>>
+  where
+    Req: OrchRequest<Res = R> + Serialize + Send + Sync + 'static,
+    R: ResponseType,
+    S: JobStore + 'static,
+  {
+    let key = key.into();
+    let bytes = serde_json::to_vec(&request).map_err(OrchError::other)?;
+    store.insert(&key, &bytes).await?;
+    Ok(self.add_tracked_job(store.clone(), key, request).await)
+  }
+
+  /// Sends every job in `store` which hasn't completed, such as those left by
+  /// a run that crashed or was interrupted. Jobs which failed are sent again
+  /// as well. Returns the key of each job along with its request ID.
+  ///
+  /// Fails if the store can't be read or a job isn't a `Req`.
+  pub async fn resume<R, Req, S>(
+    &self,
+    store: &Arc<S>,
+  ) -> Result<Vec<(String, RequestID<R>)>>
+  where
+    Req: OrchRequest<Res = R> + DeserializeOwned + Send + Sync + 'static,
+    R: ResponseType,
+    S: JobStore + 'static,
+  {
+    let mut request_ids = vec![];
+    for job in store.unfinished().await? {
+      let request: Req =
+        serde_json::from_slice(&job.request).map_err(OrchError::other)?;
+      let request_id = self
+        .add_tracked_job(store.clone(), job.key.clone(), request)
+        .await;
+      request_ids.push((job.key, request_id));
+    }
+    Ok(request_ids)
+  }
+
+  /// Adds a request whose outcome is recorded in `store` under `key` before
+  /// it is handed on to the returned request ID.
+  async fn add_tracked_job<R, Req>(
+    &self,
+    store: Arc<dyn JobStore>,
+    key: String,
+    request: Req,
+  ) -> RequestID<R>
+  where
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let inner = self.add_request(request).await;
+    let (mut request_id, tx) =
+      RequestID::channel_with_id(inner.id(), &self.unclaimed);
+    request_id.cancel = inner.cancel.clone();
     tokio::spawn(async move {
-      let _permit = semaphore
-        .acquire()
-        .await
-        .expect("failed to acquire semaphore; this is UB");
-
-      let res = request
-        .send(policies, keys, id)
-        .await
-        .map(|res| Box::new(res) as Box<dyn Any + Send>);
-      let _ = tx.send(res).await;
+      let delivery = inner.into_delivery().await;
+      // requests which were never sent stay pending, to be resumed
+      let status = match &delivery.0 {
+        Ok(res) => {
+          Some(JobStatus::Completed(res.to_cache().unwrap_or_default()))
+        }
+        Err(
+          OrchError::Cancelled
+          | OrchError::QueueFull { .. }
+          | OrchError::BudgetExceeded { .. }
+          | OrchError::ResponseMissing,
+        ) => None,
+        Err(err) => Some(JobStatus::Failed(err.to_string())),
+      };
+      if let Some(status) = status {
+        if let Err(err) = store.set_status(&key, &status).await {
+          error!("failed to record the outcome of job {}: {}", key, err);
+        }
+      }
+      tx.send(delivery);
     });
+    request_id
+  }
 
-    RequestID {
-      id,
-      _marker: PhantomData,
+  /// Add a request built from the responses to earlier requests, such as a
+  /// summary of extracted passages. Returns a request ID that can be used to
+  /// get the response.
+  ///
+  /// The request isn't made until every dependency has finished; then
+  /// `make_request` is called with their responses, in the order the
+  /// dependencies were given, and the request is added with the normal
+  /// priority. Dependents can depend on other dependents, so a multi-stage
+  /// pipeline can be added all at once, without waiting on its stages.
+  ///
+  /// If a dependency fails, the request is never made, and it fails with
+  /// `OrchError::DependencyFailed`. Cancelling the request before its
+  /// dependencies finish leaves them running. Since awaiting a `RequestID`
+  /// takes its response, each request can only be the dependency of one
+  /// dependent.
+  ///
+  /// ```rust,no_run
+  /// # use openai_orch::prelude::*;
+  /// # async fn example(orchestrator: Orchestrator, documents: Vec<String>) {
+  /// let mut extracts = vec![];
+  /// for document in documents {
+  ///   let request = ChatSisoRequest::new(
+  ///     "List the key facts in the document.".to_string(),
+  ///     document,
+  ///     Default::default(),
+  ///   );
+  ///   extracts.push(orchestrator.add_request(request).await);
+  /// }
+  ///
+  /// let summary = orchestrator
+  ///   .add_dependent_request(extracts, |extracts: Vec<ChatSisoResponse>| {
+  ///     let facts: Vec<String> = extracts.into_iter().map(String::from).collect();
+  ///     ChatSisoRequest::new(
+  ///       "Summarize the facts in one paragraph.".to_string(),
+  ///       facts.join("\n"),
+  ///       Default::default(),
+  ///     )
+  ///   })
+  ///   .await;
+  /// println!("{:?}", summary.await);
+  /// # }
+  /// ```
+  pub async fn add_dependent_request<D, R, Req, F>(
+    &self,
+    dependencies: impl IntoIterator<Item = RequestID<D>>,
+    make_request: F,
+  ) -> RequestID<R>
+  where
+    D: ResponseType,
+    F: FnOnce(Vec<D>) -> Req + Send + 'static,
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let dependencies: Vec<_> = dependencies.into_iter().collect();
+    let (request_id, tx) = RequestID::channel(&self.unclaimed);
+    let id = request_id.id();
+    let cancel = request_id.cancel.clone();
+    let orchestrator = self.clone();
+    tokio::spawn(async move {
+      let waiting = async {
+        let mut responses = Vec::with_capacity(dependencies.len());
+        for dependency in dependencies {
+          let dependency_id = dependency.id();
+          match dependency.await {
+            Ok(response) => responses.push(response),
+            Err(err) => {
+              return Err(OrchError::DependencyFailed {
+                id:    dependency_id,
+                error: Box::new(err),
+              })
+            }
+          }
+        }
+        Ok(responses)
+      };
+      let responses = tokio::select! {
+        responses = waiting => responses,
+        _ = cancel.notified() => Err(OrchError::Cancelled),
+      };
+      let responses = match responses {
+        Ok(responses) => responses,
+        Err(err) => {
+          debug!("dependent request {} was not made: {}", id, err);
+          tx.send((Err(err), ResponseMeta::new(id)));
+          return;
+        }
+      };
+
+      debug!("dependencies of request {} finished", id);
+      let request = make_request(responses);
+      let inner = orchestrator
+        .submit_with_id(id, request, Priority::Normal, Arc::new([]), None)
+        .await;
+      let inner_cancel = inner.cancel.clone();
+      let delivery = inner.into_delivery();
+      tokio::pin!(delivery);
+      let delivery = tokio::select! {
+        delivery = &mut delivery => delivery,
+        _ = cancel.notified() => {
+          inner_cancel.notify_one();
+          delivery.await
+        }
+      };
+      tx.send(delivery);
+    });
+    request_id
+  }
+
+  /// Add several requests to the `Orchestrator` at once. Returns the request
+  /// IDs in the same order as the requests were given.
+  ///
+  /// This is equivalent to calling `add_request` for each request in turn.
+  pub async fn add_requests<R, Req, I>(&self, requests: I) -> Vec<RequestID<R>>
+  where
+    I: IntoIterator<Item = Req>,
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let mut request_ids = vec![];
+    for request in requests {
+      request_ids.push(self.add_request(request).await);
     }
+    request_ids
   }
 
   /// Get the response for a given request ID.
   ///
   /// This will block until the response is received.
   ///
-  /// Behind the scenes, this listens on a channel for a task to send the
-  /// response back to the `Orchestrator`. Once the response is received, it is
-  /// returned.
+  /// Behind the scenes, this listens on the request ID's channel for the
+  /// request's task to send the response back. Once the response is received,
+  /// it is returned. This is equivalent to awaiting the `RequestID` directly.
   pub async fn get_response<R: ResponseType>(
     &self,
     request_id: RequestID<R>,
   ) -> Result<R> {
-    let mut rx = self
-      .requests
-      .lock()
-      .await
-      .remove(&request_id.id)
-      .ok_or_else(|| Error::msg("No response receiver found"))?;
+    request_id.await
+  }
 
-    rx.recv()
-      .await
-      .ok_or_else(|| Error::msg("No response found"))?
-      .map(|res| *res.downcast::<R>().expect("Failed to downcast response"))
+  /// Get the response for a request ID, waiting at most `timeout` for it.
+  /// Returns `Ok(None)` if the request hasn't finished by then, in which case
+  /// it keeps going, and its response can still be waited for or taken; call
+  /// `RequestID::cancel` to stop it instead.
+  pub async fn get_response_timeout<R: ResponseType>(
+    &self,
+    request_id: &mut RequestID<R>,
+    timeout: Duration,
+  ) -> Result<Option<R>> {
+    match tokio::time::timeout(timeout, request_id.receive()).await {
+      Ok(()) => request_id.try_get(),
+      Err(_) => Ok(None),
+    }
+  }
+
+  /// Get the response for a request ID if the request has finished, without
+  /// waiting, for callers which poll for responses, such as UI event loops.
+  /// Returns `Ok(None)` while the request is queued or in flight. This is
+  /// equivalent to `RequestID::try_get`.
+  pub fn try_get_response<R: ResponseType>(
+    &self,
+    request_id: &mut RequestID<R>,
+  ) -> Result<Option<R>> {
+    request_id.try_get()
+  }
+
+  /// Whether the request for a request ID has finished, so that
+  /// `try_get_response` won't return `Ok(None)`. This is equivalent to
+  /// `RequestID::is_ready`.
+  pub fn is_ready<R: ResponseType>(
+    &self,
+    request_id: &mut RequestID<R>,
+  ) -> bool {
+    request_id.is_ready()
+  }
+
+  /// Get the response for a request ID, along with the metadata describing
+  /// how it was served: its latency, retries, and the model that served it.
+  pub async fn get_response_with_meta<R: ResponseType>(
+    &self,
+    request_id: RequestID<R>,
+  ) -> Result<WithMeta<R>> {
+    request_id.with_meta().await
+  }
+
+  /// Get the responses for several request IDs, such as those returned by
+  /// `add_requests`. The responses are returned in the same order as the
+  /// request IDs were given.
+  ///
+  /// This will block until every response has been received. A failed request
+  /// does not prevent the remaining responses from being collected. To handle
+  /// each response as soon as it arrives, use `responses` instead.
+  pub async fn await_all<R, I>(&self, request_ids: I) -> Vec<Result<R>>
+  where
+    I: IntoIterator<Item = RequestID<R>>,
+    R: ResponseType,
+  {
+    let mut responses = vec![];
+    for request_id in request_ids {
+      responses.push(self.get_response(request_id).await);
+    }
+    responses
+  }
+
+  /// Get the responses for several request IDs as each request finishes,
+  /// rather than in the order the request IDs were given, along with the
+  /// numeric ID of the request each response is for. The stream ends once
+  /// every response has been yielded.
+  ///
+  /// ```rust,no_run
+  /// # use openai_orch::prelude::*;
+  /// # use tokio_stream::StreamExt;
+  /// # async fn example(orchestrator: Orchestrator, prompts: Vec<String>) {
+  /// let mut request_ids = vec![];
+  /// for prompt in prompts {
+  ///   let request = ChatSisoRequest::new(
+  ///     "You are a helpful assistant.".to_string(),
+  ///     prompt,
+  ///     Default::default(),
+  ///   );
+  ///   request_ids.push(orchestrator.add_request(request).await);
+  /// }
+  ///
+  /// let mut responses = orchestrator.responses(request_ids);
+  /// while let Some((id, response)) = responses.next().await {
+  ///   match response {
+  ///     Ok(response) => println!("{}: {}", id, response),
+  ///     Err(err) => println!("{} failed: {}", id, err),
+  ///   }
+  /// }
+  /// # }
+  /// ```
+  pub fn responses<R, I>(
+    &self,
+    request_ids: I,
+  ) -> impl Stream<Item = (u64, Result<R>)> + Send + 'static
+  where
+    I: IntoIterator<Item = RequestID<R>>,
+    R: ResponseType,
+  {
+    request_ids
+      .into_iter()
+      .map(|request_id| async move { (request_id.id(), request_id.await) })
+      .collect::<FuturesUnordered<_>>()
+  }
+
+  /// Shut down the `Orchestrator`, waiting for requests that are in flight to
+  /// finish.
+  ///
+  /// New requests are no longer accepted, and requests that are still queued
+  /// are not started; their responses resolve to a shutdown error. If a
+  /// deadline is given and requests are still in flight when it passes, they
+  /// are cancelled and resolve to a shutdown error as well.
+  ///
+  /// Streaming responses hold their slot until they are consumed or dropped,
+  /// so without a deadline this also waits for any outstanding streams.
+  pub async fn shutdown(&self, deadline: Option<Duration>) {
+    self.scheduler.close();
+
+    match deadline {
+      Some(deadline) => {
+        if tokio::time::timeout(deadline, self.scheduler.wait_idle())
+          .await
+          .is_err()
+        {
+          self.shutdown.send_replace(true);
+        }
+      }
+      None => self.scheduler.wait_idle().await,
+    }
+  }
+
+  /// Stops starting queued requests until `unpause` is called, for halting a
+  /// run during an incident or a storm of rate limits without losing queued
+  /// work. Requests in flight are left to finish, including their retries,
+  /// and new requests can still be added; they wait in the queue.
+  ///
+  /// Deadlines keep running while paused, so queued requests whose
+  /// `DeadlinePolicy` expires still fail.
+  pub fn pause(&self) {
+    debug!("pausing the orchestrator");
+    self.scheduler.pause();
+  }
+
+  /// Starts queued requests again after `pause`, in the order they would have
+  /// started in.
+  pub fn unpause(&self) {
+    debug!("unpausing the orchestrator");
+    self.scheduler.unpause();
+  }
+
+  /// Whether the `Orchestrator` is paused.
+  pub fn is_paused(&self) -> bool {
+    self.scheduler.is_paused()
+  }
+
+  /// Returns a snapshot of how many requests have been submitted, are queued
+  /// or in flight, and have completed or failed.
+  pub fn progress(&self) -> Progress {
+    *self.progress.borrow()
+  }
+
+  /// Returns how many requests have finished without their response being
+  /// taken from their `RequestID` yet. Responses are freed as soon as they
+  /// are taken or their `RequestID` is dropped.
+  pub fn unclaimed_responses(&self) -> usize {
+    self.unclaimed.load(Ordering::Relaxed)
+  }
+
+  /// Returns a receiver that is notified whenever the `Orchestrator`'s
+  /// progress changes, for driving a progress bar or similar.
+  pub fn watch_progress(&self) -> watch::Receiver<Progress> {
+    self.progress.subscribe()
+  }
+
+  /// Returns the number of requests currently allowed to run at once. This
+  /// changes when the `ConcurrencyPolicy` is adaptive or is replaced.
+  pub fn concurrency_limit(&self) -> usize {
+    self.scheduler.capacity()
+  }
+
+  /// Returns the policies that new requests are sent with.
+  pub fn policies(&self) -> Policies {
+    self
+      .policies
+      .read()
+      .expect("policies lock poisoned")
+      .clone()
+  }
+
+  /// Changes the policies of a running `Orchestrator`, for example to
+  /// throttle a bulk run without restarting it. Requests added afterwards
+  /// are sent with the new policies, while requests added before keep the
+  /// policies they were added with.
+  ///
+  /// A new `ConcurrencyPolicy` takes effect straight away, including for
+  /// queued requests: raising the limit starts more of them, and lowering it
+  /// holds them back until enough requests in flight finish. So do a new
+  /// `TenantPolicy` and `QueuePolicy`. The rate limits of the `RateLimitPolicy`
+  /// and the tokens-per-minute limit are set for each key when the
+  /// `Orchestrator` is created, and can't be changed.
+  pub fn update_policies(&self, f: impl FnOnce(&mut Policies)) {
+    let mut policies = self.policies.write().expect("policies lock poisoned");
+    let concurrency_policy = policies.concurrency_policy.clone();
+    let tenant_policy = policies.tenant_policy.clone();
+    let queue_policy = policies.queue_policy.clone();
+    f(&mut policies);
+    if policies.concurrency_policy != concurrency_policy {
+      self.scheduler.set_concurrency(&policies.concurrency_policy);
+    }
+    if policies.tenant_policy != tenant_policy {
+      self.scheduler.set_tenant_policy(&policies.tenant_policy);
+    }
+    if policies.queue_policy != queue_policy {
+      self.queue.resized();
+    }
+  }
+
+  /// Replaces all of the policies, as `update_policies`.
+  pub fn set_policies(&self, policies: Policies) {
+    self.update_policies(|current| *current = policies);
+  }
+
+  /// Changes how many requests may run at once, as `update_policies`. An
+  /// adaptive limit starts again from `n`, which becomes its maximum.
+  pub fn set_concurrency(&self, n: usize) {
+    self.update_policies(|policies| {
+      let concurrency_policy = &mut policies.concurrency_policy;
+      concurrency_policy.max_concurrent_requests = n;
+      if let Some(adaptive) = &mut concurrency_policy.adaptive {
+        adaptive.max_concurrent_requests = n.max(1);
+        adaptive.min_concurrent_requests =
+          adaptive.min_concurrent_requests.min(n.max(1));
+      }
+    });
+  }
+
+  /// Changes how requests added from now on are retried, as
+  /// `update_policies`.
+  pub fn set_retry_policy(&self, retry_policy: RetryPolicy) {
+    self.update_policies(|policies| policies.retry_policy = retry_policy);
+  }
+
+  /// Returns the tokens used by the requests sent so far, and what they cost.
+  pub fn spend(&self) -> Spend {
+    self.usage.spend()
+  }
+
+  /// Returns the statistics of every tag requests have been added with.
+  /// Requests with several tags are counted under each of them.
+  pub fn stats_by_tag(&self) -> HashMap<String, TagStats> {
+    self.tags.snapshot()
+  }
+
+  /// Estimates the cost of a request in US dollars before it is added, if
+  /// the request can estimate its usage and its model's price is known.
+  pub fn estimate_cost<Req: OrchRequest>(&self, request: &Req) -> Option<f64> {
+    let (model, usage) = request.estimated_usage()?;
+    self.usage.pricing().cost(model, &usage)
+  }
+
+  /// Returns the health of each of the `Orchestrator`'s keys, in the order
+  /// they were given.
+  pub fn key_status(&self) -> Vec<KeyStatus> {
+    self.keys.status()
+  }
+
+  /// Returns whether `shutdown` has been called on the `Orchestrator`.
+  pub fn is_shutdown(&self) -> bool {
+    self.scheduler.is_closed()
+  }
+}
+
+/// Sends a request with `send_hedged`, turning a panic while sending it into
+/// `OrchError::TaskPanicked` rather than letting it take down the task, which
+/// would leave the request without a response.
+async fn send_caught<Req: OrchRequest>(
+  request: &Req,
+  policies: &Policies,
+  keys: Keys,
+  id: u64,
+) -> Result<Req::Res> {
+  let sent = AssertUnwindSafe(send_hedged(request, policies, keys, id));
+  sent.catch_unwind().await.unwrap_or_else(|panic| {
+    let message = match panic.downcast::<String>() {
+      Ok(message) => *message,
+      Err(panic) => match panic.downcast::<&'static str>() {
+        Ok(message) => message.to_string(),
+        Err(_) => "unknown panic".to_string(),
+      },
+    };
+    error!("request {} panicked: {}", id, message);
+    Err(OrchError::TaskPanicked(message))
+  })
+}
+
+/// Sends a request, along with a duplicate if it is still running after the
+/// `HedgePolicy`'s delay, and returns whichever finishes first. If that one
+/// failed, the other is waited for instead.
+async fn send_hedged<Req: OrchRequest>(
+  request: &Req,
+  policies: &Policies,
+  keys: Keys,
+  id: u64,
+) -> Result<Req::Res> {
+  let first = request.send(policies.clone(), keys.clone(), id);
+  let Some(delay) = policies.hedge_policy.delay else {
+    return first.await;
+  };
+  tokio::pin!(first);
+  tokio::select! {
+    res = &mut first => return res,
+    _ = tokio::time::sleep(delay) => {}
+  }
+
+  debug!("hedging request {} after {}s", id, delay.as_secs_f32());
+  let second = request.send(policies.clone(), keys, id);
+  tokio::pin!(second);
+  // dropping the attempt which loses the race cancels it
+  tokio::select! {
+    res = &mut first => match res {
+      Ok(res) => Ok(res),
+      Err(_) => second.await,
+    },
+    res = &mut second => match res {
+      Ok(res) => Ok(res),
+      Err(_) => first.await,
+    },
+  }
+}
+
+/// Resolves once the given deadline has passed, or never without one.
+async fn deadline_passed(deadline: Option<Instant>) {
+  match deadline {
+    Some(deadline) => tokio::time::sleep_until(deadline).await,
+    None => std::future::pending().await,
+  }
+}
+
+/// Resolves once a shutdown's deadline has passed. Never resolves if the
+/// `Orchestrator` is dropped without shutting down.
+async fn aborted(shutdown: &mut watch::Receiver<bool>) {
+  if shutdown.wait_for(|aborted| *aborted).await.is_err() {
+    std::future::pending::<()>().await;
   }
 }
@@ -0,0 +1,217 @@
+//! Token buckets for rate limiting request dispatch, the retry budget shared
+//! by every request, and the bound on queued requests.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+  sync::Notify,
+  time::{sleep, Duration, Instant},
+};
+
+use crate::policies::RetryBudgetPolicy;
+
+struct BucketState {
+  available:   f64,
+  refilled_at: Instant,
+}
+
+/// A token bucket which refills continuously up to its capacity.
+///
+/// Acquiring tokens reserves them immediately, even if that puts the bucket
+/// into debt, and then waits until the debt would have been repaid. Callers
+/// are therefore served in the order they call `acquire`.
+pub(crate) struct TokenBucket {
+  capacity:    f64,
+  refill_rate: f64,
+  state:       Mutex<BucketState>,
+}
+
+impl TokenBucket {
+  /// Returns a full bucket holding `per_minute` tokens, which refills at a rate
+  /// of `per_minute` tokens per minute.
+  pub(crate) fn per_minute(per_minute: u64) -> Self {
+    let capacity = per_minute as f64;
+    Self {
+      capacity,
+      refill_rate: capacity / 60.0,
+      state: Mutex::new(BucketState {
+        available:   capacity,
+        refilled_at: Instant::now(),
+      }),
+    }
+  }
+
+  /// Takes `cost` tokens from the bucket, waiting until they are available.
+  pub(crate) async fn acquire(&self, cost: u64) {
+    let wait = self.reserve(cost);
+    if !wait.is_zero() {
+      sleep(wait).await;
+    }
+  }
+
+  /// Takes `cost` tokens from the bucket, returning how long to wait until
+  /// they would have been available.
+  pub(crate) fn reserve(&self, cost: u64) -> Duration {
+    let mut state = self.state.lock().expect("token bucket lock poisoned");
+    let now = Instant::now();
+    let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+    state.available =
+      (state.available + elapsed * self.refill_rate).min(self.capacity);
+    state.refilled_at = now;
+
+    state.available -= cost as f64;
+    if state.available < 0.0 && self.refill_rate > 0.0 {
+      Duration::from_secs_f64(-state.available / self.refill_rate)
+    } else {
+      Duration::ZERO
+    }
+  }
+}
+
+/// The number of slots a `RetryBudget`'s window is divided into. Requests
+/// and retries leave the window a slot at a time.
+const BUDGET_SLOTS: usize = 10;
+
+#[derive(Clone, Copy, Default)]
+struct BudgetSlot {
+  /// Which slot-length period since the budget was created the counts
+  /// belong to.
+  period:   u64,
+  requests: u64,
+  retries:  u64,
+}
+
+struct BudgetWindow {
+  slot_length: Duration,
+  slots:       [BudgetSlot; BUDGET_SLOTS],
+}
+
+/// The retries spent by an `Orchestrator`'s requests within a sliding window,
+/// against the allowance of its `RetryBudgetPolicy`.
+pub(crate) struct RetryBudget {
+  started_at: Instant,
+  window:     Mutex<BudgetWindow>,
+}
+
+impl Default for RetryBudget {
+  fn default() -> Self {
+    Self {
+      started_at: Instant::now(),
+      window:     Mutex::new(BudgetWindow {
+        slot_length: Duration::ZERO,
+        slots:       [BudgetSlot::default(); BUDGET_SLOTS],
+      }),
+    }
+  }
+}
+
+impl RetryBudget {
+  /// Counts a request being sent, which adds to the allowance.
+  pub(crate) fn record_request(&self, policy: &RetryBudgetPolicy) {
+    self.update(policy, |slot, _| slot.requests += 1);
+  }
+
+  /// Spends a retry if the policy allows another one.
+  pub(crate) fn try_retry(&self, policy: &RetryBudgetPolicy) -> bool {
+    let Some(ratio) = policy.ratio else {
+      return true;
+    };
+    self.update(policy, |slot, (requests, retries)| {
+      let allowance = policy.min_retries + (requests as f64 * ratio) as u64;
+      let allowed = retries < allowance;
+      if allowed {
+        slot.retries += 1;
+      }
+      allowed
+    })
+  }
+
+  /// Calls `f` with the current slot and the requests and retries counted
+  /// within the window, after forgetting the slots which have left it.
+  fn update<T>(
+    &self,
+    policy: &RetryBudgetPolicy,
+    f: impl FnOnce(&mut BudgetSlot, (u64, u64)) -> T,
+  ) -> T {
+    let mut window = self.window.lock().expect("retry budget lock poisoned");
+    let slot_length =
+      (policy.window / BUDGET_SLOTS as u32).max(Duration::from_millis(1));
+    // the counts can't be placed in slots of another length
+    if window.slot_length != slot_length {
+      window.slot_length = slot_length;
+      window.slots = [BudgetSlot::default(); BUDGET_SLOTS];
+    }
+    let period =
+      (self.started_at.elapsed().as_nanos() / slot_length.as_nanos()) as u64;
+    let oldest = period.saturating_sub(BUDGET_SLOTS as u64 - 1);
+    let totals = window
+      .slots
+      .iter()
+      .filter(|slot| slot.period >= oldest && slot.period <= period)
+      .fold((0, 0), |(requests, retries), slot| {
+        (requests + slot.requests, retries + slot.retries)
+      });
+
+    let slot = &mut window.slots[period as usize % BUDGET_SLOTS];
+    if slot.period != period {
+      *slot = BudgetSlot {
+        period,
+        ..BudgetSlot::default()
+      };
+    }
+    f(slot, totals)
+  }
+}
+
+/// The requests which were added to an `Orchestrator` and haven't started
+/// yet, against the limit of its `QueuePolicy`.
+#[derive(Default)]
+pub(crate) struct Queue {
+  queued: Mutex<usize>,
+  freed:  Notify,
+}
+
+impl Queue {
+  /// Takes a place in the queue if fewer than `max_queued` requests hold one.
+  pub(crate) fn try_enter(
+    self: &Arc<Self>,
+    max_queued: usize,
+  ) -> Option<QueueSlot> {
+    let mut queued = self.queued.lock().expect("queue lock poisoned");
+    if *queued >= max_queued {
+      return None;
+    }
+    *queued += 1;
+    Some(QueueSlot(self.clone()))
+  }
+
+  /// Takes a place in the queue, waiting until fewer than `max_queued`
+  /// requests hold one.
+  pub(crate) async fn enter(self: &Arc<Self>, max_queued: usize) -> QueueSlot {
+    loop {
+      let freed = self.freed.notified();
+      tokio::pin!(freed);
+      freed.as_mut().enable();
+
+      if let Some(slot) = self.try_enter(max_queued) {
+        return slot;
+      }
+      freed.await;
+    }
+  }
+
+  /// Wakes every request waiting for a place, for when the limit changes.
+  pub(crate) fn resized(&self) {
+    self.freed.notify_waiters();
+  }
+}
+
+/// A place in the `Queue`, given back when dropped.
+pub(crate) struct QueueSlot(Arc<Queue>);
+
+impl Drop for QueueSlot {
+  fn drop(&mut self) {
+    *self.0.queued.lock().expect("queue lock poisoned") -= 1;
+    self.0.freed.notify_one();
+  }
+}
@@ -0,0 +1,71 @@
+//! Metadata describing how a request was served.
+
+use tokio::time::{Duration, Instant};
+
+use crate::{scope::RequestStats, tags::Tags};
+
+/// How a request was served, as returned by
+/// `Orchestrator::get_response_with_meta`.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseMeta {
+  /// The ID of the request.
+  pub id:      u64,
+  /// The time from the request being added to its response being ready.
+  pub latency: Duration,
+  /// The part of `latency` spent waiting for the `ConcurrencyPolicy` and rate
+  /// limits.
+  pub queued:  Duration,
+  /// The number of attempts that failed and were retried.
+  pub retries: u32,
+  /// The model that served the request, as reported by the API. This may be
+  /// more specific than the model that was asked for, such as
+  /// `gpt-4o-2024-08-06` for `gpt-4o`, or a different model altogether if
+  /// the `FallbackPolicy` stepped in.
+  pub model:   Option<String>,
+  /// Whether the response was served from the `Orchestrator`'s cache, in
+  /// which case it wasn't sent at all.
+  pub cached:  bool,
+  /// The tags the request was added with.
+  pub tags:    Vec<String>,
+}
+
+impl ResponseMeta {
+  pub(crate) fn new(id: u64) -> Self {
+    Self {
+      id,
+      ..Default::default()
+    }
+  }
+
+  /// Returns the metadata of a request which was added at `added_at`, started
+  /// being sent at `started_at` if it got that far, and finished now.
+  pub(crate) fn finished(
+    id: u64,
+    added_at: Instant,
+    started_at: Option<Instant>,
+    stats: RequestStats,
+  ) -> Self {
+    let now = Instant::now();
+    Self {
+      id,
+      latency: now.duration_since(added_at),
+      queued: started_at.unwrap_or(now).duration_since(added_at),
+      retries: stats.retries,
+      model: stats.model,
+      cached: false,
+      tags: Vec::new(),
+    }
+  }
+
+  pub(crate) fn tagged(mut self, tags: &Tags) -> Self {
+    self.tags = tags.as_slice().to_vec();
+    self
+  }
+}
+
+/// A response along with the metadata describing how it was served.
+#[derive(Clone, Debug)]
+pub struct WithMeta<R> {
+  pub response: R,
+  pub meta:     ResponseMeta,
+}
@@ -0,0 +1,369 @@
+//! Scripted stand-ins for the API, for testing code built on the
+//! `Orchestrator` without a network connection or a key.
+//!
+//! A `MockScript` lists what each call should do in turn: reply with some
+//! content or fail with an error, optionally after a delay. Give it to a
+//! `MockTransport` to answer the chat and completion requests of this crate,
+//! or to a `MockOrchRequest` to send through the `Orchestrator` directly.
+//!
+//! The `Orchestrator` keeps time with `tokio::time`, for retry delays,
+//! timeouts, deadlines, rate limits, and cache expiry alike, as do the
+//! delays of a script. Pausing time with `tokio::time::pause`, or with
+//! `start_paused` as below, lets a test of them finish instantly: the runtime
+//! skips ahead whenever every task is waiting on a timer. This feature
+//! enables `tokio`'s `test-util` feature, which pausing needs.
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use openai_orch::{
+//!   error::OrchError,
+//!   mock::{MockOrchRequest, MockScript},
+//!   policies::RetryPolicy,
+//!   prelude::*,
+//! };
+//!
+//! # #[tokio::main(flavor = "current_thread", start_paused = true)]
+//! # async fn main() {
+//! // backs off for 10s and then 20s, which takes no time while paused
+//! let orchestrator = Orchestrator::new(
+//!   Policies {
+//!     retry_policy: RetryPolicy::exponential_backoff(
+//!       3,
+//!       Duration::from_secs(10),
+//!       Duration::from_secs(60),
+//!     ),
+//!     ..Default::default()
+//!   },
+//!   Keys::new("sk-test".to_string(), None),
+//! );
+//!
+//! let script = MockScript::new()
+//!   .fail_times(2, OrchError::RateLimited {
+//!     message:     "slow down".to_string(),
+//!     retry_after: None,
+//!   })
+//!   .reply("hello");
+//! let request = MockOrchRequest::new(script);
+//! let started_at = tokio::time::Instant::now();
+//! let response = orchestrator.add_request(request.clone()).await.await;
+//!
+//! assert_eq!(response.unwrap().content, "hello");
+//! assert_eq!(request.calls(), 3);
+//! assert!(started_at.elapsed() >= Duration::from_secs(30));
+//! # }
+//! ```
+
+use std::{
+  collections::VecDeque,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use async_openai::types::{
+  CreateChatCompletionRequest, CreateChatCompletionResponse,
+  CreateCompletionRequest, CreateCompletionResponse,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Duration};
+
+use crate::{
+  cost::{record_usage, Usage},
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  transport::Transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// What a single call answered by a mock does.
+pub struct MockStep {
+  outcome: Result<String>,
+  usage:   Option<Usage>,
+  latency: Option<Duration>,
+}
+
+impl MockStep {
+  /// Replies with `content`.
+  pub fn reply(content: impl Into<String>) -> Self {
+    Self {
+      outcome: Ok(content.into()),
+      usage:   None,
+      latency: None,
+    }
+  }
+
+  /// Fails with `error`.
+  pub fn fail(error: OrchError) -> Self {
+    Self {
+      outcome: Err(error),
+      usage:   None,
+      latency: None,
+    }
+  }
+
+  /// Reports `usage` along with the reply.
+  pub fn with_usage(mut self, usage: Usage) -> Self {
+    self.usage = Some(usage);
+    self
+  }
+
+  /// Waits for `latency` before replying or failing, instead of the script's
+  /// default latency.
+  pub fn after(mut self, latency: Duration) -> Self {
+    self.latency = Some(latency);
+    self
+  }
+}
+
+/// The steps a mock plays, one per call, in order.
+///
+/// Once every step has been played, calls reply with the content given to
+/// `otherwise`, or fail with `OrchError::Unsupported` if there is none, which
+/// is not retried.
+#[derive(Default)]
+#[must_use]
+pub struct MockScript {
+  steps:     VecDeque<MockStep>,
+  latency:   Duration,
+  otherwise: Option<String>,
+}
+
+impl MockScript {
+  /// Returns an empty script.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds a step.
+  pub fn then(mut self, step: MockStep) -> Self {
+    self.steps.push_back(step);
+    self
+  }
+
+  /// Adds a step replying with `content`.
+  pub fn reply(self, content: impl Into<String>) -> Self {
+    self.then(MockStep::reply(content))
+  }
+
+  /// Adds a step failing with `error`.
+  pub fn fail(self, error: OrchError) -> Self {
+    self.then(MockStep::fail(error))
+  }
+
+  /// Adds `n` steps failing with copies of `error`.
+  pub fn fail_times(mut self, n: usize, error: OrchError) -> Self {
+    for _ in 0..n {
+      self = self.fail(error.duplicate());
+    }
+    self
+  }
+
+  /// Sets how long every step waits before replying or failing, unless it
+  /// sets its own latency. Defaults to not waiting.
+  pub fn with_latency(mut self, latency: Duration) -> Self {
+    self.latency = latency;
+    self
+  }
+
+  /// Replies with `content` once every step has been played.
+  pub fn otherwise(mut self, content: impl Into<String>) -> Self {
+    self.otherwise = Some(content.into());
+    self
+  }
+}
+
+/// A script being played, shared between the clones of a mock.
+struct Player {
+  script: Mutex<MockScript>,
+  calls:  AtomicU32,
+}
+
+impl Player {
+  fn new(script: MockScript) -> Arc<Self> {
+    Arc::new(Self {
+      script: Mutex::new(script),
+      calls:  AtomicU32::new(0),
+    })
+  }
+
+  /// Plays the next step, returning its content and usage, and which call
+  /// this was.
+  async fn play(&self) -> Result<(String, Option<Usage>, u32)> {
+    let call = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+    let (step, latency) = {
+      let mut script = self.script.lock().expect("mock script lock poisoned");
+      let step =
+        script
+          .steps
+          .pop_front()
+          .unwrap_or_else(|| match &script.otherwise {
+            Some(content) => MockStep::reply(content.clone()),
+            None => MockStep::fail(OrchError::Unsupported(
+              "calls after the end of its script",
+            )),
+          });
+      let latency = step.latency.unwrap_or(script.latency);
+      (step, latency)
+    };
+
+    if !latency.is_zero() {
+      sleep(latency).await;
+    }
+    step.outcome.map(|content| (content, step.usage, call))
+  }
+}
+
+/// A `Transport` which answers chat and completion requests from a
+/// `MockScript` instead of calling the API. Other calls fail with
+/// `OrchError::Unsupported`.
+///
+/// The clones of a `MockTransport` share its script, so keep one to check
+/// how many calls were made after giving another to the `Orchestrator`.
+#[derive(Clone)]
+pub struct MockTransport {
+  player: Arc<Player>,
+}
+
+impl MockTransport {
+  pub fn new(script: MockScript) -> Self {
+    Self {
+      player: Player::new(script),
+    }
+  }
+
+  /// The number of calls made so far, including those that failed.
+  pub fn calls(&self) -> u32 {
+    self.player.calls.load(Ordering::Relaxed)
+  }
+}
+
+/// Builds a response in the shape the API gives, from the fields of a
+/// `MockStep`.
+fn response<T: serde::de::DeserializeOwned>(
+  object: &str,
+  model: String,
+  choice: serde_json::Value,
+  usage: Option<Usage>,
+) -> Result<T> {
+  let usage = usage.map(|usage| {
+    serde_json::json!({
+      "prompt_tokens": usage.prompt_tokens,
+      "completion_tokens": usage.completion_tokens,
+      "total_tokens": usage.total_tokens(),
+    })
+  });
+  serde_json::from_value(serde_json::json!({
+    "id": "mock",
+    "object": object,
+    "created": 0,
+    "model": model,
+    "choices": [choice],
+    "usage": usage,
+  }))
+  .map_err(OrchError::other)
+}
+
+#[async_trait]
+impl Transport for MockTransport {
+  async fn chat(
+    &self,
+    _keys: &Keys,
+    request: CreateChatCompletionRequest,
+  ) -> Result<CreateChatCompletionResponse> {
+    let (content, usage, _) = self.player.play().await?;
+    let choice = serde_json::json!({
+      "index": 0,
+      "message": { "role": "assistant", "content": content },
+      "finish_reason": "stop",
+    });
+    response("chat.completion", request.model, choice, usage)
+  }
+
+  async fn completion(
+    &self,
+    _keys: &Keys,
+    request: CreateCompletionRequest,
+  ) -> Result<CreateCompletionResponse> {
+    let (content, usage, _) = self.player.play().await?;
+    let choice = serde_json::json!({
+      "index": 0,
+      "text": content,
+      "logprobs": null,
+      "finish_reason": "stop",
+    });
+    response("text_completion", request.model, choice, usage)
+  }
+}
+
+/// A request which plays a `MockScript` each time it is sent, without
+/// calling the API, for testing how the `Orchestrator`'s policies handle
+/// responses and failures. Failed steps are retried following the
+/// `RetryPolicy` and `TimeoutPolicy`, as they are for the requests in this
+/// crate.
+///
+/// The clones of a `MockOrchRequest` share its script, so keep one to check
+/// how many times it was sent.
+#[derive(Clone)]
+pub struct MockOrchRequest {
+  player: Arc<Player>,
+  model:  String,
+}
+
+impl MockOrchRequest {
+  pub fn new(script: MockScript) -> Self {
+    Self {
+      player: Player::new(script),
+      model:  "mock".to_string(),
+    }
+  }
+
+  /// Sets the model usage is recorded against, for testing pricing and
+  /// budgets. Defaults to "mock".
+  pub fn with_model(mut self, model: impl Into<String>) -> Self {
+    self.model = model.into();
+    self
+  }
+
+  /// The number of times the request was sent, including attempts that
+  /// failed.
+  pub fn calls(&self) -> u32 {
+    self.player.calls.load(Ordering::Relaxed)
+  }
+}
+
+/// The response given by a `MockOrchRequest`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MockResponse {
+  /// The content of the step that replied.
+  pub content: String,
+  /// Which call to the request replied, counting from 1.
+  pub call:    u32,
+}
+
+impl ResponseType for MockResponse {}
+
+#[async_trait]
+impl OrchRequest for MockOrchRequest {
+  type Res = MockResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    _keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    let timeout = policies.timeout_policy.timeout();
+    with_retries(&policies, timeout, id, || async {
+      let (content, usage, call) = self.player.play().await?;
+      if let Some(usage) = usage {
+        record_usage(&self.model, usage);
+      }
+      Ok(MockResponse { content, call })
+    })
+    .await
+  }
+}
@@ -0,0 +1,146 @@
+//! Well-known OpenAI models and what is known about them.
+
+use core::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cost::{Pricing, PricingTable};
+
+/// A chat model, either one this crate knows about or any other by name.
+///
+/// Chat requests and the `FallbackPolicy` take a `Model`, as in
+/// `ChatModelParams::builder().model(Model::Gpt4oMini)` or
+/// `ChatModelParams { model: Model::Gpt41, ..Default::default() }`, and
+/// model names still convert into one. Models convert into their API names
+/// too, so a `Model` can be given wherever a model name is expected, such as
+/// to `EmbeddingParams::new`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Model {
+  Gpt4o,
+  Gpt4oMini,
+  Gpt41,
+  Gpt41Mini,
+  Gpt41Nano,
+  O3,
+  O4Mini,
+  Gpt35Turbo,
+  /// Any other model, such as a dated snapshot, a fine-tune, or a model
+  /// served by an OpenAI-compatible server.
+  Custom(String),
+}
+
+impl Model {
+  /// Every model this crate knows about.
+  pub(crate) const KNOWN: [Model; 8] = [
+    Model::Gpt4o,
+    Model::Gpt4oMini,
+    Model::Gpt41,
+    Model::Gpt41Mini,
+    Model::Gpt41Nano,
+    Model::O3,
+    Model::O4Mini,
+    Model::Gpt35Turbo,
+  ];
+
+  /// The name of the model in the API.
+  pub fn as_str(&self) -> &str {
+    match self {
+      Model::Gpt4o => "gpt-4o",
+      Model::Gpt4oMini => "gpt-4o-mini",
+      Model::Gpt41 => "gpt-4.1",
+      Model::Gpt41Mini => "gpt-4.1-mini",
+      Model::Gpt41Nano => "gpt-4.1-nano",
+      Model::O3 => "o3",
+      Model::O4Mini => "o4-mini",
+      Model::Gpt35Turbo => "gpt-3.5-turbo",
+      Model::Custom(name) => name,
+    }
+  }
+
+  /// Whether the model reasons before it answers, like the o-series. These
+  /// models take `max_completion_tokens` and a reasoning effort, and reject
+  /// sampling parameters such as `temperature`.
+  pub fn is_reasoning(&self) -> bool {
+    match self {
+      Model::O3 | Model::O4Mini => true,
+      Model::Custom(name) => ["o1", "o3", "o4", "gpt-5"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix)),
+      _ => false,
+    }
+  }
+
+  /// The size of the model's context window in tokens. Custom models are
+  /// only looked up with the `tokens` feature.
+  pub fn context_window(&self) -> Option<u64> {
+    match self {
+      Model::Gpt4o | Model::Gpt4oMini => Some(128_000),
+      Model::Gpt41 | Model::Gpt41Mini | Model::Gpt41Nano => Some(1_047_576),
+      Model::O3 | Model::O4Mini => Some(200_000),
+      Model::Gpt35Turbo => Some(16_385),
+      #[cfg(feature = "tokens")]
+      Model::Custom(name) => {
+        tiktoken_rs::model::get_context_size(name).map(|size| size as u64)
+      }
+      #[cfg(not(feature = "tokens"))]
+      Model::Custom(_) => None,
+    }
+  }
+
+  /// The model's list price. Custom models are priced as in the default
+  /// `PricingTable`, so that `gpt-4o-2024-08-06` is priced as `gpt-4o`.
+  pub fn pricing(&self) -> Option<Pricing> {
+    let (prompt, completion) = match self {
+      Model::Gpt4o => (2.5, 10.0),
+      Model::Gpt4oMini => (0.15, 0.6),
+      Model::Gpt41 | Model::O3 => (2.0, 8.0),
+      Model::Gpt41Mini => (0.4, 1.6),
+      Model::Gpt41Nano => (0.1, 0.4),
+      Model::O4Mini => (1.1, 4.4),
+      Model::Gpt35Turbo => (0.5, 1.5),
+      Model::Custom(name) => return PricingTable::default().pricing(name),
+    };
+    Some(Pricing::new(prompt, completion))
+  }
+}
+
+impl Display for Model {
+  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl From<&str> for Model {
+  fn from(name: &str) -> Self {
+    match name {
+      "gpt-4o" => Model::Gpt4o,
+      "gpt-4o-mini" => Model::Gpt4oMini,
+      "gpt-4.1" => Model::Gpt41,
+      "gpt-4.1-mini" => Model::Gpt41Mini,
+      "gpt-4.1-nano" => Model::Gpt41Nano,
+      "o3" => Model::O3,
+      "o4-mini" => Model::O4Mini,
+      "gpt-3.5-turbo" => Model::Gpt35Turbo,
+      name => Model::Custom(name.to_string()),
+    }
+  }
+}
+
+impl From<String> for Model {
+  fn from(name: String) -> Self {
+    match Model::from(name.as_str()) {
+      Model::Custom(_) => Model::Custom(name),
+      model => model,
+    }
+  }
+}
+
+impl From<Model> for String {
+  fn from(model: Model) -> Self {
+    match model {
+      Model::Custom(name) => name,
+      model => model.as_str().to_string(),
+    }
+  }
+}
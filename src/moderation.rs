@@ -0,0 +1,284 @@
+//! Requests and responses using the OpenAI Moderation API.
+
+use std::collections::HashMap;
+
+use async_openai::types::{
+  Category, CategoryScore, CreateModerationRequest, ModerationInput,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  cache::cache_key,
+  chat::{ChatMessage, ChatRole},
+  error::{OrchError, Result},
+  keys::Keys,
+  policies::Policies,
+  trace::debug,
+  transport,
+  utils::with_retries,
+  OrchRequest, ResponseType,
+};
+
+/// A category of content that the Moderation API checks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ModerationCategory {
+  Hate,
+  HateThreatening,
+  Harassment,
+  HarassmentThreatening,
+  Illicit,
+  IllicitViolent,
+  SelfHarm,
+  SelfHarmIntent,
+  SelfHarmInstructions,
+  Sexual,
+  SexualMinors,
+  Violence,
+  ViolenceGraphic,
+}
+
+impl ModerationCategory {
+  /// Every category, in the order the API documents them.
+  pub const ALL: [ModerationCategory; 13] = [
+    ModerationCategory::Hate,
+    ModerationCategory::HateThreatening,
+    ModerationCategory::Harassment,
+    ModerationCategory::HarassmentThreatening,
+    ModerationCategory::Illicit,
+    ModerationCategory::IllicitViolent,
+    ModerationCategory::SelfHarm,
+    ModerationCategory::SelfHarmIntent,
+    ModerationCategory::SelfHarmInstructions,
+    ModerationCategory::Sexual,
+    ModerationCategory::SexualMinors,
+    ModerationCategory::Violence,
+    ModerationCategory::ViolenceGraphic,
+  ];
+
+  /// The name of the category in the API, e.g. `self-harm/intent`.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ModerationCategory::Hate => "hate",
+      ModerationCategory::HateThreatening => "hate/threatening",
+      ModerationCategory::Harassment => "harassment",
+      ModerationCategory::HarassmentThreatening => "harassment/threatening",
+      ModerationCategory::Illicit => "illicit",
+      ModerationCategory::IllicitViolent => "illicit/violent",
+      ModerationCategory::SelfHarm => "self-harm",
+      ModerationCategory::SelfHarmIntent => "self-harm/intent",
+      ModerationCategory::SelfHarmInstructions => "self-harm/instructions",
+      ModerationCategory::Sexual => "sexual",
+      ModerationCategory::SexualMinors => "sexual/minors",
+      ModerationCategory::Violence => "violence",
+      ModerationCategory::ViolenceGraphic => "violence/graphic",
+    }
+  }
+
+  fn flag(&self, categories: &Category) -> bool {
+    match self {
+      ModerationCategory::Hate => categories.hate,
+      ModerationCategory::HateThreatening => categories.hate_threatening,
+      ModerationCategory::Harassment => categories.harassment,
+      ModerationCategory::HarassmentThreatening => {
+        categories.harassment_threatening
+      }
+      ModerationCategory::Illicit => categories.illicit,
+      ModerationCategory::IllicitViolent => categories.illicit_violent,
+      ModerationCategory::SelfHarm => categories.self_harm,
+      ModerationCategory::SelfHarmIntent => categories.self_harm_intent,
+      ModerationCategory::SelfHarmInstructions => {
+        categories.self_harm_instructions
+      }
+      ModerationCategory::Sexual => categories.sexual,
+      ModerationCategory::SexualMinors => categories.sexual_minors,
+      ModerationCategory::Violence => categories.violence,
+      ModerationCategory::ViolenceGraphic => categories.violence_graphic,
+    }
+  }
+
+  fn score(&self, scores: &CategoryScore) -> f32 {
+    match self {
+      ModerationCategory::Hate => scores.hate,
+      ModerationCategory::HateThreatening => scores.hate_threatening,
+      ModerationCategory::Harassment => scores.harassment,
+      ModerationCategory::HarassmentThreatening => {
+        scores.harassment_threatening
+      }
+      ModerationCategory::Illicit => scores.illicit,
+      ModerationCategory::IllicitViolent => scores.illicit_violent,
+      ModerationCategory::SelfHarm => scores.self_harm,
+      ModerationCategory::SelfHarmIntent => scores.self_harm_intent,
+      ModerationCategory::SelfHarmInstructions => scores.self_harm_instructions,
+      ModerationCategory::Sexual => scores.sexual,
+      ModerationCategory::SexualMinors => scores.sexual_minors,
+      ModerationCategory::Violence => scores.violence,
+      ModerationCategory::ViolenceGraphic => scores.violence_graphic,
+    }
+  }
+}
+
+/// A request to check a string against OpenAI's usage policies.
+///
+/// Refer to the `Orchestrator` for usage.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ModerationRequest {
+  pub input: String,
+  /// The moderation model to use, `omni-moderation-latest` by default.
+  pub model: String,
+}
+
+impl ModerationRequest {
+  pub fn new(input: String) -> Self {
+    Self {
+      input,
+      model: String::from("omni-moderation-latest"),
+    }
+  }
+
+  /// Sets the moderation model to use.
+  pub fn with_model(mut self, model: impl Into<String>) -> Self {
+    self.model = model.into();
+    self
+  }
+
+  fn build_inner_request(&self) -> CreateModerationRequest {
+    CreateModerationRequest {
+      input: ModerationInput::String(self.input.clone()),
+      model: Some(self.model.clone()),
+    }
+  }
+}
+
+/// The verdict given by a `ModerationRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModerationResponse {
+  /// Whether the input was flagged in any category.
+  pub flagged:    bool,
+  /// The categories the input was flagged in.
+  pub categories: Vec<ModerationCategory>,
+  /// The model's confidence in each category, between zero and one.
+  pub scores:     HashMap<ModerationCategory, f32>,
+}
+
+impl ModerationResponse {
+  /// Whether the input was flagged in the given category.
+  pub fn is_flagged(&self, category: ModerationCategory) -> bool {
+    self.categories.contains(&category)
+  }
+
+  /// The model's confidence in the given category, between zero and one.
+  pub fn score(&self, category: ModerationCategory) -> f32 {
+    self.scores.get(&category).copied().unwrap_or_default()
+  }
+}
+
+impl ResponseType for ModerationResponse {
+  fn to_cache(&self) -> Option<Vec<u8>> {
+    serde_json::to_vec(self).ok()
+  }
+
+  fn from_cache(bytes: &[u8]) -> Option<Self> {
+    serde_json::from_slice(bytes).ok()
+  }
+}
+
+#[async_trait]
+impl OrchRequest for ModerationRequest {
+  type Res = ModerationResponse;
+  async fn send(
+    &self,
+    policies: Policies,
+    keys: Keys,
+    id: u64,
+  ) -> Result<Self::Res> {
+    debug!("starting request {}", id);
+    let transport = transport::current();
+
+    let request = self.build_inner_request();
+    let response = with_retries(
+      &policies,
+      policies.timeout_policy.timeout(),
+      id,
+      || async { transport.moderation(&keys, request.clone()).await },
+    )
+    .await?;
+
+    let result = response.results.into_iter().next().ok_or_else(|| {
+      OrchError::InvalidResponse("response.results is empty".to_string())
+    })?;
+
+    Ok(ModerationResponse {
+      flagged:    result.flagged,
+      categories: ModerationCategory::ALL
+        .into_iter()
+        .filter(|category| category.flag(&result.categories))
+        .collect(),
+      scores:     ModerationCategory::ALL
+        .into_iter()
+        .map(|category| (category, category.score(&result.category_scores)))
+        .collect(),
+    })
+  }
+
+  fn estimated_tokens(&self) -> u64 {
+    self.input.len() as u64 / 4
+  }
+
+  fn cache_key(&self) -> Option<u64> {
+    cache_key(&self.build_inner_request())
+  }
+
+  fn to_json(&self) -> Option<serde_json::Value> {
+    serde_json::to_value(self).ok()
+  }
+}
+
+/// Screens the user messages of a chat request according to the
+/// `ModerationPolicy`, failing with `OrchError::ContentFlagged` if they are
+/// flagged in any of its categories.
+pub(crate) async fn screen(
+  messages: &[ChatMessage],
+  policies: &Policies,
+  keys: &Keys,
+  id: u64,
+) -> Result<()> {
+  let policy = &policies.moderation_policy;
+  let inputs: Vec<String> = messages
+    .iter()
+    .filter(|message| message.role == ChatRole::User)
+    .map(|message| message.content.clone())
+    .collect();
+  if !policy.is_enabled() || inputs.is_empty() {
+    return Ok(());
+  }
+
+  debug!("screening request {}", id);
+  let transport = transport::current();
+  let request = CreateModerationRequest {
+    input: ModerationInput::StringArray(inputs),
+    model: Some(policy.model.clone()),
+  };
+  let response =
+    with_retries(policies, policies.timeout_policy.timeout(), id, || async {
+      transport.moderation(keys, request.clone()).await
+    })
+    .await?;
+
+  let categories: Vec<_> = policy
+    .categories
+    .iter()
+    .copied()
+    .filter(|category| {
+      response
+        .results
+        .iter()
+        .any(|result| category.flag(&result.categories))
+    })
+    .collect();
+  if categories.is_empty() {
+    Ok(())
+  } else {
+    Err(OrchError::ContentFlagged { categories })
+  }
+}
@@ -0,0 +1,293 @@
+//! Pipelines of named steps, such as extracting, summarizing, and then
+//! classifying a document, run for each item of a bulk workload.
+//!
+//! A `Pipeline` is built step by step, each step taking the output of the
+//! one before. Requests made by its steps are sent through the
+//! `Orchestrator`, tagged with the name of their step, so they follow the
+//! same retry, concurrency, and budget policies as any other request, and
+//! show up in `Orchestrator::stats_by_tag`. The pipeline also keeps its own
+//! `StepStats`, which cover custom steps as well.
+//!
+//! ```rust,no_run
+//! # use openai_orch::prelude::*;
+//! use openai_orch::pipeline::Pipeline;
+//!
+//! # async fn example(orchestrator: Orchestrator, reviews: Vec<String>) {
+//! let pipeline = Pipeline::<String>::new()
+//!   .chat(
+//!     "translate",
+//!     "Translate the review to English.",
+//!     Default::default(),
+//!   )
+//!   .chat(
+//!     "classify",
+//!     "Reply with the sentiment of the review, in one word.",
+//!     Default::default(),
+//!   );
+//! let sentiments = pipeline.run_all(&orchestrator, reviews).await;
+//!
+//! for (name, stats) in pipeline.stats() {
+//!   println!("{}: {} completed, {} failed", name, stats.completed, stats.failed);
+//! }
+//! # }
+//! ```
+
+use std::{
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex},
+};
+
+use futures::StreamExt;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+  chat::{siso::ChatSisoRequest, ChatModelParams, ChatResponse},
+  embed::{EmbeddingParams, EmbeddingRequest},
+  error::Result,
+  trace::debug,
+  OrchRequest, Orchestrator, ResponseType,
+};
+
+type StepFuture<O> = Pin<Box<dyn Future<Output = Result<O>> + Send>>;
+type Run<I, O> = Arc<dyn Fn(Orchestrator, I) -> StepFuture<O> + Send + Sync>;
+
+/// What a step of a `Pipeline` has done so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StepStats {
+  /// The number of items the step finished with an output.
+  pub completed: u64,
+  /// The number of items the step failed on. Items which failed in an
+  /// earlier step never reach the step, and aren't counted.
+  pub failed:    u64,
+  /// The time spent in the step, summed over every item.
+  pub busy:      Duration,
+}
+
+impl StepStats {
+  /// The average time the step took for an item, if it has run at all.
+  pub fn mean_duration(&self) -> Option<Duration> {
+    let runs = self.completed + self.failed;
+    (runs > 0).then(|| self.busy / runs as u32)
+  }
+}
+
+/// The statistics of every step of a pipeline, in the order of the steps.
+#[derive(Default)]
+struct StepTable(Mutex<Vec<(String, StepStats)>>);
+
+impl StepTable {
+  /// Adds a step, returning its index.
+  fn register(&self, name: String) -> usize {
+    let mut steps = self.0.lock().expect("step table lock poisoned");
+    steps.push((name, StepStats::default()));
+    steps.len() - 1
+  }
+
+  fn record(&self, index: usize, elapsed: Duration, success: bool) {
+    let mut steps = self.0.lock().expect("step table lock poisoned");
+    let stats = &mut steps[index].1;
+    match success {
+      true => stats.completed += 1,
+      false => stats.failed += 1,
+    }
+    stats.busy += elapsed;
+  }
+}
+
+/// A chain of named steps turning an `I` into an `O`. See the module
+/// documentation.
+///
+/// Clones of a pipeline share its `StepStats`.
+pub struct Pipeline<I, O = I> {
+  run:   Run<I, O>,
+  steps: Arc<StepTable>,
+}
+
+impl<I, O> Clone for Pipeline<I, O> {
+  fn clone(&self) -> Self {
+    Self {
+      run:   self.run.clone(),
+      steps: self.steps.clone(),
+    }
+  }
+}
+
+impl<I: Send + 'static> Pipeline<I> {
+  /// Returns a pipeline without steps, which passes its input through.
+  pub fn new() -> Self {
+    Self {
+      run:   Arc::new(|_, input| Box::pin(async move { Ok(input) })),
+      steps: Arc::default(),
+    }
+  }
+}
+
+impl<I: Send + 'static> Default for Pipeline<I> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<I, O> Pipeline<I, O>
+where
+  I: Send + 'static,
+  O: Send + 'static,
+{
+  /// Adds a custom step, which is given the `Orchestrator` to send any
+  /// requests it needs. If the step fails, the item fails with its error
+  /// and the later steps are skipped.
+  pub fn then<T, F, Fut>(
+    self,
+    name: impl Into<String>,
+    step: F,
+  ) -> Pipeline<I, T>
+  where
+    T: Send + 'static,
+    F: Fn(Orchestrator, O) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<T>> + Send + 'static,
+  {
+    let index = self.steps.register(name.into());
+    let steps = self.steps.clone();
+    let (prev, step) = (self.run, Arc::new(step));
+    let run: Run<I, T> = Arc::new(move |orchestrator, input| {
+      let (prev, step, steps) = (prev.clone(), step.clone(), steps.clone());
+      Box::pin(async move {
+        let value = prev(orchestrator.clone(), input).await?;
+        let started_at = Instant::now();
+        let res = step(orchestrator, value).await;
+        steps.record(index, started_at.elapsed(), res.is_ok());
+        res
+      })
+    });
+    Pipeline {
+      run,
+      steps: self.steps,
+    }
+  }
+
+  /// Adds a step which transforms the output of the previous step without
+  /// sending anything, such as picking a field or formatting a prompt.
+  pub fn map<T, F>(self, name: impl Into<String>, f: F) -> Pipeline<I, T>
+  where
+    T: Send + 'static,
+    F: Fn(O) -> T + Send + Sync + 'static,
+  {
+    let f = Arc::new(f);
+    self.then(name, move |_, value| {
+      let f = f.clone();
+      async move { Ok(f(value)) }
+    })
+  }
+
+  /// Adds a step which sends the request made by `make_request` through the
+  /// `Orchestrator`, tagged with the name of the step, and passes on its
+  /// response.
+  pub fn request<Req, R, F>(
+    self,
+    name: impl Into<String>,
+    make_request: F,
+  ) -> Pipeline<I, R>
+  where
+    F: Fn(O) -> Req + Send + Sync + 'static,
+    Req: OrchRequest<Res = R> + Send + Sync + 'static,
+    R: ResponseType,
+  {
+    let name = name.into();
+    let make_request = Arc::new(make_request);
+    self.then(name.clone(), move |orchestrator, value| {
+      let request = make_request(value);
+      let name = name.clone();
+      async move {
+        orchestrator
+          .add_request_tagged(request, [name])
+          .await
+          .await
+      }
+    })
+  }
+
+  /// Adds a step which sends the output of the previous step as the user
+  /// prompt of a `ChatSisoRequest` with the given system prompt.
+  pub fn chat(
+    self,
+    name: impl Into<String>,
+    system_prompt: impl Into<String>,
+    model_params: ChatModelParams,
+  ) -> Pipeline<I, ChatResponse>
+  where
+    O: Into<String>,
+  {
+    let system_prompt = system_prompt.into();
+    self.request(name, move |value: O| {
+      ChatSisoRequest::new(
+        system_prompt.clone(),
+        value.into(),
+        model_params.clone(),
+      )
+    })
+  }
+
+  /// Adds a step which embeds the output of the previous step with an
+  /// `EmbeddingRequest`, and passes on the embedding.
+  pub fn embed(
+    self,
+    name: impl Into<String>,
+    params: EmbeddingParams,
+  ) -> Pipeline<I, Vec<f32>>
+  where
+    O: Into<String>,
+  {
+    let name = name.into();
+    self.then(name.clone(), move |orchestrator, value| {
+      let request = EmbeddingRequest::new(value.into(), params.clone());
+      let name = name.clone();
+      async move {
+        let response = orchestrator.add_request_tagged(request, [name]).await;
+        Ok(response.await?.0)
+      }
+    })
+  }
+
+  /// Runs the pipeline for a single item.
+  pub async fn run(&self, orchestrator: &Orchestrator, input: I) -> Result<O> {
+    (self.run)(orchestrator.clone(), input).await
+  }
+
+  /// Runs the pipeline for every item of `items`, and returns the outputs in
+  /// the order of the items.
+  ///
+  /// Items are started as earlier ones finish, with at most twice the
+  /// `Orchestrator`'s concurrency limit in the pipeline at a time, as
+  /// `bulk::orchestrate_map` does. An item failing doesn't prevent the
+  /// remaining items from being run.
+  pub async fn run_all(
+    &self,
+    orchestrator: &Orchestrator,
+    items: impl IntoIterator<Item = I>,
+  ) -> Vec<Result<O>> {
+    let window = (orchestrator.concurrency_limit() * 2).max(1);
+    let mut results: Vec<_> =
+      futures::stream::iter(items.into_iter().enumerate())
+        .map(|(index, item)| async move {
+          (index, self.run(orchestrator, item).await)
+        })
+        .buffer_unordered(window)
+        .collect()
+        .await;
+    results.sort_unstable_by_key(|(index, _)| *index);
+    debug!("pipeline finished {} items", results.len());
+    results.into_iter().map(|(_, res)| res).collect()
+  }
+
+  /// Returns the statistics of every step, along with its name, in the order
+  /// the steps run in.
+  pub fn stats(&self) -> Vec<(String, StepStats)> {
+    self
+      .steps
+      .0
+      .lock()
+      .expect("step table lock poisoned")
+      .clone()
+  }
+}
@@ -1,119 +1,300 @@
 //! Policies for controlling retry, concurrency, and timeout behavior.
+//!
+//! `Policies` can be loaded from configuration, so that deployments can tune
+//! them without recompiling. Durations are written like `"1s"` or
+//! `"1m 30s"`, and any policy left out keeps its default.
+//!
+//! ```rust
+//! use openai_orch::policies::{Policies, RetryPolicy};
+//!
+//! let policies = Policies::from_json(
+//!   r#"{
+//!     "retry_policy": {
+//!       "type": "exponential_backoff",
+//!       "max_retries": 8,
+//!       "initial_delay": "500ms",
+//!       "max_delay": "30s",
+//!       "jitter": "full"
+//!     },
+//!     "concurrency_policy": { "max_concurrent_requests": 50 },
+//!     "timeout_policy": { "type": "fixed", "timeout": "1m" }
+//!   }"#,
+//! )
+//! .unwrap();
+//! assert_eq!(policies.retry_policy.max_retries(), 8);
+//! assert_eq!(policies.concurrency_policy.max_concurrent_requests, 50);
+//! ```
 
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tinyrand::RandRange;
+use tinyrand_std::thread_rand;
 use tokio::time::Duration;
 
-#[derive(Clone, Default)]
+use crate::{
+  cost::Spend,
+  error::{OrchError, Result as OrchResult},
+  models::Model,
+  moderation::ModerationCategory,
+};
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Policies {
-  pub retry_policy:       RetryPolicy,
-  pub concurrency_policy: ConcurrencyPolicy,
-  pub timeout_policy:     TimeoutPolicy,
+  pub retry_policy:        RetryPolicy,
+  pub retry_budget_policy: RetryBudgetPolicy,
+  pub concurrency_policy:  ConcurrencyPolicy,
+  pub timeout_policy:      TimeoutPolicy,
+  pub deadline_policy:     DeadlinePolicy,
+  pub rate_limit_policy:   RateLimitPolicy,
+  pub budget_policy:       BudgetPolicy,
+  pub moderation_policy:   ModerationPolicy,
+  pub truncation_policy:   TruncationPolicy,
+  pub guardrail_policy:    GuardrailPolicy,
+  pub context_policy:      ContextPolicy,
+  pub fallback_policy:     FallbackPolicy,
+  pub hedge_policy:        HedgePolicy,
+  pub tenant_policy:       TenantPolicy,
+  pub queue_policy:        QueuePolicy,
+}
+
+impl Policies {
+  /// Reads policies from JSON.
+  pub fn from_json(json: &str) -> serde_json::Result<Self> {
+    serde_json::from_str(json)
+  }
+
+  /// Reads policies from TOML.
+  #[cfg(feature = "toml")]
+  pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+    toml::from_str(toml)
+  }
+
+  /// Reads policies from YAML.
+  #[cfg(feature = "yaml")]
+  pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
+    serde_yaml::from_str(yaml)
+  }
 }
 
 /// A policy for configuring how requests should retry when they fail.
-#[derive(Clone)]
+///
+/// The policy is only configuration, shared by every request. Each request
+/// keeps track of its own retries in a `RetryState`, started with
+/// `RetryPolicy::start`. A `Custom` policy can't be written to or read from
+/// configuration.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum RetryPolicy {
   /// Retry the request immediately.
   Immediate {
-    /// The number of retries attempted so far.
-    current_retries: u32,
     /// The maximum number of retries to attempt.
-    max_retries:     u32,
+    max_retries: u32,
   },
   /// Retry the request after a delay.
   ConstantDelay {
-    /// The number of retries attempted so far.
-    current_retries: u32,
     /// The maximum number of retries to attempt.
-    max_retries:     u32,
+    max_retries: u32,
     /// The delay between retries.
-    delay:           Duration,
+    #[serde(with = "duration")]
+    delay:       Duration,
   },
   /// Retry the request after a delay, with exponential backoff.
   ExponentialBackoff {
-    /// The number of retries attempted so far.
-    current_retries: u32,
     /// The maximum number of retries to attempt.
-    max_retries:     u32,
+    max_retries:   u32,
     /// The initial delay between retries.
-    initial_delay:   Duration,
+    #[serde(with = "duration")]
+    initial_delay: Duration,
     /// The maximum delay between retries.
-    max_delay:       Duration,
+    #[serde(with = "duration")]
+    max_delay:     Duration,
+    /// The randomness added to each delay.
+    #[serde(default)]
+    jitter:        Jitter,
   },
+  /// Retry as a custom `RetryStrategy` decides. Unlike the other variants,
+  /// the strategy is asked about every error, including those which aren't
+  /// retryable.
+  #[serde(skip)]
+  Custom(Arc<dyn RetryStrategy>),
+}
+
+/// What to do after an attempt at a request fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryDecision {
+  /// Try again after the given delay.
+  Retry(Duration),
+  /// Give up with this key and send the request again with another key from
+  /// the `Orchestrator`'s pool, if it has one that hasn't been tried. With a
+  /// single key this is the same as `GiveUp`.
+  SwitchKey,
+  /// Give up and return the error.
+  GiveUp,
+}
+
+/// Custom retry logic, for decisions the built-in `RetryPolicy` variants
+/// can't express, such as depending on the error.
+///
+/// ```rust
+/// use async_trait::async_trait;
+/// use openai_orch::{
+///   error::OrchError,
+///   policies::{RetryDecision, RetryStrategy},
+/// };
+/// use tokio::time::Duration;
+///
+/// /// Retries rate limits forever, and everything else three times.
+/// struct Patient;
+///
+/// #[async_trait]
+/// impl RetryStrategy for Patient {
+///   async fn on_failure(&self, attempt: u32, error: &OrchError) -> RetryDecision {
+///     match error {
+///       OrchError::RateLimited { retry_after, .. } => RetryDecision::Retry(
+///         retry_after.unwrap_or(Duration::from_secs(5)),
+///       ),
+///       error if error.is_retryable() && attempt <= 3 => {
+///         RetryDecision::Retry(Duration::from_secs(1))
+///       }
+///       _ => RetryDecision::GiveUp,
+///     }
+///   }
+/// }
+/// ```
+#[async_trait]
+pub trait RetryStrategy: Send + Sync {
+  /// Decides what to do after the given attempt, counting from 1, failed
+  /// with `error`. A strategy is shared by every request, so any state it
+  /// keeps is too.
+  async fn on_failure(&self, attempt: u32, error: &OrchError) -> RetryDecision;
+}
+
+/// A policy for limiting the retries of all requests together, so that a
+/// widespread outage doesn't multiply every request by its `RetryPolicy`.
+///
+/// Within any `window`, the `Orchestrator`'s requests may retry
+/// `min_retries` times, plus `ratio` times for every request sent in it,
+/// e.g. once for every ten requests with a ratio of 0.1. Once the budget is
+/// spent, failures are returned straight away as
+/// `OrchError::RetryBudgetExhausted` instead of being retried, until older
+/// requests and retries have left the window. By default retries are not
+/// budgeted.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryBudgetPolicy {
+  /// The retries allowed for every request sent, or `None` for no budget.
+  pub ratio:       Option<f64>,
+  /// The retries allowed regardless of how many requests have been sent, so
+  /// that the first few failures can be retried.
+  pub min_retries: u64,
+  /// How far back requests and retries count towards the budget.
+  #[serde(with = "duration")]
+  pub window:      Duration,
+}
+
+impl Default for RetryBudgetPolicy {
+  fn default() -> Self {
+    Self {
+      ratio:       None,
+      min_retries: 0,
+      window:      Duration::from_secs(10),
+    }
+  }
+}
+
+impl RetryBudgetPolicy {
+  /// Returns a new retry budget policy allowing `ratio` retries per request,
+  /// and 10 retries regardless, within a window of 10 seconds.
+  pub fn ratio(ratio: f64) -> Self {
+    Self {
+      ratio: Some(ratio),
+      min_retries: 10,
+      ..Self::default()
+    }
+  }
+
+  /// Sets the retries allowed regardless of how many requests were sent.
+  pub fn with_min_retries(mut self, min_retries: u64) -> Self {
+    self.min_retries = min_retries;
+    self
+  }
+
+  /// Sets how far back requests and retries count towards the budget.
+  pub fn with_window(mut self, window: Duration) -> Self {
+    self.window = window;
+    self
+  }
+
+  /// Returns a new retry budget policy which doesn't limit retries.
+  pub fn unlimited() -> Self {
+    Self::default()
+  }
+}
+
+/// How much randomness to add to `ExponentialBackoff` delays, so that many
+/// requests failing at once don't all retry at the same moment.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+  /// Wait exactly the exponential delay.
+  #[default]
+  None,
+  /// Wait a random delay between zero and the exponential delay.
+  Full,
+  /// Wait a random delay between the initial delay and three times the
+  /// previous delay, capped at the maximum delay.
+  Decorrelated,
 }
 
 impl RetryPolicy {
+  /// The maximum number of retries to attempt. A `Custom` policy has no
+  /// maximum, so this is `u32::MAX`.
   pub fn max_retries(&self) -> u32 {
     match self {
-      RetryPolicy::Immediate { max_retries, .. } => *max_retries,
+      RetryPolicy::Immediate { max_retries } => *max_retries,
       RetryPolicy::ConstantDelay { max_retries, .. } => *max_retries,
       RetryPolicy::ExponentialBackoff { max_retries, .. } => *max_retries,
+      RetryPolicy::Custom(_) => u32::MAX,
     }
   }
 
-  /// Executes a retry policy, including incrementing the retry count and
-  /// delaying if necessary.
-  pub async fn failed_request(&mut self) -> bool {
+  /// The longest delay between retries, if the policy has one.
+  pub(crate) fn max_delay(&self) -> Option<Duration> {
     match self {
-      RetryPolicy::Immediate {
-        current_retries,
-        max_retries,
-      } => {
-        if *current_retries < *max_retries {
-          *current_retries += 1;
-          true
-        } else {
-          false
-        }
-      }
-      RetryPolicy::ConstantDelay {
-        current_retries,
-        max_retries,
-        delay,
-      } => {
-        if *current_retries < *max_retries {
-          *current_retries += 1;
-          tokio::time::sleep(*delay).await;
-          true
-        } else {
-          false
-        }
-      }
-      RetryPolicy::ExponentialBackoff {
-        current_retries,
-        max_retries,
-        initial_delay,
-        max_delay,
-      } => {
-        if *current_retries < *max_retries {
-          let delay =
-            exponential_backoff(*current_retries, *initial_delay, *max_delay);
-          *current_retries += 1;
-          tokio::time::sleep(delay).await;
-          true
-        } else {
-          false
-        }
-      }
+      RetryPolicy::ExponentialBackoff { max_delay, .. } => Some(*max_delay),
+      _ => None,
+    }
+  }
+
+  /// Returns the state of a request which hasn't retried yet.
+  pub fn start(&self) -> RetryState {
+    let last_delay = match self {
+      RetryPolicy::ExponentialBackoff { initial_delay, .. } => *initial_delay,
+      _ => Duration::ZERO,
+    };
+    RetryState {
+      policy: self.clone(),
+      retries: 0,
+      last_delay,
     }
   }
 
   /// Returns a new retry policy that will retry immediately, with a maximum
   /// number of retries.
   pub fn immediate(max_retries: u32) -> Self {
-    Self::Immediate {
-      current_retries: 0,
-      max_retries,
-    }
+    Self::Immediate { max_retries }
   }
 
   /// Returns a new retry policy that will retry after a constant delay, with a
   /// maximum number of retries.
   pub fn constant_delay(max_retries: u32, delay: Duration) -> Self {
-    Self::ConstantDelay {
-      current_retries: 0,
-      max_retries,
-      delay,
-    }
+    Self::ConstantDelay { max_retries, delay }
   }
 
   /// Returns a new retry policy that will retry after an exponentially
@@ -122,16 +303,116 @@ impl RetryPolicy {
     max_retries: u32,
     initial_delay: Duration,
     max_delay: Duration,
+  ) -> Self {
+    Self::exponential_backoff_with_jitter(
+      max_retries,
+      initial_delay,
+      max_delay,
+      Jitter::None,
+    )
+  }
+
+  /// Returns a new retry policy that will retry after an exponentially
+  /// increasing delay with the given jitter, with a maximum number of retries,
+  /// and a maximum delay.
+  pub fn exponential_backoff_with_jitter(
+    max_retries: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: Jitter,
   ) -> Self {
     Self::ExponentialBackoff {
-      current_retries: 0,
       max_retries,
       initial_delay,
       max_delay,
+      jitter,
     }
   }
 }
 
+/// How far one request has got through its `RetryPolicy`.
+#[derive(Clone)]
+pub struct RetryState {
+  policy:     RetryPolicy,
+  retries:    u32,
+  /// The delay before the previous retry, which decorrelated jitter builds
+  /// on.
+  last_delay: Duration,
+}
+
+impl RetryState {
+  /// The policy being followed.
+  pub fn policy(&self) -> &RetryPolicy {
+    &self.policy
+  }
+
+  /// The number of retries attempted so far.
+  pub fn retries(&self) -> u32 {
+    self.retries
+  }
+
+  /// The number of attempts so far, counting the first.
+  pub fn attempts(&self) -> u32 {
+    self.retries + 1
+  }
+
+  /// Executes a retry policy, including incrementing the retry count and
+  /// delaying if necessary.
+  pub async fn failed_request(&mut self) -> bool {
+    match self.next_delay() {
+      Some(delay) => {
+        if !delay.is_zero() {
+          tokio::time::sleep(delay).await;
+        }
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Increments the retry count and returns how long to wait before the next
+  /// retry, or `None` if there are no retries left. A `Custom` policy always
+  /// returns `None`, since its strategy needs the error to decide.
+  pub fn next_delay(&mut self) -> Option<Duration> {
+    if self.retries >= self.policy.max_retries() {
+      return None;
+    }
+    let delay = match &self.policy {
+      RetryPolicy::Immediate { .. } => Duration::ZERO,
+      RetryPolicy::ConstantDelay { delay, .. } => *delay,
+      RetryPolicy::ExponentialBackoff {
+        initial_delay,
+        max_delay,
+        jitter,
+        ..
+      } => match jitter {
+        Jitter::None => {
+          exponential_backoff(self.retries, *initial_delay, *max_delay)
+        }
+        Jitter::Full => random_between(
+          Duration::ZERO,
+          exponential_backoff(self.retries, *initial_delay, *max_delay),
+        ),
+        Jitter::Decorrelated => {
+          random_between(*initial_delay, self.last_delay.saturating_mul(3))
+            .min(*max_delay)
+        }
+      },
+      RetryPolicy::Custom(_) => return None,
+    };
+    self.retries += 1;
+    self.last_delay = delay;
+    Some(delay)
+  }
+
+  /// Counts a retry which was decided on outside of `next_delay`, such as by
+  /// a `RetryStrategy`.
+  pub(crate) fn record_retry(&mut self, delay: Duration) {
+    self.retries += 1;
+    self.last_delay = delay;
+  }
+}
+
 impl Default for RetryPolicy {
   fn default() -> Self {
     Self::exponential_backoff(
@@ -147,52 +428,857 @@ fn exponential_backoff(
   initial_delay: Duration,
   max_delay: Duration,
 ) -> Duration {
-  let delay = initial_delay * 2u32.pow(current_retries);
-  if delay > max_delay {
-    max_delay
+  // a delay too long to represent is capped like any other
+  2u32
+    .checked_pow(current_retries)
+    .and_then(|factor| initial_delay.checked_mul(factor))
+    .map_or(max_delay, |delay| delay.min(max_delay))
+}
+
+fn random_between(low: Duration, high: Duration) -> Duration {
+  if high <= low {
+    low
   } else {
-    delay
+    thread_rand().next_range(low..high)
   }
 }
 
 /// A policy for configuring how many requests can be executed concurrently.
-#[derive(Clone)]
+///
+/// Optionally, the policy can also limit the number of tokens dispatched per
+/// minute, to stay under OpenAI's tokens-per-minute rate limits. Each request
+/// is weighted by its `OrchRequest::estimated_tokens`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ConcurrencyPolicy {
   pub max_concurrent_requests: usize,
+  pub max_tokens_per_minute:   Option<u64>,
+  /// When set, `max_concurrent_requests` is only the starting limit, which is
+  /// then adjusted as responses come back.
+  pub adaptive:                Option<AdaptiveConcurrency>,
 }
 
 impl ConcurrencyPolicy {
   pub fn new(n: usize) -> Self {
     Self {
       max_concurrent_requests: n,
+      ..Default::default()
     }
   }
+
+  /// Returns a new concurrency policy that limits the number of tokens
+  /// dispatched per minute, with the default number of concurrent requests.
+  pub fn tokens_per_minute(n: u64) -> Self {
+    Self::default().with_tokens_per_minute(n)
+  }
+
+  /// Limits the number of tokens dispatched per minute.
+  pub fn with_tokens_per_minute(mut self, n: u64) -> Self {
+    self.max_tokens_per_minute = Some(n);
+    self
+  }
+
+  /// Returns a new concurrency policy which starts at `max` concurrent
+  /// requests and adapts between `min` and `max`, with the default
+  /// `AdaptiveConcurrency` settings.
+  pub fn adaptive(min: usize, max: usize) -> Self {
+    Self::new(max).with_adaptive(AdaptiveConcurrency::new(min, max))
+  }
+
+  /// Adapts the concurrency limit to the responses from the API, starting
+  /// from `max_concurrent_requests`.
+  pub fn with_adaptive(mut self, adaptive: AdaptiveConcurrency) -> Self {
+    self.adaptive = Some(adaptive);
+    self
+  }
 }
 
 impl Default for ConcurrencyPolicy {
   fn default() -> Self {
     Self {
       max_concurrent_requests: 10,
+      max_tokens_per_minute:   None,
+      adaptive:                None,
     }
   }
 }
 
-#[derive(Clone)]
-pub struct TimeoutPolicy {
-  pub timeout: Duration,
+/// Settings for adapting the concurrency limit with AIMD (additive increase,
+/// multiplicative decrease).
+///
+/// Whenever an attempt at a request fails with a 429 or a 5xx, including
+/// attempts which are retried, the limit is multiplied by `backoff_factor`.
+/// Every successful attempt grows the limit by
+/// `1 / limit`, so it climbs by roughly one request per round of successes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdaptiveConcurrency {
+  /// The lowest the limit can shrink to.
+  pub min_concurrent_requests: usize,
+  /// The highest the limit can grow to.
+  pub max_concurrent_requests: usize,
+  /// The factor the limit is multiplied by when the API is overloaded.
+  #[serde(default = "default_backoff_factor")]
+  pub backoff_factor:          f64,
+  /// How long to wait after shrinking the limit before shrinking it again,
+  /// so that a burst of failures from the same round of requests only counts
+  /// once.
+  #[serde(default = "default_cooldown", with = "duration")]
+  pub cooldown:                Duration,
+}
+
+fn default_backoff_factor() -> f64 {
+  0.5
+}
+
+fn default_cooldown() -> Duration {
+  Duration::from_secs(1)
+}
+
+impl AdaptiveConcurrency {
+  /// Returns new adaptive settings bounded by `min` and `max`, which halve the
+  /// limit at most once per second.
+  pub fn new(min: usize, max: usize) -> Self {
+    Self {
+      min_concurrent_requests: min.max(1),
+      max_concurrent_requests: max.max(min).max(1),
+      backoff_factor:          default_backoff_factor(),
+      cooldown:                default_cooldown(),
+    }
+  }
+}
+
+/// A policy for configuring how long each attempt at a request may take.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TimeoutPolicy {
+  /// Every attempt may take up to `timeout`.
+  Fixed {
+    #[serde(with = "duration")]
+    timeout: Duration,
+  },
+  /// Attempts may take `base` plus `per_token` for each token they are
+  /// expected to process, up to `cap`. Chat requests count their prompt and
+  /// completion limit, and embedding requests their input. Other requests,
+  /// and chat requests without a completion limit, may take up to `cap`.
+  Adaptive {
+    #[serde(with = "duration")]
+    per_token: Duration,
+    #[serde(with = "duration")]
+    base:      Duration,
+    #[serde(with = "duration")]
+    cap:       Duration,
+  },
 }
 
 impl TimeoutPolicy {
   /// Returns a new timeout policy with the given timeout.
   pub fn new(timeout: Duration) -> Self {
-    Self { timeout }
+    Self::Fixed { timeout }
+  }
+
+  /// Returns a new timeout policy which scales with the size of the request.
+  pub fn adaptive(per_token: Duration, base: Duration, cap: Duration) -> Self {
+    Self::Adaptive {
+      per_token,
+      base,
+      cap,
+    }
+  }
+
+  /// The longest an attempt may take.
+  pub fn timeout(&self) -> Duration {
+    match self {
+      TimeoutPolicy::Fixed { timeout } => *timeout,
+      TimeoutPolicy::Adaptive { cap, .. } => *cap,
+    }
+  }
+
+  /// How long an attempt at a request expected to process `tokens` tokens
+  /// may take.
+  pub fn timeout_for(&self, tokens: u64) -> Duration {
+    match self {
+      TimeoutPolicy::Fixed { timeout } => *timeout,
+      TimeoutPolicy::Adaptive {
+        per_token,
+        base,
+        cap,
+      } => per_token
+        .saturating_mul(u32::try_from(tokens).unwrap_or(u32::MAX))
+        .saturating_add(*base)
+        .min(*cap),
+    }
   }
 }
 
 impl Default for TimeoutPolicy {
+  /// Allows 5s plus 10s for every 512 tokens, up to 30s.
   fn default() -> Self {
+    Self::adaptive(
+      Duration::from_secs(10) / 512,
+      Duration::from_secs(5),
+      Duration::from_secs(30),
+    )
+  }
+}
+
+/// A policy for limiting the total time a request may take, from being added
+/// to the `Orchestrator` until its response is ready.
+///
+/// Unlike the `TimeoutPolicy`, which limits each attempt, the deadline covers
+/// the time spent queued and every retry. Requests which miss it fail with
+/// `OrchError::DeadlineExceeded`. By default there is no deadline.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeadlinePolicy {
+  #[serde(with = "duration::option")]
+  pub deadline: Option<Duration>,
+}
+
+impl DeadlinePolicy {
+  /// Returns a new deadline policy with the given deadline.
+  pub fn new(deadline: Duration) -> Self {
+    Self {
+      deadline: Some(deadline),
+    }
+  }
+
+  /// Returns a new deadline policy which lets requests take as long as they
+  /// need.
+  pub fn unlimited() -> Self {
+    Self::default()
+  }
+}
+
+/// A policy for configuring how many requests can be dispatched per minute,
+/// independently of how many run concurrently.
+///
+/// Requests beyond the limit are held back until the rate allows them, rather
+/// than being sent and rejected with a 429.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitPolicy {
+  pub max_requests_per_minute: Option<u64>,
+}
+
+impl RateLimitPolicy {
+  /// Returns a new rate limit policy allowing `n` requests per minute.
+  pub fn requests_per_minute(n: u64) -> Self {
     Self {
-      timeout: Duration::from_secs(30),
+      max_requests_per_minute: Some(n),
+    }
+  }
+
+  /// Returns a new rate limit policy which doesn't limit the request rate.
+  pub fn unlimited() -> Self {
+    Self::default()
+  }
+}
+
+/// A policy for sharing an `Orchestrator` between tenants, which add their
+/// requests with `Orchestrator::add_request_for_tenant`.
+///
+/// Queued requests of the same priority are started in weighted fair order
+/// across tenants, so that a tenant with a large backlog can't starve the
+/// others: each tenant with waiting requests gets a share of the free slots
+/// in proportion to its `weight`. Each tenant's own concurrency and rate
+/// limits apply on top of the `ConcurrencyPolicy` and `RateLimitPolicy`.
+/// Requests added without a tenant are treated as one more tenant, with the
+/// default quota.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantPolicy {
+  /// The quota of every tenant not listed in `tenants`.
+  pub default_quota: TenantQuota,
+  /// The quotas of individual tenants, by name.
+  pub tenants:       HashMap<String, TenantQuota>,
+}
+
+impl TenantPolicy {
+  /// Sets the quota of every tenant without a quota of its own.
+  pub fn with_default_quota(mut self, quota: TenantQuota) -> Self {
+    self.default_quota = quota;
+    self
+  }
+
+  /// Sets the quota of the named tenant.
+  pub fn with_tenant(
+    mut self,
+    tenant: impl Into<String>,
+    quota: TenantQuota,
+  ) -> Self {
+    self.tenants.insert(tenant.into(), quota);
+    self
+  }
+
+  /// The quota of the given tenant, or of requests without a tenant.
+  pub fn quota(&self, tenant: Option<&str>) -> &TenantQuota {
+    tenant
+      .and_then(|tenant| self.tenants.get(tenant))
+      .unwrap_or(&self.default_quota)
+  }
+}
+
+/// The share of an `Orchestrator` given to a tenant by the `TenantPolicy`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TenantQuota {
+  /// The tenant's share of free slots, relative to the weights of the other
+  /// tenants with waiting requests. A weight of 0 is treated as 1.
+  pub weight:                  u32,
+  /// The most requests of the tenant which may run at once.
+  pub max_concurrent_requests: Option<usize>,
+  /// The most requests of the tenant which may be dispatched per minute.
+  pub max_requests_per_minute: Option<u64>,
+}
+
+impl TenantQuota {
+  /// Returns a new quota with a weight of 1 and no limits.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Sets the tenant's share of free slots.
+  pub fn with_weight(mut self, weight: u32) -> Self {
+    self.weight = weight;
+    self
+  }
+
+  /// Limits how many requests of the tenant may run at once.
+  pub fn with_max_concurrent_requests(mut self, n: usize) -> Self {
+    self.max_concurrent_requests = Some(n);
+    self
+  }
+
+  /// Limits how many requests of the tenant may be dispatched per minute.
+  pub fn with_max_requests_per_minute(mut self, n: u64) -> Self {
+    self.max_requests_per_minute = Some(n);
+    self
+  }
+}
+
+impl Default for TenantQuota {
+  fn default() -> Self {
+    Self {
+      weight:                  1,
+      max_concurrent_requests: None,
+      max_requests_per_minute: None,
+    }
+  }
+}
+
+/// A policy for bounding how many requests may be queued, waiting to start.
+///
+/// Every request added to an `Orchestrator` holds on to its memory until it
+/// starts, so adding millions of requests at once can exhaust it. With a
+/// limit, requests beyond it either wait in `Orchestrator::add_request` for
+/// a place, or fail with `OrchError::QueueFull`. By default the queue is
+/// unbounded.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueuePolicy {
+  /// The most requests which may be waiting to start.
+  pub max_queued: Option<usize>,
+  /// What happens to requests added while the queue is full.
+  pub when_full:  Backpressure,
+}
+
+impl QueuePolicy {
+  /// Returns a new queue policy allowing `n` queued requests, where adding
+  /// another waits for a place.
+  pub fn bounded(n: usize) -> Self {
+    Self {
+      max_queued: Some(n),
+      when_full:  Backpressure::Wait,
+    }
+  }
+
+  /// Returns a new queue policy allowing `n` queued requests, where adding
+  /// another fails with `OrchError::QueueFull`.
+  pub fn rejecting(n: usize) -> Self {
+    Self {
+      max_queued: Some(n),
+      when_full:  Backpressure::Reject,
+    }
+  }
+
+  /// Returns a new queue policy without a limit.
+  pub fn unbounded() -> Self {
+    Self::default()
+  }
+}
+
+/// What the `QueuePolicy` does with requests added while the queue is full.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+  /// Adding the request waits until there is a place in the queue.
+  #[default]
+  Wait,
+  /// The request fails with `OrchError::QueueFull`.
+  Reject,
+}
+
+/// A policy for capping what a run of requests may spend.
+///
+/// Once the `Orchestrator`'s `Spend` reaches either limit, requests that
+/// haven't started yet fail with `OrchError::BudgetExceeded` instead of being
+/// sent. Requests already in flight are allowed to finish, so the final spend
+/// can overshoot the cap slightly.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BudgetPolicy {
+  pub max_cost_usd:     Option<f64>,
+  pub max_total_tokens: Option<u64>,
+}
+
+impl BudgetPolicy {
+  /// Returns a new budget policy that stops once `usd` has been spent.
+  pub fn max_cost_usd(usd: f64) -> Self {
+    Self {
+      max_cost_usd: Some(usd),
+      ..Default::default()
+    }
+  }
+
+  /// Returns a new budget policy that stops once `n` tokens have been used.
+  pub fn max_total_tokens(n: u64) -> Self {
+    Self {
+      max_total_tokens: Some(n),
+      ..Default::default()
+    }
+  }
+
+  /// Returns a new budget policy without any limits.
+  pub fn unlimited() -> Self {
+    Self::default()
+  }
+
+  /// Whether the given spend has reached either limit.
+  pub fn is_exceeded(&self, spend: &Spend) -> bool {
+    self.max_cost_usd.is_some_and(|max| spend.cost_usd >= max)
+      || self
+        .max_total_tokens
+        .is_some_and(|max| spend.total_tokens() >= max)
+  }
+}
+
+/// A policy for screening chat requests with the Moderation API before they
+/// are sent.
+///
+/// When any categories are given, the user messages of every chat request
+/// are checked first, and requests flagged in one of those categories fail
+/// with `OrchError::ContentFlagged` instead of being sent. By default no
+/// categories are given, and requests are not screened.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModerationPolicy {
+  /// The categories which stop a request from being sent.
+  pub categories: Vec<ModerationCategory>,
+  /// The moderation model to screen requests with.
+  pub model:      String,
+}
+
+impl ModerationPolicy {
+  /// Returns a new moderation policy which stops requests flagged in any of
+  /// the given categories.
+  pub fn categories(
+    categories: impl IntoIterator<Item = ModerationCategory>,
+  ) -> Self {
+    Self {
+      categories: categories.into_iter().collect(),
+      ..Default::default()
+    }
+  }
+
+  /// Returns a new moderation policy which stops requests flagged in any
+  /// category.
+  pub fn any_category() -> Self {
+    Self::categories(ModerationCategory::ALL)
+  }
+
+  /// Returns a new moderation policy which doesn't screen requests.
+  pub fn disabled() -> Self {
+    Self::default()
+  }
+
+  /// Whether requests are screened at all.
+  pub fn is_enabled(&self) -> bool {
+    !self.categories.is_empty()
+  }
+}
+
+impl Default for ModerationPolicy {
+  fn default() -> Self {
+    Self {
+      categories: Vec::new(),
+      model:      String::from("omni-moderation-latest"),
+    }
+  }
+}
+
+/// A policy for chat completions which were cut off because they reached
+/// `max_tokens`, as told by a `finish_reason` of `Length`.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TruncationPolicy {
+  /// Truncated completions are returned like any other. Check the response's
+  /// `finish_reason` to tell them apart.
+  #[default]
+  Allow,
+  /// Truncated completions fail with `OrchError::Truncated`. Streamed
+  /// completions are not checked, since their content has already been read
+  /// by the time the stream finishes.
+  Error,
+  /// Truncated completions of a `ChatMimoRequest` or `ChatSisoRequest` are
+  /// continued by sending the partial completion back with a request to carry
+  /// on, up to `max_continuations` times, and the pieces are joined into one
+  /// response. If the completion is still truncated after that, it is
+  /// returned as it is. Other requests treat this like `Allow`.
+  Continue { max_continuations: u32 },
+}
+
+/// A policy for checking the replies of chat requests before they are
+/// returned, for output which is fed straight into other systems.
+///
+/// The guardrails apply to the content of the replies of `ChatMimoRequest`s
+/// and `ChatSisoRequest`s, and of everything built on them, such as
+/// sessions and agents. Replies with tool calls aren't checked. By default
+/// there are no guardrails.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuardrailPolicy {
+  /// The most characters a reply may have.
+  pub max_chars:         Option<usize>,
+  /// Substrings a reply may not contain. These are matched exactly; use a
+  /// pattern with `(?i)` to ignore case.
+  pub banned_substrings: Vec<String>,
+  /// Regular expressions a reply may not match anywhere. They are compiled
+  /// when the policy is built or deserialized, so an invalid pattern is
+  /// rejected there rather than failing requests.
+  pub banned_patterns:   Vec<BannedPattern>,
+  /// A prefix every reply must start with.
+  pub required_prefix:   Option<String>,
+  /// What happens to replies which break a guardrail.
+  pub on_violation:      GuardrailAction,
+}
+
+/// A regular expression of the `GuardrailPolicy`, compiled once. It is
+/// written as the pattern in configuration.
+#[derive(Clone)]
+pub struct BannedPattern(Regex);
+
+impl BannedPattern {
+  /// Compiles `pattern`, failing if it isn't a valid regex.
+  pub fn new(pattern: &str) -> OrchResult<Self> {
+    Regex::new(pattern).map(Self).map_err(OrchError::other)
+  }
+
+  /// Returns the pattern the regex was compiled from.
+  pub fn as_str(&self) -> &str {
+    self.0.as_str()
+  }
+}
+
+impl From<Regex> for BannedPattern {
+  fn from(regex: Regex) -> Self {
+    Self(regex)
+  }
+}
+
+impl std::fmt::Debug for BannedPattern {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Debug::fmt(self.as_str(), f)
+  }
+}
+
+impl PartialEq for BannedPattern {
+  fn eq(&self, other: &Self) -> bool {
+    self.as_str() == other.as_str()
+  }
+}
+
+impl Eq for BannedPattern {}
+
+impl Serialize for BannedPattern {
+  fn serialize<S: serde::Serializer>(
+    &self,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(self.as_str())
+  }
+}
+
+impl<'de> Deserialize<'de> for BannedPattern {
+  fn deserialize<D: serde::Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern)
+      .map(Self)
+      .map_err(serde::de::Error::custom)
+  }
+}
+
+/// What the `GuardrailPolicy` does with a reply which breaks a guardrail.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum GuardrailAction {
+  /// The request fails with `OrchError::GuardrailViolated`.
+  #[default]
+  Error,
+  /// The attempt fails with `OrchError::GuardrailViolated`, and is retried
+  /// following the `RetryPolicy`, to sample another reply.
+  Retry,
+  /// The reply is cut off before the first content which breaks a
+  /// guardrail, i.e. at `max_chars` or before a banned substring or
+  /// pattern. A reply without the required prefix can't be fixed this way,
+  /// and fails as with `Error`.
+  Truncate,
+}
+
+/// How a reply breaks the `GuardrailPolicy`.
+pub(crate) struct Violation {
+  /// Which guardrail the reply breaks first.
+  pub(crate) reason:      String,
+  /// The length of the reply before the first content which breaks a
+  /// guardrail, or `None` if truncating it wouldn't help.
+  pub(crate) valid_up_to: Option<usize>,
+}
+
+impl GuardrailPolicy {
+  /// Returns a new guardrail policy which limits replies to `n` characters.
+  pub fn max_chars(n: usize) -> Self {
+    Self {
+      max_chars: Some(n),
+      ..Default::default()
+    }
+  }
+
+  /// Returns a new guardrail policy which bans the given substrings.
+  pub fn banned_substrings(
+    substrings: impl IntoIterator<Item = impl Into<String>>,
+  ) -> Self {
+    Self {
+      banned_substrings: substrings.into_iter().map(Into::into).collect(),
+      ..Default::default()
+    }
+  }
+
+  /// Returns a new guardrail policy without any guardrails.
+  pub fn disabled() -> Self {
+    Self::default()
+  }
+
+  /// Bans replies matching the given regular expression. Fails if the
+  /// pattern isn't a valid regex.
+  pub fn with_banned_pattern(mut self, pattern: &str) -> OrchResult<Self> {
+    self.banned_patterns.push(BannedPattern::new(pattern)?);
+    Ok(self)
+  }
+
+  /// Bans replies matching `regex`.
+  pub fn with_banned_regex(mut self, regex: Regex) -> Self {
+    self.banned_patterns.push(regex.into());
+    self
+  }
+
+  /// Requires replies to start with `prefix`.
+  pub fn with_required_prefix(mut self, prefix: impl Into<String>) -> Self {
+    self.required_prefix = Some(prefix.into());
+    self
+  }
+
+  /// Sets what happens to replies which break a guardrail.
+  pub fn with_action(mut self, on_violation: GuardrailAction) -> Self {
+    self.on_violation = on_violation;
+    self
+  }
+
+  /// Whether replies are checked at all.
+  pub fn is_enabled(&self) -> bool {
+    self.max_chars.is_some()
+      || !self.banned_substrings.is_empty()
+      || !self.banned_patterns.is_empty()
+      || self.required_prefix.is_some()
+  }
+
+  /// Returns how `content` breaks the guardrails, if it does.
+  pub(crate) fn violation(&self, content: &str) -> Option<Violation> {
+    if let Some(prefix) = &self.required_prefix {
+      if !content.starts_with(prefix.as_str()) {
+        return Some(Violation {
+          reason:      format!("the reply doesn't start with {:?}", prefix),
+          valid_up_to: None,
+        });
+      }
+    }
+
+    // the earliest offending content decides where a reply is cut off
+    let mut first: Option<(usize, String)> = None;
+    let mut offend = |at: usize, reason: String| {
+      if first.as_ref().is_none_or(|(first, _)| at < *first) {
+        first = Some((at, reason));
+      }
+    };
+    if let Some(max_chars) = self.max_chars {
+      if let Some((at, _)) = content.char_indices().nth(max_chars) {
+        offend(
+          at,
+          format!("the reply is longer than {} characters", max_chars),
+        );
+      }
+    }
+    for substring in &self.banned_substrings {
+      if let Some(at) = content.find(substring.as_str()) {
+        offend(at, format!("the reply contains {:?}", substring));
+      }
+    }
+    for pattern in &self.banned_patterns {
+      if let Some(found) = pattern.0.find(content) {
+        offend(
+          found.start(),
+          format!("the reply matches `{}`", pattern.as_str()),
+        );
+      }
+    }
+    first.map(|(at, reason)| Violation {
+      reason,
+      valid_up_to: Some(at),
+    })
+  }
+}
+
+/// A policy for chat requests whose prompt and completion would not fit in
+/// the model's context window.
+///
+/// Prompts are counted exactly with the `tokens` feature, and otherwise
+/// estimated at roughly four characters a token. Only models whose context
+/// window is known are checked: those of `Model`, and with the `tokens`
+/// feature, any other that `tiktoken` knows. The leading system messages and
+/// the newest message are never dropped, and tool calls are dropped together
+/// with their results. If the prompt still doesn't fit, the request fails.
+#[derive(
+  Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextPolicy {
+  /// Requests that don't fit fail with `OrchError::ContextLengthExceeded`
+  /// before they are sent.
+  #[default]
+  Fail,
+  /// The oldest messages are dropped until the request fits.
+  TruncateOldest,
+  /// Messages from the middle of the conversation are dropped until the
+  /// request fits, keeping the opening turns which often set up the task.
+  TruncateMiddle,
+}
+
+/// A policy for trying other models when a chat request's model keeps
+/// failing, for example during a model-specific outage.
+///
+/// When a chat request gives up after its `RetryPolicy`, or the API reports
+/// that its model doesn't exist, it is sent again with each of the `models`
+/// in turn, each with a fresh set of retries. The model which served the
+/// response is reported in its `ResponseMeta`. By default no models are
+/// given, and requests don't fall back.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FallbackPolicy {
+  /// The models to try, in order, after the request's own model.
+  pub models: Vec<Model>,
+}
+
+impl FallbackPolicy {
+  /// Returns a new fallback policy which tries the given models in order.
+  pub fn models(models: impl IntoIterator<Item = impl Into<Model>>) -> Self {
+    Self {
+      models: models.into_iter().map(Into::into).collect(),
+    }
+  }
+
+  /// Returns a new fallback policy which doesn't try other models.
+  pub fn disabled() -> Self {
+    Self::default()
+  }
+}
+
+/// A policy for cutting tail latency by sending a duplicate of a request
+/// which is taking too long.
+///
+/// When a `delay` is given and a request hasn't finished by then, the same
+/// request is sent again alongside it. Whichever finishes first is returned
+/// and the other is cancelled, unless the first to finish failed, in which
+/// case the other is waited for. The duplicate shares the original's place in
+/// the `ConcurrencyPolicy`, but its tokens are paid for like any other
+/// request's. By default requests are not hedged.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HedgePolicy {
+  /// How long to wait for a request before sending a duplicate.
+  #[serde(with = "duration::option")]
+  pub delay: Option<Duration>,
+}
+
+impl HedgePolicy {
+  /// Returns a new hedge policy which sends a duplicate of any request that
+  /// hasn't finished after `delay`.
+  pub fn after(delay: Duration) -> Self {
+    Self { delay: Some(delay) }
+  }
+
+  /// Returns a new hedge policy which doesn't send duplicates.
+  pub fn disabled() -> Self {
+    Self::default()
+  }
+}
+
+/// Writes durations as human-readable strings such as `"1m 30s"`.
+mod duration {
+  use serde::{de::Error, Deserialize, Deserializer, Serializer};
+  use tokio::time::Duration;
+
+  pub(super) fn serialize<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_duration(*duration))
+  }
+
+  pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Duration, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration).map_err(D::Error::custom)
+  }
+
+  /// The same, for optional durations.
+  pub(super) mod option {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use tokio::time::Duration;
+
+    pub(in super::super) fn serialize<S: Serializer>(
+      duration: &Option<Duration>,
+      serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+      match duration {
+        Some(duration) => super::serialize(duration, serializer),
+        None => serializer.serialize_none(),
+      }
+    }
+
+    pub(in super::super) fn deserialize<'de, D: Deserializer<'de>>(
+      deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+      #[derive(Deserialize)]
+      struct Wrapper(#[serde(with = "super")] Duration);
+      let duration = Option::<Wrapper>::deserialize(deserializer)?;
+      Ok(duration.map(|Wrapper(duration)| duration))
     }
   }
 }
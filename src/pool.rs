@@ -0,0 +1,179 @@
+//! The pool of keys that requests are distributed across.
+
+use std::sync::{
+  atomic::{AtomicUsize, Ordering},
+  Arc, Mutex,
+};
+
+use tokio::time::{Duration, Instant};
+
+use crate::{
+  keys::{KeyBalancing, KeyStatus, Keys},
+  limiter::TokenBucket,
+  policies::Policies,
+};
+
+/// How long an unhealthy key is left alone before a request is sent with it
+/// again, to see whether it has recovered.
+const REPROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Entry {
+  keys:      Keys,
+  in_flight: AtomicUsize,
+  /// When the key was last rejected or probed, if it is unhealthy.
+  unhealthy: Mutex<Option<Instant>>,
+  requests:  Option<TokenBucket>,
+  tokens:    Option<TokenBucket>,
+}
+
+impl Entry {
+  fn unhealthy(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+    self.unhealthy.lock().expect("key pool lock poisoned")
+  }
+
+  /// Whether the key can be used now, either because it is healthy or
+  /// because it is due to be probed.
+  fn is_available(&self, now: Instant) -> bool {
+    self
+      .unhealthy()
+      .is_none_or(|at| now.duration_since(at) >= REPROBE_INTERVAL)
+  }
+}
+
+/// A set of keys, each with its own rate limits, since OpenAI accounts for
+/// rate limits per organization.
+pub(crate) struct KeyPool {
+  entries:   Vec<Entry>,
+  balancing: KeyBalancing,
+  next:      AtomicUsize,
+}
+
+impl KeyPool {
+  /// Returns a pool of the given keys. Panics if `keys` is empty.
+  pub(crate) fn new(
+    keys: Vec<Keys>,
+    balancing: KeyBalancing,
+    policies: &Policies,
+  ) -> Arc<Self> {
+    assert!(!keys.is_empty(), "an Orchestrator needs at least one key");
+    let entries = keys
+      .into_iter()
+      .map(|keys| Entry {
+        keys,
+        in_flight: AtomicUsize::new(0),
+        unhealthy: Mutex::new(None),
+        requests: policies
+          .rate_limit_policy
+          .max_requests_per_minute
+          .map(TokenBucket::per_minute),
+        tokens: policies
+          .concurrency_policy
+          .max_tokens_per_minute
+          .map(TokenBucket::per_minute),
+      })
+      .collect();
+    Arc::new(Self {
+      entries,
+      balancing,
+      next: AtomicUsize::new(0),
+    })
+  }
+
+  /// Picks a key for a request, and waits for the request and its `tokens`
+  /// to fit within that key's rate limits.
+  pub(crate) async fn acquire(self: &Arc<Self>, tokens: u64) -> KeyLease {
+    let index = self.pick();
+    let entry = &self.entries[index];
+    entry.in_flight.fetch_add(1, Ordering::SeqCst);
+    let lease = KeyLease {
+      pool: self.clone(),
+      index,
+    };
+
+    if let Some(bucket) = &entry.requests {
+      bucket.acquire(1).await;
+    }
+    if let Some(bucket) = &entry.tokens {
+      bucket.acquire(tokens).await;
+    }
+    lease
+  }
+
+  /// The number of keys in the pool.
+  pub(crate) fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Whether any key can be used right now.
+  pub(crate) fn any_available(&self) -> bool {
+    let now = Instant::now();
+    self.entries.iter().any(|entry| entry.is_available(now))
+  }
+
+  pub(crate) fn status(&self) -> Vec<KeyStatus> {
+    self
+      .entries
+      .iter()
+      .enumerate()
+      .map(|(index, entry)| KeyStatus {
+        index,
+        openai_org_id: entry.keys.openai_org_id.clone(),
+        healthy: entry.unhealthy().is_none(),
+        in_flight: entry.in_flight.load(Ordering::SeqCst),
+      })
+      .collect()
+  }
+
+  fn pick(&self) -> usize {
+    let len = self.entries.len();
+    let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+    let now = Instant::now();
+
+    // starting from the round-robin position spreads out ties
+    let mut available = (0..len)
+      .map(|offset| (start + offset) % len)
+      .filter(|&index| self.entries[index].is_available(now));
+    let picked = match self.balancing {
+      KeyBalancing::RoundRobin => available.next(),
+      KeyBalancing::LeastInFlight => available.min_by_key(|&index| {
+        self.entries[index].in_flight.load(Ordering::SeqCst)
+      }),
+    };
+    // when every key is unhealthy, keep trying them rather than stalling
+    let index = picked.unwrap_or(start);
+
+    // if the key is being probed, hold off other probes for another interval
+    let mut unhealthy = self.entries[index].unhealthy();
+    if unhealthy.is_some() {
+      *unhealthy = Some(now);
+    }
+    index
+  }
+}
+
+/// The key chosen for a request, which counts as in flight until the lease is
+/// dropped.
+pub(crate) struct KeyLease {
+  pool:  Arc<KeyPool>,
+  index: usize,
+}
+
+impl KeyLease {
+  pub(crate) fn keys(&self) -> &Keys {
+    &self.pool.entries[self.index].keys
+  }
+
+  /// Marks the key healthy or unhealthy according to whether the API
+  /// accepted it.
+  pub(crate) fn record(&self, rejected: bool) {
+    *self.pool.entries[self.index].unhealthy() = rejected.then(Instant::now);
+  }
+}
+
+impl Drop for KeyLease {
+  fn drop(&mut self) {
+    self.pool.entries[self.index]
+      .in_flight
+      .fetch_sub(1, Ordering::SeqCst);
+  }
+}
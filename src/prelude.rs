@@ -1,8 +1,22 @@
 //! Provides a useful collection of `openai-orch` types
 
 pub use crate::{
-  chat::siso::{ChatSisoRequest, ChatSisoResponse},
-  keys::Keys,
+  cache::{CacheStore, MemoryCache},
+  chat::{
+    ensemble::{ChatEnsembleRequest, ChatEnsembleResponse},
+    mimo::{ChatMimoRequest, ChatMimoResponse},
+    session::{ChatSession, HistoryStrategy},
+    simo::{ChatChoice, ChatSimoRequest, ChatSimoResponse},
+    siso::{ChatSisoRequest, ChatSisoResponse},
+    stream::{ChatSisoStreamRequest, ChatSisoStreamResponse},
+    structured::{ChatStructuredRequest, ChatStructuredResponse},
+    ChatFinishReason, ChatImage, ChatMessage, ChatReasoningEffort, ChatReply,
+    ChatResponse, ChatResponseFormat, ChatRole, ImageDetail,
+  },
+  error::OrchError,
+  hooks::OrchestratorHooks,
+  keys::{KeyBalancing, KeyStatus, Keys, Provider},
+  models::Model,
   policies::Policies,
-  Orchestrator,
-};
\ No newline at end of file
+  Orchestrator, Priority,
+};
@@ -0,0 +1,82 @@
+//! Progress reporting for requests sent through the `Orchestrator`.
+
+use tokio::sync::watch;
+
+use crate::telemetry;
+
+/// A snapshot of the state of every request added to an `Orchestrator`.
+///
+/// Use `Orchestrator::progress` to get the current snapshot, or
+/// `Orchestrator::watch_progress` to be notified whenever it changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+  /// The number of requests added to the `Orchestrator`.
+  pub submitted: u64,
+  /// The number of requests waiting for a slot in the `ConcurrencyPolicy`.
+  pub queued:    u64,
+  /// The number of requests currently being sent.
+  pub in_flight: u64,
+  /// The number of requests that finished with a response.
+  pub completed: u64,
+  /// The number of requests that finished with an error.
+  pub failed:    u64,
+}
+
+impl Progress {
+  /// The number of requests that have finished, successfully or not.
+  pub fn finished(&self) -> u64 {
+    self.completed + self.failed
+  }
+
+  /// Whether every submitted request has finished.
+  pub fn is_done(&self) -> bool {
+    self.finished() == self.submitted
+  }
+}
+
+/// The transitions a request goes through, as seen by `Progress` and the
+/// exported metrics.
+pub(crate) trait ProgressExt {
+  fn submitted(&self);
+  fn started(&self);
+  fn cancelled(&self);
+  fn finished(&self, success: bool);
+}
+
+impl ProgressExt for watch::Sender<Progress> {
+  fn submitted(&self) {
+    telemetry::submitted();
+    self.send_modify(|progress| {
+      progress.submitted += 1;
+      progress.queued += 1;
+    });
+  }
+
+  fn started(&self) {
+    telemetry::started();
+    self.send_modify(|progress| {
+      progress.queued -= 1;
+      progress.in_flight += 1;
+    });
+  }
+
+  fn cancelled(&self) {
+    telemetry::cancelled();
+    self.send_modify(|progress| {
+      progress.queued -= 1;
+      progress.failed += 1;
+    });
+  }
+
+  fn finished(&self, success: bool) {
+    telemetry::finished(success);
+    self.send_modify(|progress| {
+      progress.in_flight -= 1;
+      if success {
+        progress.completed += 1;
+      } else {
+        progress.failed += 1;
+      }
+    });
+  }
+}
@@ -0,0 +1,533 @@
+//! Logging the full prompts and completions of calls to the API, for
+//! auditing and debugging what was sent and received.
+//!
+//! Give a `PromptLog` to `Orchestrator::with_prompt_log`, and every call to
+//! the API is recorded as a `PromptRecord`: the request and response as
+//! JSON, or the error, along with when it was sent and how long it took.
+//! Records go to a JSONL file or to a callback, apart from the operational
+//! logs written through `log` or `tracing`, which never hold prompts.
+//!
+//! The API key is masked by default, wherever it appears, and can be left
+//! out or logged in full with `with_key_redaction`. Fields such as `user`
+//! can be redacted by name, and the text of prompts and completions with a
+//! `Redactor`.
+//!
+//! ```rust,no_run
+//! use openai_orch::{
+//!   prelude::*,
+//!   prompt_log::PromptLog,
+//!   redact::Redactor,
+//! };
+//!
+//! # fn example() -> Result<(), OrchError> {
+//! let log = PromptLog::open("prompts.jsonl")?
+//!   .with_redacted_field("user")
+//!   .with_redactor(Redactor::pii());
+//! let orchestrator = Orchestrator::builder()
+//!   .key(Keys::from_env().unwrap())
+//!   .prompt_log(log)
+//!   .build();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+  fs::File,
+  path::{Path, PathBuf},
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::SystemTime,
+};
+
+use async_openai::types::{
+  ChatCompletionResponseStream, CreateChatCompletionRequest,
+  CreateChatCompletionResponse, CreateCompletionRequest,
+  CreateCompletionResponse, CreateEmbeddingRequest, CreateEmbeddingResponse,
+  CreateImageRequest, CreateModerationRequest, CreateModerationResponse,
+  CreateTranscriptionRequest, ImagesResponse,
+};
+use async_trait::async_trait;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+use crate::{
+  error::Result,
+  jsonl,
+  keys::Keys,
+  redact::Redactor,
+  trace::error,
+  transport::{Call, Transport},
+};
+
+/// What redacted fields and keys are replaced with.
+const REDACTED: &str = "[REDACTED]";
+
+/// A call to the API, as a line of a `PromptLog`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PromptRecord {
+  /// The `Transport` method called, such as "chat".
+  pub call:       String,
+  /// The API key the call was made with, redacted as the log's
+  /// `KeyRedaction` says.
+  pub key:        Option<String>,
+  /// The request. The audio of a transcription isn't written.
+  pub request:    Value,
+  /// The response, if the call succeeded. A streamed chat completion is
+  /// written as the array of its chunks, and a transcription as text.
+  pub response:   Option<Value>,
+  /// The error the call failed with. A stream which failed partway has both
+  /// the chunks received before the error and the error.
+  pub error:      Option<String>,
+  /// When the call was made, in RFC 3339 format.
+  pub sent_at:    String,
+  /// How long the call took, in milliseconds. For a stream, this is until
+  /// it ended or was dropped.
+  pub latency_ms: u64,
+}
+
+/// How the API key is written to a `PromptLog`. Wherever the key appears in
+/// a record, such as in an error, it is replaced the same way, and the
+/// organization ID with `[REDACTED]` unless the key is `Unredacted`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyRedaction {
+  /// Keeps the first three and last four characters of the key, such as
+  /// `sk-...wxyz`, which is enough to tell keys apart. Keys too short to
+  /// mask are replaced with `[REDACTED]`.
+  #[default]
+  Masked,
+  /// Leaves the key out of records, and replaces it with `[REDACTED]`.
+  Omitted,
+  /// Writes the key in full. Only use this where the log is as safe as the
+  /// key itself.
+  Unredacted,
+}
+
+impl KeyRedaction {
+  /// What the key is replaced with wherever it appears.
+  fn apply(self, key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    match self {
+      KeyRedaction::Masked if chars.len() > 12 => format!(
+        "{}...{}",
+        chars[..3].iter().collect::<String>(),
+        chars[chars.len() - 4..].iter().collect::<String>()
+      ),
+      KeyRedaction::Masked | KeyRedaction::Omitted => REDACTED.to_string(),
+      KeyRedaction::Unredacted => key.to_string(),
+    }
+  }
+}
+
+/// Where a `PromptLog` writes its records.
+enum Sink {
+  File { path: PathBuf, file: Mutex<File> },
+  Callback(Box<dyn Fn(PromptRecord) + Send + Sync>),
+}
+
+/// Records calls to the API to a JSONL file or a callback. See the module
+/// documentation.
+pub struct PromptLog {
+  sink:            Sink,
+  key_redaction:   KeyRedaction,
+  redacted_fields: Vec<String>,
+  redactor:        Option<Redactor>,
+}
+
+impl PromptLog {
+  /// Opens the log at `path` to append records to, creating it if it
+  /// doesn't exist.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    let file = jsonl::open(&path)?;
+    Ok(Self::with_sink(Sink::File {
+      path,
+      file: Mutex::new(file),
+    }))
+  }
+
+  /// Returns a log which calls `f` with every record, once its call has
+  /// finished. `f` is called on the task that made the call, so it should
+  /// hand slow work off elsewhere.
+  pub fn callback(f: impl Fn(PromptRecord) + Send + Sync + 'static) -> Self {
+    Self::with_sink(Sink::Callback(Box::new(f)))
+  }
+
+  fn with_sink(sink: Sink) -> Self {
+    Self {
+      sink,
+      key_redaction: KeyRedaction::default(),
+      redacted_fields: vec![],
+      redactor: None,
+    }
+  }
+
+  /// Reads every record in the log at `path`.
+  pub fn read(path: impl AsRef<Path>) -> Result<Vec<PromptRecord>> {
+    jsonl::read(path.as_ref())
+  }
+
+  /// Sets how the API key is written. Defaults to `KeyRedaction::Masked`.
+  pub fn with_key_redaction(mut self, key_redaction: KeyRedaction) -> Self {
+    self.key_redaction = key_redaction;
+    self
+  }
+
+  /// Replaces the value of every field named `name` in requests and
+  /// responses with `[REDACTED]`, however deeply it is nested.
+  pub fn with_redacted_field(mut self, name: impl Into<String>) -> Self {
+    self.redacted_fields.push(name.into());
+    self
+  }
+
+  /// Scrubs the text of requests, responses, and errors with `redactor`
+  /// before they are written. Unlike `Orchestrator::with_redactor`, the
+  /// calls themselves are sent as they are.
+  pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+    self.redactor = Some(redactor);
+    self
+  }
+
+  /// The file the log is written to, if it isn't written to a callback.
+  pub fn path(&self) -> Option<&Path> {
+    match &self.sink {
+      Sink::File { path, .. } => Some(path),
+      Sink::Callback(_) => None,
+    }
+  }
+
+  /// Redacts a finished call and writes it to the sink. Failing to write is
+  /// logged rather than failing the call.
+  fn write(
+    &self,
+    pending: Pending,
+    mut response: Option<Value>,
+    mut error: Option<String>,
+  ) {
+    let Pending {
+      call,
+      key,
+      org_id,
+      mut request,
+      sent_at,
+      started_at,
+    } = pending;
+
+    for value in std::iter::once(&mut request).chain(response.as_mut()) {
+      for name in &self.redacted_fields {
+        redact_field(value, name);
+      }
+      if let Some(redactor) = &self.redactor {
+        redactor.redact_json(value);
+      }
+    }
+    if let (Some(error), Some(redactor)) = (&mut error, &self.redactor) {
+      redactor.redact_in_place(error);
+    }
+    let masked_key = self.key_redaction.apply(&key);
+    if self.key_redaction != KeyRedaction::Unredacted {
+      let secrets = std::iter::once((key.as_str(), masked_key.as_str()))
+        .chain(org_id.as_deref().map(|org_id| (org_id, REDACTED)))
+        .filter(|(secret, _)| !secret.is_empty());
+      for (secret, replacement) in secrets {
+        for value in std::iter::once(&mut request).chain(response.as_mut()) {
+          replace_in_strings(value, secret, replacement);
+        }
+        if let Some(error) = &mut error {
+          *error = error.replace(secret, replacement);
+        }
+      }
+    }
+
+    let record = PromptRecord {
+      key: match self.key_redaction {
+        KeyRedaction::Omitted => None,
+        _ => Some(masked_key),
+      },
+      latency_ms: started_at.elapsed().as_millis() as u64,
+      sent_at: humantime::format_rfc3339_millis(sent_at).to_string(),
+      call,
+      request,
+      response,
+      error,
+    };
+    match &self.sink {
+      Sink::Callback(f) => f(record),
+      Sink::File { path, file } => {
+        if let Err(err) = jsonl::append(file, &record) {
+          error!(
+            "failed to write a {} call to {}: {}",
+            record.call,
+            path.display(),
+            err
+          );
+        }
+      }
+    }
+  }
+}
+
+/// Replaces the value of every field named `name` in `value`.
+fn redact_field(value: &mut Value, name: &str) {
+  match value {
+    Value::Object(object) => {
+      for (key, value) in object {
+        match key == name {
+          true => *value = Value::String(REDACTED.to_string()),
+          false => redact_field(value, name),
+        }
+      }
+    }
+    Value::Array(values) => values
+      .iter_mut()
+      .for_each(|value| redact_field(value, name)),
+    Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => {}
+  }
+}
+
+/// Replaces `secret` with `replacement` in every string of `value`.
+fn replace_in_strings(value: &mut Value, secret: &str, replacement: &str) {
+  match value {
+    Value::String(text) if text.contains(secret) => {
+      *text = text.replace(secret, replacement)
+    }
+    Value::Object(object) => object
+      .values_mut()
+      .for_each(|value| replace_in_strings(value, secret, replacement)),
+    Value::Array(values) => values
+      .iter_mut()
+      .for_each(|value| replace_in_strings(value, secret, replacement)),
+    _ => {}
+  }
+}
+
+/// Serializes a request or response for a record.
+fn to_json(value: &impl Serialize) -> Value {
+  serde_json::to_value(value).unwrap_or_default()
+}
+
+/// A call which has been sent, but not yet written to the log.
+struct Pending {
+  call:       String,
+  key:        String,
+  org_id:     Option<String>,
+  request:    Value,
+  sent_at:    SystemTime,
+  started_at: Instant,
+}
+
+impl Pending {
+  fn new(call: &str, keys: &Keys, request: Value) -> Self {
+    Self {
+      call: call.to_string(),
+      key: keys.openai_api_key.clone(),
+      org_id: keys.openai_org_id.clone(),
+      request,
+      sent_at: SystemTime::now(),
+      started_at: Instant::now(),
+    }
+  }
+}
+
+/// A streamed chat completion which collects its chunks, and writes them to
+/// the log when it ends or is dropped.
+struct LoggedStream {
+  inner:   ChatCompletionResponseStream,
+  log:     Arc<PromptLog>,
+  pending: Option<Pending>,
+  chunks:  Vec<Value>,
+  error:   Option<String>,
+}
+
+impl LoggedStream {
+  fn finish(&mut self) {
+    if let Some(pending) = self.pending.take() {
+      let chunks = std::mem::take(&mut self.chunks);
+      self
+        .log
+        .write(pending, Some(Value::Array(chunks)), self.error.take());
+    }
+  }
+}
+
+impl Stream for LoggedStream {
+  type Item = <ChatCompletionResponseStream as Stream>::Item;
+
+  fn poll_next(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Self::Item>> {
+    let item = self.inner.as_mut().poll_next(cx);
+    match &item {
+      Poll::Ready(Some(Ok(chunk))) => {
+        let chunk = to_json(chunk);
+        self.chunks.push(chunk);
+      }
+      Poll::Ready(Some(Err(err))) => self.error = Some(err.to_string()),
+      Poll::Ready(None) => self.finish(),
+      Poll::Pending => {}
+    }
+    item
+  }
+}
+
+impl Drop for LoggedStream {
+  fn drop(&mut self) {
+    self.finish();
+  }
+}
+
+/// A `Transport` which makes its calls with another transport, and records
+/// each of them to a `PromptLog`.
+pub struct LoggingTransport {
+  inner: Arc<dyn Transport>,
+  log:   Arc<PromptLog>,
+}
+
+impl LoggingTransport {
+  /// Returns a transport which records the calls it makes with `inner` to
+  /// `log`.
+  pub fn new(inner: impl Transport + 'static, log: PromptLog) -> Self {
+    Self::wrapping(Arc::new(inner), Arc::new(log))
+  }
+
+  pub(crate) fn wrapping(
+    inner: Arc<dyn Transport>,
+    log: Arc<PromptLog>,
+  ) -> Self {
+    Self { inner, log }
+  }
+
+  /// Makes and records a single call.
+  async fn call<Req, Res>(
+    &self,
+    call: &str,
+    keys: &Keys,
+    request: Req,
+    send: Call<Req, Res>,
+  ) -> Result<Res>
+  where
+    Req: Serialize + Send,
+    Res: Serialize,
+  {
+    let pending = Pending::new(call, keys, to_json(&request));
+    let res = send(self.inner.as_ref(), keys, request).await;
+    match &res {
+      Ok(response) => self.log.write(pending, Some(to_json(response)), None),
+      Err(err) => self.log.write(pending, None, Some(err.to_string())),
+    }
+    res
+  }
+}
+
+#[async_trait]
+impl Transport for LoggingTransport {
+  async fn chat(
+    &self,
+    keys: &Keys,
+    request: CreateChatCompletionRequest,
+  ) -> Result<CreateChatCompletionResponse> {
+    self
+      .call("chat", keys, request, |transport, keys, request| {
+        transport.chat(keys, request)
+      })
+      .await
+  }
+
+  async fn chat_stream(
+    &self,
+    keys: &Keys,
+    request: CreateChatCompletionRequest,
+  ) -> Result<ChatCompletionResponseStream> {
+    let pending = Pending::new("chat_stream", keys, to_json(&request));
+    match self.inner.chat_stream(keys, request).await {
+      Ok(stream) => Ok(Box::pin(LoggedStream {
+        inner:   stream,
+        log:     self.log.clone(),
+        pending: Some(pending),
+        chunks:  vec![],
+        error:   None,
+      })),
+      Err(err) => {
+        self.log.write(pending, None, Some(err.to_string()));
+        Err(err)
+      }
+    }
+  }
+
+  async fn completion(
+    &self,
+    keys: &Keys,
+    request: CreateCompletionRequest,
+  ) -> Result<CreateCompletionResponse> {
+    self
+      .call("completion", keys, request, |transport, keys, request| {
+        transport.completion(keys, request)
+      })
+      .await
+  }
+
+  async fn embedding(
+    &self,
+    keys: &Keys,
+    request: CreateEmbeddingRequest,
+  ) -> Result<CreateEmbeddingResponse> {
+    self
+      .call("embedding", keys, request, |transport, keys, request| {
+        transport.embedding(keys, request)
+      })
+      .await
+  }
+
+  async fn moderation(
+    &self,
+    keys: &Keys,
+    request: CreateModerationRequest,
+  ) -> Result<CreateModerationResponse> {
+    self
+      .call("moderation", keys, request, |transport, keys, request| {
+        transport.moderation(keys, request)
+      })
+      .await
+  }
+
+  async fn image(
+    &self,
+    keys: &Keys,
+    request: CreateImageRequest,
+  ) -> Result<ImagesResponse> {
+    self
+      .call("image", keys, request, |transport, keys, request| {
+        transport.image(keys, request)
+      })
+      .await
+  }
+
+  async fn transcription(
+    &self,
+    keys: &Keys,
+    request: CreateTranscriptionRequest,
+  ) -> Result<Vec<u8>> {
+    let request_json = serde_json::json!({
+      "model": request.model,
+      "prompt": request.prompt,
+      "response_format": request.response_format,
+      "temperature": request.temperature,
+      "language": request.language,
+    });
+    let pending = Pending::new("transcription", keys, request_json);
+    let res = self.inner.transcription(keys, request).await;
+    match &res {
+      Ok(transcript) => {
+        let transcript = String::from_utf8_lossy(transcript).into_owned();
+        self
+          .log
+          .write(pending, Some(Value::String(transcript)), None)
+      }
+      Err(err) => self.log.write(pending, None, Some(err.to_string())),
+    }
+    res
+  }
+}
@@ -0,0 +1,350 @@
+//! Scrubbing personal information, such as email addresses and phone
+//! numbers, from prompts before they are sent and from what is logged about
+//! them.
+//!
+//! A `Redactor` replaces every match of its patterns with a placeholder such
+//! as `[EMAIL]`. Give one to `Orchestrator::with_redactor`, and the
+//! `Orchestrator` sends every call to the API through a `RedactingTransport`,
+//! and redacts the requests and errors it writes to its `FailureLog`.
+//!
+//! Redaction is pattern matching, so it can both miss personal information
+//! and scrub text which only looks like it. Only the text of prompts is
+//! redacted; images, audio, and tool definitions are sent as they are.
+//!
+//! ```rust
+//! use openai_orch::redact::Redactor;
+//!
+//! let redactor = Redactor::pii()
+//!   .with_pattern(r"\bACCT-\d+\b", "[ACCOUNT]")
+//!   .unwrap();
+//! assert_eq!(
+//!   redactor.redact("Mail jane@example.com about ACCT-1234."),
+//!   "Mail [EMAIL] about [ACCOUNT].",
+//! );
+//! ```
+
+use std::sync::Arc;
+
+use async_openai::types::{
+  ChatCompletionRequestAssistantMessageContent,
+  ChatCompletionRequestAssistantMessageContentPart,
+  ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
+  ChatCompletionRequestSystemMessageContent,
+  ChatCompletionRequestSystemMessageContentPart,
+  ChatCompletionRequestToolMessageContent,
+  ChatCompletionRequestToolMessageContentPart,
+  ChatCompletionRequestUserMessageContent,
+  ChatCompletionRequestUserMessageContentPart, ChatCompletionResponseStream,
+  CreateChatCompletionRequest, CreateChatCompletionResponse,
+  CreateCompletionRequest, CreateCompletionResponse, CreateEmbeddingRequest,
+  CreateEmbeddingResponse, CreateImageRequest, CreateModerationRequest,
+  CreateModerationResponse, CreateTranscriptionRequest, EmbeddingInput,
+  ImagesResponse, ModerationInput, Prompt,
+};
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+  error::{OrchError, Result},
+  keys::Keys,
+  transport::Transport,
+};
+
+/// Matches email addresses.
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+/// Matches phone numbers of ten digits or more, in the usual groupings,
+/// with or without a country code.
+const PHONE_PATTERN: &str =
+  r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{3}\)|\d{3})[\s.-]?\d{3}[\s.-]?\d{4}\b";
+
+/// Replaces the matches of a set of patterns in text. See the module
+/// documentation.
+#[derive(Clone, Debug, Default)]
+pub struct Redactor {
+  patterns: Vec<(Regex, String)>,
+}
+
+impl Redactor {
+  /// Returns a redactor without any patterns, which leaves text as it is.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns a redactor which scrubs email addresses and phone numbers.
+  pub fn pii() -> Self {
+    Self::new().with_emails().with_phone_numbers()
+  }
+
+  /// Replaces email addresses with `[EMAIL]`.
+  pub fn with_emails(self) -> Self {
+    self.with_regex(Regex::new(EMAIL_PATTERN).unwrap(), "[EMAIL]")
+  }
+
+  /// Replaces phone numbers with `[PHONE]`.
+  pub fn with_phone_numbers(self) -> Self {
+    self.with_regex(Regex::new(PHONE_PATTERN).unwrap(), "[PHONE]")
+  }
+
+  /// Replaces the matches of `pattern` with `replacement`. Fails if the
+  /// pattern isn't a valid regex.
+  pub fn with_pattern(
+    self,
+    pattern: &str,
+    replacement: impl Into<String>,
+  ) -> Result<Self> {
+    let regex = Regex::new(pattern).map_err(OrchError::other)?;
+    Ok(self.with_regex(regex, replacement))
+  }
+
+  /// Replaces the matches of `regex` with `replacement`.
+  pub fn with_regex(
+    mut self,
+    regex: Regex,
+    replacement: impl Into<String>,
+  ) -> Self {
+    self.patterns.push((regex, replacement.into()));
+    self
+  }
+
+  /// Returns `text` with every match of the patterns replaced, in the order
+  /// the patterns were added.
+  pub fn redact(&self, text: &str) -> String {
+    let mut text = text.to_string();
+    self.redact_in_place(&mut text);
+    text
+  }
+
+  /// Replaces every match of the patterns in `text`.
+  pub fn redact_in_place(&self, text: &mut String) {
+    for (regex, replacement) in &self.patterns {
+      if let std::borrow::Cow::Owned(redacted) =
+        regex.replace_all(text, regex::NoExpand(replacement))
+      {
+        *text = redacted;
+      }
+    }
+  }
+
+  /// Replaces every match of the patterns in every string of `value`.
+  /// Object keys are left as they are.
+  pub fn redact_json(&self, value: &mut Value) {
+    match value {
+      Value::String(text) => self.redact_in_place(text),
+      Value::Array(values) => {
+        values.iter_mut().for_each(|value| self.redact_json(value))
+      }
+      Value::Object(object) => object
+        .values_mut()
+        .for_each(|value| self.redact_json(value)),
+      Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+  }
+
+  /// Redacts the text of a chat message, including the arguments of the
+  /// tool calls of an assistant message.
+  fn redact_message(&self, message: &mut ChatCompletionRequestMessage) {
+    match message {
+      ChatCompletionRequestMessage::Developer(message) => {
+        match &mut message.content {
+          ChatCompletionRequestDeveloperMessageContent::Text(text) => {
+            self.redact_in_place(text)
+          }
+          ChatCompletionRequestDeveloperMessageContent::Array(parts) => parts
+            .iter_mut()
+            .for_each(|part| self.redact_in_place(&mut part.text)),
+        }
+      }
+      ChatCompletionRequestMessage::System(message) => {
+        match &mut message.content {
+          ChatCompletionRequestSystemMessageContent::Text(text) => {
+            self.redact_in_place(text)
+          }
+          ChatCompletionRequestSystemMessageContent::Array(parts) => {
+            for part in parts {
+              let ChatCompletionRequestSystemMessageContentPart::Text(part) =
+                part;
+              self.redact_in_place(&mut part.text);
+            }
+          }
+        }
+      }
+      ChatCompletionRequestMessage::User(message) => {
+        match &mut message.content {
+          ChatCompletionRequestUserMessageContent::Text(text) => {
+            self.redact_in_place(text)
+          }
+          ChatCompletionRequestUserMessageContent::Array(parts) => {
+            for part in parts {
+              if let ChatCompletionRequestUserMessageContentPart::Text(part) =
+                part
+              {
+                self.redact_in_place(&mut part.text);
+              }
+            }
+          }
+        }
+      }
+      ChatCompletionRequestMessage::Assistant(message) => {
+        match &mut message.content {
+          Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => {
+            self.redact_in_place(text)
+          }
+          Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => {
+            for part in parts {
+              match part {
+                ChatCompletionRequestAssistantMessageContentPart::Text(
+                  part,
+                ) => self.redact_in_place(&mut part.text),
+                ChatCompletionRequestAssistantMessageContentPart::Refusal(
+                  part,
+                ) => self.redact_in_place(&mut part.refusal),
+              }
+            }
+          }
+          None => {}
+        }
+        for tool_call in message.tool_calls.iter_mut().flatten() {
+          self.redact_in_place(&mut tool_call.function.arguments);
+        }
+      }
+      ChatCompletionRequestMessage::Tool(message) => {
+        match &mut message.content {
+          ChatCompletionRequestToolMessageContent::Text(text) => {
+            self.redact_in_place(text)
+          }
+          ChatCompletionRequestToolMessageContent::Array(parts) => {
+            for part in parts {
+              let ChatCompletionRequestToolMessageContentPart::Text(part) =
+                part;
+              self.redact_in_place(&mut part.text);
+            }
+          }
+        }
+      }
+      ChatCompletionRequestMessage::Function(message) => {
+        if let Some(content) = &mut message.content {
+          self.redact_in_place(content);
+        }
+      }
+    }
+  }
+
+  fn redact_chat(&self, request: &mut CreateChatCompletionRequest) {
+    request
+      .messages
+      .iter_mut()
+      .for_each(|message| self.redact_message(message));
+  }
+}
+
+/// A `Transport` which redacts the prompts of the calls it makes with a
+/// `Redactor`, then makes them with another transport. Responses are
+/// returned as they are.
+///
+/// The prompts of chat completions, legacy completions, embeddings,
+/// moderations, and images are redacted. Transcriptions are sent as they
+/// are.
+pub struct RedactingTransport {
+  inner:    Arc<dyn Transport>,
+  redactor: Redactor,
+}
+
+impl RedactingTransport {
+  /// Returns a transport which redacts prompts before making its calls with
+  /// `inner`.
+  pub fn new(inner: impl Transport + 'static, redactor: Redactor) -> Self {
+    Self::wrapping(Arc::new(inner), redactor)
+  }
+
+  pub(crate) fn wrapping(
+    inner: Arc<dyn Transport>,
+    redactor: Redactor,
+  ) -> Self {
+    Self { inner, redactor }
+  }
+
+  fn redact_strings(&self, strings: &mut [String]) {
+    for string in strings {
+      self.redactor.redact_in_place(string);
+    }
+  }
+}
+
+#[async_trait]
+impl Transport for RedactingTransport {
+  async fn chat(
+    &self,
+    keys: &Keys,
+    mut request: CreateChatCompletionRequest,
+  ) -> Result<CreateChatCompletionResponse> {
+    self.redactor.redact_chat(&mut request);
+    self.inner.chat(keys, request).await
+  }
+
+  async fn chat_stream(
+    &self,
+    keys: &Keys,
+    mut request: CreateChatCompletionRequest,
+  ) -> Result<ChatCompletionResponseStream> {
+    self.redactor.redact_chat(&mut request);
+    self.inner.chat_stream(keys, request).await
+  }
+
+  async fn completion(
+    &self,
+    keys: &Keys,
+    mut request: CreateCompletionRequest,
+  ) -> Result<CreateCompletionResponse> {
+    match &mut request.prompt {
+      Prompt::String(prompt) => self.redactor.redact_in_place(prompt),
+      Prompt::StringArray(prompts) => self.redact_strings(prompts),
+      Prompt::IntegerArray(_) | Prompt::ArrayOfIntegerArray(_) => {}
+    }
+    self.inner.completion(keys, request).await
+  }
+
+  async fn embedding(
+    &self,
+    keys: &Keys,
+    mut request: CreateEmbeddingRequest,
+  ) -> Result<CreateEmbeddingResponse> {
+    match &mut request.input {
+      EmbeddingInput::String(input) => self.redactor.redact_in_place(input),
+      EmbeddingInput::StringArray(inputs) => self.redact_strings(inputs),
+      EmbeddingInput::IntegerArray(_)
+      | EmbeddingInput::ArrayOfIntegerArray(_) => {}
+    }
+    self.inner.embedding(keys, request).await
+  }
+
+  async fn moderation(
+    &self,
+    keys: &Keys,
+    mut request: CreateModerationRequest,
+  ) -> Result<CreateModerationResponse> {
+    match &mut request.input {
+      ModerationInput::String(input) => self.redactor.redact_in_place(input),
+      ModerationInput::StringArray(inputs) => self.redact_strings(inputs),
+      ModerationInput::MultiModal(_) => {}
+    }
+    self.inner.moderation(keys, request).await
+  }
+
+  async fn image(
+    &self,
+    keys: &Keys,
+    mut request: CreateImageRequest,
+  ) -> Result<ImagesResponse> {
+    self.redactor.redact_in_place(&mut request.prompt);
+    self.inner.image(keys, request).await
+  }
+
+  async fn transcription(
+    &self,
+    keys: &Keys,
+    request: CreateTranscriptionRequest,
+  ) -> Result<Vec<u8>> {
+    self.inner.transcription(keys, request).await
+  }
+}
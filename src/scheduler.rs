@@ -0,0 +1,477 @@
+//! The scheduler that decides when queued requests may start.
+
+use std::{
+  cmp::Ordering,
+  collections::{BinaryHeap, HashMap},
+  sync::{Arc, Mutex},
+};
+
+use tokio::{
+  sync::{oneshot, Notify},
+  time::{sleep_until, Instant},
+};
+
+use crate::{
+  limiter::TokenBucket,
+  policies::{AdaptiveConcurrency, ConcurrencyPolicy, Policies, TenantPolicy},
+  pool::KeyLease,
+};
+
+/// The tenant of a request, or `None` for requests added without one.
+pub(crate) type Tenant = Option<Arc<str>>;
+
+/// The priority of a request. When a slot in the `ConcurrencyPolicy` frees
+/// up, it is given to the highest priority request that is waiting. Requests
+/// of the same priority are started in the order they were added, or in
+/// weighted fair order across tenants when there are several.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+  /// Background work that should yield to everything else.
+  Low,
+  /// The priority used by `Orchestrator::add_request`.
+  #[default]
+  Normal,
+  /// Work that should jump ahead of everything else.
+  High,
+}
+
+/// A slot in the `Orchestrator`'s `ConcurrencyPolicy`. The slot is freed when
+/// the permit is dropped.
+pub struct Permit {
+  scheduler: Option<Arc<Scheduler>>,
+  tenant:    Tenant,
+  lease:     Option<KeyLease>,
+}
+
+impl Permit {
+  fn new(scheduler: Arc<Scheduler>, tenant: Tenant) -> Self {
+    Self {
+      scheduler: Some(scheduler),
+      tenant,
+      lease: None,
+    }
+  }
+
+  /// Ties the key used by the request to the permit, so that the key counts
+  /// as in use for as long as the slot does.
+  pub(crate) fn attach_lease(&mut self, lease: KeyLease) {
+    self.lease = Some(lease);
+  }
+
+  /// Disarms the permit without releasing its slot, for when the slot is
+  /// accounted for by the caller.
+  fn forget(mut self) {
+    self.scheduler = None;
+  }
+}
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    if let Some(scheduler) = self.scheduler.take() {
+      scheduler.release(&self.tenant);
+    }
+  }
+}
+
+struct Waiter {
+  priority: Priority,
+  /// The virtual time at which the waiter's share of the slots runs out.
+  /// Tenants with a higher weight advance through virtual time more slowly,
+  /// and so get more of the slots.
+  finish:   f64,
+  seq:      u64,
+  tx:       oneshot::Sender<Permit>,
+}
+
+impl PartialEq for Waiter {
+  fn eq(&self, other: &Self) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Waiter {
+  // the heap is a max-heap, so the "greatest" waiter is the one to run next:
+  // highest priority first, then earliest in virtual time, then earliest
+  // queued
+  fn cmp(&self, other: &Self) -> Ordering {
+    self
+      .priority
+      .cmp(&other.priority)
+      .then_with(|| other.finish.total_cmp(&self.finish))
+      .then_with(|| other.seq.cmp(&self.seq))
+  }
+}
+
+/// A request's place in line for a slot, taken by `Scheduler::enqueue`.
+pub(crate) enum Ticket {
+  Queued(oneshot::Receiver<Permit>),
+  /// The tenant's rate limit holds the request back until `until`.
+  Delayed {
+    scheduler: Arc<Scheduler>,
+    priority:  Priority,
+    tenant:    Tenant,
+    until:     Instant,
+  },
+}
+
+impl Ticket {
+  /// Waits for a free slot, yielding to any waiting requests with a higher
+  /// priority, to tenants with more of their share left, and to requests
+  /// ahead in line. Returns `None` if the scheduler is closed.
+  pub(crate) async fn acquire(self) -> Option<Permit> {
+    let rx = match self {
+      Ticket::Queued(rx) => rx,
+      Ticket::Delayed {
+        scheduler,
+        priority,
+        tenant,
+        until,
+      } => {
+        sleep_until(until).await;
+        scheduler.join(priority, &tenant)
+      }
+    };
+    // the sender is only dropped without a permit when the scheduler closes
+    rx.await.ok()
+  }
+}
+
+/// The requests of a tenant which are waiting or in flight.
+#[derive(Default)]
+struct TenantState {
+  waiting:     BinaryHeap<Waiter>,
+  in_flight:   usize,
+  /// The virtual finish time of the tenant's most recently queued waiter.
+  last_finish: f64,
+}
+
+/// The state of an `AdaptiveConcurrency` limit.
+struct Adaptive {
+  settings:      AdaptiveConcurrency,
+  limit:         f64,
+  last_decrease: Option<Instant>,
+}
+
+impl Adaptive {
+  fn new(settings: AdaptiveConcurrency, initial: usize) -> Self {
+    let limit = initial.clamp(
+      settings.min_concurrent_requests,
+      settings.max_concurrent_requests,
+    ) as f64;
+    Self {
+      settings,
+      limit,
+      last_decrease: None,
+    }
+  }
+
+  /// Adjusts the limit for the outcome of a request, returning the new
+  /// capacity.
+  fn record(&mut self, overloaded: bool) -> usize {
+    let min = self.settings.min_concurrent_requests as f64;
+    let max = self.settings.max_concurrent_requests as f64;
+    if overloaded {
+      let now = Instant::now();
+      let cooled_down = self
+        .last_decrease
+        .is_none_or(|at| now.duration_since(at) >= self.settings.cooldown);
+      if cooled_down {
+        self.limit = (self.limit * self.settings.backoff_factor).max(min);
+        self.last_decrease = Some(now);
+      }
+    } else {
+      self.limit = (self.limit + 1.0 / self.limit).min(max);
+    }
+    self.limit as usize
+  }
+}
+
+struct State {
+  capacity:      usize,
+  in_flight:     usize,
+  tenants:       HashMap<Tenant, TenantState>,
+  tenant_policy: TenantPolicy,
+  /// The virtual finish time of the most recently started waiter.
+  virtual_time:  f64,
+  next_seq:      u64,
+  closed:        bool,
+  /// Whether slots are being held back from waiting requests.
+  paused:        bool,
+  adaptive:      Option<Adaptive>,
+}
+
+/// A priority-aware replacement for a semaphore, which shares its slots
+/// fairly between tenants.
+pub(crate) struct Scheduler {
+  state: Mutex<State>,
+  rates: Mutex<HashMap<Tenant, Arc<TokenBucket>>>,
+  idle:  Notify,
+}
+
+impl Scheduler {
+  pub(crate) fn new(policies: &Policies) -> Arc<Self> {
+    let concurrency_policy = &policies.concurrency_policy;
+    Arc::new(Self {
+      state: Mutex::new(State {
+        capacity:      concurrency_policy.max_concurrent_requests,
+        in_flight:     0,
+        tenants:       HashMap::new(),
+        tenant_policy: policies.tenant_policy.clone(),
+        virtual_time:  0.0,
+        next_seq:      0,
+        closed:        false,
+        paused:        false,
+        adaptive:      concurrency_policy.adaptive.clone().map(|settings| {
+          Adaptive::new(settings, concurrency_policy.max_concurrent_requests)
+        }),
+      }),
+      rates: Mutex::new(HashMap::new()),
+      idle:  Notify::new(),
+    })
+  }
+
+  /// Takes a place in line for a free slot. Places are taken in the order
+  /// this is called, so requests which are enqueued as they are added start
+  /// in the order they were added, whenever their priority and tenant are
+  /// the same.
+  ///
+  /// A tenant with a rate limit reserves its turn straight away, but only
+  /// joins the line once the rate allows it.
+  pub(crate) fn enqueue(
+    self: &Arc<Self>,
+    priority: Priority,
+    tenant: Tenant,
+  ) -> Ticket {
+    let wait = self
+      .rate(&tenant)
+      .map(|bucket| bucket.reserve(1))
+      .unwrap_or_default();
+    if !wait.is_zero() {
+      return Ticket::Delayed {
+        scheduler: self.clone(),
+        priority,
+        tenant,
+        until: Instant::now() + wait,
+      };
+    }
+    Ticket::Queued(self.join(priority, &tenant))
+  }
+
+  /// Adds a waiter for a slot, starting it straight away if one is free.
+  /// The receiver gets nothing if the scheduler is closed.
+  fn join(
+    self: &Arc<Self>,
+    priority: Priority,
+    tenant: &Tenant,
+  ) -> oneshot::Receiver<Permit> {
+    let (tx, rx) = oneshot::channel();
+    {
+      let mut state = self.state.lock().expect("scheduler lock poisoned");
+      if state.closed {
+        return rx;
+      }
+
+      let weight = state.tenant_policy.quota(tenant.as_deref()).weight.max(1);
+      let virtual_time = state.virtual_time;
+      let seq = state.next_seq;
+      state.next_seq += 1;
+      let tenant_state = state.tenants.entry(tenant.clone()).or_default();
+      let finish =
+        tenant_state.last_finish.max(virtual_time) + 1.0 / weight as f64;
+      tenant_state.last_finish = finish;
+
+      tenant_state.waiting.push(Waiter {
+        priority,
+        finish,
+        seq,
+        tx,
+      });
+      self.dispatch(&mut state);
+    }
+    rx
+  }
+
+  /// The rate limit of the tenant, if its quota has one.
+  fn rate(&self, tenant: &Tenant) -> Option<Arc<TokenBucket>> {
+    let per_minute = self
+      .state
+      .lock()
+      .expect("scheduler lock poisoned")
+      .tenant_policy
+      .quota(tenant.as_deref())
+      .max_requests_per_minute?;
+    let mut rates = self.rates.lock().expect("scheduler lock poisoned");
+    let bucket = rates
+      .entry(tenant.clone())
+      .or_insert_with(|| Arc::new(TokenBucket::per_minute(per_minute)));
+    Some(bucket.clone())
+  }
+
+  /// Replaces the tenants' quotas. Rate limits start again from full.
+  pub(crate) fn set_tenant_policy(
+    self: &Arc<Self>,
+    tenant_policy: &TenantPolicy,
+  ) {
+    let mut state = self.state.lock().expect("scheduler lock poisoned");
+    state.tenant_policy = tenant_policy.clone();
+    self.rates.lock().expect("scheduler lock poisoned").clear();
+    self.dispatch(&mut state);
+  }
+
+  /// Stops handing out slots. Waiting and future calls to `acquire` return
+  /// `None`, while permits that are already held stay valid.
+  pub(crate) fn close(&self) {
+    let mut state = self.state.lock().expect("scheduler lock poisoned");
+    state.closed = true;
+    state.tenants.clear();
+  }
+
+  pub(crate) fn is_closed(&self) -> bool {
+    self.state.lock().expect("scheduler lock poisoned").closed
+  }
+
+  /// Stops handing out slots until `unpause` is called. Requests keep their
+  /// place in line, and permits that are already held stay valid.
+  pub(crate) fn pause(&self) {
+    self.state.lock().expect("scheduler lock poisoned").paused = true;
+  }
+
+  /// Starts handing out slots again, starting any waiting requests that fit.
+  pub(crate) fn unpause(self: &Arc<Self>) {
+    let mut state = self.state.lock().expect("scheduler lock poisoned");
+    state.paused = false;
+    self.dispatch(&mut state);
+  }
+
+  pub(crate) fn is_paused(&self) -> bool {
+    self.state.lock().expect("scheduler lock poisoned").paused
+  }
+
+  /// Waits until every permit has been released.
+  pub(crate) async fn wait_idle(&self) {
+    loop {
+      let notified = self.idle.notified();
+      tokio::pin!(notified);
+      notified.as_mut().enable();
+
+      if self
+        .state
+        .lock()
+        .expect("scheduler lock poisoned")
+        .in_flight
+        == 0
+      {
+        return;
+      }
+      notified.await;
+    }
+  }
+
+  /// The number of requests currently allowed to run at once.
+  pub(crate) fn capacity(&self) -> usize {
+    self.state.lock().expect("scheduler lock poisoned").capacity
+  }
+
+  /// Replaces the concurrency limit, starting any waiting requests that now
+  /// fit. An adaptive limit starts again from the policy's maximum.
+  pub(crate) fn set_concurrency(
+    self: &Arc<Self>,
+    concurrency_policy: &ConcurrencyPolicy,
+  ) {
+    let mut state = self.state.lock().expect("scheduler lock poisoned");
+    let max = concurrency_policy.max_concurrent_requests;
+    state.adaptive = concurrency_policy
+      .adaptive
+      .clone()
+      .map(|settings| Adaptive::new(settings, max));
+    state.capacity = match &state.adaptive {
+      Some(adaptive) => adaptive.limit as usize,
+      None => max,
+    };
+    self.dispatch(&mut state);
+  }
+
+  /// Feeds the outcome of a request to the `AdaptiveConcurrency` limit, if
+  /// there is one. Requests already in flight are not interrupted when the
+  /// limit shrinks; new ones just wait until enough of them finish.
+  pub(crate) fn record_outcome(self: &Arc<Self>, overloaded: bool) {
+    let mut state = self.state.lock().expect("scheduler lock poisoned");
+    let Some(adaptive) = state.adaptive.as_mut() else {
+      return;
+    };
+    state.capacity = adaptive.record(overloaded);
+    self.dispatch(&mut state);
+  }
+
+  fn release(self: &Arc<Self>, tenant: &Tenant) {
+    let mut state = self.state.lock().expect("scheduler lock poisoned");
+    state.in_flight -= 1;
+    state.untrack(tenant);
+    self.dispatch(&mut state);
+    if state.in_flight == 0 {
+      self.idle.notify_waiters();
+    }
+  }
+
+  /// Hands out free slots to waiting requests, in priority order and then
+  /// in weighted fair order across the tenants below their concurrency
+  /// limits.
+  fn dispatch(self: &Arc<Self>, state: &mut State) {
+    while !state.paused && state.in_flight < state.capacity {
+      let tenant_policy = &state.tenant_policy;
+      let next = state
+        .tenants
+        .iter()
+        .filter(|(tenant, tenant_state)| {
+          tenant_policy
+            .quota(tenant.as_deref())
+            .max_concurrent_requests
+            .is_none_or(|max| tenant_state.in_flight < max)
+        })
+        .filter_map(|(tenant, tenant_state)| {
+          Some((tenant, tenant_state.waiting.peek()?))
+        })
+        .max_by(|(_, a), (_, b)| a.cmp(b))
+        .map(|(tenant, _)| tenant.clone());
+      let Some(tenant) = next else {
+        break;
+      };
+      let tenant_state = state.tenants.get_mut(&tenant).expect("tenant exists");
+      let waiter = tenant_state.waiting.pop().expect("tenant has a waiter");
+
+      tenant_state.in_flight += 1;
+      state.in_flight += 1;
+      state.virtual_time = state.virtual_time.max(waiter.finish);
+      let permit = Permit::new(self.clone(), tenant.clone());
+      if let Err(permit) = waiter.tx.send(permit) {
+        // the waiter was cancelled; we still hold the lock, so take the slot
+        // back by hand instead of dropping the permit
+        permit.forget();
+        state.in_flight -= 1;
+        state.untrack(&tenant);
+      }
+    }
+  }
+}
+
+impl State {
+  /// Gives back a slot held by the tenant, forgetting the tenant once it has
+  /// nothing waiting or in flight.
+  fn untrack(&mut self, tenant: &Tenant) {
+    let Some(tenant_state) = self.tenants.get_mut(tenant) else {
+      return;
+    };
+    tenant_state.in_flight = tenant_state.in_flight.saturating_sub(1);
+    if tenant_state.in_flight == 0 && tenant_state.waiting.is_empty() {
+      self.tenants.remove(tenant);
+    }
+  }
+}
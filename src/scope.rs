@@ -0,0 +1,90 @@
+//! State shared between the `Orchestrator` and the request it is sending.
+
+use std::{
+  future::Future,
+  sync::{Arc, Mutex},
+};
+
+use crate::{
+  cost::UsageRecorder, hooks::Hooks, limiter::RetryBudget,
+  scheduler::Scheduler, transport::Transport,
+};
+
+/// What the `Orchestrator` learns about a request while it is being sent.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RequestStats {
+  /// The number of attempts to call the API which failed and were retried.
+  pub(crate) retries:          u32,
+  /// The model that served the request, as reported by the API.
+  pub(crate) model:            Option<String>,
+  /// Whether the `RetryStrategy` asked for the request to be sent again with
+  /// another key.
+  pub(crate) switch_key:       bool,
+  /// Whether the outcome of any attempt was fed to the scheduler's
+  /// `AdaptiveConcurrency` limit.
+  pub(crate) recorded_outcome: bool,
+}
+
+/// The scope a request is sent in, available to the request through helpers
+/// such as `cost::record_usage` without being passed to `OrchRequest::send`.
+#[derive(Clone)]
+pub(crate) struct RequestScope {
+  pub(crate) usage:        UsageRecorder,
+  pub(crate) hooks:        Hooks,
+  pub(crate) stats:        Arc<Mutex<RequestStats>>,
+  pub(crate) retry_budget: Arc<RetryBudget>,
+  pub(crate) transport:    Arc<dyn Transport>,
+  scheduler:               Arc<Scheduler>,
+}
+
+impl RequestScope {
+  pub(crate) fn new(
+    usage: UsageRecorder,
+    hooks: Hooks,
+    retry_budget: Arc<RetryBudget>,
+    transport: Arc<dyn Transport>,
+    scheduler: Arc<Scheduler>,
+  ) -> Self {
+    Self {
+      usage,
+      hooks,
+      stats: Arc::default(),
+      retry_budget,
+      transport,
+      scheduler,
+    }
+  }
+
+  /// Feeds the outcome of an attempt to the `AdaptiveConcurrency` limit, if
+  /// there is one: whether the API was overloaded, or it succeeded.
+  pub(crate) fn record_outcome(&self, overloaded: bool) {
+    self.update_stats(|stats| stats.recorded_outcome = true);
+    self.scheduler.record_outcome(overloaded);
+  }
+
+  pub(crate) fn stats(&self) -> RequestStats {
+    self
+      .stats
+      .lock()
+      .expect("request stats lock poisoned")
+      .clone()
+  }
+
+  pub(crate) fn update_stats(&self, f: impl FnOnce(&mut RequestStats)) {
+    f(&mut self.stats.lock().expect("request stats lock poisoned"));
+  }
+
+  /// Runs `future` within the scope.
+  pub(crate) async fn run<F: Future>(self, future: F) -> F::Output {
+    SCOPE.scope(self, future).await
+  }
+}
+
+tokio::task_local! {
+  static SCOPE: RequestScope;
+}
+
+/// Calls `f` with the scope of the request being sent, if there is one.
+pub(crate) fn with_current<T>(f: impl FnOnce(&RequestScope) -> T) -> Option<T> {
+  SCOPE.try_with(f).ok()
+}
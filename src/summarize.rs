@@ -0,0 +1,160 @@
+//! Summarizing documents too long for a single request, map-reduce style.
+//!
+//! The document is split into chunks of about `chunk_tokens` tokens, which
+//! are summarized side by side through the `Orchestrator`. The summaries are
+//! then combined `fan_in` at a time, and the combined summaries combined
+//! again, until a single summary is left.
+//!
+//! ```rust,no_run
+//! # use openai_orch::prelude::*;
+//! use openai_orch::summarize::{summarize, SummarizeConfig};
+//!
+//! # async fn example(
+//! #   orchestrator: Orchestrator,
+//! #   report: String,
+//! # ) -> Result<(), OrchError> {
+//! let config = SummarizeConfig {
+//!   chunk_tokens: 1000,
+//!   ..Default::default()
+//! };
+//! let summary = summarize(&orchestrator, &report, &config).await?;
+//! println!("{}", summary);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+  bulk::orchestrate_map,
+  chat::{siso::ChatSisoRequest, ChatModelParams},
+  error::Result,
+  trace::debug,
+  Orchestrator,
+};
+
+/// How `summarize` splits a document and what it asks the model.
+#[derive(Clone)]
+pub struct SummarizeConfig {
+  /// The most tokens in a chunk of the document. Tokens are counted exactly
+  /// with the `tokens` feature, and otherwise assumed to be roughly four
+  /// characters each.
+  pub chunk_tokens:  usize,
+  /// The most summaries combined by a single request.
+  pub fan_in:        usize,
+  /// The system prompt for summarizing a chunk of the document, which is
+  /// sent as the user prompt.
+  pub map_prompt:    String,
+  /// The system prompt for combining summaries, which are sent as the user
+  /// prompt, separated by blank lines.
+  pub reduce_prompt: String,
+  /// The params of every request.
+  pub model_params:  ChatModelParams,
+}
+
+impl Default for SummarizeConfig {
+  fn default() -> Self {
+    Self {
+      chunk_tokens:  2000,
+      fan_in:        8,
+      map_prompt:    String::from(
+        "Summarize the following excerpt of a longer document. Keep every key \
+         fact, name, and figure.",
+      ),
+      reduce_prompt: String::from(
+        "The following are summaries of consecutive parts of a document. \
+         Combine them into a single summary, in the same order, keeping every \
+         key fact, name, and figure.",
+      ),
+      model_params:  ChatModelParams::default(),
+    }
+  }
+}
+
+/// Summarizes `document`, as described in the module documentation. An
+/// empty document has an empty summary, and a document which fits in one
+/// chunk is summarized with a single request.
+///
+/// Fails with the error of the first request that failed; the requests
+/// already added are still sent.
+pub async fn summarize(
+  orchestrator: &Orchestrator,
+  document: &str,
+  config: &SummarizeConfig,
+) -> Result<String> {
+  let chunks = chunk(
+    document,
+    config.model_params.model.as_str(),
+    config.chunk_tokens,
+  );
+  if chunks.is_empty() {
+    return Ok(String::new());
+  }
+  debug!("summarizing a document in {} chunks", chunks.len());
+  let mut summaries =
+    summarize_each(orchestrator, chunks, &config.map_prompt, config).await?;
+
+  let fan_in = config.fan_in.max(2);
+  while summaries.len() > 1 {
+    debug!("combining {} summaries", summaries.len());
+    let groups = summaries
+      .chunks(fan_in)
+      .map(|group| group.join("\n\n"))
+      .collect();
+    summaries =
+      summarize_each(orchestrator, groups, &config.reduce_prompt, config)
+        .await?;
+  }
+  Ok(summaries.pop().unwrap_or_default())
+}
+
+/// Sends a request with the given system prompt for each of `texts`, and
+/// returns the completions in the same order.
+async fn summarize_each(
+  orchestrator: &Orchestrator,
+  texts: Vec<String>,
+  system_prompt: &str,
+  config: &SummarizeConfig,
+) -> Result<Vec<String>> {
+  orchestrate_map(orchestrator, texts, |text| {
+    ChatSisoRequest::new(
+      system_prompt.to_string(),
+      text,
+      config.model_params.clone(),
+    )
+  })
+  .await
+  .into_iter()
+  .map(|response| response.map(String::from))
+  .collect()
+}
+
+/// Splits `text` into chunks of at most `max_tokens` tokens for `model`,
+/// between words, so that no word is cut in two. A word longer than
+/// `max_tokens` gets a chunk of its own. Chunks of only whitespace are
+/// dropped.
+fn chunk(text: &str, model: &str, max_tokens: usize) -> Vec<String> {
+  let mut chunks = vec![];
+  let mut current = String::new();
+  let mut current_tokens = 0;
+  for word in text.split_inclusive(char::is_whitespace) {
+    let tokens = count_tokens(model, word);
+    if current_tokens + tokens > max_tokens && !current.is_empty() {
+      chunks.push(std::mem::take(&mut current));
+      current_tokens = 0;
+    }
+    current.push_str(word);
+    current_tokens += tokens;
+  }
+  chunks.push(current);
+  chunks.retain(|chunk| !chunk.trim().is_empty());
+  chunks
+}
+
+#[cfg(feature = "tokens")]
+fn count_tokens(model: &str, text: &str) -> usize {
+  crate::tokens::count_tokens(model, text)
+}
+
+#[cfg(not(feature = "tokens"))]
+fn count_tokens(_model: &str, text: &str) -> usize {
+  text.len().div_ceil(4)
+}
@@ -0,0 +1,92 @@
+//! Tags attached to requests, and the statistics kept for each tag.
+//!
+//! Tags are given with `Orchestrator::add_request_tagged`, and show up in
+//! the `ResponseMeta` passed to hooks, in logs, and in metrics. Use
+//! `Orchestrator::stats_by_tag` to see how the requests with each tag fared.
+
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+
+use crate::{
+  cost::{Spend, Usage},
+  meta::ResponseMeta,
+  telemetry,
+};
+
+/// What the requests with a tag have done so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TagStats {
+  /// The number of requests added with the tag.
+  pub submitted: u64,
+  /// The number of requests with the tag that finished with a response.
+  pub completed: u64,
+  /// The number of requests with the tag that finished with an error.
+  pub failed:    u64,
+  /// The number of attempts that failed and were retried.
+  pub retries:   u64,
+  /// What the requests with the tag have used, including attempts that were
+  /// retried.
+  pub spend:     Spend,
+}
+
+/// The statistics of every tag seen by an `Orchestrator`.
+#[derive(Default)]
+pub(crate) struct TagTable(Mutex<HashMap<String, TagStats>>);
+
+impl TagTable {
+  pub(crate) fn snapshot(&self) -> HashMap<String, TagStats> {
+    self.0.lock().expect("tag table lock poisoned").clone()
+  }
+
+  fn update(&self, tags: &[String], mut f: impl FnMut(&mut TagStats)) {
+    if tags.is_empty() {
+      return;
+    }
+    let mut table = self.0.lock().expect("tag table lock poisoned");
+    for tag in tags {
+      f(table.entry(tag.clone()).or_default());
+    }
+  }
+}
+
+/// The tags of a single request, along with the table they are counted in.
+#[derive(Clone, Default)]
+pub(crate) struct Tags {
+  tags:  Arc<[String]>,
+  table: Arc<TagTable>,
+}
+
+impl Tags {
+  pub(crate) fn new(tags: Arc<[String]>, table: Arc<TagTable>) -> Self {
+    Self { tags, table }
+  }
+
+  pub(crate) fn as_slice(&self) -> &[String] {
+    &self.tags
+  }
+
+  pub(crate) fn submitted(&self) {
+    self.table.update(&self.tags, |stats| stats.submitted += 1);
+  }
+
+  pub(crate) fn finished(&self, meta: &ResponseMeta, success: bool) {
+    self.table.update(&self.tags, |stats| {
+      match success {
+        true => stats.completed += 1,
+        false => stats.failed += 1,
+      }
+      stats.retries += u64::from(meta.retries);
+    });
+    for tag in self.tags.iter() {
+      telemetry::tagged_finished(tag, success);
+    }
+  }
+
+  pub(crate) fn spent(&self, usage: &Usage, cost: Option<f64>) {
+    self
+      .table
+      .update(&self.tags, |stats| stats.spend.add(usage, cost));
+  }
+}
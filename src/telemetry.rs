@@ -0,0 +1,140 @@
+//! Metrics through the `metrics` facade when the `metrics` feature is
+//! enabled. Without it, every function here does nothing.
+//!
+//! The metrics recorded are:
+//! - `openai_orch_requests_submitted_total`: requests added to an
+//!   `Orchestrator`.
+//! - `openai_orch_requests_finished_total`: finished requests, labelled with an
+//!   `outcome` of `completed`, `failed`, or `cancelled`.
+//! - `openai_orch_requests_in_flight`: requests currently being sent.
+//! - `openai_orch_tagged_requests_finished_total`: finished requests with tags,
+//!   labelled with each `tag` and an `outcome` of `completed` or `failed`.
+//! - `openai_orch_request_duration_seconds`: the time from adding a request to
+//!   its response.
+//! - `openai_orch_retries_total`: attempts retried by the `RetryPolicy`.
+//! - `openai_orch_timeouts_total`: attempts which timed out.
+//! - `openai_orch_tokens_total`: tokens used, labelled with the `model` and a
+//!   `kind` of `prompt` or `completion`.
+
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+
+use std::time::Duration;
+
+use crate::cost::Usage;
+
+/// Describes every metric to the installed recorder.
+pub(crate) fn describe() {
+  #[cfg(feature = "metrics")]
+  {
+    use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+    describe_counter!(
+      "openai_orch_requests_submitted_total",
+      "Requests added to an Orchestrator."
+    );
+    describe_counter!(
+      "openai_orch_requests_finished_total",
+      "Finished requests, by outcome."
+    );
+    describe_counter!(
+      "openai_orch_tagged_requests_finished_total",
+      "Finished requests with tags, by tag and outcome."
+    );
+    describe_gauge!(
+      "openai_orch_requests_in_flight",
+      "Requests currently being sent."
+    );
+    describe_histogram!(
+      "openai_orch_request_duration_seconds",
+      Unit::Seconds,
+      "Time from adding a request to its response."
+    );
+    describe_counter!(
+      "openai_orch_retries_total",
+      "Attempts retried by the RetryPolicy."
+    );
+    describe_counter!(
+      "openai_orch_timeouts_total",
+      "Attempts which timed out."
+    );
+    describe_counter!(
+      "openai_orch_tokens_total",
+      Unit::Count,
+      "Tokens used, by model and kind."
+    );
+  }
+}
+
+pub(crate) fn submitted() {
+  #[cfg(feature = "metrics")]
+  metrics::counter!("openai_orch_requests_submitted_total").increment(1);
+}
+
+pub(crate) fn started() {
+  #[cfg(feature = "metrics")]
+  metrics::gauge!("openai_orch_requests_in_flight").increment(1.0);
+}
+
+pub(crate) fn cancelled() {
+  #[cfg(feature = "metrics")]
+  metrics::counter!("openai_orch_requests_finished_total", "outcome" => "cancelled")
+    .increment(1);
+}
+
+pub(crate) fn finished(success: bool) {
+  #[cfg(feature = "metrics")]
+  {
+    let outcome = if success { "completed" } else { "failed" };
+    metrics::gauge!("openai_orch_requests_in_flight").decrement(1.0);
+    metrics::counter!("openai_orch_requests_finished_total", "outcome" => outcome)
+      .increment(1);
+  }
+}
+
+pub(crate) fn tagged_finished(tag: &str, success: bool) {
+  #[cfg(feature = "metrics")]
+  {
+    let outcome = if success { "completed" } else { "failed" };
+    metrics::counter!(
+      "openai_orch_tagged_requests_finished_total",
+      "tag" => tag.to_string(),
+      "outcome" => outcome,
+    )
+    .increment(1);
+  }
+}
+
+pub(crate) fn latency(latency: Duration) {
+  #[cfg(feature = "metrics")]
+  metrics::histogram!("openai_orch_request_duration_seconds")
+    .record(latency.as_secs_f64());
+}
+
+pub(crate) fn retried() {
+  #[cfg(feature = "metrics")]
+  metrics::counter!("openai_orch_retries_total").increment(1);
+}
+
+pub(crate) fn timed_out() {
+  #[cfg(feature = "metrics")]
+  metrics::counter!("openai_orch_timeouts_total").increment(1);
+}
+
+pub(crate) fn tokens(model: &str, usage: &Usage) {
+  #[cfg(feature = "metrics")]
+  {
+    let model = model.to_string();
+    metrics::counter!(
+      "openai_orch_tokens_total",
+      "model" => model.clone(),
+      "kind" => "prompt",
+    )
+    .increment(usage.prompt_tokens);
+    metrics::counter!(
+      "openai_orch_tokens_total",
+      "model" => model,
+      "kind" => "completion",
+    )
+    .increment(usage.completion_tokens);
+  }
+}
@@ -0,0 +1,189 @@
+//! Prompt templates with named placeholders, for generating many requests
+//! from the same prompt.
+//!
+//! ```rust
+//! use openai_orch::templates::ChatTemplate;
+//! use serde::Serialize;
+//!
+//! #[derive(Serialize)]
+//! struct Product {
+//!   name:     &'static str,
+//!   category: &'static str,
+//! }
+//!
+//! let template = ChatTemplate::new(
+//!   "You write product descriptions for a {category} store.",
+//!   "Describe the {name} in one sentence.",
+//!   Default::default(),
+//! )
+//! .unwrap();
+//! let requests = template
+//!   .requests([
+//!     Product { name: "trail shoe", category: "outdoor" },
+//!     Product { name: "tent", category: "outdoor" },
+//!   ])
+//!   .unwrap();
+//! assert_eq!(requests[1].user_prompt, "Describe the tent in one sentence.");
+//! ```
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+  chat::{siso::ChatSisoRequest, ChatModelParams},
+  error::Result,
+};
+
+/// The ways a template can fail to parse or render.
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+  /// A `{` at the given byte offset was never closed.
+  #[error("unclosed placeholder at {0}")]
+  Unclosed(usize),
+  /// A `}` at the given byte offset doesn't close a placeholder. Write `}}`
+  /// for a literal brace.
+  #[error("unmatched '}}' at {0}")]
+  Unmatched(usize),
+  /// The variables have no value for the named placeholder.
+  #[error("no value for placeholder '{0}'")]
+  MissingVariable(String),
+  /// The variables don't serialize to a map of names to values.
+  #[error("template variables must serialize to a map: {0}")]
+  InvalidVariables(String),
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+  Text(String),
+  Placeholder(String),
+}
+
+/// A prompt with named placeholders, written as `{name}`. Literal braces are
+/// written as `{{` and `}}`.
+///
+/// Templates are rendered with anything that serializes to a map, such as a
+/// struct or a `HashMap`. Strings are inserted as they are, and other values
+/// as JSON.
+#[derive(Clone, Debug)]
+pub struct PromptTemplate {
+  segments: Vec<Segment>,
+}
+
+impl PromptTemplate {
+  /// Parses a template, failing if its braces don't match up.
+  pub fn new(template: &str) -> Result<Self, TemplateError> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+      match c {
+        '{' if chars.next_if(|(_, c)| *c == '{').is_some() => text.push('{'),
+        '}' if chars.next_if(|(_, c)| *c == '}').is_some() => text.push('}'),
+        '{' => {
+          let mut name = String::new();
+          loop {
+            match chars.next() {
+              Some((_, '}')) => break,
+              Some((_, c)) => name.push(c),
+              None => return Err(TemplateError::Unclosed(i)),
+            }
+          }
+          if !text.is_empty() {
+            segments.push(Segment::Text(std::mem::take(&mut text)));
+          }
+          segments.push(Segment::Placeholder(name.trim().to_string()));
+        }
+        '}' => return Err(TemplateError::Unmatched(i)),
+        c => text.push(c),
+      }
+    }
+    if !text.is_empty() {
+      segments.push(Segment::Text(text));
+    }
+    Ok(Self { segments })
+  }
+
+  /// The names of the template's placeholders, in order of appearance.
+  pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+    self.segments.iter().filter_map(|segment| match segment {
+      Segment::Placeholder(name) => Some(name.as_str()),
+      Segment::Text(_) => None,
+    })
+  }
+
+  /// Renders the template with the given variables.
+  pub fn render(
+    &self,
+    variables: &impl Serialize,
+  ) -> Result<String, TemplateError> {
+    let variables = match serde_json::to_value(variables) {
+      Ok(Value::Object(variables)) => variables,
+      Ok(value) => {
+        return Err(TemplateError::InvalidVariables(format!("got {}", value)))
+      }
+      Err(err) => return Err(TemplateError::InvalidVariables(err.to_string())),
+    };
+
+    let mut rendered = String::new();
+    for segment in &self.segments {
+      match segment {
+        Segment::Text(text) => rendered.push_str(text),
+        Segment::Placeholder(name) => match variables.get(name) {
+          Some(Value::String(value)) => rendered.push_str(value),
+          Some(value) => rendered.push_str(&value.to_string()),
+          None => return Err(TemplateError::MissingVariable(name.clone())),
+        },
+      }
+    }
+    Ok(rendered)
+  }
+}
+
+/// A system prompt and a user prompt template, combined with the
+/// `ChatModelParams` to make `ChatSisoRequest`s with.
+#[derive(Clone)]
+pub struct ChatTemplate {
+  pub system:       PromptTemplate,
+  pub user:         PromptTemplate,
+  pub model_params: ChatModelParams,
+}
+
+impl ChatTemplate {
+  /// Parses the system and user prompt templates.
+  pub fn new(
+    system: &str,
+    user: &str,
+    model_params: ChatModelParams,
+  ) -> Result<Self, TemplateError> {
+    Ok(Self {
+      system: PromptTemplate::new(system)?,
+      user: PromptTemplate::new(user)?,
+      model_params,
+    })
+  }
+
+  /// Renders both templates with the given variables into a request.
+  pub fn request(
+    &self,
+    variables: &impl Serialize,
+  ) -> Result<ChatSisoRequest, TemplateError> {
+    Ok(ChatSisoRequest::new(
+      self.system.render(variables)?,
+      self.user.render(variables)?,
+      self.model_params.clone(),
+    ))
+  }
+
+  /// Renders a request for each set of variables, failing on the first set
+  /// which can't be rendered. The requests can then be given to
+  /// `Orchestrator::add_requests`.
+  pub fn requests<V: Serialize>(
+    &self,
+    variables: impl IntoIterator<Item = V>,
+  ) -> Result<Vec<ChatSisoRequest>, TemplateError> {
+    variables
+      .into_iter()
+      .map(|variables| self.request(&variables))
+      .collect()
+  }
+}
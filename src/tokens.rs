@@ -0,0 +1,65 @@
+//! Counting tokens with `tiktoken`, so that prompts which don't fit in a
+//! model's context window are caught before they are sent.
+//!
+//! Only available with the `tokens` feature.
+
+use tiktoken_rs::{
+  bpe_for_model, bpe_for_tokenizer, tokenizer::Tokenizer, CoreBPE,
+};
+
+use crate::{
+  chat::{image_tokens, ChatMessage},
+  models::Model,
+};
+
+/// The tokens added to every message by the chat format.
+const TOKENS_PER_MESSAGE: usize = 3;
+/// The tokens that prime the assistant's reply.
+const TOKENS_PER_REPLY: usize = 3;
+
+fn bpe(model: &str) -> &'static CoreBPE {
+  // models `tiktoken` doesn't know, such as those served by
+  // OpenAI-compatible servers, are counted with the newest encoding
+  bpe_for_model(model).unwrap_or_else(|_| {
+    bpe_for_tokenizer(Tokenizer::O200kBase)
+      .expect("o200k_base is built into tiktoken")
+  })
+}
+
+/// Returns the number of tokens `text` is made of for the given model.
+pub fn count_tokens(model: &str, text: &str) -> usize {
+  bpe(model).encode_with_special_tokens(text).len()
+}
+
+/// Returns the IDs of the tokens `text` is made of for the given model.
+pub fn encode(model: &str, text: &str) -> Vec<u32> {
+  bpe(model).encode_with_special_tokens(text)
+}
+
+/// Returns the number of prompt tokens the given messages use for the given
+/// model, including the overhead of the chat format. Attached images are
+/// estimated, since their size isn't known without decoding them.
+pub fn count_message_tokens(model: &str, messages: &[ChatMessage]) -> usize {
+  let bpe = bpe(model);
+  let count = |text: &str| bpe.encode_with_special_tokens(text).len();
+  let message_tokens: usize = messages
+    .iter()
+    .map(|message| {
+      let tool_call_tokens: usize = message
+        .tool_calls
+        .iter()
+        .map(|call| count(&call.name) + count(&call.arguments.to_string()))
+        .sum();
+      TOKENS_PER_MESSAGE + count(&message.content) + tool_call_tokens
+    })
+    .sum();
+  message_tokens + image_tokens(messages) as usize + TOKENS_PER_REPLY
+}
+
+/// Returns the size of the given model's context window, if it is known, as
+/// `Model::context_window`.
+pub fn context_window(model: &str) -> Option<usize> {
+  Model::from(model)
+    .context_window()
+    .map(|size| size as usize)
+}
@@ -0,0 +1,7 @@
+//! Logging through `tracing` when the `tracing` feature is enabled, and
+//! through `log` otherwise.
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error};
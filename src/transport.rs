@@ -0,0 +1,282 @@
+//! The calls to the API made by the requests in this crate, behind a trait so
+//! that they can be sent some other way.
+//!
+//! By default an `Orchestrator` makes its calls with `async-openai`, through
+//! `OpenAITransport`. Give it another `Transport` with
+//! `Orchestrator::with_transport` to use a different HTTP stack, to serve a
+//! backend with a different API, or to answer requests in tests without
+//! sending them. The transport is used for every attempt, so the retry,
+//! timeout, and fallback policies apply to it as they would to the API.
+//!
+//! ```rust
+//! use async_openai::types::{
+//!   CreateChatCompletionRequest, CreateChatCompletionResponse,
+//! };
+//! use async_trait::async_trait;
+//! use openai_orch::{
+//!   error::{OrchError, Result},
+//!   keys::Keys,
+//!   transport::Transport,
+//! };
+//!
+//! /// Fails every chat request as if the API were rate limiting it.
+//! struct Overloaded;
+//!
+//! #[async_trait]
+//! impl Transport for Overloaded {
+//!   async fn chat(
+//!     &self,
+//!     _keys: &Keys,
+//!     _request: CreateChatCompletionRequest,
+//!   ) -> Result<CreateChatCompletionResponse> {
+//!     Err(OrchError::RateLimited {
+//!       message:     "slow down".to_string(),
+//!       retry_after: None,
+//!     })
+//!   }
+//! }
+//! ```
+
+use std::{
+  future::Future,
+  pin::Pin,
+  sync::{Arc, LazyLock},
+};
+
+use async_openai::{
+  config::Config,
+  error::OpenAIError,
+  types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest,
+    CreateChatCompletionResponse, CreateCompletionRequest,
+    CreateCompletionResponse, CreateEmbeddingRequest, CreateEmbeddingResponse,
+    CreateImageRequest, CreateModerationRequest, CreateModerationResponse,
+    CreateTranscriptionRequest, EncodingFormat, ImagesResponse,
+  },
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+  error::{OrchError, Result},
+  keys::Keys,
+  scope,
+  utils::{client_config, get_openai_client},
+};
+
+/// Makes the calls to the API, one call per attempt.
+///
+/// Each method makes a single call with the given keys and returns the
+/// response, or the error to give to the `RetryPolicy`. Methods which aren't
+/// implemented fail with `OrchError::Unsupported`.
+#[async_trait]
+pub trait Transport: Send + Sync {
+  /// Creates a chat completion.
+  async fn chat(
+    &self,
+    _keys: &Keys,
+    _request: CreateChatCompletionRequest,
+  ) -> Result<CreateChatCompletionResponse> {
+    Err(OrchError::Unsupported("chat completions"))
+  }
+
+  /// Creates a chat completion, streaming it in chunks.
+  async fn chat_stream(
+    &self,
+    _keys: &Keys,
+    _request: CreateChatCompletionRequest,
+  ) -> Result<ChatCompletionResponseStream> {
+    Err(OrchError::Unsupported("streamed chat completions"))
+  }
+
+  /// Creates a legacy completion.
+  async fn completion(
+    &self,
+    _keys: &Keys,
+    _request: CreateCompletionRequest,
+  ) -> Result<CreateCompletionResponse> {
+    Err(OrchError::Unsupported("completions"))
+  }
+
+  /// Creates embeddings.
+  async fn embedding(
+    &self,
+    _keys: &Keys,
+    _request: CreateEmbeddingRequest,
+  ) -> Result<CreateEmbeddingResponse> {
+    Err(OrchError::Unsupported("embeddings"))
+  }
+
+  /// Classifies text with the Moderation API.
+  async fn moderation(
+    &self,
+    _keys: &Keys,
+    _request: CreateModerationRequest,
+  ) -> Result<CreateModerationResponse> {
+    Err(OrchError::Unsupported("moderations"))
+  }
+
+  /// Generates images.
+  async fn image(
+    &self,
+    _keys: &Keys,
+    _request: CreateImageRequest,
+  ) -> Result<ImagesResponse> {
+    Err(OrchError::Unsupported("images"))
+  }
+
+  /// Transcribes audio, returning the body of the response in the format the
+  /// request asks for.
+  async fn transcription(
+    &self,
+    _keys: &Keys,
+    _request: CreateTranscriptionRequest,
+  ) -> Result<Vec<u8>> {
+    Err(OrchError::Unsupported("transcriptions"))
+  }
+}
+
+/// Makes a single call with a `Transport`, for transports which wrap another
+/// and handle each of its calls the same way, such as `Cassette` and
+/// `LoggingTransport`.
+pub(crate) type Call<Req, Res> =
+  for<'a> fn(
+    &'a dyn Transport,
+    &'a Keys,
+    Req,
+  ) -> Pin<Box<dyn Future<Output = Result<Res>> + Send + 'a>>;
+
+/// The default `Transport`, which calls the OpenAI API, or whichever
+/// `Provider` and base URL the keys select.
+///
+/// Calls are made with the keys' `reqwest` client, so that the status and
+/// headers of failed responses, such as how long a rate limit asks to wait,
+/// reach the `RetryPolicy`. Streamed chat completions and transcriptions are
+/// made with `async-openai`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenAITransport;
+
+/// The client for keys which don't bring their own.
+static HTTP_CLIENT: LazyLock<reqwest::Client> =
+  LazyLock::new(reqwest::Client::new);
+
+/// Posts `request` as JSON to the API's `path` and parses the response.
+async fn post<Req, Res>(keys: &Keys, path: &str, request: &Req) -> Result<Res>
+where
+  Req: Serialize + Sync,
+  Res: DeserializeOwned,
+{
+  let config = client_config(keys);
+  let response = keys
+    .http_client
+    .as_ref()
+    .unwrap_or(&HTTP_CLIENT)
+    .post(config.url(path))
+    .query(&config.query())
+    .headers(config.headers())
+    .json(request)
+    .send()
+    .await
+    .map_err(OpenAIError::Reqwest)?;
+
+  let status = response.status();
+  let headers = response.headers().clone();
+  let body = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+  if !status.is_success() {
+    return Err(OrchError::from_response(status.as_u16(), &headers, &body));
+  }
+  serde_json::from_slice(&body)
+    .map_err(|err| OrchError::InvalidResponse(err.to_string()))
+}
+
+/// Fails the way `async-openai` does for requests it can't make without
+/// streaming or decoding the response.
+fn invalid_argument(message: &str) -> OrchError {
+  OrchError::OpenAI(OpenAIError::InvalidArgument(message.to_string()))
+}
+
+#[async_trait]
+impl Transport for OpenAITransport {
+  async fn chat(
+    &self,
+    keys: &Keys,
+    request: CreateChatCompletionRequest,
+  ) -> Result<CreateChatCompletionResponse> {
+    if request.stream == Some(true) {
+      return Err(invalid_argument(
+        "When stream is true, use Transport::chat_stream",
+      ));
+    }
+    post(keys, "/chat/completions", &request).await
+  }
+
+  async fn chat_stream(
+    &self,
+    keys: &Keys,
+    request: CreateChatCompletionRequest,
+  ) -> Result<ChatCompletionResponseStream> {
+    Ok(
+      get_openai_client(keys)
+        .chat()
+        .create_stream(request)
+        .await?,
+    )
+  }
+
+  async fn completion(
+    &self,
+    keys: &Keys,
+    request: CreateCompletionRequest,
+  ) -> Result<CreateCompletionResponse> {
+    if request.stream == Some(true) {
+      return Err(invalid_argument("streamed completions are not supported"));
+    }
+    post(keys, "/completions", &request).await
+  }
+
+  async fn embedding(
+    &self,
+    keys: &Keys,
+    request: CreateEmbeddingRequest,
+  ) -> Result<CreateEmbeddingResponse> {
+    if matches!(request.encoding_format, Some(EncodingFormat::Base64)) {
+      return Err(invalid_argument(
+        "base64 encoded embeddings are not supported",
+      ));
+    }
+    post(keys, "/embeddings", &request).await
+  }
+
+  async fn moderation(
+    &self,
+    keys: &Keys,
+    request: CreateModerationRequest,
+  ) -> Result<CreateModerationResponse> {
+    post(keys, "/moderations", &request).await
+  }
+
+  async fn image(
+    &self,
+    keys: &Keys,
+    request: CreateImageRequest,
+  ) -> Result<ImagesResponse> {
+    post(keys, "/images/generations", &request).await
+  }
+
+  async fn transcription(
+    &self,
+    keys: &Keys,
+    request: CreateTranscriptionRequest,
+  ) -> Result<Vec<u8>> {
+    let client = get_openai_client(keys);
+    Ok(client.audio().transcribe_raw(request).await?.to_vec())
+  }
+}
+
+/// Returns the transport of the `Orchestrator` sending the current request,
+/// or an `OpenAITransport` outside of one. Custom `OrchRequest`s can use it
+/// to make their calls the same way as the requests in this crate.
+pub fn current() -> Arc<dyn Transport> {
+  scope::with_current(|scope| scope.transport.clone())
+    .unwrap_or_else(|| Arc::new(OpenAITransport))
+}
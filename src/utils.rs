@@ -1,14 +1,248 @@
 //! Utilites for use when writing custom requests.
 
-use async_openai::{config::OpenAIConfig, Client as OpenAIClient};
+use std::future::Future;
 
-use crate::keys::Keys;
+use async_openai::{
+  config::{AzureConfig, Config, OpenAIConfig},
+  Client as OpenAIClient,
+};
+use reqwest::header::HeaderMap;
+use secrecy::SecretString;
+use tokio::time::{sleep, timeout, Duration, Instant};
 
-pub(crate) fn get_openai_client(keys: &Keys) -> OpenAIClient<OpenAIConfig> {
-  let config = OpenAIConfig::new().with_api_key(&keys.openai_api_key);
-  let config = match &keys.openai_org_id {
-    Some(openai_org_id) => config.with_org_id(openai_org_id),
-    None => config,
+use crate::{
+  error::{OrchError, Result},
+  hooks::OrchestratorHooks,
+  keys::{Keys, Provider},
+  policies::{Policies, RetryDecision, RetryPolicy},
+  scope, telemetry,
+  trace::{debug, error},
+};
+
+/// The configuration for whichever `Provider` the `Keys` select.
+#[derive(Clone, Debug)]
+pub(crate) enum ClientConfig {
+  OpenAI(OpenAIConfig),
+  Azure(AzureConfig),
+}
+
+impl Config for ClientConfig {
+  fn headers(&self) -> HeaderMap {
+    match self {
+      ClientConfig::OpenAI(config) => config.headers(),
+      ClientConfig::Azure(config) => config.headers(),
+    }
+  }
+
+  fn url(&self, path: &str) -> String {
+    match self {
+      ClientConfig::OpenAI(config) => config.url(path),
+      ClientConfig::Azure(config) => config.url(path),
+    }
+  }
+
+  fn query(&self) -> Vec<(&str, &str)> {
+    match self {
+      ClientConfig::OpenAI(config) => config.query(),
+      ClientConfig::Azure(config) => config.query(),
+    }
+  }
+
+  fn api_base(&self) -> &str {
+    match self {
+      ClientConfig::OpenAI(config) => config.api_base(),
+      ClientConfig::Azure(config) => config.api_base(),
+    }
+  }
+
+  fn api_key(&self) -> &SecretString {
+    match self {
+      ClientConfig::OpenAI(config) => config.api_key(),
+      ClientConfig::Azure(config) => config.api_key(),
+    }
+  }
+}
+
+/// Returns the configuration for calling the API with `keys`.
+pub(crate) fn client_config(keys: &Keys) -> ClientConfig {
+  match &keys.provider {
+    Provider::OpenAI => {
+      let config = OpenAIConfig::new().with_api_key(&keys.openai_api_key);
+      let config = match &keys.openai_org_id {
+        Some(openai_org_id) => config.with_org_id(openai_org_id),
+        None => config,
+      };
+      let config = match &keys.base_url {
+        Some(base_url) => config.with_api_base(base_url),
+        None => config,
+      };
+      ClientConfig::OpenAI(config)
+    }
+    Provider::Azure {
+      endpoint,
+      deployment,
+      api_version,
+    } => ClientConfig::Azure(
+      AzureConfig::new()
+        .with_api_key(&keys.openai_api_key)
+        .with_api_base(endpoint)
+        .with_deployment_id(deployment)
+        .with_api_version(api_version),
+    ),
+  }
+}
+
+pub(crate) fn get_openai_client(keys: &Keys) -> OpenAIClient<ClientConfig> {
+  // retries are left to the `RetryPolicy`, so that rate limits and server
+  // errors are visible to it and to the `ConcurrencyPolicy`
+  let no_backoff = backoff::ExponentialBackoff {
+    max_elapsed_time: Some(Duration::ZERO),
+    ..Default::default()
   };
-  OpenAIClient::<OpenAIConfig>::with_config(config)
+  let client =
+    OpenAIClient::with_config(client_config(keys)).with_backoff(no_backoff);
+  match &keys.http_client {
+    Some(http_client) => client.with_http_client(http_client.clone()),
+    None => client,
+  }
+}
+
+/// The error returned once a request stops retrying: the error itself if it
+/// isn't retryable, or `OrchError::MaxRetriesExceeded` wrapping it.
+fn give_up(id: u64, attempts: u32, err: OrchError) -> OrchError {
+  if !err.is_retryable() {
+    error!("request {} failed permanently: {}", id, err);
+    return err;
+  }
+  error!("request {} reached max retry", id);
+  OrchError::MaxRetriesExceeded {
+    attempts,
+    last: Box::new(err),
+  }
+}
+
+/// Runs `attempt` until it succeeds, following the given policies.
+///
+/// Each attempt is limited to `timeout_duration`. When an attempt fails with a
+/// retryable error or times out, the `RetryPolicy` decides whether to try
+/// again; once it refuses, the last error is returned. Errors which are not
+/// retryable are returned straight away, unless the policy is a custom
+/// `RetryStrategy`, which decides about every error. If the API said how long
+/// to wait, that delay is used instead of a built-in policy's.
+///
+/// Within the `Orchestrator`, the outcome of every attempt is fed to the
+/// `AdaptiveConcurrency` limit, so that rate limits shrink it even when the
+/// request goes on to succeed.
+pub async fn with_retries<T, F, Fut>(
+  policies: &Policies,
+  timeout_duration: Duration,
+  id: u64,
+  mut attempt: F,
+) -> Result<T>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T>>,
+{
+  let mut state = policies.retry_policy.start();
+
+  // continue trying until we get a response or we reach max retry
+  loop {
+    let started_at = Instant::now();
+    let attempts = state.attempts();
+    let future = timeout(timeout_duration, attempt());
+    #[cfg(feature = "tracing")]
+    let future = tracing::Instrument::instrument(
+      future,
+      tracing::debug_span!("attempt", attempt = attempts),
+    );
+
+    let err = match future.await {
+      Ok(Ok(response)) => {
+        scope::with_current(|scope| scope.record_outcome(false));
+        debug!(
+          "got response for {} in {}",
+          id,
+          started_at.elapsed().as_secs_f32()
+        );
+        return Ok(response);
+      }
+      Ok(Err(err)) => {
+        debug!("request {} failed: {}", id, err);
+        // every overloaded attempt counts, so that the limit shrinks while
+        // requests are still retrying rather than once they give up
+        if err.is_overloaded() {
+          scope::with_current(|scope| scope.record_outcome(true));
+        }
+        err
+      }
+      Err(_) => {
+        debug!(
+          "request {} timed out after {}s",
+          id,
+          timeout_duration.as_secs_f32()
+        );
+        telemetry::timed_out();
+        OrchError::Timeout(timeout_duration)
+      }
+    };
+
+    let max_delay = state.policy().max_delay();
+    let decision = match state.policy() {
+      RetryPolicy::Custom(strategy) => {
+        let decision = strategy.on_failure(attempts, &err).await;
+        if let RetryDecision::Retry(delay) = decision {
+          state.record_retry(delay);
+        }
+        decision
+      }
+      _ if !err.is_retryable() => RetryDecision::GiveUp,
+      // the API knows better than the blind schedule when to try again, but
+      // not to wait longer than the policy allows
+      _ => match state.next_delay() {
+        Some(delay) => {
+          let retry_after = err.retry_after().map(|retry_after| {
+            max_delay
+              .map_or(retry_after, |max_delay| retry_after.min(max_delay))
+          });
+          RetryDecision::Retry(retry_after.unwrap_or(delay))
+        }
+        None => RetryDecision::GiveUp,
+      },
+    };
+    let within_budget = || {
+      scope::with_current(|scope| {
+        scope.retry_budget.try_retry(&policies.retry_budget_policy)
+      })
+      .unwrap_or(true)
+    };
+    let delay = match decision {
+      RetryDecision::Retry(delay) if within_budget() => delay,
+      RetryDecision::Retry(_) => {
+        error!("request {} is out of retry budget", id);
+        return Err(OrchError::RetryBudgetExhausted {
+          attempts,
+          last: Box::new(err),
+        });
+      }
+      RetryDecision::SwitchKey => {
+        debug!("request {} is switching keys", id);
+        scope::with_current(|scope| {
+          scope.update_stats(|stats| stats.switch_key = true)
+        });
+        return Err(give_up(id, attempts, err));
+      }
+      RetryDecision::GiveUp => return Err(give_up(id, attempts, err)),
+    };
+    telemetry::retried();
+    scope::with_current(|scope| {
+      scope.update_stats(|stats| stats.retries += 1);
+      #[cfg(feature = "tracing")]
+      tracing::Span::current().record("retries", scope.stats().retries);
+      scope.hooks.on_retry(id, attempts, &err, delay);
+    });
+    if !delay.is_zero() {
+      debug!("retrying request {} in {}s", id, delay.as_secs_f32());
+      sleep(delay).await;
+    }
+  }
 }